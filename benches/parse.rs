@@ -1,12 +1,14 @@
 use std::cell::RefCell;
-use std::rc::Rc;
 
 use ahash::{HashMap, HashMapExt};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use ipfixrw::parse_ipfix_message;
 use pprof::criterion::PProfProfiler;
 
+use ipfixrw::borrowed::BorrowedMessage;
+use ipfixrw::compact::CompactMessage;
 use ipfixrw::information_elements::get_default_formatter;
+use ipfixrw::parser::ParseOptions;
 
 fn parse_data_with_template(c: &mut Criterion) {
     // contains templates 500, 999, 501
@@ -15,22 +17,53 @@ fn parse_data_with_template(c: &mut Criterion) {
     // contains data sets for templates 999, 500, 999
     let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
 
-    let templates = Rc::new(RefCell::new(HashMap::new()));
-    let formatter = Rc::new(get_default_formatter());
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
 
     // parse the template so parsing data can be done
-    let _ = parse_ipfix_message(
-        black_box(template_bytes),
-        templates.clone(),
-        formatter.clone(),
-    )
-    .unwrap();
+    let _ = parse_ipfix_message(black_box(template_bytes), &templates, &formatter).unwrap();
 
     c.bench_function("data_with_template", |b| {
         b.iter(|| {
-            let _ =
-                parse_ipfix_message(black_box(data_bytes), templates.clone(), formatter.clone())
-                    .unwrap();
+            let _ = parse_ipfix_message(black_box(data_bytes), &templates, &formatter).unwrap();
+        })
+    });
+
+    c.bench_function("data_with_template_compact", |b| {
+        b.iter(|| {
+            let _ = CompactMessage::parse(
+                black_box(data_bytes),
+                &templates,
+                &formatter,
+                ParseOptions::default(),
+            )
+            .unwrap();
+        })
+    });
+
+    c.bench_function("data_with_template_lazy", |b| {
+        b.iter(|| {
+            let message =
+                BorrowedMessage::parse(black_box(data_bytes), &templates, &formatter).unwrap();
+            for data_set in message.iter_data_sets() {
+                for record in data_set.lazy_records() {
+                    let record = record.unwrap();
+                    black_box(record.get("sourceIPv4Address"));
+                    black_box(record.get("octetDeltaCount"));
+                }
+            }
+        })
+    });
+
+    c.bench_function("data_with_template_borrowed", |b| {
+        b.iter(|| {
+            let message =
+                BorrowedMessage::parse(black_box(data_bytes), &templates, &formatter).unwrap();
+            for data_set in message.iter_data_sets() {
+                for record in data_set.records() {
+                    black_box(record.unwrap());
+                }
+            }
         })
     });
 }
@@ -39,18 +72,70 @@ fn parse_template(c: &mut Criterion) {
     // contains templates 500, 999, 501
     let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
 
-    let templates = Rc::new(RefCell::new(HashMap::new()));
-    let formatter = Rc::new(get_default_formatter());
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
 
     // parse the template so parsing data can be done
     c.bench_function("template", |b| {
         b.iter(|| {
-            let _ = parse_ipfix_message(
-                black_box(template_bytes),
-                templates.clone(),
-                formatter.clone(),
-            )
-            .unwrap();
+            let _ = parse_ipfix_message(black_box(template_bytes), &templates, &formatter).unwrap();
+        })
+    });
+}
+
+/// A message with a template of a few fixed size fields, and one with
+/// many data sets of a couple of records with that template
+fn small_template_messages() -> (Vec<u8>, Vec<u8>) {
+    fn message(sets: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let length: usize = 16 + sets.iter().map(|(_, set)| 4 + set.len()).sum::<usize>();
+        let mut bytes = vec![0, 10];
+        bytes.extend((length as u16).to_be_bytes());
+        bytes.extend([0; 12]);
+        for (set_id, set) in sets {
+            bytes.extend(set_id.to_be_bytes());
+            bytes.extend(((4 + set.len()) as u16).to_be_bytes());
+            bytes.extend(set);
+        }
+        bytes
+    }
+
+    // sourceIPv4Address, destinationIPv4Address, destinationTransportPort,
+    // octetDeltaCount
+    let fields: [(u16, u16); 4] = [(8, 4), (12, 4), (11, 2), (1, 8)];
+    let mut template = vec![];
+    template.extend(256u16.to_be_bytes());
+    template.extend((fields.len() as u16).to_be_bytes());
+    for (id, length) in fields {
+        template.extend(id.to_be_bytes());
+        template.extend(length.to_be_bytes());
+    }
+    let record = [[10, 0, 0, 1], [10, 0, 0, 2], [0, 80, 0, 0], [0, 0, 0, 0]].concat();
+    let record = [&record[..], &[0, 0, 0, 0, 0, 0, 0, 100][..]].concat();
+    let data_sets = vec![(256, [&record[..], &record[..]].concat()); 20];
+    (message(&[(2, template)]), message(&data_sets))
+}
+
+fn parse_data_with_small_template(c: &mut Criterion) {
+    let (template_bytes, data_bytes) = small_template_messages();
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let _ = parse_ipfix_message(&template_bytes, &templates, &formatter).unwrap();
+
+    c.bench_function("data_with_small_template", |b| {
+        b.iter(|| {
+            let _ = parse_ipfix_message(black_box(&data_bytes), &templates, &formatter).unwrap();
+        })
+    });
+
+    c.bench_function("data_with_small_template_borrowed", |b| {
+        b.iter(|| {
+            let message =
+                BorrowedMessage::parse(black_box(&data_bytes), &templates, &formatter).unwrap();
+            for data_set in message.iter_data_sets() {
+                for record in data_set.records() {
+                    black_box(record.unwrap());
+                }
+            }
         })
     });
 }
@@ -67,6 +152,6 @@ fn profiler() -> PProfProfiler<'static, 'static> {
 criterion_group! {
     name = benches;
     config = Criterion::default().with_profiler(profiler());
-    targets = parse_template, parse_data_with_template
+    targets = parse_template, parse_data_with_template, parse_data_with_small_template
 }
 criterion_main!(benches);
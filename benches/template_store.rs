@@ -0,0 +1,54 @@
+//! Compare template store backends when parsing from multiple threads
+
+use std::sync::RwLock;
+
+use ahash::{HashMap, HashMapExt};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use dashmap::DashMap;
+use ipfixrw::information_elements::{get_default_formatter, Formatter};
+use ipfixrw::parse_ipfix_message;
+use ipfixrw::template_store::TemplateStorage;
+
+const THREADS: usize = 4;
+const MESSAGES_PER_THREAD: usize = 100;
+
+/// Parse the data sample `MESSAGES_PER_THREAD` times on each of `THREADS` threads
+fn parse_concurrently<T: TemplateStorage + Sync>(templates: &T, formatter: &Formatter) {
+    // contains data sets for templates 999, 500, 999
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    std::thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..MESSAGES_PER_THREAD {
+                    let _ =
+                        parse_ipfix_message(black_box(data_bytes), templates, formatter).unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn concurrent_templates(c: &mut Criterion) {
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let formatter = get_default_formatter();
+
+    let rwlock_templates = RwLock::new(HashMap::new());
+    parse_ipfix_message(template_bytes, &rwlock_templates, &formatter).unwrap();
+
+    let dashmap_templates = DashMap::new();
+    parse_ipfix_message(template_bytes, &dashmap_templates, &formatter).unwrap();
+
+    let mut group = c.benchmark_group("concurrent_templates");
+    group.bench_function("rwlock", |b| {
+        b.iter(|| parse_concurrently(&rwlock_templates, &formatter))
+    });
+    group.bench_function("dashmap", |b| {
+        b.iter(|| parse_concurrently(&dashmap_templates, &formatter))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_templates);
+criterion_main!(benches);
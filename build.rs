@@ -1,5 +1,7 @@
 //! Build the information elements hashmap from the official iana IPFIX Entities csv
 //! <https://www.iana.org/assignments/ipfix/ipfix.xhtml>
+//! and the protocol identifier enum from the iana Protocol Numbers csv
+//! <https://www.iana.org/assignments/protocol-numbers/protocol-numbers.xhtml>
 
 use std::env;
 use std::fs::File;
@@ -8,10 +10,18 @@ use std::path::Path;
 
 fn main() {
     println!("cargo:rerun-if-changed=resources/ipfix-information-elements.csv");
+    println!("cargo:rerun-if-changed=resources/protocol-numbers.csv");
     println!("cargo:rerun-if-changed=build.rs");
 
     let out_dir = env::var_os("OUT_DIR").unwrap();
-    let dest_path = Path::new(&out_dir).join("ipfix-information-elements.rs");
+    build_information_elements(Path::new(&out_dir));
+    build_protocol_numbers(Path::new(&out_dir));
+    build_subregistries(Path::new(&out_dir));
+    build_enterprise_elements(Path::new(&out_dir));
+}
+
+fn build_information_elements(out_dir: &Path) {
+    let dest_path = out_dir.join("ipfix-information-elements.rs");
     let mut out_file = File::create(dest_path).unwrap();
 
     let in_file = File::open("resources/ipfix-information-elements.csv").unwrap();
@@ -24,56 +34,370 @@ fn main() {
         .iter()
         .position(|x| x == "Abstract Data Type")
         .unwrap();
-
-    write!(
-        out_file,
-        "/// default information element types for no enterprise / enterprise number 0\n\
-         pub fn get_default_formatter() -> Formatter {{\n\
-             formatter! {{\n"
-    )
-    .unwrap();
+    let semantics_pos = headers
+        .iter()
+        .position(|x| x == "Data Type Semantics")
+        .unwrap();
+    let mut semantics = Vec::new();
+    let mut elements = phf_codegen::Map::new();
 
     for result in csv_reader.records() {
         let record = result.unwrap();
         let element_id = &record[element_id_pos];
         let name = &record[name_pos];
         let abstract_data_type = &record[abstract_data_type_pos];
-        let data_type = match abstract_data_type {
-            "octetArray" => "Bytes",
-            "unsigned8" => "UnsignedInt",
-            "unsigned16" => "UnsignedInt",
-            "unsigned32" => "UnsignedInt",
-            "unsigned64" => "UnsignedInt",
-            "signed8" => "SignedInt",
-            "signed16" => "SignedInt",
-            "signed32" => "SignedInt",
-            "signed64" => "SignedInt",
-            "float32" => "Float",
-            "float64" => "Float",
-            "boolean" => "Bool",
-            "macAddress" => "MacAddress",
-            "string" => "String",
-            "dateTimeSeconds" => "DateTimeSeconds",
-            "dateTimeMilliseconds" => "DateTimeMilliseconds",
-            "dateTimeMicroseconds" => "DateTimeMicroseconds",
-            "dateTimeNanoseconds" => "DateTimeNanoseconds",
-            "ipv4Address" => "Ipv4Addr",
-            "ipv6Address" => "Ipv6Addr",
-            // TODO: support for lists [RFC6313]
-            "basicList" => continue,
-            "subTemplateList" => continue,
-            "subTemplateMultiList" => continue,
-            "" => continue,
-            d => panic!("Unknown abstract data type {d}!"),
+        let semantic = match &record[semantics_pos] {
+            "quantity" => Some("Quantity"),
+            "totalCounter" => Some("TotalCounter"),
+            "deltaCounter" => Some("DeltaCounter"),
+            "identifier" => Some("Identifier"),
+            "flags" => Some("Flags"),
+            "list" => Some("List"),
+            "snmpCounter" => Some("SnmpCounter"),
+            "snmpGauge" => Some("SnmpGauge"),
+            "default" | "" => None,
+            s => panic!("Unknown data type semantics {s}!"),
+        };
+        if let Some(semantic) = semantic {
+            semantics.push(format!(
+                "        ((0, {element_id}), DataTypeSemantics::{semantic}),"
+            ));
+        }
+        let Some(data_type) = data_type(abstract_data_type) else {
+            continue;
         };
 
+        elements.entry(
+            element_id.parse::<u16>().unwrap(),
+            &format!("(Cow::Borrowed(\"{name}\"), DataRecordType::{data_type})"),
+        );
+    }
+
+    writeln!(
+        out_file,
+        "/// default information element names and types for no enterprise /\n\
+         /// enterprise number 0, by information element identifier\n\
+         static DEFAULT_INFORMATION_ELEMENTS: phf::Map<u16, (Cow<str>, DataRecordType)> = {};\n",
+        elements.build()
+    )
+    .unwrap();
+
+    writeln!(
+        out_file,
+        "/// default data type semantics for no enterprise / enterprise number 0\n\
+         pub fn get_default_semantics() -> Semantics {{\n    \
+             HashMap::from_iter([\n{}\n    ])\n}}",
+        semantics.join("\n")
+    )
+    .unwrap();
+}
+
+/// The `DataRecordType` variant for an abstract data type, or None for
+/// those that aren't supported
+fn data_type(abstract_data_type: &str) -> Option<&'static str> {
+    Some(match abstract_data_type {
+        "octetArray" => "Bytes",
+        "unsigned8" => "UnsignedInt",
+        "unsigned16" => "UnsignedInt",
+        "unsigned32" => "UnsignedInt",
+        "unsigned64" => "UnsignedInt",
+        "signed8" => "SignedInt",
+        "signed16" => "SignedInt",
+        "signed32" => "SignedInt",
+        "signed64" => "SignedInt",
+        "float32" => "Float",
+        "float64" => "Float",
+        "boolean" => "Bool",
+        "macAddress" => "MacAddress",
+        "string" => "String",
+        "dateTimeSeconds" => "DateTimeSeconds",
+        "dateTimeMilliseconds" => "DateTimeMilliseconds",
+        "dateTimeMicroseconds" => "DateTimeMicroseconds",
+        "dateTimeNanoseconds" => "DateTimeNanoseconds",
+        "ipv4Address" => "Ipv4Addr",
+        "ipv6Address" => "Ipv6Addr",
+        // TODO: support for lists [RFC6313]
+        "basicList" => return None,
+        "subTemplateList" => return None,
+        "subTemplateMultiList" => return None,
+        "" => return None,
+        d => panic!("Unknown abstract data type {d}!"),
+    })
+}
+
+/// Enterprise information element registries: (csv, generated file,
+/// static name). The csvs have `ElementID`, `Name`, `Abstract Data Type`
+/// and `Description` columns
+const ENTERPRISE_REGISTRIES: &[(&str, &str, &str)] = &[
+    (
+        "resources/cisco-information-elements.csv",
+        "cisco-information-elements.rs",
+        "ELEMENTS",
+    ),
+    (
+        "resources/cisco-nsel-fields.csv",
+        "cisco-nsel-fields.rs",
+        "NSEL_ELEMENTS",
+    ),
+    (
+        "resources/juniper-information-elements.csv",
+        "juniper-information-elements.rs",
+        "ELEMENTS",
+    ),
+    (
+        "resources/ntop-information-elements.csv",
+        "ntop-information-elements.rs",
+        "ELEMENTS",
+    ),
+    (
+        "resources/vmware-information-elements.csv",
+        "vmware-information-elements.rs",
+        "ELEMENTS",
+    ),
+];
+
+fn build_enterprise_elements(out_dir: &Path) {
+    for (path, file_name, static_name) in ENTERPRISE_REGISTRIES {
+        println!("cargo:rerun-if-changed={path}");
+        let mut out_file = File::create(out_dir.join(file_name)).unwrap();
+        let mut csv_reader = csv::Reader::from_reader(File::open(path).unwrap());
+
+        let headers = csv_reader.headers().unwrap();
+        let element_id_pos = headers.iter().position(|x| x == "ElementID").unwrap();
+        let name_pos = headers.iter().position(|x| x == "Name").unwrap();
+        let abstract_data_type_pos = headers
+            .iter()
+            .position(|x| x == "Abstract Data Type")
+            .unwrap();
+        let mut elements = phf_codegen::Map::new();
+        for result in csv_reader.records() {
+            let record = result.unwrap();
+            let name = &record[name_pos];
+            let Some(data_type) = data_type(&record[abstract_data_type_pos]) else {
+                continue;
+            };
+            elements.entry(
+                record[element_id_pos].parse::<u16>().unwrap(),
+                &format!("(\"{name}\", DataRecordType::{data_type})"),
+            );
+        }
+
         writeln!(
             out_file,
-            "        (0, {element_id}) => (\"{name}\", {data_type}), // {abstract_data_type}"
+            "/// information element names and types, by information element identifier\n\
+             static {static_name}: phf::Map<u16, (&str, DataRecordType)> = {};",
+            elements.build()
         )
         .unwrap();
-        //out_file.write("");
     }
+}
+
+/// Convert an iana keyword like "IPv6-ICMP" into a variant name like "Ipv6Icmp"
+fn variant_name(keyword: &str) -> String {
+    // drop notes like "(deprecated)" or "(Historic)"
+    let keyword = match keyword.find(" (") {
+        Some(i) if keyword.ends_with(')') => &keyword[..i],
+        _ => keyword,
+    };
+    let keyword = keyword.replace("++", " Plus Plus");
+    let mut name: String = keyword
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first)
+                .chain(chars.map(|c| c.to_ascii_lowercase()))
+                .collect::<String>()
+        })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert_str(0, "Protocol");
+    }
+    name
+}
+
+/// Write an enum with a variant per `(value, name, description)`, a
+/// `fallback(repr)` variant for everything else, and conversions to and
+/// from `repr`, the unsigned type of the information element
+fn write_enum(
+    out_file: &mut File,
+    doc: &str,
+    enum_name: &str,
+    fallback: &str,
+    repr: &str,
+    variants: &[(u16, String, String)],
+) {
+    writeln!(
+        out_file,
+        "{doc}\n\
+         #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]\n\
+         pub enum {enum_name} {{"
+    )
+    .unwrap();
+    for (value, name, description) in variants {
+        writeln!(out_file, "    /// {description} ({value})\n    {name},").unwrap();
+    }
+    writeln!(
+        out_file,
+        "    /// A value without a name in the registry\n    {fallback}({repr}),\n}}\n"
+    )
+    .unwrap();
+
+    writeln!(
+        out_file,
+        "impl From<{repr}> for {enum_name} {{\n    \
+             fn from(value: {repr}) -> Self {{\n        \
+                 match value {{"
+    )
+    .unwrap();
+    for (value, name, _) in variants {
+        writeln!(out_file, "            {value} => Self::{name},").unwrap();
+    }
+    writeln!(
+        out_file,
+        "            other => Self::{fallback}(other),\n        }}\n    }}\n}}\n"
+    )
+    .unwrap();
+
+    writeln!(
+        out_file,
+        "impl From<{enum_name}> for {repr} {{\n    \
+             fn from(value: {enum_name}) -> Self {{\n        \
+                 match value {{"
+    )
+    .unwrap();
+    for (value, name, _) in variants {
+        writeln!(out_file, "            {enum_name}::{name} => {value},").unwrap();
+    }
+    writeln!(
+        out_file,
+        "            {enum_name}::{fallback}(other) => other,\n        }}\n    }}\n}}\n"
+    )
+    .unwrap();
+}
+
+/// Read `(value, name, description)` from an iana registry csv, skipping
+/// ranges, reserved and unassigned values, and later duplicates
+fn read_registry(
+    path: &str,
+    value_column: &str,
+    name_column: &str,
+    description_column: &str,
+) -> Vec<(u16, String, String)> {
+    let in_file = File::open(path).unwrap();
+    let mut csv_reader = csv::Reader::from_reader(in_file);
+
+    let headers = csv_reader.headers().unwrap();
+    let value_pos = headers.iter().position(|x| x == value_column).unwrap();
+    let name_pos = headers.iter().position(|x| x == name_column).unwrap();
+    let description_pos = headers
+        .iter()
+        .position(|x| x == description_column)
+        .unwrap();
+
+    let mut variants: Vec<(u16, String, String)> = Vec::new();
+    for result in csv_reader.records() {
+        let record = result.unwrap();
+        let name = &record[name_pos];
+        let Ok(value) = record[value_pos].parse::<u16>() else {
+            continue;
+        };
+        if name.is_empty() || name == "Reserved" || name == "Unassigned" {
+            continue;
+        }
+        if variants.iter().any(|(v, _, _)| *v == value) {
+            continue;
+        }
+        let description = match &record[description_pos] {
+            "" => name,
+            description => description,
+        };
+        variants.push((value, variant_name(name), description.to_string()));
+    }
+    variants
+}
 
-    write!(out_file, "    }}\n}}").unwrap();
+fn build_protocol_numbers(out_dir: &Path) {
+    let mut out_file = File::create(out_dir.join("protocol-numbers.rs")).unwrap();
+    let variants = read_registry(
+        "resources/protocol-numbers.csv",
+        "Decimal",
+        "Keyword",
+        "Protocol",
+    );
+    write_enum(
+        &mut out_file,
+        "/// Value of protocolIdentifier (IE 4), from the iana Protocol Numbers registry\n\
+         /// <https://www.iana.org/assignments/protocol-numbers/protocol-numbers.xhtml>",
+        "ProtocolIdentifier",
+        "Other",
+        "u8",
+        &variants,
+    );
+}
+
+/// IPFIX subregistries of coded values: (csv, enum, information element,
+/// anchor, type of the information element)
+const SUBREGISTRIES: &[(&str, &str, &str, &str, &str)] = &[
+    (
+        "resources/ipfix-flow-end-reason.csv",
+        "FlowEndReason",
+        "flowEndReason (IE 136)",
+        "ipfix-flow-end-reason",
+        "u8",
+    ),
+    (
+        "resources/ipfix-forwarding-status.csv",
+        "ForwardingStatus",
+        "forwardingStatus (IE 89)",
+        "forwarding-status",
+        "u8",
+    ),
+    (
+        "resources/ipfix-nat-event-type.csv",
+        "NatEvent",
+        "natEvent (IE 230)",
+        "ipfix-nat-event-type",
+        "u8",
+    ),
+    (
+        "resources/ipfix-firewall-event.csv",
+        "FirewallEvent",
+        "firewallEvent (IE 233)",
+        "firewall-event",
+        "u8",
+    ),
+    (
+        "resources/psamp-selector-algorithm.csv",
+        "SelectorAlgorithm",
+        "selectorAlgorithm (IE 304)",
+        "psamp-parameters-1",
+        "u16",
+    ),
+];
+
+fn build_subregistries(out_dir: &Path) {
+    let mut out_file = File::create(out_dir.join("ipfix-subregistries.rs")).unwrap();
+    for (path, enum_name, information_element, anchor, repr) in SUBREGISTRIES {
+        println!("cargo:rerun-if-changed={path}");
+        let mut variants = read_registry(path, "Value", "Description", "Description");
+        // keep the fallback name free
+        for (_, name, _) in &mut variants {
+            if name == "Unknown" {
+                *name = "Unspecified".to_string();
+            }
+        }
+        write_enum(
+            &mut out_file,
+            &format!(
+                "/// Value of {information_element}\n\
+                 /// <https://www.iana.org/assignments/ipfix/ipfix.xhtml#{anchor}>"
+            ),
+            enum_name,
+            "Unknown",
+            repr,
+            &variants,
+        );
+    }
 }
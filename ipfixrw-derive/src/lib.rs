@@ -0,0 +1,162 @@
+//! `#[derive(IpfixRecord)]` for ipfixrw
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitInt};
+
+/// Implement `ipfixrw::record::IpfixRecord` for a struct with named
+/// fields, each annotated with its information element:
+/// `#[ipfix(id = 8)]`, optionally with an enterprise number `pen = 30351`
+/// and a field `length = 4`, which otherwise depends on the field's type
+#[proc_macro_derive(IpfixRecord, attributes(ipfix))]
+pub fn derive_ipfix_record(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+struct IpfixField {
+    ident: syn::Ident,
+    ty: syn::Type,
+    enterprise_number: TokenStream2,
+    id: LitInt,
+    length: TokenStream2,
+}
+
+fn parse_field(field: &syn::Field) -> syn::Result<IpfixField> {
+    let ident = field.ident.clone().unwrap();
+    let ty = field.ty.clone();
+    let mut pen: Option<LitInt> = None;
+    let mut id: Option<LitInt> = None;
+    let mut length: Option<LitInt> = None;
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("ipfix")) {
+        attr.parse_nested_meta(|meta| {
+            let target = if meta.path.is_ident("pen") {
+                &mut pen
+            } else if meta.path.is_ident("id") {
+                &mut id
+            } else if meta.path.is_ident("length") {
+                &mut length
+            } else {
+                return Err(meta.error("expected `pen`, `id` or `length`"));
+            };
+            *target = Some(meta.value()?.parse()?);
+            Ok(())
+        })?;
+    }
+    let id = id.ok_or_else(|| {
+        syn::Error::new_spanned(field, "missing information element `#[ipfix(id = ...)]`")
+    })?;
+    let enterprise_number = match pen {
+        Some(pen) => quote!(::core::option::Option::Some(#pen)),
+        None => quote!(::core::option::Option::None),
+    };
+    let length = match length {
+        Some(length) => quote!(#length),
+        None => quote!(<#ty as ::ipfixrw::record::IpfixValue>::FIELD_LENGTH),
+    };
+    Ok(IpfixField {
+        ident,
+        ty,
+        enterprise_number,
+        id,
+        length,
+    })
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input,
+                    "IpfixRecord requires named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input,
+                "IpfixRecord can only be derived for structs",
+            ))
+        }
+    };
+    let fields = fields
+        .iter()
+        .map(parse_field)
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let field_specifiers = fields.iter().map(
+        |IpfixField {
+             enterprise_number,
+             id,
+             length,
+             ..
+         }| {
+            quote!(::ipfixrw::parser::FieldSpecifier::new(#enterprise_number, #id, #length))
+        },
+    );
+    let inserts = fields.iter().map(
+        |IpfixField {
+             ident,
+             enterprise_number,
+             id,
+             ..
+         }| {
+            quote! {
+                ::ipfixrw::record::insert_value(
+                    &mut record,
+                    template,
+                    #enterprise_number,
+                    #id,
+                    ::core::clone::Clone::clone(&self.#ident),
+                )?;
+            }
+        },
+    );
+    let gets = fields.iter().map(
+        |IpfixField {
+             ident,
+             ty,
+             enterprise_number,
+             id,
+             ..
+         }| {
+            quote! {
+                #ident: ::ipfixrw::record::get_value::<#ty>(record, template, #enterprise_number, #id)?,
+            }
+        },
+    );
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::ipfixrw::record::IpfixRecord for #name #ty_generics #where_clause {
+            fn field_specifiers() -> ::std::vec::Vec<::ipfixrw::parser::FieldSpecifier> {
+                ::std::vec![#(#field_specifiers),*]
+            }
+
+            fn to_data_record(
+                &self,
+                template: &::ipfixrw::template_store::Template,
+            ) -> ::core::result::Result<::ipfixrw::parser::DataRecord, ::ipfixrw::parser::IpfixError> {
+                let mut record = ::ipfixrw::parser::DataRecord::default();
+                #(#inserts)*
+                ::core::result::Result::Ok(record)
+            }
+
+            fn from_data_record(
+                record: &::ipfixrw::parser::DataRecord,
+                template: &::ipfixrw::template_store::Template,
+            ) -> ::core::result::Result<Self, ::ipfixrw::parser::IpfixError> {
+                ::core::result::Result::Ok(Self {
+                    #(#gets)*
+                })
+            }
+        }
+    })
+}
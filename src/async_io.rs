@@ -0,0 +1,366 @@
+//! Reading and writing messages with tokio. Messages are framed and
+//! transferred asynchronously, then decoded or encoded in memory.
+//!
+//! Readers and writers take a `Sync` template store, such as
+//! `RwLock<HashMap>` or `DashMap`, so their futures are `Send`. The
+//! collectors keep a `CollectorSession`, which isn't `Send`, so they
+//! must run on a `LocalSet` or current-thread runtime. They can also be
+//! turned into a `Stream` of their data records
+
+use std::collections::VecDeque;
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use binrw::{BinRead, BinResult};
+pub use futures_util::Stream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+
+use crate::collector::udp::UdpEvent;
+use crate::collector::{Collected, CollectorSession};
+use crate::information_elements::Formatter;
+use crate::parser::{DataRecord, Message, ParseOptions, Records, WriteOptions};
+use crate::stream::message_length;
+use crate::template_store::TemplateStorage;
+
+/// The largest possible message, as the Length field is 16 bits
+const MAX_MESSAGE_LENGTH: usize = u16::MAX as usize;
+
+/// Read the next message from `reader` into `buf`, framed by the length
+/// in its header, or return false at the end of the stream
+async fn read_message_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    position: u64,
+) -> BinResult<bool> {
+    let mut header = [0; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]).await? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            n => filled += n,
+        }
+    }
+
+    let length = message_length(header, position)?;
+    buf.clear();
+    buf.extend_from_slice(&header);
+    buf.resize(length.into(), 0);
+    reader.read_exact(&mut buf[header.len()..]).await?;
+    Ok(true)
+}
+
+/// Where a data record came from: the Transport Session, by its peer's
+/// address, and the Observation Domain
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct SessionKey {
+    pub peer: SocketAddr,
+    pub observation_domain_id: u32,
+}
+
+/// Queue the data records of `message`, for a record stream
+fn queue_records(
+    queue: &mut VecDeque<(SessionKey, DataRecord)>,
+    peer: SocketAddr,
+    message: Message,
+) {
+    let key = SessionKey {
+        peer,
+        observation_domain_id: message.observation_domain_id,
+    };
+    for set in message.sets {
+        if let Records::Data { data, .. } = set.records {
+            queue.extend(data.into_iter().map(|record| (key, record)));
+        }
+    }
+}
+
+/// Reads messages from `reader` one at a time, like
+/// `stream::MessageStream`
+pub struct AsyncMessageReader<'a, R> {
+    reader: R,
+    templates: &'a (dyn TemplateStorage + Sync),
+    formatter: &'a Formatter,
+    options: ParseOptions,
+    /// Bytes read from `reader` so far, for error positions
+    position: u64,
+    buf: Vec<u8>,
+}
+
+impl<'a, R: AsyncRead + Unpin> AsyncMessageReader<'a, R> {
+    pub fn new(
+        reader: R,
+        templates: &'a (dyn TemplateStorage + Sync),
+        formatter: &'a Formatter,
+    ) -> Self {
+        Self {
+            reader,
+            templates,
+            formatter,
+            options: ParseOptions::default(),
+            position: 0,
+            buf: vec![],
+        }
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Read and parse the next message, or `None` at the end of the
+    /// stream
+    pub async fn read(&mut self) -> BinResult<Option<Message>> {
+        if !read_message_frame(&mut self.reader, &mut self.buf, self.position).await? {
+            return Ok(None);
+        }
+        self.position += self.buf.len() as u64;
+        Message::read_args(
+            &mut Cursor::new(&self.buf),
+            (self.templates, self.formatter, self.options),
+        )
+        .map(Some)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Writes messages to `writer`, like `stream::MessageWriter`
+pub struct AsyncMessageWriter<'a, W> {
+    writer: W,
+    templates: &'a (dyn TemplateStorage + Sync),
+    formatter: &'a Formatter,
+    options: WriteOptions,
+    buf: Vec<u8>,
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncMessageWriter<'a, W> {
+    pub fn new(
+        writer: W,
+        templates: &'a (dyn TemplateStorage + Sync),
+        formatter: &'a Formatter,
+    ) -> Self {
+        Self {
+            writer,
+            templates,
+            formatter,
+            options: WriteOptions::default(),
+            buf: vec![],
+        }
+    }
+
+    /// Write messages with `options`
+    pub fn options(mut self, options: WriteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Encode and write `message`, adding any templates it defines
+    pub async fn write(&mut self, message: &Message) -> BinResult<()> {
+        message.write_into(&mut self.buf, self.templates, self.formatter, self.options)?;
+        self.writer.write_all(&self.buf).await?;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> BinResult<()> {
+        Ok(self.writer.flush().await?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Receives messages on a UDP socket, like `collector::udp::UdpCollector`
+pub struct AsyncUdpCollector {
+    socket: UdpSocket,
+    session: CollectorSession<SocketAddr>,
+    buf: Vec<u8>,
+    sweep_interval: Duration,
+    last_sweep: Instant,
+}
+
+impl AsyncUdpCollector {
+    pub fn new(socket: UdpSocket, formatter: Formatter, template_lifetime: Duration) -> Self {
+        Self {
+            socket,
+            session: CollectorSession::new(formatter, template_lifetime),
+            buf: vec![0; MAX_MESSAGE_LENGTH],
+            sweep_interval: template_lifetime / 2,
+            last_sweep: Instant::now(),
+        }
+    }
+
+    pub async fn bind(
+        addr: impl ToSocketAddrs,
+        formatter: Formatter,
+        template_lifetime: Duration,
+    ) -> io::Result<Self> {
+        Ok(Self::new(
+            UdpSocket::bind(addr).await?,
+            formatter,
+            template_lifetime,
+        ))
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.session = self.session.options(options);
+        self
+    }
+
+    /// Sweep expired templates every `interval`
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn session(&self) -> &CollectorSession<SocketAddr> {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut CollectorSession<SocketAddr> {
+        &mut self.session
+    }
+
+    /// Wait until a datagram is received or templates expire
+    pub async fn recv(&mut self) -> io::Result<UdpEvent> {
+        loop {
+            let next_sweep = self.last_sweep + self.sweep_interval;
+            let now = Instant::now();
+            if now >= next_sweep {
+                self.last_sweep = now;
+                let events = self.session.sweep();
+                if !events.is_empty() {
+                    return Ok(UdpEvent::Swept(events));
+                }
+                continue;
+            }
+
+            let received =
+                tokio::time::timeout(next_sweep - now, self.socket.recv_from(&mut self.buf)).await;
+            if let Ok(received) = received {
+                let (length, peer) = received?;
+                let result = self.session.handle_datagram(peer, &self.buf[..length]);
+                return Ok(UdpEvent::Datagram { peer, result });
+            }
+        }
+    }
+}
+
+impl AsyncUdpCollector {
+    /// The data records of each datagram, as a stream. The next datagram
+    /// is only received once all records of the last have been taken, so
+    /// only one message is queued, and a slow consumer leaves datagrams
+    /// in the socket's receive buffer rather than in memory. Datagrams
+    /// that fail to decode and session events are dropped, and the stream
+    /// ends if receiving fails
+    pub fn into_record_stream(self) -> impl Stream<Item = (SessionKey, DataRecord)> {
+        let state = (self, VecDeque::new());
+        futures_util::stream::unfold(state, |(mut collector, mut queue)| async move {
+            loop {
+                if let Some(item) = queue.pop_front() {
+                    return Some((item, (collector, queue)));
+                }
+                match collector.recv().await {
+                    Ok(UdpEvent::Datagram {
+                        peer,
+                        result: Ok(collected),
+                    }) => queue_records(&mut queue, peer, collected.message),
+                    Ok(_) => {}
+                    Err(_) => return None,
+                }
+            }
+        })
+    }
+}
+
+/// The messages of one Transport Session read from `reader`, like
+/// `collector::tcp::TcpSession`
+pub struct AsyncTcpSession<R> {
+    reader: R,
+    peer: SocketAddr,
+    session: CollectorSession<SocketAddr>,
+    /// Bytes read from `reader` so far, for error positions
+    position: u64,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncTcpSession<R> {
+    pub fn new(reader: R, peer: SocketAddr, formatter: Formatter) -> Self {
+        Self {
+            reader,
+            peer,
+            session: CollectorSession::new(formatter, Duration::MAX),
+            position: 0,
+            buf: vec![],
+            done: false,
+        }
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.session = self.session.options(options);
+        self
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn session(&self) -> &CollectorSession<SocketAddr> {
+        &self.session
+    }
+
+    /// Read and decode the next message, or `None` once the connection
+    /// closes or after a framing error
+    pub async fn next(&mut self) -> Option<BinResult<Collected>> {
+        if self.done {
+            return None;
+        }
+        match read_message_frame(&mut self.reader, &mut self.buf, self.position).await {
+            Ok(true) => {
+                self.position += self.buf.len() as u64;
+                Some(self.session.handle_datagram(self.peer, &self.buf))
+            }
+            Ok(false) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncTcpSession<R> {
+    /// The data records of each message, as a stream. As with
+    /// `AsyncUdpCollector::into_record_stream`, the next message is only
+    /// read once all records of the last have been taken. Messages that
+    /// fail to decode are dropped, and the stream ends with the session
+    pub fn into_record_stream(self) -> impl Stream<Item = (SessionKey, DataRecord)> {
+        let state = (self, VecDeque::new());
+        futures_util::stream::unfold(state, |(mut session, mut queue)| async move {
+            loop {
+                if let Some(item) = queue.pop_front() {
+                    return Some((item, (session, queue)));
+                }
+                if let Ok(collected) = session.next().await? {
+                    queue_records(&mut queue, session.peer, collected.message);
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,382 @@
+//! Decoding of data records that borrow from the input, rather than
+//! allocating. Records are split into fields up front, and each field is
+//! only decoded when it is looked up, with octet arrays and strings
+//! borrowed from the input. Template sets are decoded as usual
+//!
+//! Lookups are lenient: a value that can't be decoded as its type is
+//! returned as `BorrowedValue::Bytes`
+
+use std::io::Cursor;
+
+use binrw::{BinRead, BinResult, Endian};
+
+use crate::information_elements::Formatter;
+use crate::lazy::LazyDataRecord;
+use crate::parser::{
+    DataRecord, DataRecordKey, DataRecordType, DataRecordValue, DataRecordValues, IpfixError,
+    ParseOptions, Set, OPTIONS_TEMPLATE_SET_ID, TEMPLATE_SET_ID,
+};
+use crate::template_store::{ExpandedFieldSpecifier, Template, TemplateStorage};
+
+/// A value borrowed from the input where possible
+#[derive(PartialEq, Clone, Debug)]
+pub enum BorrowedValue<'a> {
+    Bytes(&'a [u8]),
+    String(&'a str),
+    /// A fixed size value, which is cheap to decode
+    Decoded(DataRecordValue),
+}
+
+impl BorrowedValue<'_> {
+    pub fn to_owned(&self) -> DataRecordValue {
+        match self {
+            BorrowedValue::Bytes(bytes) => DataRecordValue::Bytes(bytes.to_vec()),
+            BorrowedValue::String(s) => DataRecordValue::String(s.to_string()),
+            BorrowedValue::Decoded(value) => value.clone(),
+        }
+    }
+}
+
+/// A message whose data sets borrow from the input
+#[derive(PartialEq, Debug)]
+pub struct BorrowedMessage<'a> {
+    pub export_time: u32,
+    pub sequence_number: u32,
+    pub observation_domain_id: u32,
+    pub sets: Vec<BorrowedSet<'a>>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum BorrowedSet<'a> {
+    /// A Template or Options Template Set, decoded as usual
+    Decoded(Set),
+    Data(BorrowedDataSet<'a>),
+}
+
+/// The undecoded records of a data set, with their template
+#[derive(PartialEq, Debug)]
+pub struct BorrowedDataSet<'a> {
+    pub set_id: u16,
+    pub template: Template,
+    pub(crate) bytes: &'a [u8],
+}
+
+/// A data record split into fields in template order, borrowing from the
+/// input
+#[derive(PartialEq, Debug)]
+pub struct BorrowedRecord<'t, 'a> {
+    template: &'t Template,
+    fields: Vec<&'a [u8]>,
+}
+
+pub(crate) fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        buf.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn truncated(pos: usize) -> binrw::Error {
+    binrw::Error::AssertFail {
+        pos: pos as u64,
+        message: "unexpected end of input".to_string(),
+    }
+}
+
+/// The fields of a message header after the version and length
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) struct MessageHeader {
+    pub export_time: u32,
+    pub sequence_number: u32,
+    pub observation_domain_id: u32,
+}
+
+/// Split the message at the start of `buf` from anything after it,
+/// returning its bytes and header
+pub(crate) fn split_message(buf: &[u8]) -> BinResult<(&[u8], MessageHeader)> {
+    let version = read_u16(buf, 0).ok_or_else(|| truncated(0))?;
+    if version != 10 {
+        return Err(binrw::Error::BadMagic {
+            pos: 0,
+            found: Box::new(version),
+        });
+    }
+    let length = read_u16(buf, 2).ok_or_else(|| truncated(2))?;
+    let buf = buf
+        .get(..length.into())
+        .ok_or_else(|| truncated(buf.len()))?;
+    let header = MessageHeader {
+        export_time: read_u32(buf, 4).ok_or_else(|| truncated(4))?,
+        sequence_number: read_u32(buf, 8).ok_or_else(|| truncated(8))?,
+        observation_domain_id: read_u32(buf, 12).ok_or_else(|| truncated(12))?,
+    };
+    Ok((buf, header))
+}
+
+/// Call `on_set` with the Set ID, offset and bytes (including the set
+/// header) of each set of `message`. Reserved Set IDs are an error
+pub(crate) fn split_sets<'a>(
+    message: &'a [u8],
+    mut on_set: impl FnMut(u16, usize, &'a [u8]) -> BinResult<()>,
+) -> BinResult<()> {
+    let mut offset = 16;
+    while offset < message.len() {
+        let set_id = read_u16(message, offset).ok_or_else(|| truncated(offset))?;
+        let set_length = read_u16(message, offset + 2).ok_or_else(|| truncated(offset))?;
+        if set_length <= 4 {
+            return Err(binrw::Error::AssertFail {
+                pos: offset as u64,
+                message: format!("invalid set length: [{set_length} <= 4]"),
+            });
+        }
+        if !matches!(set_id, TEMPLATE_SET_ID | OPTIONS_TEMPLATE_SET_ID | 256..) {
+            return Err(binrw::Error::AssertFail {
+                pos: offset as u64,
+                message: format!("Set IDs 0-1 and 4-255 are reserved [set_id: {set_id}]"),
+            });
+        }
+        let set_bytes = message
+            .get(offset..offset + usize::from(set_length))
+            .ok_or_else(|| truncated(offset))?;
+        on_set(set_id, offset, set_bytes)?;
+        offset += usize::from(set_length);
+    }
+    Ok(())
+}
+
+impl<'a> BorrowedMessage<'a> {
+    /// Parse the message at the start of `buf`. Templates it defines are
+    /// added to `templates`, and data sets must have a known template
+    pub fn parse(
+        buf: &'a [u8],
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+    ) -> BinResult<Self> {
+        let (buf, header) = split_message(buf)?;
+        let mut message = Self {
+            export_time: header.export_time,
+            sequence_number: header.sequence_number,
+            observation_domain_id: header.observation_domain_id,
+            sets: vec![],
+        };
+        split_sets(buf, |set_id, offset, set_bytes| {
+            let set = match set_id {
+                TEMPLATE_SET_ID | OPTIONS_TEMPLATE_SET_ID => BorrowedSet::Decoded(Set::read_args(
+                    &mut Cursor::new(set_bytes),
+                    (templates, formatter, ParseOptions::default()),
+                )?),
+                _ => BorrowedSet::Data(BorrowedDataSet {
+                    set_id,
+                    template: templates.get_template(set_id).ok_or_else(|| {
+                        IpfixError::MissingTemplate(set_id).into_binrw_error(offset as u64)
+                    })?,
+                    bytes: &set_bytes[4..],
+                }),
+            };
+            message.sets.push(set);
+            Ok(())
+        })?;
+        Ok(message)
+    }
+
+    pub fn iter_data_sets(&self) -> impl Iterator<Item = &BorrowedDataSet<'a>> {
+        self.sets.iter().filter_map(|set| match set {
+            BorrowedSet::Data(data_set) => Some(data_set),
+            BorrowedSet::Decoded(_) => None,
+        })
+    }
+}
+
+impl<'a> BorrowedDataSet<'a> {
+    /// Split the records of the set, stopping at padding shorter than a
+    /// record
+    pub fn records(&self) -> impl Iterator<Item = BinResult<BorrowedRecord<'_, 'a>>> {
+        let min_length = self.template.min_record_length().max(1) as usize;
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            if self.bytes.len() - offset < min_length {
+                return None;
+            }
+            let result = self.split_record(offset);
+            match &result {
+                // records of no bytes would repeat forever
+                Ok((_, end)) if *end == offset => return None,
+                Ok((_, end)) => offset = *end,
+                Err(_) => offset = self.bytes.len(),
+            }
+            Some(result.map(|(record, _)| record))
+        })
+    }
+
+    /// The records of the set, each only decoded as its fields are
+    /// looked up
+    pub fn lazy_records(&self) -> impl Iterator<Item = BinResult<LazyDataRecord<'_, 'a>>> {
+        let min_length = self.template.min_record_length().max(1) as usize;
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            if self.bytes.len() - offset < min_length {
+                return None;
+            }
+            let start = offset;
+            let result = split_fields(&self.template, self.bytes, start, |_, _| {});
+            match &result {
+                // records of no bytes would repeat forever
+                Ok(end) if *end == start => return None,
+                Ok(end) => offset = *end,
+                Err(_) => offset = self.bytes.len(),
+            }
+            Some(result.map(|end| LazyDataRecord::new(&self.template, &self.bytes[start..end])))
+        })
+    }
+
+    /// Split the record starting at `offset` into fields, returning it
+    /// and its end
+    fn split_record(&self, offset: usize) -> BinResult<(BorrowedRecord<'_, 'a>, usize)> {
+        let mut fields = Vec::with_capacity(self.template.field_specifiers().size_hint().0);
+        let end = split_fields(&self.template, self.bytes, offset, |_, field| {
+            fields.push(field);
+        })?;
+        let record = BorrowedRecord {
+            template: &self.template,
+            fields,
+        };
+        Ok((record, end))
+    }
+}
+
+/// Split the record of `template` starting at `offset` in `bytes`,
+/// calling `on_field` with each field in template order. Returns the end
+/// of the record
+pub(crate) fn split_fields<'a>(
+    template: &Template,
+    bytes: &'a [u8],
+    mut offset: usize,
+    mut on_field: impl FnMut(&ExpandedFieldSpecifier, &'a [u8]),
+) -> BinResult<usize> {
+    for field_spec in template.field_specifiers() {
+        let field = next_field(bytes, &mut offset, field_spec.field_length)?;
+        on_field(field_spec, field);
+    }
+    Ok(offset)
+}
+
+/// The number of records of `template` in the `bytes` of a data set,
+/// ignoring padding or a truncated record at the end
+pub(crate) fn record_count(template: &Template, bytes: &[u8]) -> usize {
+    let min_length = template.min_record_length() as usize;
+    if min_length == 0 {
+        return 0;
+    }
+    let mut count = 0;
+    let mut offset = 0;
+    while bytes.len() - offset >= min_length {
+        match split_fields(template, bytes, offset, |_, _| {}) {
+            Ok(end) => offset = end,
+            Err(_) => break,
+        }
+        count += 1;
+    }
+    count
+}
+
+/// The field of `field_length` at `offset` in `bytes`, reading the
+/// length first for variable length fields. Moves `offset` to the end of
+/// the field
+pub(crate) fn next_field<'a>(
+    bytes: &'a [u8],
+    offset: &mut usize,
+    field_length: u16,
+) -> BinResult<&'a [u8]> {
+    let length = match field_length {
+        u16::MAX => {
+            let length = *bytes.get(*offset).ok_or_else(|| truncated(*offset))?;
+            *offset += 1;
+            if length == 255 {
+                let length = read_u16(bytes, *offset).ok_or_else(|| truncated(*offset))?;
+                *offset += 2;
+                length.into()
+            } else {
+                length.into()
+            }
+        }
+        length => usize::from(length),
+    };
+    let field = bytes
+        .get(*offset..*offset + length)
+        .ok_or_else(|| truncated(*offset))?;
+    *offset += length;
+    Ok(field)
+}
+
+impl<'t, 'a> BorrowedRecord<'t, 'a> {
+    /// Decode the value of `key`, if the template has it
+    pub fn get(&self, key: impl Into<DataRecordKey>) -> Option<BorrowedValue<'a>> {
+        let key = key.into();
+        self.template
+            .field_specifiers()
+            .zip(&self.fields)
+            .find(|(field_spec, _)| field_spec.name == key)
+            .map(|(field_spec, bytes)| decode(field_spec.ty, bytes))
+    }
+
+    /// Decode all values, in template order
+    pub fn values(&self) -> impl Iterator<Item = (&'t DataRecordKey, BorrowedValue<'a>)> + '_ {
+        self.template
+            .field_specifiers()
+            .zip(&self.fields)
+            .map(|(field_spec, bytes)| (&field_spec.name, decode(field_spec.ty, bytes)))
+    }
+
+    /// The raw bytes of each field, in template order
+    pub fn fields(&self) -> &[&'a [u8]] {
+        &self.fields
+    }
+
+    /// Decode into an owned `DataRecord`
+    pub fn to_owned(&self) -> DataRecord {
+        let scope_field_count = self.template.scope_field_specifiers().len();
+        let mut scope_values = DataRecordValues::default();
+        let mut values = DataRecordValues::default();
+        for (i, (key, value)) in self.values().enumerate() {
+            if i < scope_field_count {
+                scope_values.insert(key.clone(), value.to_owned());
+            } else {
+                values.insert(key.clone(), value.to_owned());
+            }
+        }
+        DataRecord {
+            values,
+            scope_values,
+        }
+    }
+}
+
+pub(crate) fn decode(ty: DataRecordType, bytes: &[u8]) -> BorrowedValue<'_> {
+    match ty {
+        DataRecordType::Bytes => BorrowedValue::Bytes(bytes),
+        DataRecordType::String => match std::str::from_utf8(bytes) {
+            Ok(s) => BorrowedValue::String(s),
+            Err(_) => BorrowedValue::Bytes(bytes),
+        },
+        _ => {
+            let options = ParseOptions {
+                lenient_field_lengths: true,
+                ..Default::default()
+            };
+            let length = u16::try_from(bytes.len()).unwrap_or(u16::MAX);
+            match DataRecordValue::read_options(
+                &mut Cursor::new(bytes),
+                Endian::Big,
+                (ty, length, options),
+            ) {
+                Ok(DataRecordValue::Bytes(_)) | Err(_) => BorrowedValue::Bytes(bytes),
+                Ok(value) => BorrowedValue::Decoded(value),
+            }
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! A Collecting Process for messages from many Exporters, keeping
+//! templates and Sequence Numbers separately for each peer and
+//! Observation Domain
+
+pub mod tcp;
+pub mod udp;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::io::Cursor;
+use std::time::Duration;
+
+use binrw::BinResult;
+
+use crate::information_elements::Formatter;
+use crate::netflow::{v5, v9};
+use crate::parser::{IpfixError, Message, ParseOptions, Records};
+use crate::sequence::{SequenceEvent, SequenceStatistics, SequenceTracker};
+use crate::statistics::MeteringProcessStatistics;
+use crate::template_store::{
+    ExpiringTemplateStore, ScopedTemplateStore, Template, TemplateObserver,
+};
+
+/// Something that happened in a session while handling a datagram or
+/// sweeping templates
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum SessionEvent {
+    TemplateAdded {
+        observation_domain_id: u32,
+        template_id: u16,
+    },
+    TemplateReplaced {
+        observation_domain_id: u32,
+        template_id: u16,
+    },
+    TemplateWithdrawn {
+        observation_domain_id: u32,
+        template_id: u16,
+    },
+    TemplateExpired {
+        observation_domain_id: u32,
+        template_id: u16,
+    },
+    /// A data set for a withdrawn template was dropped from a message
+    DataSetDiscarded {
+        observation_domain_id: u32,
+        template_id: u16,
+    },
+    /// A message's Sequence Number was not the expected one, or it was
+    /// the first message of its Observation Domain
+    Sequence {
+        observation_domain_id: u32,
+        event: SequenceEvent,
+    },
+}
+
+/// A decoded message, with the events caused by handling it
+#[derive(PartialEq, Clone, Debug)]
+pub struct Collected {
+    pub message: Message,
+    pub events: Vec<SessionEvent>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum TemplateChange {
+    Added,
+    Replaced,
+    Withdrawn,
+    Expired,
+}
+
+/// Records the template changes of one scope, to be reported as events
+#[derive(Debug, Default)]
+struct TemplateChangeLog(RefCell<Vec<(TemplateChange, u16)>>);
+
+impl TemplateChangeLog {
+    fn drain(&self, observation_domain_id: u32) -> impl Iterator<Item = SessionEvent> {
+        let changes = std::mem::take(&mut *self.0.borrow_mut());
+        changes
+            .into_iter()
+            .map(move |(change, template_id)| match change {
+                TemplateChange::Added => SessionEvent::TemplateAdded {
+                    observation_domain_id,
+                    template_id,
+                },
+                TemplateChange::Replaced => SessionEvent::TemplateReplaced {
+                    observation_domain_id,
+                    template_id,
+                },
+                TemplateChange::Withdrawn => SessionEvent::TemplateWithdrawn {
+                    observation_domain_id,
+                    template_id,
+                },
+                TemplateChange::Expired => SessionEvent::TemplateExpired {
+                    observation_domain_id,
+                    template_id,
+                },
+            })
+    }
+}
+
+impl TemplateObserver for TemplateChangeLog {
+    fn on_insert(&self, template_id: u16, _template: &Template) {
+        self.0
+            .borrow_mut()
+            .push((TemplateChange::Added, template_id));
+    }
+    fn on_replace(&self, template_id: u16, _old: &Template, _new: &Template) {
+        self.0
+            .borrow_mut()
+            .push((TemplateChange::Replaced, template_id));
+    }
+    fn on_withdraw(&self, template_id: u16, _template: &Template) {
+        self.0
+            .borrow_mut()
+            .push((TemplateChange::Withdrawn, template_id));
+    }
+    fn on_expire(&self, template_id: u16, _template: &Template) {
+        self.0
+            .borrow_mut()
+            .push((TemplateChange::Expired, template_id));
+    }
+}
+
+type ScopeStore = ExpiringTemplateStore<RefCell<ahash::HashMap<u16, Template>>, TemplateChangeLog>;
+
+/// Decodes messages from many peers, such as the sources of UDP
+/// datagrams, keeping templates and Sequence Numbers for each (peer,
+/// observation_domain_id) pair. Templates expire after `template_lifetime`
+/// without being refreshed
+pub struct CollectorSession<P> {
+    templates: ScopedTemplateStore<P, ScopeStore>,
+    template_lifetime: Duration,
+    sequence: SequenceTracker<P>,
+    formatter: Formatter,
+    options: ParseOptions,
+}
+
+/// Stores for each scope, expiring templates and options templates after
+/// their lifetimes
+fn scoped_store<P>(
+    template_lifetime: Duration,
+    options_template_lifetime: Duration,
+) -> ScopedTemplateStore<P, ScopeStore> {
+    ScopedTemplateStore::with_factory(move || {
+        ExpiringTemplateStore::new(RefCell::default(), template_lifetime)
+            .options_lifetime(options_template_lifetime)
+            .with_observer(TemplateChangeLog::default())
+    })
+}
+
+impl<P: Hash + Eq + Clone> CollectorSession<P> {
+    pub fn new(formatter: Formatter, template_lifetime: Duration) -> Self {
+        Self {
+            templates: scoped_store(template_lifetime, template_lifetime),
+            template_lifetime,
+            sequence: SequenceTracker::new(),
+            formatter,
+            options: ParseOptions::default(),
+        }
+    }
+
+    /// Expire options templates after `lifetime` rather than
+    /// `template_lifetime`. This drops any templates already received
+    pub fn options_template_lifetime(mut self, lifetime: Duration) -> Self {
+        self.templates = scoped_store(self.template_lifetime, lifetime);
+        self
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn formatter(&self) -> &Formatter {
+        &self.formatter
+    }
+
+    /// Decode a message received from `peer`, updating its templates and
+    /// checking its Sequence Number. NetFlow v5 and v9 packets are
+    /// accepted too, converted as in `crate::netflow`, but as v9 Sequence
+    /// Numbers count packets rather than records they aren't checked
+    pub fn handle_datagram(&mut self, peer: P, bytes: &[u8]) -> BinResult<Collected> {
+        let result = self.decode(peer, bytes);
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_result(&result);
+        result
+    }
+
+    fn decode(&mut self, peer: P, bytes: &[u8]) -> BinResult<Collected> {
+        let v9 = bytes.starts_with(&v9::VERSION.to_be_bytes());
+        let converted;
+        let bytes = if v9 {
+            converted = v9::to_ipfix_bytes(bytes)?;
+            &converted[..]
+        } else if bytes.starts_with(&v5::VERSION.to_be_bytes()) {
+            converted = v5::to_ipfix_bytes(bytes)?;
+            &converted[..]
+        } else {
+            bytes
+        };
+        let result = Message::read_scoped(
+            &mut Cursor::new(bytes),
+            &self.templates,
+            peer.clone(),
+            &self.formatter,
+            self.options,
+        );
+        // report template changes even if the rest of the message failed
+        let mut events = vec![];
+        if let Some(&[a, b, c, d]) = bytes.get(12..16) {
+            let observation_domain_id = u32::from_be_bytes([a, b, c, d]);
+            let store = self.templates.scope(peer.clone(), observation_domain_id);
+            events.extend(store.observer().drain(observation_domain_id));
+        }
+        let message = result?;
+        if v9 {
+            return Ok(Collected { message, events });
+        }
+
+        let event = self.sequence.observe(peer.clone(), &message);
+        if let Some(&[a, b]) = bytes.get(2..4) {
+            self.sequence.add_octets(
+                peer,
+                message.observation_domain_id,
+                u16::from_be_bytes([a, b]).into(),
+            );
+        }
+        if event != SequenceEvent::InOrder {
+            events.push(SessionEvent::Sequence {
+                observation_domain_id: message.observation_domain_id,
+                event,
+            });
+        }
+        Ok(Collected { message, events })
+    }
+
+    /// Remove templates that have outlived their lifetime, from all peers
+    pub fn sweep(&mut self) -> Vec<SessionEvent> {
+        let mut events = vec![];
+        for ((_, observation_domain_id), store) in self.templates.scopes() {
+            store.sweep();
+            events.extend(store.observer().drain(observation_domain_id));
+        }
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_events(&events);
+        events
+    }
+
+    /// Sequence Number counts for `peer` and `observation_domain_id`
+    pub fn statistics(&self, peer: P, observation_domain_id: u32) -> Option<SequenceStatistics> {
+        self.sequence.statistics(peer, observation_domain_id)
+    }
+
+    /// Metering Process Statistics of what was received from `peer` for
+    /// `observation_domain_id`, such as for a Mediator to export
+    pub fn metering_statistics(
+        &self,
+        peer: P,
+        observation_domain_id: u32,
+    ) -> Option<MeteringProcessStatistics> {
+        self.statistics(peer, observation_domain_id)
+            .map(|statistics| metering_statistics(observation_domain_id, statistics))
+    }
+
+    /// Drop all state for `peer`, such as when its Transport Session ends
+    pub fn remove_peer(&mut self, peer: &P) {
+        self.templates.remove_peer(peer);
+        self.sequence.remove_peer(peer);
+    }
+}
+
+fn metering_statistics(
+    observation_domain_id: u32,
+    statistics: SequenceStatistics,
+) -> MeteringProcessStatistics {
+    MeteringProcessStatistics {
+        observation_domain_id,
+        exported_message_total_count: statistics.messages,
+        exported_flow_record_total_count: statistics.data_records,
+        exported_octet_total_count: statistics.octets,
+    }
+}
+
+/// The transport of a Transport Session
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-10>
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Transport {
+    /// Templates expire unless refreshed, and there is no connection to
+    /// reset them
+    Udp,
+    /// Templates last until the connection closes
+    Tcp,
+    /// Templates last until the association closes
+    Sctp,
+}
+
+/// The Collecting Process rules of RFC 7011 section 10 over a
+/// `CollectorSession`: templates are scoped to each Transport Session and
+/// Observation Domain, expire over UDP with separate lifetimes for
+/// options templates, and are dropped when a TCP connection or SCTP
+/// association is opened again or closed. Data sets for templates that
+/// were withdrawn are discarded rather than failing the message
+pub struct SessionManager<P> {
+    udp: CollectorSession<P>,
+    connected: CollectorSession<P>,
+    transports: HashMap<P, Transport>,
+    /// Templates withdrawn and not defined again, for each scope
+    withdrawn: HashMap<(P, u32), HashSet<u16>>,
+    /// Whether to keep data sets with unknown templates, rather than
+    /// failing the message
+    keep_raw_sets: bool,
+}
+
+impl<P: Hash + Eq + Clone> SessionManager<P> {
+    /// Expire templates received over UDP after `template_lifetime`, and
+    /// options templates after `options_template_lifetime`
+    pub fn new(
+        formatter: Formatter,
+        template_lifetime: Duration,
+        options_template_lifetime: Duration,
+    ) -> Self {
+        let options = ParseOptions {
+            keep_raw_sets: true,
+            ..ParseOptions::default()
+        };
+        Self {
+            udp: CollectorSession::new(formatter.clone(), template_lifetime)
+                .options_template_lifetime(options_template_lifetime)
+                .options(options),
+            connected: CollectorSession::new(formatter, Duration::MAX).options(options),
+            transports: HashMap::new(),
+            withdrawn: HashMap::new(),
+            keep_raw_sets: false,
+        }
+    }
+
+    /// Parse messages with `options`. Data sets with unknown templates
+    /// are kept as `Records::RawData` if `options.keep_raw_sets` is set,
+    /// and fail the message otherwise
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.keep_raw_sets = options.keep_raw_sets;
+        let options = ParseOptions {
+            keep_raw_sets: true,
+            ..options
+        };
+        self.udp = self.udp.options(options);
+        self.connected = self.connected.options(options);
+        self
+    }
+
+    fn session(&mut self, peer: &P) -> &mut CollectorSession<P> {
+        match self.transports.get(peer) {
+            Some(Transport::Tcp | Transport::Sctp) => &mut self.connected,
+            Some(Transport::Udp) | None => &mut self.udp,
+        }
+    }
+
+    /// Start a Transport Session with `peer`, dropping any state left
+    /// from an earlier one, such as when an Exporter reconnects
+    pub fn open(&mut self, peer: P, transport: Transport) {
+        self.close(&peer);
+        self.transports.insert(peer, transport);
+    }
+
+    /// End the Transport Session with `peer`, dropping its templates and
+    /// Sequence Numbers
+    pub fn close(&mut self, peer: &P) {
+        self.udp.remove_peer(peer);
+        self.connected.remove_peer(peer);
+        self.transports.remove(peer);
+        self.withdrawn.retain(|(p, _), _| p != peer);
+    }
+
+    pub fn transport(&self, peer: &P) -> Option<Transport> {
+        self.transports.get(peer).copied()
+    }
+
+    /// Decode a message from `peer`. Peers that weren't opened are
+    /// treated as UDP
+    pub fn handle_message(&mut self, peer: P, bytes: &[u8]) -> BinResult<Collected> {
+        let result = self.decode(peer, bytes);
+        // once the message is accepted or rejected here, not by the session
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_result(&result);
+        result
+    }
+
+    fn decode(&mut self, peer: P, bytes: &[u8]) -> BinResult<Collected> {
+        let mut collected = self.session(&peer).decode(peer.clone(), bytes)?;
+        let observation_domain_id = collected.message.observation_domain_id;
+        let withdrawn = self
+            .withdrawn
+            .entry((peer, observation_domain_id))
+            .or_default();
+        for event in &collected.events {
+            match *event {
+                SessionEvent::TemplateWithdrawn { template_id, .. } => {
+                    withdrawn.insert(template_id);
+                }
+                SessionEvent::TemplateAdded { template_id, .. }
+                | SessionEvent::TemplateReplaced { template_id, .. } => {
+                    withdrawn.remove(&template_id);
+                }
+                _ => {}
+            }
+        }
+
+        let mut discarded = vec![];
+        let mut missing = None;
+        collected.message.sets.retain(|set| match set.records {
+            Records::RawData { set_id, .. } if withdrawn.contains(&set_id) => {
+                discarded.push(SessionEvent::DataSetDiscarded {
+                    observation_domain_id,
+                    template_id: set_id,
+                });
+                false
+            }
+            Records::RawData { set_id, .. } if !self.keep_raw_sets => {
+                missing.get_or_insert(set_id);
+                true
+            }
+            _ => true,
+        });
+        if let Some(set_id) = missing {
+            return Err(IpfixError::MissingTemplate(set_id).into_binrw_error(0));
+        }
+        collected.events.extend(discarded);
+        Ok(collected)
+    }
+
+    /// Remove UDP templates that have outlived their lifetime
+    pub fn sweep(&mut self) -> Vec<SessionEvent> {
+        self.udp.sweep()
+    }
+
+    /// Sequence Number counts for `peer` and `observation_domain_id`
+    pub fn statistics(&self, peer: P, observation_domain_id: u32) -> Option<SequenceStatistics> {
+        match self.transports.get(&peer) {
+            Some(Transport::Tcp | Transport::Sctp) => {
+                self.connected.statistics(peer, observation_domain_id)
+            }
+            Some(Transport::Udp) | None => self.udp.statistics(peer, observation_domain_id),
+        }
+    }
+
+    /// Metering Process Statistics of what was received from `peer` for
+    /// `observation_domain_id`, as in `CollectorSession`
+    pub fn metering_statistics(
+        &self,
+        peer: P,
+        observation_domain_id: u32,
+    ) -> Option<MeteringProcessStatistics> {
+        self.statistics(peer, observation_domain_id)
+            .map(|statistics| metering_statistics(observation_domain_id, statistics))
+    }
+}
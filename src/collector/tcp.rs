@@ -0,0 +1,198 @@
+//! A blocking TCP Collecting Process. Messages are framed by the Length
+//! in their header, and each connection is its own Transport Session:
+//! templates don't expire, and are all dropped when the connection
+//! closes (RFC 7011 section 10.4)
+
+#[cfg(feature = "tls")]
+use std::io::Write;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::mpsc::Sender;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use binrw::BinResult;
+
+use super::{Collected, CollectorSession};
+use crate::information_elements::Formatter;
+use crate::parser::ParseOptions;
+use crate::stream::read_message_frame;
+
+/// Something that happened on a `TcpCollector`
+#[derive(Debug)]
+pub enum TcpEvent {
+    Connected(SocketAddr),
+    /// A message from `peer`, and the result of decoding it
+    Message {
+        peer: SocketAddr,
+        result: BinResult<Collected>,
+    },
+    /// The connection closed, and its templates were dropped
+    Disconnected(SocketAddr),
+}
+
+/// The messages of one Transport Session, read from `reader`. Messages
+/// that fail to decode are returned as errors and skipped, but framing
+/// errors, such as a bad version or length, end the session
+pub struct TcpSession<R> {
+    reader: R,
+    peer: SocketAddr,
+    session: CollectorSession<SocketAddr>,
+    /// Bytes read from `reader` so far, for error positions
+    position: u64,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> TcpSession<R> {
+    pub fn new(reader: R, peer: SocketAddr, formatter: Formatter) -> Self {
+        Self {
+            reader,
+            peer,
+            session: CollectorSession::new(formatter, Duration::MAX),
+            position: 0,
+            buf: vec![],
+            done: false,
+        }
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.session = self.session.options(options);
+        self
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn session(&self) -> &CollectorSession<SocketAddr> {
+        &self.session
+    }
+}
+
+impl<R: Read> Iterator for TcpSession<R> {
+    type Item = BinResult<Collected>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match read_message_frame(&mut self.reader, &mut self.buf, self.position) {
+            Ok(true) => {
+                self.position += self.buf.len() as u64;
+                Some(self.session.handle_datagram(self.peer, &self.buf))
+            }
+            Ok(false) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Accepts connections from many Exporters, reading each in its own
+/// thread with its own `TcpSession`
+pub struct TcpCollector {
+    listener: TcpListener,
+    formatter: Formatter,
+    options: ParseOptions,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl TcpCollector {
+    pub fn new(listener: TcpListener, formatter: Formatter) -> Self {
+        Self {
+            listener,
+            formatter,
+            options: ParseOptions::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    pub fn bind(addr: impl ToSocketAddrs, formatter: Formatter) -> io::Result<Self> {
+        Ok(Self::new(TcpListener::bind(addr)?, formatter))
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Require TLS on all connections, as in `crate::tls::server_config`
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections, sending the events of all of them to `sender`.
+    /// Returns when accepting fails, or on the next connection after the
+    /// receiver is dropped
+    pub fn run(&self, sender: Sender<TcpEvent>) -> io::Result<()> {
+        loop {
+            let (stream, peer) = self.listener.accept()?;
+            if sender.send(TcpEvent::Connected(peer)).is_err() {
+                return Ok(());
+            }
+            let formatter = self.formatter.clone();
+            let options = self.options;
+            let sender = sender.clone();
+            #[cfg(feature = "tls")]
+            let tls = self.tls.clone();
+            std::thread::spawn(move || {
+                #[cfg(feature = "tls")]
+                if let Some(config) = tls {
+                    let result = crate::tls::accept(stream, config).map(|mut stream| {
+                        send_messages(&mut stream, peer, formatter, options, &sender);
+                        stream.conn.send_close_notify();
+                        let _ = stream.flush();
+                    });
+                    if let Err(err) = result {
+                        let result = Err(err.into());
+                        let _ = sender.send(TcpEvent::Message { peer, result });
+                    }
+                    let _ = sender.send(TcpEvent::Disconnected(peer));
+                    return;
+                }
+                send_messages(&stream, peer, formatter, options, &sender);
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                let _ = sender.send(TcpEvent::Disconnected(peer));
+            });
+        }
+    }
+
+    /// `run` in a new thread
+    pub fn spawn(self, sender: Sender<TcpEvent>) -> JoinHandle<io::Result<()>> {
+        std::thread::spawn(move || self.run(sender))
+    }
+}
+
+/// Send the messages of a connection to `sender`, until it closes or
+/// the receiver is dropped
+fn send_messages(
+    reader: impl Read,
+    peer: SocketAddr,
+    formatter: Formatter,
+    options: ParseOptions,
+    sender: &Sender<TcpEvent>,
+) {
+    for result in TcpSession::new(reader, peer, formatter).options(options) {
+        if sender.send(TcpEvent::Message { peer, result }).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! A blocking UDP Collecting Process. Each datagram is one message, and
+//! each source address is its own peer, with its own templates and
+//! Sequence Numbers (RFC 7011 section 10.3)
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::ops::ControlFlow;
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use binrw::BinResult;
+
+use super::{Collected, CollectorSession, SessionEvent};
+use crate::information_elements::Formatter;
+use crate::parser::ParseOptions;
+
+/// The largest possible message, as the Length field is 16 bits
+const MAX_MESSAGE_LENGTH: usize = u16::MAX as usize;
+
+/// Something received or swept by a `UdpCollector`
+#[derive(Debug)]
+pub enum UdpEvent {
+    /// A datagram from `peer`, and the result of decoding it
+    Datagram {
+        peer: SocketAddr,
+        result: BinResult<Collected>,
+    },
+    /// Templates expired while sweeping
+    Swept(Vec<SessionEvent>),
+}
+
+/// Receives messages on a UDP socket, keeping a `CollectorSession` for
+/// the peers. Templates are swept every `sweep_interval`, which defaults
+/// to half of the template lifetime
+pub struct UdpCollector {
+    socket: UdpSocket,
+    session: CollectorSession<SocketAddr>,
+    buf: Vec<u8>,
+    sweep_interval: Duration,
+    last_sweep: Instant,
+}
+
+impl UdpCollector {
+    pub fn new(socket: UdpSocket, formatter: Formatter, template_lifetime: Duration) -> Self {
+        Self {
+            socket,
+            session: CollectorSession::new(formatter, template_lifetime),
+            buf: vec![0; MAX_MESSAGE_LENGTH],
+            sweep_interval: template_lifetime / 2,
+            last_sweep: Instant::now(),
+        }
+    }
+
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        formatter: Formatter,
+        template_lifetime: Duration,
+    ) -> io::Result<Self> {
+        Ok(Self::new(
+            UdpSocket::bind(addr)?,
+            formatter,
+            template_lifetime,
+        ))
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.session = self.session.options(options);
+        self
+    }
+
+    /// Sweep expired templates every `interval`
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn session(&self) -> &CollectorSession<SocketAddr> {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut CollectorSession<SocketAddr> {
+        &mut self.session
+    }
+
+    /// Block until a datagram is received or templates expire. Datagrams
+    /// that fail to decode are returned as an `Err` result, while errors
+    /// from the socket end the collector
+    pub fn recv(&mut self) -> io::Result<UdpEvent> {
+        loop {
+            let next_sweep = self.last_sweep + self.sweep_interval;
+            let now = Instant::now();
+            if now >= next_sweep {
+                self.last_sweep = now;
+                let events = self.session.sweep();
+                if !events.is_empty() {
+                    return Ok(UdpEvent::Swept(events));
+                }
+                continue;
+            }
+
+            // a zero timeout is an error, so it must be at least 1ms
+            let timeout = (next_sweep - now).max(Duration::from_millis(1));
+            self.socket.set_read_timeout(Some(timeout))?;
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((length, peer)) => {
+                    let result = self.session.handle_datagram(peer, &self.buf[..length]);
+                    return Ok(UdpEvent::Datagram { peer, result });
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Call `callback` with each event until it breaks or the socket
+    /// fails
+    pub fn run(&mut self, mut callback: impl FnMut(UdpEvent) -> ControlFlow<()>) -> io::Result<()> {
+        loop {
+            if callback(self.recv()?).is_break() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Receive on `socket` in a new thread, sending each event to
+    /// `sender` until its receiver is dropped. The session isn't `Send`,
+    /// so it is created in the thread
+    pub fn spawn(
+        socket: UdpSocket,
+        formatter: Formatter,
+        template_lifetime: Duration,
+        options: ParseOptions,
+        sender: Sender<UdpEvent>,
+    ) -> JoinHandle<io::Result<()>> {
+        std::thread::spawn(move || {
+            UdpCollector::new(socket, formatter, template_lifetime)
+                .options(options)
+                .run(|event| match sender.send(event) {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(_) => ControlFlow::Break(()),
+                })
+        })
+    }
+}
@@ -0,0 +1,193 @@
+//! Data sets stored as one `Vec` of values in template field order,
+//! rather than a map per record. This avoids hashing and cloning keys
+//! for every value, with lookups going through the set's template
+//! instead
+
+use std::io::Cursor;
+
+use binrw::{BinReaderExt, BinResult, Endian};
+
+use crate::borrowed::{BorrowedDataSet, BorrowedMessage, BorrowedSet};
+use crate::information_elements::Formatter;
+use crate::parser::{DataRecord, DataRecordKey, DataRecordValue, ParseOptions, Set};
+use crate::template_store::{Template, TemplateStorage};
+
+/// A message whose data sets are decoded to `CompactDataSet`s
+#[derive(PartialEq, Clone, Debug)]
+pub struct CompactMessage {
+    pub export_time: u32,
+    pub sequence_number: u32,
+    pub observation_domain_id: u32,
+    pub sets: Vec<CompactSet>,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum CompactSet {
+    /// A Template or Options Template Set
+    Decoded(Set),
+    Data(CompactDataSet),
+}
+
+/// The records of a data set, with the values of all records in one
+/// `Vec`, in template field order
+#[derive(PartialEq, Clone, Debug)]
+pub struct CompactDataSet {
+    pub set_id: u16,
+    pub template: Template,
+    values: Vec<DataRecordValue>,
+    field_count: usize,
+}
+
+/// A record of a `CompactDataSet`
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct CompactRecord<'s> {
+    template: &'s Template,
+    values: &'s [DataRecordValue],
+}
+
+impl CompactMessage {
+    /// Parse the message at the start of `buf`. Templates it defines are
+    /// added to `templates`, and data sets must have a known template
+    pub fn parse(
+        buf: &[u8],
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+        options: ParseOptions,
+    ) -> BinResult<Self> {
+        let message = BorrowedMessage::parse(buf, templates, formatter)?;
+        Ok(Self {
+            export_time: message.export_time,
+            sequence_number: message.sequence_number,
+            observation_domain_id: message.observation_domain_id,
+            sets: message
+                .sets
+                .into_iter()
+                .map(|set| match set {
+                    BorrowedSet::Decoded(set) => Ok(CompactSet::Decoded(set)),
+                    BorrowedSet::Data(data_set) => {
+                        CompactDataSet::decode(data_set, options).map(CompactSet::Data)
+                    }
+                })
+                .collect::<BinResult<_>>()?,
+        })
+    }
+
+    pub fn iter_data_sets(&self) -> impl Iterator<Item = &CompactDataSet> {
+        self.sets.iter().filter_map(|set| match set {
+            CompactSet::Data(data_set) => Some(data_set),
+            CompactSet::Decoded(_) => None,
+        })
+    }
+}
+
+impl CompactDataSet {
+    /// Decode all records of `data_set`
+    pub fn decode(data_set: BorrowedDataSet<'_>, options: ParseOptions) -> BinResult<Self> {
+        let mut values = vec![];
+        decode_values(&data_set.template, data_set.bytes, options, &mut values)?;
+        Ok(Self {
+            set_id: data_set.set_id,
+            field_count: data_set.template.field_specifiers().count(),
+            template: data_set.template,
+            values,
+        })
+    }
+
+    /// The position of `key` in the template, to look values up with
+    /// `CompactRecord::value`
+    pub fn field_index(&self, key: impl Into<DataRecordKey>) -> Option<usize> {
+        let key = key.into();
+        self.template
+            .field_specifiers()
+            .position(|field_spec| field_spec.name == key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values
+            .len()
+            .checked_div(self.field_count)
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = CompactRecord<'_>> {
+        // a template with no fields has no values to split
+        self.values
+            .chunks(self.field_count.max(1))
+            .map(|values| CompactRecord {
+                template: &self.template,
+                values,
+            })
+    }
+}
+
+/// Decode all records of `template` in the `bytes` of a data set,
+/// appending their values to `values`
+pub(crate) fn decode_values(
+    template: &Template,
+    bytes: &[u8],
+    options: ParseOptions,
+    values: &mut Vec<DataRecordValue>,
+) -> BinResult<()> {
+    let min_length = template.min_record_length().max(1);
+    let length = bytes.len() as u64;
+    let mut reader = Cursor::new(bytes);
+    while length - reader.position() >= min_length {
+        let (record_start, values_len) = (reader.position(), values.len());
+        for field_spec in template.field_specifiers() {
+            values.push(reader.read_type_args(
+                Endian::Big,
+                (field_spec.ty, field_spec.field_length, options),
+            )?);
+        }
+        // a template with no fields, or only zero length fields, makes
+        // records of no bytes, which would repeat forever
+        if reader.position() == record_start {
+            values.truncate(values_len);
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl<'s> CompactRecord<'s> {
+    pub(crate) fn new(template: &'s Template, values: &'s [DataRecordValue]) -> Self {
+        Self { template, values }
+    }
+
+    /// The value at `index` in template order, from
+    /// `CompactDataSet::field_index`
+    pub fn value(&self, index: usize) -> Option<&'s DataRecordValue> {
+        self.values.get(index)
+    }
+
+    /// The value of `key`, if the template has it
+    pub fn get(&self, key: impl Into<DataRecordKey>) -> Option<&'s DataRecordValue> {
+        let key = key.into();
+        self.values().find(|(k, _)| **k == key).map(|(_, v)| v)
+    }
+
+    /// All values, in template order
+    pub fn values(&self) -> impl Iterator<Item = (&'s DataRecordKey, &'s DataRecordValue)> {
+        self.template
+            .field_specifiers()
+            .map(|field_spec| &field_spec.name)
+            .zip(self.values)
+    }
+
+    pub fn to_data_record(&self) -> DataRecord {
+        let scope_field_count = self.template.scope_field_specifiers().len();
+        let mut record = DataRecord::default();
+        for (i, (key, value)) in self.values().enumerate() {
+            if i < scope_field_count {
+                record.scope_values.insert(key.clone(), value.clone());
+            } else {
+                record.values.insert(key.clone(), value.clone());
+            }
+        }
+        record
+    }
+}
@@ -0,0 +1,174 @@
+//! Conversions between `DataRecordValue` and Rust types
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::parser::{DataRecordValue, MacAddress, ValueConversionError};
+
+/// Implement TryFrom for owned and borrowed `DataRecordValue`s, with
+/// the borrowed conversion given by `$convert`
+macro_rules! impl_try_from_value {
+    ($($ty:ty => $convert:expr),+ $(,)?) => {
+        $(
+            impl TryFrom<&DataRecordValue> for $ty {
+                type Error = ValueConversionError;
+
+                fn try_from(value: &DataRecordValue) -> Result<Self, Self::Error> {
+                    let convert: fn(&DataRecordValue) -> Option<$ty> = $convert;
+                    convert(value).ok_or(ValueConversionError)
+                }
+            }
+
+            impl TryFrom<DataRecordValue> for $ty {
+                type Error = ValueConversionError;
+
+                fn try_from(value: DataRecordValue) -> Result<Self, Self::Error> {
+                    (&value).try_into()
+                }
+            }
+        )+
+    };
+}
+
+/// Any integer variant, if the value fits in the target type
+fn integer<T: TryFrom<u64> + TryFrom<i64>>(value: &DataRecordValue) -> Option<T> {
+    match (value.as_u64(), value.as_i64()) {
+        (Some(x), _) => x.try_into().ok(),
+        (_, Some(x)) => x.try_into().ok(),
+        _ => None,
+    }
+}
+
+impl_try_from_value! {
+    u8 => integer,
+    u16 => integer,
+    u32 => integer,
+    u64 => integer,
+    i8 => integer,
+    i16 => integer,
+    i32 => integer,
+    i64 => integer,
+    f32 => |value| match value {
+        DataRecordValue::F32(x) => Some(*x),
+        _ => None,
+    },
+    f64 => |value| match value {
+        DataRecordValue::F32(x) => Some((*x).into()),
+        DataRecordValue::F64(x) => Some(*x),
+        _ => None,
+    },
+    bool => |value| match value {
+        DataRecordValue::Bool(x) => Some(*x),
+        _ => None,
+    },
+    MacAddress => |value| match value {
+        DataRecordValue::MacAddress(x) => Some(*x),
+        _ => None,
+    },
+    [u8; 6] => |value| match value {
+        DataRecordValue::MacAddress(x) => Some(x.0),
+        _ => None,
+    },
+    String => |value| match value {
+        DataRecordValue::String(x) => Some(x.clone()),
+        _ => None,
+    },
+    Vec<u8> => |value| match value {
+        DataRecordValue::Bytes(x) => Some(x.clone()),
+        DataRecordValue::String(x) => Some(x.clone().into_bytes()),
+        _ => None,
+    },
+    Ipv4Addr => |value| match value {
+        DataRecordValue::Ipv4Addr(x) => Some(*x),
+        _ => None,
+    },
+    Ipv6Addr => |value| match value {
+        DataRecordValue::Ipv6Addr(x) => Some(*x),
+        _ => None,
+    },
+    IpAddr => |value| match value {
+        DataRecordValue::Ipv4Addr(x) => Some((*x).into()),
+        DataRecordValue::Ipv6Addr(x) => Some((*x).into()),
+        _ => None,
+    },
+}
+
+/// Implement From for `DataRecordValue`, using the variant `$variant`
+macro_rules! impl_from_for_value {
+    ($($ty:ty => $variant:ident),+ $(,)?) => {
+        $(
+            impl From<$ty> for DataRecordValue {
+                fn from(value: $ty) -> Self {
+                    DataRecordValue::$variant(value.into())
+                }
+            }
+        )+
+    };
+}
+
+impl_from_for_value! {
+    u8 => U8,
+    u16 => U16,
+    u32 => U32,
+    u64 => U64,
+    i8 => I8,
+    i16 => I16,
+    i32 => I32,
+    i64 => I64,
+    f32 => F32,
+    f64 => F64,
+    bool => Bool,
+    MacAddress => MacAddress,
+    [u8; 6] => MacAddress,
+    String => String,
+    &str => String,
+    Vec<u8> => Bytes,
+    &[u8] => Bytes,
+    Ipv4Addr => Ipv4Addr,
+    Ipv6Addr => Ipv6Addr,
+}
+
+impl From<IpAddr> for DataRecordValue {
+    fn from(address: IpAddr) -> Self {
+        match address {
+            IpAddr::V4(address) => DataRecordValue::Ipv4Addr(address),
+            IpAddr::V6(address) => DataRecordValue::Ipv6Addr(address),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a DataRecordValue> for &'a str {
+    type Error = ValueConversionError;
+
+    fn try_from(value: &'a DataRecordValue) -> Result<Self, Self::Error> {
+        match value {
+            DataRecordValue::String(x) => Ok(x),
+            _ => Err(ValueConversionError),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a DataRecordValue> for &'a [u8] {
+    type Error = ValueConversionError;
+
+    fn try_from(value: &'a DataRecordValue) -> Result<Self, Self::Error> {
+        match value {
+            DataRecordValue::Bytes(x) => Ok(x),
+            DataRecordValue::String(x) => Ok(x.as_bytes()),
+            _ => Err(ValueConversionError),
+        }
+    }
+}
+
+#[cfg(feature = "macaddr")]
+impl From<macaddr::MacAddr6> for MacAddress {
+    fn from(mac: macaddr::MacAddr6) -> Self {
+        Self(mac.into_array())
+    }
+}
+
+#[cfg(feature = "macaddr")]
+impl From<MacAddress> for macaddr::MacAddr6 {
+    fn from(mac: MacAddress) -> Self {
+        mac.0.into()
+    }
+}
@@ -0,0 +1,226 @@
+//! Human-readable `Display` implementations, for debugging and CLI output
+//!
+//! Messages are shown one line per template or record, indented under
+//! their set. Timestamps are shown in RFC 3339 format, in UTC
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::parser::{
+    DataRecord, DataRecordKey, DataRecordValue, FieldSpecifier, Message, NtpTimestamp,
+    OptionsTemplateRecord, Records, Set, TemplateRecord,
+};
+
+/// Write `nanos` since the Unix epoch as an RFC 3339 timestamp with
+/// `precision` fractional digits
+fn write_unix_nanos(f: &mut Formatter<'_>, nanos: i64, precision: u32) -> fmt::Result {
+    let seconds = nanos.div_euclid(1_000_000_000);
+    let nanos = nanos.rem_euclid(1_000_000_000);
+    let (days, seconds) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+
+    // days to civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    write!(
+        f,
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )?;
+    if precision > 0 {
+        let fraction = nanos / 10_i64.pow(9 - precision);
+        write!(f, ".{fraction:0width$}", width = precision as usize)?;
+    }
+    write!(f, "Z")
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Message exported ")?;
+        write_unix_nanos(f, i64::from(self.export_time) * 1_000_000_000, 0)?;
+        writeln!(
+            f,
+            ", sequence number {}, observation domain {}",
+            self.sequence_number, self.observation_domain_id
+        )?;
+        for set in &self.sets {
+            for line in set.to_string().lines() {
+                writeln!(f, "  {line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Set {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.records {
+            Records::Template(records) => {
+                writeln!(f, "Template Set ({})", self.records.set_id())?;
+                for record in records {
+                    writeln!(f, "  {record}")?;
+                }
+            }
+            Records::OptionsTemplate(records) => {
+                writeln!(f, "Options Template Set ({})", self.records.set_id())?;
+                for record in records {
+                    writeln!(f, "  {record}")?;
+                }
+            }
+            Records::Data { set_id, data } => {
+                writeln!(f, "Data Set ({set_id})")?;
+                for record in data {
+                    writeln!(f, "  {record}")?;
+                }
+            }
+            Records::RawData { set_id, bytes } => {
+                writeln!(f, "Undecoded Data Set ({set_id}): {} bytes", bytes.len())?;
+            }
+            Records::Unsupported { set_id, data } => {
+                writeln!(f, "Unsupported Set ({set_id}): {} bytes", data.len())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_field_specifiers(
+    f: &mut Formatter<'_>,
+    field_specifiers: &[FieldSpecifier],
+) -> fmt::Result {
+    for (i, field_spec) in field_specifiers.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{field_spec}")?;
+    }
+    Ok(())
+}
+
+impl Display for TemplateRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.field_specifiers.is_empty() {
+            return write!(f, "Template {} withdrawn", self.template_id);
+        }
+        write!(f, "Template {}: ", self.template_id)?;
+        write_field_specifiers(f, &self.field_specifiers)
+    }
+}
+
+impl Display for OptionsTemplateRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_withdrawal() {
+            return write!(f, "Options Template {} withdrawn", self.template_id);
+        }
+        let scope_field_specifiers = self.scope_field_specifiers();
+        write!(f, "Options Template {}: scope ", self.template_id)?;
+        write_field_specifiers(f, scope_field_specifiers)?;
+        write!(f, "; ")?;
+        write_field_specifiers(f, &self.field_specifiers[scope_field_specifiers.len()..])
+    }
+}
+
+/// Formats as "{id}[{length}]", with the id prefixed by "{enterprise_number}:"
+/// for enterprise-specific elements
+impl Display for FieldSpecifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(enterprise_number) = self.enterprise_number {
+            write!(f, "{enterprise_number}:")?;
+        }
+        write!(f, "{}", self.information_element_identifier)?;
+        if self.field_length == u16::MAX {
+            write!(f, "[variable]")
+        } else {
+            write!(f, "[{}]", self.field_length)
+        }
+    }
+}
+
+/// Formats as "{name}={value}" pairs, scope values first, in the order
+/// of the values maps
+impl Display for DataRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut values = self.scope_values.iter().chain(&self.values);
+        if let Some((key, value)) = values.next() {
+            write!(f, "{key}={value}")?;
+        }
+        for (key, value) in values {
+            write!(f, " {key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats as the information element name, or
+/// "{enterprise_number}:{information_element_identifier}:{field_length}"
+/// for unrecognized fields, with enterprise number 0 meaning none
+impl Display for DataRecordKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DataRecordKey::Str(name) => write!(f, "{name}"),
+            DataRecordKey::Owned(name) => write!(f, "{name}"),
+            DataRecordKey::Unrecognized(field_spec) => write!(
+                f,
+                "{}:{}:{}",
+                field_spec.enterprise_number.unwrap_or(0),
+                field_spec.information_element_identifier,
+                field_spec.field_length
+            ),
+            DataRecordKey::Err(err) => write!(f, "error:{err}"),
+        }
+    }
+}
+
+/// Formats octet arrays as hex, strings quoted, and timestamps in RFC 3339
+/// format at the precision of their type
+impl Display for DataRecordValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DataRecordValue::U8(x) => write!(f, "{x}"),
+            DataRecordValue::U16(x) => write!(f, "{x}"),
+            DataRecordValue::U32(x) => write!(f, "{x}"),
+            DataRecordValue::U64(x) => write!(f, "{x}"),
+            DataRecordValue::I8(x) => write!(f, "{x}"),
+            DataRecordValue::I16(x) => write!(f, "{x}"),
+            DataRecordValue::I32(x) => write!(f, "{x}"),
+            DataRecordValue::I64(x) => write!(f, "{x}"),
+            DataRecordValue::F32(x) => write!(f, "{x}"),
+            DataRecordValue::F64(x) => write!(f, "{x}"),
+            DataRecordValue::Bool(x) => write!(f, "{x}"),
+            DataRecordValue::MacAddress(x) => write!(f, "{x}"),
+            DataRecordValue::Bytes(x) => {
+                write!(f, "0x")?;
+                x.iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
+            DataRecordValue::String(x) => write!(f, "{x:?}"),
+            DataRecordValue::DateTimeSeconds(x) => {
+                write_unix_nanos(f, i64::from(*x) * 1_000_000_000, 0)
+            }
+            DataRecordValue::DateTimeMilliseconds(x) => match i64::try_from(*x) {
+                Ok(millis) if millis <= i64::MAX / 1_000_000 => {
+                    write_unix_nanos(f, millis * 1_000_000, 3)
+                }
+                _ => write!(f, "{x}ms"),
+            },
+            DataRecordValue::DateTimeMicroseconds(x) => write_unix_nanos(f, x.to_unix_nanos(), 6),
+            DataRecordValue::DateTimeNanoseconds(x) => write!(f, "{x}"),
+            DataRecordValue::Ipv4Addr(x) => write!(f, "{x}"),
+            DataRecordValue::Ipv6Addr(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+/// Formats as an RFC 3339 timestamp with nanosecond precision
+impl Display for NtpTimestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write_unix_nanos(f, self.to_unix_nanos(), 9)
+    }
+}
@@ -0,0 +1,517 @@
+//! IPFIX over DTLS, with OpenSSL (RFC 7011 section 11). Each message is
+//! one DTLS record in one datagram, so messages must fit in the MTU
+//! after the record overhead. Collectors keep templates for each DTLS
+//! association, and drop them when it closes or fails, as well as
+//! expiring them as for UDP
+//! <https://www.rfc-editor.org/rfc/rfc7011#section-11>
+//!
+//! Lost handshake datagrams aren't retransmitted, so handshakes across
+//! lossy networks may need to be retried
+//!
+//! Collectors answer a ClientHello without a cookie with a
+//! HelloVerifyRequest, without keeping any state, so associations only
+//! start for peers that can receive at their source address
+//! <https://www.rfc-editor.org/rfc/rfc6347#section-4.2.1>
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::ops::Range;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use binrw::{BinResult, BinWrite, Endian};
+pub use openssl;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::ssl::{
+    ErrorCode, Ssl, SslAcceptor, SslAcceptorBuilder, SslConnector, SslOptions, SslStream,
+};
+
+use crate::collector::{Collected, CollectorSession, SessionEvent};
+use crate::information_elements::Formatter;
+use crate::parser::{Message, ParseOptions, WriteOptions};
+use crate::template_store::TemplateStorage;
+
+/// A conservative bound on the bytes a DTLS 1.2 record adds to a
+/// message: the header, explicit IV or nonce, and MAC or tag with padding
+pub const DTLS_RECORD_OVERHEAD: u16 = 64;
+
+/// The largest possible message, as the Length field is 16 bits
+const MAX_MESSAGE_LENGTH: usize = u16::MAX as usize;
+
+/// The DTLS record header: type, version, epoch, sequence number, length
+const RECORD_HEADER_LENGTH: usize = 13;
+
+/// The DTLS handshake header: type, length, message_seq, fragment_offset
+/// and fragment_length
+const HANDSHAKE_HEADER_LENGTH: usize = 12;
+
+fn ssl_error(err: impl std::fmt::Display) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+/// A connected UDP socket as a stream of datagrams
+#[derive(Debug)]
+struct ConnectedSocket(UdpSocket);
+
+impl Read for ConnectedSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl Write for ConnectedSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The datagrams of one peer on a shared socket. Reads take the
+/// datagrams routed to it by `DtlsCollector::run`, timing out after
+/// `timeout`, and writes are sent to the peer, except the first
+/// `discard_writes`
+#[derive(Debug)]
+struct PeerChannel {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    incoming: Receiver<Vec<u8>>,
+    timeout: Duration,
+    discard_writes: usize,
+    last_read: Instant,
+}
+
+impl Read for PeerChannel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.incoming.recv_timeout(self.timeout) {
+            Ok(datagram) => {
+                self.last_read = Instant::now();
+                let length = datagram.len().min(buf.len());
+                buf[..length].copy_from_slice(&datagram[..length]);
+                Ok(length)
+            }
+            Err(RecvTimeoutError::Timeout) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(RecvTimeoutError::Disconnected) => Ok(0),
+        }
+    }
+}
+
+impl Write for PeerChannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.discard_writes > 0 {
+            self.discard_writes -= 1;
+            return Ok(buf.len());
+        }
+        self.socket.send_to(buf, self.peer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends messages to a Collector over DTLS, one message per datagram
+pub struct DtlsExporter<'a> {
+    stream: SslStream<ConnectedSocket>,
+    templates: &'a dyn TemplateStorage,
+    formatter: &'a Formatter,
+    options: WriteOptions,
+    max_message_length: usize,
+    buf: Vec<u8>,
+}
+
+impl<'a> DtlsExporter<'a> {
+    /// Handshake with the Collector at `addr`, which must have a
+    /// certificate for `domain`. `mtu` is the largest UDP payload to send
+    pub fn connect(
+        socket: UdpSocket,
+        addr: impl ToSocketAddrs,
+        connector: &SslConnector,
+        domain: &str,
+        mtu: u16,
+        templates: &'a dyn TemplateStorage,
+        formatter: &'a Formatter,
+    ) -> io::Result<Self> {
+        socket.connect(addr)?;
+        let mut ssl = connector
+            .configure()
+            .and_then(|config| config.into_ssl(domain))
+            .map_err(ssl_error)?;
+        ssl.set_mtu(mtu.into()).map_err(ssl_error)?;
+        let mut stream = SslStream::new(ssl, ConnectedSocket(socket)).map_err(ssl_error)?;
+        stream.connect().map_err(ssl_error)?;
+        Ok(Self {
+            stream,
+            templates,
+            formatter,
+            options: WriteOptions::default(),
+            max_message_length: mtu.saturating_sub(DTLS_RECORD_OVERHEAD).into(),
+            buf: vec![],
+        })
+    }
+
+    /// Write messages with `options`
+    pub fn options(mut self, options: WriteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The longest message that fits in one datagram
+    pub fn max_message_length(&self) -> usize {
+        self.max_message_length
+    }
+
+    /// Encode `message` and send it in one datagram, failing if it is
+    /// longer than `max_message_length`
+    pub fn send(&mut self, message: &Message) -> BinResult<()> {
+        self.buf.clear();
+        message.write_options(
+            &mut io::Cursor::new(&mut self.buf),
+            Endian::Big,
+            (self.templates, self.formatter, self.options),
+        )?;
+        if self.buf.len() > self.max_message_length {
+            return Err(binrw::Error::AssertFail {
+                pos: 0,
+                message: format!(
+                    "message too long for DTLS MTU: [{} > {}]",
+                    self.buf.len(),
+                    self.max_message_length
+                ),
+            });
+        }
+        self.stream.ssl_write(&self.buf).map_err(ssl_error)?;
+        Ok(())
+    }
+
+    /// Close the association, which drops its templates on the Collector
+    pub fn close(mut self) -> io::Result<()> {
+        self.stream.shutdown().map_err(ssl_error)?;
+        Ok(())
+    }
+}
+
+/// Something that happened on a `DtlsCollector`
+#[derive(Debug)]
+pub enum DtlsEvent {
+    /// A new association, before its handshake
+    Connected(SocketAddr),
+    /// A message from `peer`, and the result of decoding it. Handshake
+    /// failures are reported as an error before `Disconnected`
+    Message {
+        peer: SocketAddr,
+        result: BinResult<Collected>,
+    },
+    /// Templates of `peer` expired while sweeping
+    Swept {
+        peer: SocketAddr,
+        events: Vec<SessionEvent>,
+    },
+    /// The association closed, and its templates were dropped
+    Disconnected(SocketAddr),
+}
+
+/// Receives DTLS associations on a UDP socket, each in its own thread
+/// with its own `CollectorSession`. Templates are swept every half
+/// `template_lifetime`, and associations close after `idle_timeout`
+/// without a datagram
+pub struct DtlsCollector {
+    socket: Arc<UdpSocket>,
+    acceptor: Arc<SslAcceptor>,
+    cookie_key: PKey<Private>,
+    cookie_index: Index<Ssl, Vec<u8>>,
+    formatter: Formatter,
+    template_lifetime: Duration,
+    idle_timeout: Duration,
+    options: ParseOptions,
+    mtu: u16,
+    max_associations: usize,
+}
+
+impl DtlsCollector {
+    /// Enables the cookie exchange on `acceptor`, replacing any cookie
+    /// callbacks it has
+    pub fn new(
+        socket: UdpSocket,
+        mut acceptor: SslAcceptorBuilder,
+        formatter: Formatter,
+        template_lifetime: Duration,
+    ) -> io::Result<Self> {
+        let mut secret = [0; 32];
+        openssl::rand::rand_bytes(&mut secret).map_err(ssl_error)?;
+        let cookie_key = PKey::hmac(&secret).map_err(ssl_error)?;
+        // associations are only started for a ClientHello with a valid
+        // cookie, so the cookie callbacks just repeat it
+        let cookie_index = Ssl::new_ex_index::<Vec<u8>>().map_err(ssl_error)?;
+        acceptor.set_options(SslOptions::COOKIE_EXCHANGE);
+        acceptor.set_cookie_generate_cb(move |ssl, buf| {
+            let cookie = ssl.ex_data(cookie_index).map_or(&[][..], Vec::as_slice);
+            let length = cookie.len().min(buf.len());
+            buf[..length].copy_from_slice(&cookie[..length]);
+            Ok(length)
+        });
+        acceptor.set_cookie_verify_cb(move |ssl, cookie| {
+            ssl.ex_data(cookie_index).is_some_and(|expected| {
+                expected.len() == cookie.len() && memcmp::eq(expected, cookie)
+            })
+        });
+        Ok(Self {
+            socket: Arc::new(socket),
+            acceptor: Arc::new(acceptor.build()),
+            cookie_key,
+            cookie_index,
+            formatter,
+            template_lifetime,
+            idle_timeout: template_lifetime,
+            options: ParseOptions::default(),
+            mtu: 1472,
+            max_associations: 1024,
+        })
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The largest UDP payload to send during handshakes, 1472 by default
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Close associations after `idle_timeout` without a datagram,
+    /// `template_lifetime` by default
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Ignore new peers while `max_associations` are open, 1024 by default
+    pub fn max_associations(mut self, max_associations: usize) -> Self {
+        self.max_associations = max_associations;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The cookie of `peer`, so only a peer receiving at its address can
+    /// start an association
+    fn cookie(&self, peer: SocketAddr) -> io::Result<Vec<u8>> {
+        let mut signer =
+            Signer::new(MessageDigest::sha256(), &self.cookie_key).map_err(ssl_error)?;
+        signer
+            .update(peer.to_string().as_bytes())
+            .map_err(ssl_error)?;
+        signer.sign_to_vec().map_err(ssl_error)
+    }
+
+    /// Route datagrams to their association, starting one for each new
+    /// peer with a valid cookie, and send the events of all of them to
+    /// `sender`. Returns when receiving fails, or on the next new peer
+    /// after the receiver is dropped
+    pub fn run(&self, sender: Sender<DtlsEvent>) -> io::Result<()> {
+        let mut peers: HashMap<SocketAddr, (u64, Sender<Vec<u8>>)> = HashMap::new();
+        let (finished_sender, finished) = channel();
+        let mut next_association = 0;
+        let mut buf = vec![0; MAX_MESSAGE_LENGTH];
+        loop {
+            let (length, peer) = self.socket.recv_from(&mut buf)?;
+            for (peer, association) in finished.try_iter() {
+                if peers.get(&peer).is_some_and(|(id, _)| *id == association) {
+                    peers.remove(&peer);
+                }
+            }
+            let datagram = &buf[..length];
+            if let Some((_, incoming)) = peers.get(&peer) {
+                if incoming.send(datagram.to_vec()).is_ok() {
+                    continue;
+                }
+                // the association ended, so this may start a new one
+                peers.remove(&peer);
+            }
+
+            let Some(hello) = ClientHello::parse(datagram) else {
+                continue;
+            };
+            let cookie = self.cookie(peer)?;
+            let peer_cookie = &datagram[hello.cookie.clone()];
+            if peer_cookie.len() != cookie.len() || !memcmp::eq(peer_cookie, &cookie) {
+                // failing to answer one peer shouldn't stop the others
+                let _ = self
+                    .socket
+                    .send_to(&hello.verify_request(datagram, &cookie), peer);
+                continue;
+            }
+            if peers.len() >= self.max_associations {
+                continue;
+            }
+
+            if sender.send(DtlsEvent::Connected(peer)).is_err() {
+                return Ok(());
+            }
+            let (incoming, receiver) = channel();
+            let _ = incoming.send(hello.initial(datagram));
+            let _ = incoming.send(datagram.to_vec());
+            next_association += 1;
+            let association = next_association;
+            peers.insert(peer, (association, incoming));
+            let channel = PeerChannel {
+                socket: self.socket.clone(),
+                peer,
+                incoming: receiver,
+                timeout: (self.template_lifetime / 2).min(self.idle_timeout),
+                // the HelloVerifyRequest for the initial ClientHello,
+                // which the peer already has
+                discard_writes: 1,
+                last_read: Instant::now(),
+            };
+            let mut ssl = Ssl::new(self.acceptor.context()).map_err(ssl_error)?;
+            ssl.set_ex_data(self.cookie_index, cookie);
+            ssl.set_mtu(self.mtu.into()).map_err(ssl_error)?;
+            let session = (self.formatter.clone(), self.template_lifetime, self.options);
+            let idle_timeout = self.idle_timeout;
+            let sender = sender.clone();
+            let finished_sender = finished_sender.clone();
+            std::thread::spawn(move || {
+                let (formatter, template_lifetime, options) = session;
+                let session = CollectorSession::new(formatter, template_lifetime).options(options);
+                if let Err(err) = read_association(channel, ssl, idle_timeout, session, &sender) {
+                    let result = Err(err.into());
+                    let _ = sender.send(DtlsEvent::Message { peer, result });
+                }
+                let _ = finished_sender.send((peer, association));
+                let _ = sender.send(DtlsEvent::Disconnected(peer));
+            });
+        }
+    }
+
+    /// `run` in a new thread
+    pub fn spawn(self, sender: Sender<DtlsEvent>) -> JoinHandle<io::Result<()>> {
+        std::thread::spawn(move || self.run(sender))
+    }
+}
+
+/// Where the cookie is in the first fragment of a ClientHello, in a
+/// handshake record of epoch 0
+#[derive(Debug)]
+struct ClientHello {
+    cookie: Range<usize>,
+}
+
+impl ClientHello {
+    fn parse(datagram: &[u8]) -> Option<Self> {
+        let record = datagram.get(..RECORD_HEADER_LENGTH)?;
+        if record[0] != 22 || record[3..5] != [0, 0] {
+            return None;
+        }
+        let record_end =
+            RECORD_HEADER_LENGTH + usize::from(u16::from_be_bytes([record[11], record[12]]));
+        let header = datagram.get(RECORD_HEADER_LENGTH..record_end)?;
+        let header = header.get(..HANDSHAKE_HEADER_LENGTH)?;
+        if header[0] != 1 || header[6..9] != [0, 0, 0] {
+            return None;
+        }
+        let fragment_length = u32::from_be_bytes([0, header[9], header[10], header[11]]) as usize;
+        let end = RECORD_HEADER_LENGTH + HANDSHAKE_HEADER_LENGTH + fragment_length;
+        // after client_version and random
+        let session_id = RECORD_HEADER_LENGTH + HANDSHAKE_HEADER_LENGTH + 34;
+        let cookie_length = session_id + 1 + usize::from(*datagram.get(session_id)?);
+        let cookie_start = cookie_length + 1;
+        let cookie = cookie_start..cookie_start + usize::from(*datagram.get(cookie_length)?);
+        (end <= record_end && cookie.end <= end).then_some(Self { cookie })
+    }
+
+    /// A HelloVerifyRequest with `cookie`, in a record with the sequence
+    /// number of the ClientHello
+    fn verify_request(&self, datagram: &[u8], cookie: &[u8]) -> Vec<u8> {
+        let body_length = (3 + cookie.len() as u32).to_be_bytes();
+        let record_length = (HANDSHAKE_HEADER_LENGTH + 3 + cookie.len()) as u16;
+        let mut request = vec![22, 0xfe, 0xff, 0, 0];
+        request.extend_from_slice(&datagram[5..11]);
+        request.extend_from_slice(&record_length.to_be_bytes());
+        request.push(3);
+        request.extend_from_slice(&body_length[1..]);
+        request.extend_from_slice(&[0, 0, 0, 0, 0]);
+        request.extend_from_slice(&body_length[1..]);
+        // DTLS 1.0, as servers should send whatever version they use
+        request.extend_from_slice(&[0xfe, 0xff, cookie.len() as u8]);
+        request.extend_from_slice(cookie);
+        request
+    }
+
+    /// The ClientHello the peer sent before this one, up to its empty
+    /// cookie, with sequence numbers of 0. A server with the cookie
+    /// exchange reads no further before answering it, so reading it first
+    /// continues the handshake as if the server had sent the
+    /// HelloVerifyRequest itself
+    fn initial(&self, datagram: &[u8]) -> Vec<u8> {
+        let mut hello = datagram[..self.cookie.start].to_vec();
+        hello[self.cookie.start - 1] = 0;
+        let record_length = (hello.len() - RECORD_HEADER_LENGTH) as u16;
+        let body_length = (usize::from(record_length) - HANDSHAKE_HEADER_LENGTH) as u32;
+        hello[5..11].fill(0);
+        hello[11..13].copy_from_slice(&record_length.to_be_bytes());
+        hello[14..17].copy_from_slice(&body_length.to_be_bytes()[1..]);
+        hello[17..19].fill(0);
+        hello[22..25].copy_from_slice(&body_length.to_be_bytes()[1..]);
+        hello
+    }
+}
+
+/// Handshake and read messages until the association closes, or is idle
+/// for `idle_timeout`. The handshake fails if the peer is silent for a
+/// sweep interval
+fn read_association(
+    channel: PeerChannel,
+    ssl: Ssl,
+    idle_timeout: Duration,
+    mut session: CollectorSession<SocketAddr>,
+    sender: &Sender<DtlsEvent>,
+) -> io::Result<()> {
+    let peer = channel.peer;
+    let sweep_interval = channel.timeout;
+    let mut stream = SslStream::new(ssl, channel).map_err(ssl_error)?;
+    stream.accept().map_err(ssl_error)?;
+
+    let mut buf = vec![0; MAX_MESSAGE_LENGTH];
+    let mut last_sweep = Instant::now();
+    loop {
+        match stream.ssl_read(&mut buf) {
+            Ok(length) => {
+                let result = session.handle_datagram(peer, &buf[..length]);
+                if sender.send(DtlsEvent::Message { peer, result }).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(err) if err.code() == ErrorCode::ZERO_RETURN => return Ok(()),
+            // no datagram within the sweep interval
+            Err(err) if err.code() == ErrorCode::WANT_READ => {
+                if stream.get_ref().last_read.elapsed() >= idle_timeout {
+                    let _ = stream.shutdown();
+                    return Ok(());
+                }
+            }
+            Err(err) => return Err(ssl_error(err)),
+        }
+        if last_sweep.elapsed() >= sweep_interval {
+            last_sweep = Instant::now();
+            let events = session.sweep();
+            if !events.is_empty() && sender.send(DtlsEvent::Swept { peer, events }).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,830 @@
+//! Building messages for export, with the header fields filled in
+//! automatically
+
+pub mod batch;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Cursor;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use binrw::{BinResult, BinWrite, Endian};
+
+use crate::borrowed::record_count;
+use crate::information_elements::Formatter;
+use crate::parser::{
+    DataRecord, DataRecordKey, FieldSpecifier, IpfixError, Message, OptionsTemplateRecord, Records,
+    Set, Sets, TemplateRecord, WriteOptions, OPTIONS_TEMPLATE_SET_ID, TEMPLATE_SET_ID,
+};
+use crate::statistics::{ExportingProcessStatistics, MeteringProcessStatistics};
+use crate::template_store::{template_records, Template, TemplateStorage};
+
+/// Length of the message header
+const MESSAGE_HEADER_LENGTH: usize = 16;
+/// Length of the set header
+const SET_HEADER_LENGTH: usize = 4;
+
+/// The header state of an exporter for one Observation Domain, which
+/// numbers messages by the count of data records sent before them
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-3.1>
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ExportSession {
+    observation_domain_id: u32,
+    sequence_number: u32,
+}
+
+impl ExportSession {
+    pub fn new(observation_domain_id: u32) -> Self {
+        Self {
+            observation_domain_id,
+            sequence_number: 0,
+        }
+    }
+
+    pub fn observation_domain_id(&self) -> u32 {
+        self.observation_domain_id
+    }
+
+    /// Sequence number of the next message
+    pub fn sequence_number(&self) -> u32 {
+        self.sequence_number
+    }
+
+    /// Start building the next message
+    pub fn message(&mut self) -> MessageBuilder<'_> {
+        MessageBuilder {
+            session: self,
+            export_time: None,
+            max_size: None,
+            repeat_templates: false,
+            sets: Sets::new(),
+        }
+    }
+
+    /// The next message, advancing the sequence number by the number of
+    /// data records in `sets`. Records of raw data sets are only counted
+    /// if their template is in `templates`
+    fn next_message(
+        &mut self,
+        export_time: u32,
+        sets: Sets,
+        templates: Option<&dyn TemplateStorage>,
+    ) -> Message {
+        let message = Message {
+            export_time,
+            sequence_number: self.sequence_number,
+            observation_domain_id: self.observation_domain_id,
+            sets,
+        };
+        let raw_record_count: usize = message
+            .sets
+            .iter()
+            .filter_map(|set| match &set.records {
+                Records::RawData { set_id, bytes } => templates?
+                    .get_template(*set_id)
+                    .map(|template| record_count(&template, bytes)),
+                _ => None,
+            })
+            .sum();
+        let data_record_count = message.iter_data_records().count() + raw_record_count;
+        // modulo 2^32
+        self.sequence_number = self.sequence_number.wrapping_add(data_record_count as u32);
+        message
+    }
+}
+
+/// Assembles the sets of a message, then fills in its header from an
+/// `ExportSession`
+#[derive(Debug)]
+pub struct MessageBuilder<'a> {
+    session: &'a mut ExportSession,
+    export_time: Option<u32>,
+    max_size: Option<usize>,
+    repeat_templates: bool,
+    sets: Sets,
+}
+
+impl MessageBuilder<'_> {
+    /// Use `export_time` rather than the current time
+    pub fn export_time(mut self, export_time: u32) -> Self {
+        self.export_time = Some(export_time);
+        self
+    }
+
+    /// Split into messages of at most `max_size` bytes with
+    /// `build_messages`, such as 1464 bytes to fit an Ethernet MTU
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// When splitting, start each later message with the templates of
+    /// its data sets, so every message can be decoded alone
+    pub fn repeat_templates(mut self, repeat_templates: bool) -> Self {
+        self.repeat_templates = repeat_templates;
+        self
+    }
+
+    pub fn set(mut self, set: Set) -> Self {
+        self.sets.push(set);
+        self
+    }
+
+    pub fn template_set(self, records: Vec<TemplateRecord>) -> Self {
+        self.set(Set {
+            records: Records::Template(records),
+        })
+    }
+
+    pub fn options_template_set(self, records: Vec<OptionsTemplateRecord>) -> Self {
+        self.set(Set {
+            records: Records::OptionsTemplate(records),
+        })
+    }
+
+    pub fn data_set(self, set_id: u16, data: Vec<DataRecord>) -> Self {
+        self.set(Set {
+            records: Records::Data { set_id, data },
+        })
+    }
+
+    fn resolved_export_time(&self) -> u32 {
+        self.export_time.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs() as u32)
+        })
+    }
+
+    /// Finish the message, advancing the session's sequence number by
+    /// the number of data records in it. The export time is the current
+    /// time unless one was given. Records of raw data sets aren't
+    /// counted, as that needs their templates, so use `build_messages`
+    /// for those
+    pub fn build(self) -> Message {
+        let export_time = self.resolved_export_time();
+        self.session.next_message(export_time, self.sets, None)
+    }
+
+    /// Finish as one or more messages that each encode to at most
+    /// `max_size` bytes, splitting sets between records. Templates
+    /// defined by the sets are added to `templates`, as when writing.
+    /// Without a `max_size`, this is the same as `build`
+    pub fn build_messages(
+        self,
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+        options: WriteOptions,
+    ) -> BinResult<Vec<Message>> {
+        let export_time = self.resolved_export_time();
+        let Some(max_size) = self.max_size else {
+            return Ok(vec![self.session.next_message(
+                export_time,
+                self.sets,
+                Some(templates),
+            )]);
+        };
+
+        let mut splitter = Splitter {
+            max_size,
+            options,
+            repeat_templates: self.repeat_templates,
+            definitions: HashMap::new(),
+            messages: vec![],
+            sets: Sets::new(),
+            size: MESSAGE_HEADER_LENGTH,
+            last_set_length: 0,
+            defined: HashSet::new(),
+        };
+        let (template_records, options_template_records) = template_records(templates);
+        for record in template_records {
+            splitter.define(SplitRecord::Template(record));
+        }
+        for record in options_template_records {
+            splitter.define(SplitRecord::OptionsTemplate(record));
+        }
+
+        for set in self.sets {
+            match set.records {
+                Records::Template(records) => {
+                    for record in records {
+                        templates
+                            .insert_template_records(std::slice::from_ref(&record), formatter)
+                            .map_err(|e| e.into_binrw_error(0))?;
+                        splitter.push(SplitRecord::Template(record), templates, options)?;
+                    }
+                }
+                Records::OptionsTemplate(records) => {
+                    for record in records {
+                        templates
+                            .insert_options_template_records(
+                                std::slice::from_ref(&record),
+                                formatter,
+                            )
+                            .map_err(|e| e.into_binrw_error(0))?;
+                        splitter.push(SplitRecord::OptionsTemplate(record), templates, options)?;
+                    }
+                }
+                Records::Data { set_id, data } => {
+                    for record in data {
+                        splitter.push(SplitRecord::Data(set_id, record), templates, options)?;
+                    }
+                }
+                // the contents are undecoded, so can't be split
+                records @ (Records::RawData { .. } | Records::Unsupported { .. }) => {
+                    splitter.push(SplitRecord::Opaque(records), templates, options)?;
+                }
+            }
+        }
+        splitter.finish_message();
+
+        Ok(splitter
+            .messages
+            .into_iter()
+            .map(|sets| {
+                self.session
+                    .next_message(export_time, sets, Some(templates))
+            })
+            .collect())
+    }
+}
+
+/// A template defined by an `Exporter`
+#[derive(Clone, Debug)]
+enum Definition {
+    Template(TemplateRecord),
+    OptionsTemplate(OptionsTemplateRecord),
+}
+
+/// The write-side counterpart to `parse_ipfix_message`: an exporter for
+/// one Observation Domain that owns its templates, allocating their IDs
+/// and sending each before its first use, and batches data records into
+/// messages
+#[derive(Debug)]
+pub struct Exporter {
+    session: ExportSession,
+    templates: RefCell<ahash::HashMap<u16, Template>>,
+    formatter: Formatter,
+    options: WriteOptions,
+    max_size: Option<usize>,
+    definitions: BTreeMap<u16, Definition>,
+    next_template_id: u16,
+    /// Templates to send in the next message
+    unsent: Vec<u16>,
+    /// Templates to withdraw after the data of the next message
+    withdrawn: Vec<Definition>,
+    /// Data records of the next message, in sets
+    sets: Sets,
+    statistics: MeteringProcessStatistics,
+    not_sent: ExportingProcessStatistics,
+}
+
+impl Exporter {
+    pub fn new(observation_domain_id: u32, formatter: Formatter) -> Self {
+        Self {
+            session: ExportSession::new(observation_domain_id),
+            templates: RefCell::default(),
+            formatter,
+            options: WriteOptions::default(),
+            max_size: None,
+            definitions: BTreeMap::new(),
+            next_template_id: 256,
+            unsent: vec![],
+            withdrawn: vec![],
+            sets: Sets::new(),
+            statistics: MeteringProcessStatistics {
+                observation_domain_id,
+                ..Default::default()
+            },
+            not_sent: ExportingProcessStatistics::default(),
+        }
+    }
+
+    /// Write messages with `options`
+    pub fn options(mut self, options: WriteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Split each flush into messages of at most `max_size` bytes,
+    /// repeating templates in each message that uses them
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    pub fn session(&self) -> &ExportSession {
+        &self.session
+    }
+
+    /// The templates defined so far, to encode the flushed messages with
+    pub fn templates(&self) -> &dyn TemplateStorage {
+        &self.templates
+    }
+
+    pub fn formatter(&self) -> &Formatter {
+        &self.formatter
+    }
+
+    /// Counts of the messages and data records flushed so far, to
+    /// export with `MeteringProcessStatistics::options_template`. Octets
+    /// are only counted for messages encoded by `flush_bytes`
+    pub fn statistics(&self) -> MeteringProcessStatistics {
+        self.statistics
+    }
+
+    /// Counts of the data records dropped by flushes that failed, such
+    /// as with a record too large for `max_size`, to export with
+    /// `ExportingProcessStatistics::options_template`
+    pub fn not_sent_statistics(&self, exporting_process_id: u32) -> ExportingProcessStatistics {
+        ExportingProcessStatistics {
+            exporting_process_id,
+            ..self.not_sent
+        }
+    }
+
+    /// Data records waiting for the next flush
+    pub fn pending_records(&self) -> usize {
+        self.sets
+            .iter()
+            .map(|set| match &set.records {
+                Records::Data { data, .. } => data.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// An unused Template ID, preferring ones never used before. IDs
+    /// waiting to be withdrawn aren't reused until the next flush
+    fn allocate_template_id(&mut self) -> Result<u16, IpfixError> {
+        let withdrawn: HashSet<u16> = self
+            .withdrawn
+            .iter()
+            .map(|definition| match definition {
+                Definition::Template(record) => record.template_id,
+                Definition::OptionsTemplate(record) => record.template_id,
+            })
+            .collect();
+        let template_id = (self.next_template_id..=u16::MAX)
+            .chain(256..self.next_template_id)
+            .find(|id| !self.definitions.contains_key(id) && !withdrawn.contains(id))
+            .ok_or(IpfixError::TemplateIdsExhausted)?;
+        self.next_template_id = template_id.wrapping_add(1).max(256);
+        Ok(template_id)
+    }
+
+    fn define(&mut self, template_id: u16, definition: Definition) -> Result<u16, IpfixError> {
+        match &definition {
+            Definition::Template(record) => self
+                .templates
+                .insert_template_records(std::slice::from_ref(record), &self.formatter)?,
+            Definition::OptionsTemplate(record) => self
+                .templates
+                .insert_options_template_records(std::slice::from_ref(record), &self.formatter)?,
+        }
+        self.definitions.insert(template_id, definition);
+        self.unsent.push(template_id);
+        Ok(template_id)
+    }
+
+    /// Define a template of `field_specifiers`, returning its ID. An
+    /// identical template that is already defined is reused
+    pub fn add_template(
+        &mut self,
+        field_specifiers: Vec<FieldSpecifier>,
+    ) -> Result<u16, IpfixError> {
+        let existing = self.definitions.iter().find(|(_, definition)| {
+            matches!(definition, Definition::Template(record) if record.field_specifiers[..] == field_specifiers[..])
+        });
+        if let Some((&template_id, _)) = existing {
+            return Ok(template_id);
+        }
+        let template_id = self.allocate_template_id()?;
+        let record = TemplateRecord {
+            template_id,
+            field_specifiers: field_specifiers.into_iter().collect(),
+        };
+        self.define(template_id, Definition::Template(record))
+    }
+
+    /// Define an options template, returning its ID. An identical
+    /// template that is already defined is reused. Returns `None` as in
+    /// `OptionsTemplateRecord::new`
+    pub fn add_options_template(
+        &mut self,
+        scope_field_specifiers: Vec<FieldSpecifier>,
+        field_specifiers: Vec<FieldSpecifier>,
+    ) -> Result<Option<u16>, IpfixError> {
+        match OptionsTemplateRecord::new(0, scope_field_specifiers, field_specifiers) {
+            Some(record) => self.add_options_template_record(record).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Define an options template with the scope field count and field
+    /// specifiers of `record`, returning its ID, like
+    /// `add_options_template`. The Template ID of `record` is ignored, and
+    /// it isn't checked, so it can pass on options templates from upstream
+    /// unchanged
+    pub fn add_options_template_record(
+        &mut self,
+        mut record: OptionsTemplateRecord,
+    ) -> Result<u16, IpfixError> {
+        let existing = self.definitions.iter().find(|(_, definition)| {
+            matches!(definition, Definition::OptionsTemplate(existing)
+                if existing.scope_field_count == record.scope_field_count
+                    && existing.field_specifiers == record.field_specifiers)
+        });
+        if let Some((&template_id, _)) = existing {
+            return Ok(template_id);
+        }
+        record.template_id = self.allocate_template_id()?;
+        let template_id = record.template_id;
+        self.define(template_id, Definition::OptionsTemplate(record))
+    }
+
+    /// Withdraw `template_id` after the records already queued for it,
+    /// freeing its ID. Returns false if it isn't defined
+    pub fn withdraw_template(&mut self, template_id: u16) -> bool {
+        let Some(definition) = self.definitions.remove(&template_id) else {
+            return false;
+        };
+        self.withdrawn.push(match definition {
+            Definition::Template(_) => {
+                Definition::Template(TemplateRecord::withdrawal(template_id))
+            }
+            Definition::OptionsTemplate(_) => {
+                Definition::OptionsTemplate(OptionsTemplateRecord::withdrawal(template_id))
+            }
+        });
+        true
+    }
+
+    /// Send all templates again in the next message, as Exporters over
+    /// UDP must do periodically
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-8.4>
+    pub fn resend_templates(&mut self) {
+        self.unsent = self.definitions.keys().copied().collect();
+    }
+
+    /// Queue `record` for the next flush, with the template `template_id`
+    pub fn push(&mut self, template_id: u16, record: DataRecord) -> Result<(), IpfixError> {
+        if !self.definitions.contains_key(&template_id) {
+            return Err(IpfixError::MissingTemplate(template_id));
+        }
+        match self.sets.last_mut() {
+            Some(Set {
+                records: Records::Data { set_id, data },
+            }) if *set_id == template_id => data.push(record),
+            _ => self.sets.push(Set {
+                records: Records::Data {
+                    set_id: template_id,
+                    data: vec![record],
+                },
+            }),
+        }
+        Ok(())
+    }
+
+    /// Queue the undecoded records of a `Records::RawData` set for the
+    /// next flush, with the template `template_id`. They are sent as one
+    /// set, even if that makes a message larger than `max_size`
+    pub fn push_raw(&mut self, template_id: u16, bytes: Vec<u8>) -> Result<(), IpfixError> {
+        if !self.definitions.contains_key(&template_id) {
+            return Err(IpfixError::MissingTemplate(template_id));
+        }
+        self.sets.push(Set {
+            records: Records::RawData {
+                set_id: template_id,
+                bytes,
+            },
+        });
+        Ok(())
+    }
+
+    /// Build messages of the unsent templates, queued records and
+    /// withdrawals, in that order, with the current export time. Returns
+    /// no messages if there is nothing to send
+    pub fn flush(&mut self) -> BinResult<Vec<Message>> {
+        self.flush_with(|builder| builder)
+    }
+
+    /// `flush` with the given export time
+    pub fn flush_at(&mut self, export_time: u32) -> BinResult<Vec<Message>> {
+        self.flush_with(|builder| builder.export_time(export_time))
+    }
+
+    fn flush_with(
+        &mut self,
+        configure: impl FnOnce(MessageBuilder<'_>) -> MessageBuilder<'_>,
+    ) -> BinResult<Vec<Message>> {
+        if self.unsent.is_empty() && self.sets.is_empty() && self.withdrawn.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut template_records = vec![];
+        let mut options_template_records = vec![];
+        for template_id in std::mem::take(&mut self.unsent) {
+            match self.definitions.get(&template_id) {
+                Some(Definition::Template(record)) => template_records.push(record.clone()),
+                Some(Definition::OptionsTemplate(record)) => {
+                    options_template_records.push(record.clone())
+                }
+                // withdrawn before it was sent
+                None => {}
+            }
+        }
+
+        let sequence_number = self.session.sequence_number();
+        let mut builder = configure(self.session.message());
+        if let Some(max_size) = self.max_size {
+            builder = builder.max_size(max_size).repeat_templates(true);
+        }
+        if !template_records.is_empty() {
+            builder = builder.template_set(template_records);
+        }
+        if !options_template_records.is_empty() {
+            builder = builder.options_template_set(options_template_records);
+        }
+        let sets = std::mem::take(&mut self.sets);
+        let not_sent = not_sent_statistics(&sets);
+        for set in sets {
+            builder = builder.set(set);
+        }
+        for withdrawal in std::mem::take(&mut self.withdrawn) {
+            builder = match withdrawal {
+                Definition::Template(record) => builder.template_set(vec![record]),
+                Definition::OptionsTemplate(record) => builder.options_template_set(vec![record]),
+            };
+        }
+        let messages = builder
+            .build_messages(&self.templates, &self.formatter, self.options)
+            .inspect_err(|_| {
+                self.not_sent.not_sent_flow_total_count += not_sent.not_sent_flow_total_count;
+                self.not_sent.not_sent_packet_total_count += not_sent.not_sent_packet_total_count;
+                self.not_sent.not_sent_octet_total_count += not_sent.not_sent_octet_total_count;
+            })?;
+        self.statistics.exported_message_total_count += messages.len() as u64;
+        self.statistics.exported_flow_record_total_count +=
+            u64::from(self.session.sequence_number().wrapping_sub(sequence_number));
+        Ok(messages)
+    }
+
+    /// `flush`, encoding each message
+    pub fn flush_bytes(&mut self) -> BinResult<Vec<Vec<u8>>> {
+        let messages = self.flush()?;
+        let bytes = messages
+            .iter()
+            .map(|message| message.to_bytes(&self.templates, &self.formatter, self.options))
+            .collect::<BinResult<Vec<_>>>()?;
+        self.statistics.exported_octet_total_count +=
+            bytes.iter().map(|bytes| bytes.len() as u64).sum::<u64>();
+        Ok(bytes)
+    }
+}
+
+/// The data records of `sets`, with the packets and octets they count,
+/// for if they are dropped
+fn not_sent_statistics(sets: &[Set]) -> ExportingProcessStatistics {
+    let mut statistics = ExportingProcessStatistics::default();
+    let count = |record: &DataRecord, name| {
+        record
+            .values
+            .get(&DataRecordKey::Str(name))
+            .and_then(|value| value.as_u64())
+            .unwrap_or(0)
+    };
+    for set in sets {
+        if let Records::Data { data, .. } = &set.records {
+            for record in data {
+                statistics.not_sent_flow_total_count += 1;
+                statistics.not_sent_packet_total_count += count(record, "packetDeltaCount");
+                statistics.not_sent_octet_total_count += count(record, "octetDeltaCount");
+            }
+        }
+    }
+    statistics
+}
+
+/// A single record, with the set it belongs in
+#[derive(Clone)]
+enum SplitRecord {
+    Template(TemplateRecord),
+    OptionsTemplate(OptionsTemplateRecord),
+    Data(u16, DataRecord),
+    /// A whole set of undecoded bytes
+    Opaque(Records),
+}
+
+impl SplitRecord {
+    fn set_id(&self) -> u16 {
+        match self {
+            SplitRecord::Template(_) => TEMPLATE_SET_ID,
+            SplitRecord::OptionsTemplate(_) => OPTIONS_TEMPLATE_SET_ID,
+            SplitRecord::Data(set_id, _) => *set_id,
+            SplitRecord::Opaque(records) => records.set_id(),
+        }
+    }
+
+    /// Whether this record can be added to `set`
+    fn extends(&self, set: &Set) -> bool {
+        match (self, &set.records) {
+            (SplitRecord::Template(_), Records::Template(_))
+            | (SplitRecord::OptionsTemplate(_), Records::OptionsTemplate(_)) => true,
+            (SplitRecord::Data(set_id, _), Records::Data { set_id: id, .. }) => set_id == id,
+            _ => false,
+        }
+    }
+
+    fn encoded_length(
+        &self,
+        templates: &dyn TemplateStorage,
+        options: WriteOptions,
+    ) -> BinResult<usize> {
+        let mut writer = Cursor::new(Vec::new());
+        match self {
+            SplitRecord::Opaque(
+                Records::RawData { bytes, .. } | Records::Unsupported { data: bytes, .. },
+            ) => return Ok(bytes.len()),
+            SplitRecord::Opaque(_) => unreachable!("decoded sets are split"),
+            SplitRecord::Template(record) => record.write_be(&mut writer)?,
+            SplitRecord::OptionsTemplate(record) => record.write_be(&mut writer)?,
+            SplitRecord::Data(set_id, record) => {
+                record.write_options(&mut writer, Endian::Big, (*set_id, templates, options))?
+            }
+        }
+        Ok(writer.into_inner().len())
+    }
+}
+
+/// Packs records into sets and messages of limited size
+struct Splitter {
+    max_size: usize,
+    options: WriteOptions,
+    repeat_templates: bool,
+    /// The latest definition of each template, to repeat
+    definitions: HashMap<u16, SplitRecord>,
+    /// Sets of each finished message
+    messages: Vec<Sets>,
+    /// Sets of the current message
+    sets: Sets,
+    /// Length of the current message
+    size: usize,
+    /// Unpadded length of the last set of the current message
+    last_set_length: usize,
+    /// Templates defined in the current message
+    defined: HashSet<u16>,
+}
+
+impl Splitter {
+    /// `length` padded to the alignment of sets with ID `set_id`
+    fn padded(&self, set_id: u16, length: usize) -> usize {
+        let alignment = usize::from(self.options.set_alignment(set_id));
+        length.div_ceil(alignment) * alignment
+    }
+
+    /// Whether the current message still fits with `added` more bytes,
+    /// including the message padding
+    fn fits(&self, added: usize) -> bool {
+        let alignment = usize::from(self.options.message_alignment.max(1));
+        (self.size + added).div_ceil(alignment) * alignment <= self.max_size
+    }
+
+    /// Remember the definition of a template, or forget it if `record`
+    /// is a withdrawal
+    fn define(&mut self, record: SplitRecord) {
+        let (template_id, is_withdrawal) = match &record {
+            SplitRecord::Template(record) => (record.template_id, record.is_withdrawal()),
+            SplitRecord::OptionsTemplate(record) => (record.template_id, record.is_withdrawal()),
+            SplitRecord::Data(..) | SplitRecord::Opaque(_) => return,
+        };
+        if is_withdrawal {
+            self.definitions.remove(&template_id);
+        } else {
+            self.definitions.insert(template_id, record);
+        }
+    }
+
+    /// Bytes added to the current message by appending `record` of
+    /// `length`
+    fn added_length(&self, record: &SplitRecord, length: usize) -> usize {
+        let set_id = record.set_id();
+        match self.sets.last() {
+            Some(set) if record.extends(set) => {
+                self.padded(set_id, self.last_set_length + length)
+                    - self.padded(set_id, self.last_set_length)
+            }
+            _ => self.padded(set_id, SET_HEADER_LENGTH + length),
+        }
+    }
+
+    fn finish_message(&mut self) {
+        if !self.sets.is_empty() {
+            self.messages.push(std::mem::take(&mut self.sets));
+        }
+        self.size = MESSAGE_HEADER_LENGTH;
+        self.last_set_length = 0;
+        self.defined.clear();
+    }
+
+    fn push(
+        &mut self,
+        record: SplitRecord,
+        templates: &dyn TemplateStorage,
+        options: WriteOptions,
+    ) -> BinResult<()> {
+        let length = record.encoded_length(templates, options)?;
+        let mut definition = self.definition_to_repeat(&record, templates, options)?;
+        let mut added = self.added_length(&record, length);
+        if let Some((definition, definition_length)) = &definition {
+            added += self.padded(definition.set_id(), SET_HEADER_LENGTH + definition_length);
+        }
+
+        if !self.fits(added) && !self.sets.is_empty() {
+            self.finish_message();
+            definition = self.definition_to_repeat(&record, templates, options)?;
+            added = self.padded(record.set_id(), SET_HEADER_LENGTH + length);
+            if let Some((definition, definition_length)) = &definition {
+                added += self.padded(definition.set_id(), SET_HEADER_LENGTH + definition_length);
+            }
+        }
+        if !self.fits(added) {
+            return Err(IpfixError::RecordTooLarge {
+                size: MESSAGE_HEADER_LENGTH + added,
+                max_size: self.max_size,
+            }
+            .into_binrw_error(0));
+        }
+
+        if let Some((definition, definition_length)) = definition {
+            self.append(definition, definition_length);
+        }
+        self.append(record, length);
+        Ok(())
+    }
+
+    /// The definition of the template used by `record`, if it should be
+    /// repeated before it in the current message
+    fn definition_to_repeat(
+        &self,
+        record: &SplitRecord,
+        templates: &dyn TemplateStorage,
+        options: WriteOptions,
+    ) -> BinResult<Option<(SplitRecord, usize)>> {
+        let SplitRecord::Data(set_id, _) = record else {
+            return Ok(None);
+        };
+        // the first message has the definitions as given
+        if !self.repeat_templates || self.messages.is_empty() || self.defined.contains(set_id) {
+            return Ok(None);
+        }
+        let Some(definition) = self.definitions.get(set_id) else {
+            return Ok(None);
+        };
+        let length = definition.encoded_length(templates, options)?;
+        Ok(Some((definition.clone(), length)))
+    }
+
+    fn append(&mut self, record: SplitRecord, length: usize) {
+        self.size += self.added_length(&record, length);
+        if let SplitRecord::Opaque(records) = record {
+            self.last_set_length = SET_HEADER_LENGTH + length;
+            self.sets.push(Set { records });
+            return;
+        }
+        let same_set = matches!(self.sets.last(), Some(set) if record.extends(set));
+        if same_set {
+            self.last_set_length += length;
+        } else {
+            self.last_set_length = SET_HEADER_LENGTH + length;
+            self.sets.push(Set {
+                records: match &record {
+                    SplitRecord::Template(_) => Records::Template(vec![]),
+                    SplitRecord::OptionsTemplate(_) => Records::OptionsTemplate(vec![]),
+                    SplitRecord::Data(set_id, _) => Records::Data {
+                        set_id: *set_id,
+                        data: vec![],
+                    },
+                    SplitRecord::Opaque(_) => unreachable!("opaque sets are added whole"),
+                },
+            });
+        }
+
+        if let SplitRecord::Template(TemplateRecord { template_id, .. })
+        | SplitRecord::OptionsTemplate(OptionsTemplateRecord { template_id, .. }) = &record
+        {
+            self.defined.insert(*template_id);
+            self.define(record.clone());
+        }
+        let Some(set) = self.sets.last_mut() else {
+            return;
+        };
+        match (&mut set.records, record) {
+            (Records::Template(records), SplitRecord::Template(record)) => records.push(record),
+            (Records::OptionsTemplate(records), SplitRecord::OptionsTemplate(record)) => {
+                records.push(record)
+            }
+            (Records::Data { data, .. }, SplitRecord::Data(_, record)) => data.push(record),
+            _ => unreachable!("set matches record"),
+        }
+    }
+}
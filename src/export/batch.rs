@@ -0,0 +1,265 @@
+//! Sending an `Exporter`'s records in batches, without sending messages
+//! faster than a collector can take them
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+use binrw::BinResult;
+
+use super::Exporter;
+use crate::parser::{DataRecord, IpfixError};
+
+/// Batches records by template and sends them as messages with `send`,
+/// such as `|bytes| socket.send(bytes).map(drop)`. Records are flushed
+/// once `max_records` are waiting or the oldest has waited `max_delay`,
+/// and messages beyond the packets-per-second budget are queued until
+/// it allows them. Call `poll` regularly to act on the time limits.
+/// Over UDP, the exporter should have a `max_size`
+///
+/// While `max_queued_messages` are queued, records aren't accepted or
+/// flushed, and a `WouldBlock` error is returned instead, so the caller
+/// can wait for `next_deadline` and try again
+pub struct BatchWriter<S> {
+    exporter: Exporter,
+    send: S,
+    max_records: usize,
+    max_delay: Duration,
+    template_refresh: Option<Duration>,
+    last_refresh: Instant,
+    packets_per_second: Option<u32>,
+    /// Messages that may be sent now, for the packets-per-second budget
+    tokens: f64,
+    last_refill: Instant,
+    /// Records waiting to be flushed, by template
+    pending: BTreeMap<u16, Vec<DataRecord>>,
+    pending_records: usize,
+    oldest_pending: Option<Instant>,
+    /// Encoded messages waiting for the budget
+    queue: VecDeque<Vec<u8>>,
+    max_queued_messages: usize,
+}
+
+impl<S: FnMut(&[u8]) -> io::Result<()>> BatchWriter<S> {
+    /// Flushes every 1000 records or 1 second, with no rate limit or
+    /// template refreshes, and queues up to 1000 messages
+    pub fn new(exporter: Exporter, send: S) -> Self {
+        let now = Instant::now();
+        Self {
+            exporter,
+            send,
+            max_records: 1000,
+            max_delay: Duration::from_secs(1),
+            template_refresh: None,
+            last_refresh: now,
+            packets_per_second: None,
+            tokens: 0.0,
+            last_refill: now,
+            pending: BTreeMap::new(),
+            pending_records: 0,
+            oldest_pending: None,
+            queue: VecDeque::new(),
+            max_queued_messages: 1000,
+        }
+    }
+
+    /// Flush once `max_records` records are waiting
+    pub fn max_records(mut self, max_records: usize) -> Self {
+        self.max_records = max_records.max(1);
+        self
+    }
+
+    /// Flush once the oldest waiting record has waited `max_delay`
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Send at most `packets_per_second` messages a second, in bursts of
+    /// up to that many
+    pub fn packets_per_second(mut self, packets_per_second: u32) -> Self {
+        let packets_per_second = packets_per_second.max(1);
+        self.packets_per_second = Some(packets_per_second);
+        self.tokens = packets_per_second.into();
+        self
+    }
+
+    /// Stop accepting records while `max_queued_messages` are waiting
+    /// for the budget or failed to send. A flush may queue several
+    /// messages, going over the limit
+    pub fn max_queued_messages(mut self, max_queued_messages: usize) -> Self {
+        self.max_queued_messages = max_queued_messages.max(1);
+        self
+    }
+
+    /// Send all templates again every `interval`, as Exporters over UDP
+    /// must do <https://www.rfc-editor.org/rfc/rfc7011#section-8.4>
+    pub fn template_refresh(mut self, interval: Duration) -> Self {
+        self.template_refresh = Some(interval);
+        self
+    }
+
+    pub fn exporter(&self) -> &Exporter {
+        &self.exporter
+    }
+
+    /// The exporter, to define and withdraw templates
+    pub fn exporter_mut(&mut self) -> &mut Exporter {
+        &mut self.exporter
+    }
+
+    /// Records waiting to be flushed
+    pub fn pending_records(&self) -> usize {
+        self.pending_records
+    }
+
+    /// Messages waiting for the packets-per-second budget
+    pub fn queued_messages(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Queue `record` with the template `template_id`, flushing if
+    /// `max_records` are now waiting. Fails with `WouldBlock`, without
+    /// taking the record, if the message queue is full
+    pub fn push(&mut self, template_id: u16, record: DataRecord) -> BinResult<()> {
+        self.push_at(template_id, record, Instant::now())
+    }
+
+    /// `push`, at `now`
+    pub fn push_at(&mut self, template_id: u16, record: DataRecord, now: Instant) -> BinResult<()> {
+        // check the template now, rather than when flushing
+        if self
+            .exporter
+            .templates()
+            .get_template(template_id)
+            .is_none()
+        {
+            return Err(IpfixError::MissingTemplate(template_id).into_binrw_error(0));
+        }
+        self.send_queued(now)?;
+        self.check_queue()?;
+        self.pending.entry(template_id).or_default().push(record);
+        self.pending_records += 1;
+        self.oldest_pending.get_or_insert(now);
+        if self.pending_records >= self.max_records {
+            self.flush_at(now)?;
+        }
+        Ok(())
+    }
+
+    /// Flush or refresh templates if their time has come, and send
+    /// queued messages the budget now allows. Flushing waits while the
+    /// message queue is full
+    pub fn poll(&mut self) -> BinResult<()> {
+        self.poll_at(Instant::now())
+    }
+
+    /// `poll`, at `now`
+    pub fn poll_at(&mut self, now: Instant) -> BinResult<()> {
+        let flush_due = self
+            .oldest_pending
+            .is_some_and(|oldest| now.saturating_duration_since(oldest) >= self.max_delay);
+        self.send_queued(now)?;
+        if (self.refresh_due(now) || flush_due) && self.check_queue().is_ok() {
+            self.flush_at(now)?;
+        }
+        Ok(())
+    }
+
+    /// The next time `poll` has something to do, if anything is waiting
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let flush = self.oldest_pending.map(|oldest| oldest + self.max_delay);
+        let refresh = self
+            .template_refresh
+            .map(|interval| self.last_refresh + interval);
+        [flush, refresh, self.send_deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn refresh_due(&self, now: Instant) -> bool {
+        self.template_refresh
+            .is_some_and(|interval| now.saturating_duration_since(self.last_refresh) >= interval)
+    }
+
+    /// When the budget next allows a queued message
+    fn send_deadline(&self) -> Option<Instant> {
+        let rate = self.packets_per_second.filter(|_| !self.queue.is_empty())?;
+        let wait = (1.0 - self.tokens).max(0.0) / f64::from(rate);
+        Some(self.last_refill + Duration::from_secs_f64(wait))
+    }
+
+    /// Flush all waiting records into messages, sending them as the
+    /// budget allows
+    pub fn flush(&mut self) -> BinResult<()> {
+        self.flush_at(Instant::now())
+    }
+
+    fn flush_at(&mut self, now: Instant) -> BinResult<()> {
+        self.send_queued(now)?;
+        self.check_queue()?;
+        if self.refresh_due(now) {
+            self.exporter.resend_templates();
+            self.last_refresh = now;
+        }
+        for (template_id, records) in std::mem::take(&mut self.pending) {
+            for record in records {
+                self.exporter
+                    .push(template_id, record)
+                    .map_err(|err| err.into_binrw_error(0))?;
+            }
+        }
+        self.pending_records = 0;
+        self.oldest_pending = None;
+        self.queue.extend(self.exporter.flush_bytes()?);
+        self.send_queued(now)
+    }
+
+    /// A `WouldBlock` error if the queue is full
+    fn check_queue(&self) -> BinResult<()> {
+        if self.queue.len() >= self.max_queued_messages {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock).into());
+        }
+        Ok(())
+    }
+
+    /// Send queued messages while the budget allows. A message that
+    /// fails to send stays queued, and doesn't use the budget
+    fn send_queued(&mut self, now: Instant) -> BinResult<()> {
+        if let Some(rate) = self.packets_per_second {
+            let elapsed = now.saturating_duration_since(self.last_refill);
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * f64::from(rate)).min(rate.into());
+            self.last_refill = now.max(self.last_refill);
+        }
+        while let Some(message) = self.queue.front() {
+            if self.packets_per_second.is_some() && self.tokens < 1.0 {
+                break;
+            }
+            (self.send)(message)?;
+            self.queue.pop_front();
+            if self.packets_per_second.is_some() {
+                self.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send every queued message, waiting for the budget if needed
+    fn send_all_queued(&mut self) -> BinResult<()> {
+        while !self.queue.is_empty() {
+            if let Some(deadline) = self.send_deadline() {
+                std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            }
+            self.send_queued(Instant::now())?;
+        }
+        Ok(())
+    }
+
+    /// Flush, and send everything, waiting for the budget if needed
+    pub fn finish(mut self) -> BinResult<()> {
+        self.send_all_queued()?;
+        self.flush()?;
+        self.send_all_queued()
+    }
+}
@@ -0,0 +1,635 @@
+//! IPFIX files as in RFC 5655: messages back to back, from any number of
+//! Observation Domains, with each domain's templates written ahead of the
+//! data that uses them. Files end with a Time Window options record for
+//! each domain, describing the export times the file covers, and an
+//! Export Session Details record for domains given one. Messages can
+//! carry a Message Details record of when and where they were received
+//! <https://www.rfc-editor.org/rfc/rfc5655>
+
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+
+use binrw::BinResult;
+
+use crate::information_elements::Formatter;
+use crate::parser::{
+    DataRecord, DataRecordKey, DataRecordValue, FieldSpecifier, IpfixError, Message,
+    OptionsTemplateRecord, ParseOptions, Records, Set, Sets, WriteOptions,
+};
+use crate::stream::read_message_frame;
+use crate::template_store::{
+    template_records, template_sets, ScopedTemplateStore, TemplateStorage,
+};
+
+/// sessionScope, the scope of the Time Window and Export Session
+/// Details options templates
+const SESSION_SCOPE: u16 = 267;
+/// messageScope, the scope of the Message Details options template
+const MESSAGE_SCOPE: u16 = 263;
+const MIN_EXPORT_SECONDS: u16 = 264;
+const MAX_EXPORT_SECONDS: u16 = 260;
+const EXPORTER_IPV4_ADDRESS: u16 = 130;
+const EXPORTER_IPV6_ADDRESS: u16 = 131;
+const EXPORTER_TRANSPORT_PORT: u16 = 217;
+const COLLECTOR_IPV4_ADDRESS: u16 = 211;
+const COLLECTOR_IPV6_ADDRESS: u16 = 212;
+const COLLECTOR_TRANSPORT_PORT: u16 = 216;
+const EXPORT_PROTOCOL_VERSION: u16 = 214;
+const EXPORT_TRANSPORT_PROTOCOL: u16 = 215;
+const COLLECTION_TIME_MILLISECONDS: u16 = 258;
+
+/// The export times of the messages of one Observation Domain in a file
+/// <https://www.rfc-editor.org/rfc/rfc5655#section-8.1.2>
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct TimeWindow {
+    pub min_export_seconds: u32,
+    pub max_export_seconds: u32,
+}
+
+impl TimeWindow {
+    /// The Time Window options template, with sessionScope as its scope
+    pub fn template(template_id: u16) -> OptionsTemplateRecord {
+        OptionsTemplateRecord::new(
+            template_id,
+            vec![FieldSpecifier::new(None, SESSION_SCOPE, 1)],
+            vec![
+                FieldSpecifier::new(None, MIN_EXPORT_SECONDS, 4),
+                FieldSpecifier::new(None, MAX_EXPORT_SECONDS, 4),
+            ],
+        )
+        .expect("has a scope field")
+    }
+
+    /// A record for `TimeWindow::template`. The session is always 0, as
+    /// files written here don't record their Transport Sessions
+    pub fn to_record(self) -> DataRecord {
+        let mut record = DataRecord::default();
+        record
+            .scope_values
+            .insert(DataRecordKey::Str("sessionScope"), DataRecordValue::U8(0));
+        record.values.insert(
+            DataRecordKey::Str("minExportSeconds"),
+            DataRecordValue::DateTimeSeconds(self.min_export_seconds),
+        );
+        record.values.insert(
+            DataRecordKey::Str("maxExportSeconds"),
+            DataRecordValue::DateTimeSeconds(self.max_export_seconds),
+        );
+        record
+    }
+
+    /// Read a Time Window options record, or return None if `record` isn't one
+    pub fn from_record(record: &DataRecord) -> Option<Self> {
+        let seconds = |name| match record.values.get(&DataRecordKey::Str(name)) {
+            Some(&DataRecordValue::DateTimeSeconds(seconds)) => Some(seconds),
+            _ => None,
+        };
+        record
+            .scope_values
+            .contains_key(&DataRecordKey::Str("sessionScope"))
+            .then_some(())?;
+        Some(Self {
+            min_export_seconds: seconds("minExportSeconds")?,
+            max_export_seconds: seconds("maxExportSeconds")?,
+        })
+    }
+
+    fn extend(&mut self, export_time: u32) {
+        self.min_export_seconds = self.min_export_seconds.min(export_time);
+        self.max_export_seconds = self.max_export_seconds.max(export_time);
+    }
+}
+
+/// The Transport Session the messages of one Observation Domain in a file
+/// were received on
+/// <https://www.rfc-editor.org/rfc/rfc5655#section-8.1>
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ExportSessionDetails {
+    pub exporter: SocketAddr,
+    pub collector: SocketAddr,
+    /// exportTransportProtocol, the protocol number such as 17 for UDP
+    pub transport_protocol: u8,
+}
+
+impl ExportSessionDetails {
+    /// The Export Session Details options template, with sessionScope as
+    /// its scope. The address fields depend on the address families of
+    /// this session
+    pub fn template(&self, template_id: u16) -> OptionsTemplateRecord {
+        let mut fields = endpoint_fields(
+            self.exporter,
+            EXPORTER_IPV4_ADDRESS,
+            EXPORTER_IPV6_ADDRESS,
+            EXPORTER_TRANSPORT_PORT,
+        );
+        fields.extend(endpoint_fields(
+            self.collector,
+            COLLECTOR_IPV4_ADDRESS,
+            COLLECTOR_IPV6_ADDRESS,
+            COLLECTOR_TRANSPORT_PORT,
+        ));
+        fields.push(FieldSpecifier::new(None, EXPORT_TRANSPORT_PROTOCOL, 1));
+        fields.push(FieldSpecifier::new(None, EXPORT_PROTOCOL_VERSION, 1));
+        OptionsTemplateRecord::new(
+            template_id,
+            vec![FieldSpecifier::new(None, SESSION_SCOPE, 1)],
+            fields,
+        )
+        .expect("has a scope field")
+    }
+
+    /// A record for `template`, with a session of 0 as for `TimeWindow`
+    pub fn to_record(self) -> DataRecord {
+        let mut record = DataRecord::default();
+        record
+            .scope_values
+            .insert(DataRecordKey::Str("sessionScope"), DataRecordValue::U8(0));
+        insert_endpoint(
+            &mut record,
+            self.exporter,
+            "exporterIPv4Address",
+            "exporterIPv6Address",
+            "exporterTransportPort",
+        );
+        insert_endpoint(
+            &mut record,
+            self.collector,
+            "collectorIPv4Address",
+            "collectorIPv6Address",
+            "collectorTransportPort",
+        );
+        record.values.insert(
+            DataRecordKey::Str("exportTransportProtocol"),
+            DataRecordValue::U8(self.transport_protocol),
+        );
+        record.values.insert(
+            DataRecordKey::Str("exportProtocolVersion"),
+            DataRecordValue::U8(10),
+        );
+        record
+    }
+
+    /// Read an Export Session Details options record, or return None if
+    /// `record` isn't one
+    pub fn from_record(record: &DataRecord) -> Option<Self> {
+        record
+            .scope_values
+            .contains_key(&DataRecordKey::Str("sessionScope"))
+            .then_some(())?;
+        Some(Self {
+            exporter: endpoint(
+                record,
+                "exporterIPv4Address",
+                "exporterIPv6Address",
+                "exporterTransportPort",
+            )?,
+            collector: endpoint(
+                record,
+                "collectorIPv4Address",
+                "collectorIPv6Address",
+                "collectorTransportPort",
+            )?,
+            transport_protocol: match record
+                .values
+                .get(&DataRecordKey::Str("exportTransportProtocol"))
+            {
+                Some(&DataRecordValue::U8(protocol)) => protocol,
+                _ => return None,
+            },
+        })
+    }
+}
+
+/// When and where a message in a file was received
+/// <https://www.rfc-editor.org/rfc/rfc5655#section-8.1>
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct MessageDetails {
+    /// collectionTimeMilliseconds, milliseconds since the UNIX epoch
+    pub collection_time_milliseconds: u64,
+    pub exporter: SocketAddr,
+    pub collector: SocketAddr,
+}
+
+impl MessageDetails {
+    /// The Message Details options template, with messageScope as its
+    /// scope. The address fields depend on the address families of these
+    /// details
+    pub fn template(&self, template_id: u16) -> OptionsTemplateRecord {
+        let mut fields = vec![FieldSpecifier::new(None, COLLECTION_TIME_MILLISECONDS, 8)];
+        fields.extend(endpoint_fields(
+            self.exporter,
+            EXPORTER_IPV4_ADDRESS,
+            EXPORTER_IPV6_ADDRESS,
+            EXPORTER_TRANSPORT_PORT,
+        ));
+        fields.extend(endpoint_fields(
+            self.collector,
+            COLLECTOR_IPV4_ADDRESS,
+            COLLECTOR_IPV6_ADDRESS,
+            COLLECTOR_TRANSPORT_PORT,
+        ));
+        OptionsTemplateRecord::new(
+            template_id,
+            vec![FieldSpecifier::new(None, MESSAGE_SCOPE, 1)],
+            fields,
+        )
+        .expect("has a scope field")
+    }
+
+    /// A record for `template`, describing the message it is in
+    pub fn to_record(self) -> DataRecord {
+        let mut record = DataRecord::default();
+        record
+            .scope_values
+            .insert(DataRecordKey::Str("messageScope"), DataRecordValue::U8(0));
+        record.values.insert(
+            DataRecordKey::Str("collectionTimeMilliseconds"),
+            DataRecordValue::DateTimeMilliseconds(self.collection_time_milliseconds),
+        );
+        insert_endpoint(
+            &mut record,
+            self.exporter,
+            "exporterIPv4Address",
+            "exporterIPv6Address",
+            "exporterTransportPort",
+        );
+        insert_endpoint(
+            &mut record,
+            self.collector,
+            "collectorIPv4Address",
+            "collectorIPv6Address",
+            "collectorTransportPort",
+        );
+        record
+    }
+
+    /// Read a Message Details options record, or return None if `record`
+    /// isn't one
+    pub fn from_record(record: &DataRecord) -> Option<Self> {
+        record
+            .scope_values
+            .contains_key(&DataRecordKey::Str("messageScope"))
+            .then_some(())?;
+        Some(Self {
+            collection_time_milliseconds: match record
+                .values
+                .get(&DataRecordKey::Str("collectionTimeMilliseconds"))
+            {
+                Some(&DataRecordValue::DateTimeMilliseconds(milliseconds)) => milliseconds,
+                _ => return None,
+            },
+            exporter: endpoint(
+                record,
+                "exporterIPv4Address",
+                "exporterIPv6Address",
+                "exporterTransportPort",
+            )?,
+            collector: endpoint(
+                record,
+                "collectorIPv4Address",
+                "collectorIPv6Address",
+                "collectorTransportPort",
+            )?,
+        })
+    }
+}
+
+/// The address and port fields of `addr`
+fn endpoint_fields(addr: SocketAddr, ipv4: u16, ipv6: u16, port: u16) -> Vec<FieldSpecifier> {
+    vec![
+        match addr.ip() {
+            IpAddr::V4(_) => FieldSpecifier::new(None, ipv4, 4),
+            IpAddr::V6(_) => FieldSpecifier::new(None, ipv6, 16),
+        },
+        FieldSpecifier::new(None, port, 2),
+    ]
+}
+
+fn insert_endpoint(
+    record: &mut DataRecord,
+    addr: SocketAddr,
+    ipv4: &'static str,
+    ipv6: &'static str,
+    port: &'static str,
+) {
+    let (key, value) = match addr.ip() {
+        IpAddr::V4(ip) => (ipv4, DataRecordValue::Ipv4Addr(ip)),
+        IpAddr::V6(ip) => (ipv6, DataRecordValue::Ipv6Addr(ip)),
+    };
+    record.values.insert(DataRecordKey::Str(key), value);
+    record
+        .values
+        .insert(DataRecordKey::Str(port), DataRecordValue::U16(addr.port()));
+}
+
+fn endpoint(
+    record: &DataRecord,
+    ipv4: &'static str,
+    ipv6: &'static str,
+    port: &'static str,
+) -> Option<SocketAddr> {
+    let ip = match (
+        record.values.get(&DataRecordKey::Str(ipv4)),
+        record.values.get(&DataRecordKey::Str(ipv6)),
+    ) {
+        (Some(&DataRecordValue::Ipv4Addr(ip)), _) => IpAddr::V4(ip),
+        (_, Some(&DataRecordValue::Ipv6Addr(ip))) => IpAddr::V6(ip),
+        _ => return None,
+    };
+    match record.values.get(&DataRecordKey::Str(port)) {
+        Some(&DataRecordValue::U16(port)) => Some(SocketAddr::new(ip, port)),
+        _ => None,
+    }
+}
+
+/// Iterator over the messages of a file, keeping templates separately
+/// for each Observation Domain. Ends at the end of `reader`, or after
+/// the first error
+pub struct FileReader<R> {
+    reader: R,
+    templates: ScopedTemplateStore<()>,
+    formatter: Formatter,
+    options: ParseOptions,
+    /// Bytes read from `reader` so far, for error positions
+    position: u64,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> FileReader<R> {
+    pub fn new(reader: R, formatter: Formatter) -> Self {
+        Self {
+            reader,
+            templates: ScopedTemplateStore::new(),
+            formatter,
+            options: ParseOptions::default(),
+            position: 0,
+            buf: vec![],
+            done: false,
+        }
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The templates read so far for `observation_domain_id`
+    pub fn templates(&self, observation_domain_id: u32) -> Rc<impl TemplateStorage> {
+        self.templates.scope((), observation_domain_id)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for FileReader<R> {
+    type Item = BinResult<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = match read_message_frame(&mut self.reader, &mut self.buf, self.position) {
+            Ok(false) => {
+                self.done = true;
+                return None;
+            }
+            Ok(true) => {
+                self.position += self.buf.len() as u64;
+                Message::read_scoped(
+                    &mut Cursor::new(&self.buf),
+                    &self.templates,
+                    (),
+                    &self.formatter,
+                    self.options,
+                )
+            }
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            self.done = true;
+        }
+        Some(result)
+    }
+}
+
+/// The state of one Observation Domain being written
+#[derive(Debug)]
+struct Domain {
+    /// None until a message is written to the current file
+    time_window: Option<TimeWindow>,
+    export_session: Option<ExportSessionDetails>,
+    last_export_time: u32,
+    /// The Sequence Number after the last message written
+    next_sequence_number: u32,
+}
+
+/// Writes messages to a file, keeping templates separately for each
+/// Observation Domain. Data sets must use templates written earlier in
+/// the file, or in the same message
+pub struct FileWriter<W> {
+    writer: W,
+    templates: ScopedTemplateStore<()>,
+    formatter: Formatter,
+    options: WriteOptions,
+    domains: BTreeMap<u32, Domain>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> FileWriter<W> {
+    /// `formatter` must know the Time Window information elements, as
+    /// the default one does
+    pub fn new(writer: W, formatter: Formatter) -> Self {
+        Self {
+            writer,
+            templates: ScopedTemplateStore::new(),
+            formatter,
+            options: WriteOptions::default(),
+            domains: BTreeMap::new(),
+            buf: vec![],
+        }
+    }
+
+    /// Write messages with `options`
+    pub fn options(mut self, options: WriteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The templates written so far for `observation_domain_id`
+    pub fn templates(&self, observation_domain_id: u32) -> Rc<impl TemplateStorage> {
+        self.templates.scope((), observation_domain_id)
+    }
+
+    /// Record the Transport Session of `observation_domain_id`, written
+    /// as an Export Session Details record at the end of each file
+    pub fn export_session(&mut self, observation_domain_id: u32, details: ExportSessionDetails) {
+        self.domain(observation_domain_id).export_session = Some(details);
+    }
+
+    /// Like `write`, but with a Message Details record for `message`
+    /// added to it, defining its template if needed
+    pub fn write_with_details(
+        &mut self,
+        message: &Message,
+        details: MessageDetails,
+    ) -> BinResult<()> {
+        let templates = self.templates.scope((), message.observation_domain_id);
+        let mut message = message.clone();
+        let template_id =
+            options_template_id(&*templates, &mut message.sets, |id| details.template(id))?;
+        message.sets.push(Set {
+            records: Records::Data {
+                set_id: template_id,
+                data: vec![details.to_record()],
+            },
+        });
+        self.write(&message)
+    }
+
+    /// Encode and write `message`, adding any templates it defines to
+    /// those of its Observation Domain
+    pub fn write(&mut self, message: &Message) -> BinResult<()> {
+        let templates = self.templates.scope((), message.observation_domain_id);
+        message.write_into(&mut self.buf, &*templates, &self.formatter, self.options)?;
+        self.writer.write_all(&self.buf)?;
+
+        let domain = self.domain(message.observation_domain_id);
+        let time_window = domain.time_window.get_or_insert(TimeWindow {
+            min_export_seconds: message.export_time,
+            max_export_seconds: message.export_time,
+        });
+        time_window.extend(message.export_time);
+        domain.last_export_time = message.export_time;
+        let data_records = message.iter_data_records().count() as u32;
+        domain.next_sequence_number = message.sequence_number.wrapping_add(data_records);
+        Ok(())
+    }
+
+    fn domain(&mut self, observation_domain_id: u32) -> &mut Domain {
+        self.domains.entry(observation_domain_id).or_insert(Domain {
+            time_window: None,
+            export_session: None,
+            last_export_time: 0,
+            next_sequence_number: 0,
+        })
+    }
+
+    /// Finish the current file and continue in a new one written to
+    /// `writer`, starting with the templates of every Observation Domain
+    /// so it can be read on its own. Returns the finished writer
+    pub fn rotate(&mut self, writer: W) -> BinResult<W> {
+        self.write_session_records()?;
+        self.writer.flush()?;
+        let finished = std::mem::replace(&mut self.writer, writer);
+        for (&observation_domain_id, domain) in &mut self.domains {
+            domain.time_window = None;
+            let templates = self.templates.scope((), observation_domain_id);
+            let sets = template_sets(&*templates);
+            if sets.is_empty() {
+                continue;
+            }
+            let message = Message {
+                export_time: domain.last_export_time,
+                sequence_number: domain.next_sequence_number,
+                observation_domain_id,
+                sets,
+            };
+            message.write_into(&mut self.buf, &*templates, &self.formatter, self.options)?;
+            self.writer.write_all(&self.buf)?;
+        }
+        Ok(finished)
+    }
+
+    /// Finish the file with a Time Window options record, and any Export
+    /// Session Details record, for each Observation Domain, and return
+    /// the writer
+    pub fn finish(mut self) -> BinResult<W> {
+        self.write_session_records()?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Write a Time Window options record, and any Export Session
+    /// Details record, for each Observation Domain written to in the
+    /// current file, defining their templates if needed
+    fn write_session_records(&mut self) -> BinResult<()> {
+        let domains: Vec<_> = self
+            .domains
+            .iter()
+            .filter_map(|(&observation_domain_id, domain)| {
+                Some((
+                    observation_domain_id,
+                    domain.time_window?,
+                    domain.export_session,
+                    domain.next_sequence_number,
+                ))
+            })
+            .collect();
+        for (observation_domain_id, time_window, export_session, sequence_number) in domains {
+            let templates = self.templates.scope((), observation_domain_id);
+            let mut sets = Sets::new();
+            let template_id = options_template_id(&*templates, &mut sets, TimeWindow::template)?;
+            sets.push(Set {
+                records: Records::Data {
+                    set_id: template_id,
+                    data: vec![time_window.to_record()],
+                },
+            });
+            if let Some(export_session) = export_session {
+                let template_id =
+                    options_template_id(&*templates, &mut sets, |id| export_session.template(id))?;
+                sets.push(Set {
+                    records: Records::Data {
+                        set_id: template_id,
+                        data: vec![export_session.to_record()],
+                    },
+                });
+            }
+            self.write(&Message {
+                export_time: time_window.max_export_seconds,
+                sequence_number,
+                observation_domain_id,
+                sets,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// The ID of the options template made by `template` in `templates`. If
+/// there is none, it is defined in `sets` with an ID not used by either
+fn options_template_id(
+    templates: &dyn TemplateStorage,
+    sets: &mut Sets,
+    template: impl Fn(u16) -> OptionsTemplateRecord,
+) -> BinResult<u16> {
+    let (_, options_template_records) = template_records(templates);
+    if let Some(record) = options_template_records
+        .into_iter()
+        .find(|record| *record == template(record.template_id))
+    {
+        return Ok(record.template_id);
+    }
+    let defined_in_sets = |template_id| {
+        sets.iter().any(|set| match &set.records {
+            Records::Template(records) => records.iter().any(|r| r.template_id == template_id),
+            Records::OptionsTemplate(records) => {
+                records.iter().any(|r| r.template_id == template_id)
+            }
+            _ => false,
+        })
+    };
+    let template_id = (256..=u16::MAX)
+        .find(|&id| templates.get_template(id).is_none() && !defined_in_sets(id))
+        .ok_or_else(|| IpfixError::TemplateIdsExhausted.into_binrw_error(0))?;
+    sets.push(Set {
+        records: Records::OptionsTemplate(vec![template(template_id)]),
+    });
+    Ok(template_id)
+}
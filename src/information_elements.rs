@@ -1,16 +1,177 @@
+use std::borrow::Cow;
+
 use ahash::HashMap;
 
-use crate::parser::DataRecordType;
+use crate::parser::{DataRecord, DataRecordKey, DataRecordType, DataRecordValue, Message};
+
+#[cfg(feature = "cisco")]
+pub mod cisco;
+#[cfg(feature = "juniper")]
+pub mod juniper;
+#[cfg(feature = "ntop")]
+pub mod ntop;
+#[cfg(feature = "vmware")]
+pub mod vmware;
+
+/// The name and type of an information element
+pub type Element = (Cow<'static, str>, DataRecordType);
+
+/// mapping of (enterprise_number, information_element_identifier) -> (name, type).
+/// The IANA elements of a default formatter are looked up in a static
+/// table, and other elements, such as learned or loaded ones, in a map
+/// that takes precedence over it. Names known at compile time are
+/// borrowed, and are cheaper to use as keys
+#[derive(Clone, Debug, Default)]
+pub struct Formatter {
+    /// Whether `DEFAULT_INFORMATION_ELEMENTS` is included
+    iana: bool,
+    elements: HashMap<(u32, u16), Element>,
+}
+
+impl Formatter {
+    /// A formatter with no elements
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &(u32, u16)) -> Option<&Element> {
+        match self.elements.get(key) {
+            Some(element) => Some(element),
+            None if self.iana && key.0 == 0 => DEFAULT_INFORMATION_ELEMENTS.get(&key.1),
+            None => None,
+        }
+    }
+
+    pub fn contains_key(&self, key: &(u32, u16)) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Add or replace an element, returning the one it replaced
+    pub fn insert(&mut self, key: (u32, u16), element: Element) -> Option<Element> {
+        let old = self.elements.insert(key, element);
+        old.or_else(|| match key {
+            (0, id) if self.iana => DEFAULT_INFORMATION_ELEMENTS.get(&id).cloned(),
+            _ => None,
+        })
+    }
+
+    /// All elements, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = ((u32, u16), &Element)> {
+        let iana = self
+            .iana
+            .then(|| DEFAULT_INFORMATION_ELEMENTS.entries())
+            .into_iter()
+            .flatten()
+            .filter(|(id, _)| !self.elements.contains_key(&(0, **id)))
+            .map(|(id, element)| ((0, *id), element));
+        self.elements
+            .iter()
+            .map(|(key, element)| (*key, element))
+            .chain(iana)
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
+
+/// Formatters are equal if they have the same elements, however they
+/// are stored
+impl PartialEq for Formatter {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, element)| other.get(&key) == Some(element))
+    }
+}
+
+impl Eq for Formatter {}
+
+impl Extend<((u32, u16), Element)> for Formatter {
+    fn extend<I: IntoIterator<Item = ((u32, u16), Element)>>(&mut self, iter: I) {
+        self.elements.extend(iter);
+    }
+}
+
+impl FromIterator<((u32, u16), Element)> for Formatter {
+    fn from_iter<I: IntoIterator<Item = ((u32, u16), Element)>>(iter: I) -> Self {
+        Self {
+            iana: false,
+            elements: HashMap::from_iter(iter),
+        }
+    }
+}
 
-/// mapping of (enterprise_number, information_element_identifier) -> (name, type)
-pub type Formatter = HashMap<(u32, u16), (&'static str, DataRecordType)>;
+impl<'a> IntoIterator for &'a Formatter {
+    type Item = ((u32, u16), &'a Element);
+    type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+impl IntoIterator for Formatter {
+    type Item = ((u32, u16), Element);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+            .map(|(key, element)| (key, element.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+/// Lookups on a `Formatter` by information element name
+pub trait FormatterExt {
+    /// The (enterprise_number, information_element_identifier, type) of
+    /// the element named `name`. Elements with no enterprise are
+    /// preferred, then the lowest enterprise number. This scans every
+    /// element, so resolve names once rather than per record
+    fn resolve(&self, name: &str) -> Option<(u32, u16, DataRecordType)>;
+}
+
+impl FormatterExt for Formatter {
+    fn resolve(&self, name: &str) -> Option<(u32, u16, DataRecordType)> {
+        self.iter()
+            .filter(|(_, (element_name, _))| element_name == name)
+            .map(|((enterprise_number, id), (_, ty))| (enterprise_number, id, *ty))
+            .min_by_key(|&(enterprise_number, id, _)| (enterprise_number, id))
+    }
+}
+
+/// Data Type Semantics of an information element
+/// <https://www.rfc-editor.org/rfc/rfc7012#section-3.2>
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum DataTypeSemantics {
+    Quantity,
+    TotalCounter,
+    DeltaCounter,
+    Identifier,
+    Flags,
+    List,
+    /// <https://www.rfc-editor.org/rfc/rfc8038#section-3.3>
+    SnmpCounter,
+    /// <https://www.rfc-editor.org/rfc/rfc8038#section-3.4>
+    SnmpGauge,
+}
+
+/// mapping of (enterprise_number, information_element_identifier) ->
+/// semantics, for elements with semantics other than the default
+pub type Semantics = HashMap<(u32, u16), DataTypeSemantics>;
 
 /// slightly nicer syntax to make a `Formatter`
 #[macro_export]
 macro_rules! formatter {
     { $(($key:expr, $id:expr) => ($string:expr, $value:ident)),+ $(,)? } => {
-        HashMap::from_iter([
-            $( ((($key, $id), ($string, DataRecordType::$value))), )+
+        $crate::information_elements::Formatter::from_iter([
+            $( (($key, $id), (::std::borrow::Cow::from($string), DataRecordType::$value)), )+
         ])
     };
 }
@@ -20,9 +181,147 @@ macro_rules! formatter {
 macro_rules! extend_formatter(
     { $formatter:ident += { $(($key:expr, $id:expr) => ($string:expr, $value:ident)),+ $(,)? } } => {
         $formatter.extend([
-            $( ((($key, $id), ($string, DataRecordType::$value))), )+
+            $( (($key, $id), (::std::borrow::Cow::from($string), DataRecordType::$value)), )+
         ])
     };
 );
 
 include!(concat!(env!("OUT_DIR"), "/ipfix-information-elements.rs"));
+
+/// default information element types for no enterprise / enterprise number 0.
+/// This doesn't copy the static table, so is cheap to call
+pub fn get_default_formatter() -> Formatter {
+    Formatter {
+        iana: true,
+        elements: HashMap::default(),
+    }
+}
+
+/// A `Formatter` of `elements` under `enterprise_number`
+#[cfg(any(
+    feature = "cisco",
+    feature = "juniper",
+    feature = "ntop",
+    feature = "vmware"
+))]
+fn enterprise_formatter(
+    enterprise_number: u32,
+    elements: &phf::Map<u16, (&'static str, DataRecordType)>,
+) -> Formatter {
+    elements
+        .entries()
+        .map(|(id, (name, ty))| ((enterprise_number, *id), (Cow::Borrowed(*name), *ty)))
+        .collect()
+}
+
+/// The name and type of an IANA information element, from a static
+/// table rather than a `Formatter`
+pub fn default_information_element(
+    information_element_identifier: u16,
+) -> Option<(&'static str, DataRecordType)> {
+    DEFAULT_INFORMATION_ELEMENTS
+        .get(&information_element_identifier)
+        .map(|(name, ty)| (name.as_ref(), *ty))
+}
+
+/// Convert an informationElementDataType value to a `DataRecordType`,
+/// if it is supported
+/// <https://www.rfc-editor.org/rfc/rfc5610#section-3.1>
+pub fn data_type_from_rfc5610(data_type: u8) -> Option<DataRecordType> {
+    Some(match data_type {
+        0 => DataRecordType::Bytes,
+        1..=4 => DataRecordType::UnsignedInt,
+        5..=8 => DataRecordType::SignedInt,
+        9 | 10 => DataRecordType::Float,
+        11 => DataRecordType::Bool,
+        12 => DataRecordType::MacAddress,
+        13 => DataRecordType::String,
+        14 => DataRecordType::DateTimeSeconds,
+        15 => DataRecordType::DateTimeMilliseconds,
+        16 => DataRecordType::DateTimeMicroseconds,
+        17 => DataRecordType::DateTimeNanoseconds,
+        18 => DataRecordType::Ipv4Addr,
+        19 => DataRecordType::Ipv6Addr,
+        // TODO: support for lists [RFC6313]
+        _ => return None,
+    })
+}
+
+/// Convert an abstract data type name, as used by the iana registry, to
+/// a `DataRecordType`, if it is supported
+/// <https://www.rfc-editor.org/rfc/rfc7012#section-3.1>
+pub fn data_type_from_name(name: &str) -> Option<DataRecordType> {
+    Some(match name {
+        "octetArray" => DataRecordType::Bytes,
+        "unsigned8" | "unsigned16" | "unsigned32" | "unsigned64" => DataRecordType::UnsignedInt,
+        "signed8" | "signed16" | "signed32" | "signed64" => DataRecordType::SignedInt,
+        "float32" | "float64" => DataRecordType::Float,
+        "boolean" => DataRecordType::Bool,
+        "macAddress" => DataRecordType::MacAddress,
+        "string" => DataRecordType::String,
+        "dateTimeSeconds" => DataRecordType::DateTimeSeconds,
+        "dateTimeMilliseconds" => DataRecordType::DateTimeMilliseconds,
+        "dateTimeMicroseconds" => DataRecordType::DateTimeMicroseconds,
+        "dateTimeNanoseconds" => DataRecordType::DateTimeNanoseconds,
+        "ipv4Address" => DataRecordType::Ipv4Addr,
+        "ipv6Address" => DataRecordType::Ipv6Addr,
+        // TODO: support for lists [RFC6313]
+        _ => return None,
+    })
+}
+
+/// Learn the names and types of information elements described by
+/// Information Element Type Options records in `message`, adding them
+/// to `formatter`. Returns the (enterprise_number,
+/// information_element_identifier) of each element that was added or
+/// changed.
+/// <https://www.rfc-editor.org/rfc/rfc5610>
+pub fn learn_information_elements(message: &Message, formatter: &mut Formatter) -> Vec<(u32, u16)> {
+    let mut learned = vec![];
+    for record in message.iter_data_records() {
+        let Some((key, name, ty)) = information_element_type(record) else {
+            continue;
+        };
+        if formatter
+            .get(&key)
+            .is_some_and(|(old_name, old_ty)| old_name == name && *old_ty == ty)
+        {
+            continue;
+        }
+        formatter.insert(key, (Cow::Owned(name.to_string()), ty));
+        learned.push(key);
+    }
+    learned
+}
+
+/// Extract an information element description from an Information
+/// Element Type Options record, if `record` is one
+fn information_element_type(record: &DataRecord) -> Option<((u32, u16), &str, DataRecordType)> {
+    // scope fields are informationElementId and privateEnterpriseNumber,
+    // but allow them anywhere in the record
+    let get = |name| {
+        let key = DataRecordKey::Str(name);
+        record
+            .scope_values
+            .get(&key)
+            .or_else(|| record.values.get(&key))
+    };
+    let unsigned = |name| match get(name)? {
+        DataRecordValue::U8(x) => Some(u64::from(*x)),
+        DataRecordValue::U16(x) => Some(u64::from(*x)),
+        DataRecordValue::U32(x) => Some(u64::from(*x)),
+        DataRecordValue::U64(x) => Some(*x),
+        _ => None,
+    };
+
+    let information_element_id = u16::try_from(unsigned("informationElementId")?).ok()?;
+    let enterprise_number = match unsigned("privateEnterpriseNumber") {
+        Some(enterprise_number) => u32::try_from(enterprise_number).ok()?,
+        None => 0,
+    };
+    let ty = data_type_from_rfc5610(u8::try_from(unsigned("informationElementDataType")?).ok()?)?;
+    let DataRecordValue::String(name) = get("informationElementName")? else {
+        return None;
+    };
+    Some(((enterprise_number, information_element_id), name, ty))
+}
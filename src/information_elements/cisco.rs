@@ -0,0 +1,32 @@
+//! Cisco enterprise information elements. Generated from
+//! `resources/cisco-information-elements.csv`, with the Application
+//! Visibility and Control (AVC) and QoS elements of Flexible NetFlow, and
+//! `resources/cisco-nsel-fields.csv`, with the NetFlow Security Event
+//! Logging (NSEL) fields of ASA firewalls
+//!
+//! AVC application IDs and names are the iana elements applicationId
+//! (95) and applicationName (96), so are already in the default
+//! `Formatter`, as are most NSEL fields, like firewallEvent (233).
+
+use super::{enterprise_formatter, Formatter};
+use crate::netflow::v9::VENDOR_ENTERPRISE_NUMBER;
+use crate::parser::DataRecordType;
+
+/// Cisco's Private Enterprise Number
+pub const ENTERPRISE_NUMBER: u32 = 9;
+
+include!(concat!(env!("OUT_DIR"), "/cisco-information-elements.rs"));
+include!(concat!(env!("OUT_DIR"), "/cisco-nsel-fields.rs"));
+
+/// Cisco's IPFIX information elements, to add to a `Formatter` with
+/// `formatter.extend(cisco::elements())`
+pub fn elements() -> Formatter {
+    enterprise_formatter(ENTERPRISE_NUMBER, &ELEMENTS)
+}
+
+/// The NSEL fields ASA firewalls send in NetFlow v9, which has no
+/// enterprise numbers. Field types from 32768 up are read as elements of
+/// `VENDOR_ENTERPRISE_NUMBER`, so field 33000 is element 232
+pub fn nsel_elements() -> Formatter {
+    enterprise_formatter(VENDOR_ENTERPRISE_NUMBER, &NSEL_ELEMENTS)
+}
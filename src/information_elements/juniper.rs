@@ -0,0 +1,20 @@
+//! Juniper enterprise information elements, as exported by jFlow on MX
+//! and SRX devices. Generated from
+//! `resources/juniper-information-elements.csv`
+//!
+//! Flow keys and counters are iana elements, so are already in the
+//! default `Formatter`.
+
+use super::{enterprise_formatter, Formatter};
+use crate::parser::DataRecordType;
+
+/// Juniper's Private Enterprise Number
+pub const ENTERPRISE_NUMBER: u32 = 2636;
+
+include!(concat!(env!("OUT_DIR"), "/juniper-information-elements.rs"));
+
+/// Juniper's information elements, to add to a `Formatter` with
+/// `formatter.extend(juniper::elements())`
+pub fn elements() -> Formatter {
+    enterprise_formatter(ENTERPRISE_NUMBER, &ELEMENTS)
+}
@@ -0,0 +1,21 @@
+//! Information elements of ntop's nProbe, under its Private Enterprise
+//! Number. Generated from `resources/ntop-information-elements.csv`, which
+//! follows the field list printed by `nprobe -H`
+//! <https://www.ntop.org/guides/nprobe/>
+//!
+//! nProbe exports the same elements over NetFlow v9 with IDs offset by
+//! 57472, e.g. `SRC_FRAGMENTS` is IPFIX 35632.80 and NetFlow v9 57552.
+
+use super::{enterprise_formatter, Formatter};
+use crate::parser::DataRecordType;
+
+/// ntop's Private Enterprise Number
+pub const ENTERPRISE_NUMBER: u32 = 35632;
+
+include!(concat!(env!("OUT_DIR"), "/ntop-information-elements.rs"));
+
+/// nProbe's information elements, to add to a `Formatter` with
+/// `formatter.extend(ntop::elements())`
+pub fn elements() -> Formatter {
+    enterprise_formatter(ENTERPRISE_NUMBER, &ELEMENTS)
+}
@@ -0,0 +1,21 @@
+//! VMware enterprise information elements, as exported by vSphere
+//! Distributed Switches and NSX. Generated from
+//! `resources/vmware-information-elements.csv`
+//!
+//! Most describe the inner (tenant) packets of overlay flows. The VXLAN
+//! Network Identifier is the iana element layer2SegmentId (351), so is
+//! already in the default `Formatter`.
+
+use super::{enterprise_formatter, Formatter};
+use crate::parser::DataRecordType;
+
+/// VMware's Private Enterprise Number
+pub const ENTERPRISE_NUMBER: u32 = 6876;
+
+include!(concat!(env!("OUT_DIR"), "/vmware-information-elements.rs"));
+
+/// VMware's information elements, to add to a `Formatter` with
+/// `formatter.extend(vmware::elements())`
+pub fn elements() -> Formatter {
+    enterprise_formatter(ENTERPRISE_NUMBER, &ELEMENTS)
+}
@@ -0,0 +1,381 @@
+//! Conversion of messages to and from JSON, for log pipelines and other
+//! JSON consumers
+//!
+//! A message is an object with `export_time`, `sequence_number`,
+//! `observation_domain_id` and `sets`. Each set is one of:
+//!
+//! - `{"template": [{"template_id": 256, "fields": [...]}]}`
+//! - `{"options_template": [{"template_id": 257, "scope_fields": [...], "fields": [...]}]}`
+//! - `{"data": {"template_id": 256, "records": [{...}]}}`
+//! - `{"raw_data": {"template_id": 256, "data": "0a0b..."}}`, for data
+//!   sets whose template was missing
+//! - `{"unsupported": {"set_id": 4, "data": "0a0b..."}}`, for sets with
+//!   reserved Set IDs
+//!
+//! where fields are `{"enterprise_number": null, "id": 8, "length": 4}`.
+//! Records are objects keyed by information element name, or
+//! `"{enterprise_number}:{id}:{length}"` for unrecognized elements,
+//! including any scope fields. Values are:
+//!
+//! - integers, floats and booleans as JSON numbers and booleans
+//! - strings, addresses, and MAC addresses (`"00:11:22:aa:bb:cc"`) as strings
+//! - octet arrays as lowercase hex strings
+//! - dateTimeSeconds and dateTimeMilliseconds as numbers since the Unix epoch
+//! - dateTimeMicroseconds and dateTimeNanoseconds as NTP timestamps,
+//!   `{"seconds": ..., "fraction": ...}`
+
+use serde_json::{json, Map, Value};
+
+use crate::information_elements::Formatter;
+use crate::parser::{
+    DataRecord, DataRecordKey, DataRecordType, DataRecordValue, DataRecordValues, FieldSpecifier,
+    IpfixError, Message, NtpTimestamp, OptionsTemplateRecord, Records, Set, TemplateRecord,
+};
+use crate::template_store::{ExpandedFieldSpecifier, TemplateStorage};
+
+#[derive(derive_more::Display, derive_more::From, Debug)]
+pub enum JsonError {
+    #[display(fmt = "Invalid JSON for {_0}")]
+    #[from(ignore)]
+    Schema(&'static str),
+    #[display(fmt = "{_0}")]
+    Ipfix(IpfixError),
+}
+
+impl std::error::Error for JsonError {}
+
+impl Message {
+    /// This message as JSON, as described in the `json` module
+    pub fn to_json(&self) -> Value {
+        json!({
+            "export_time": self.export_time,
+            "sequence_number": self.sequence_number,
+            "observation_domain_id": self.observation_domain_id,
+            "sets": self.sets.iter().map(set_to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Read a message from JSON, as described in the `json` module.
+    /// Template sets are added to `templates`, and data sets are decoded
+    /// using the types of their templates
+    pub fn from_json(
+        value: &Value,
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+    ) -> Result<Self, JsonError> {
+        Ok(Message {
+            export_time: get_u32(value, "export_time")?,
+            sequence_number: get_u32(value, "sequence_number")?,
+            observation_domain_id: get_u32(value, "observation_domain_id")?,
+            sets: value["sets"]
+                .as_array()
+                .ok_or(JsonError::Schema("sets"))?
+                .iter()
+                .map(|set| set_from_json(set, templates, formatter))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+fn get_u32(value: &Value, name: &'static str) -> Result<u32, JsonError> {
+    value[name]
+        .as_u64()
+        .and_then(|x| x.try_into().ok())
+        .ok_or(JsonError::Schema(name))
+}
+
+fn get_u16(value: &Value, name: &'static str) -> Result<u16, JsonError> {
+    value[name]
+        .as_u64()
+        .and_then(|x| x.try_into().ok())
+        .ok_or(JsonError::Schema(name))
+}
+
+fn set_to_json(set: &Set) -> Value {
+    match &set.records {
+        Records::Template(records) => json!({
+            "template": records.iter().map(|record| json!({
+                "template_id": record.template_id,
+                "fields": fields_to_json(&record.field_specifiers),
+            })).collect::<Vec<_>>(),
+        }),
+        Records::OptionsTemplate(records) => json!({
+            "options_template": records.iter().map(|record| {
+                let scope_field_count = record.scope_field_specifiers().len();
+                json!({
+                    "template_id": record.template_id,
+                    "scope_fields": fields_to_json(&record.field_specifiers[..scope_field_count]),
+                    "fields": fields_to_json(&record.field_specifiers[scope_field_count..]),
+                })
+            }).collect::<Vec<_>>(),
+        }),
+        Records::Data { set_id, data } => json!({
+            "data": {
+                "template_id": set_id,
+                "records": data.iter().map(record_to_json).collect::<Vec<_>>(),
+            },
+        }),
+        Records::RawData { set_id, bytes } => json!({
+            "raw_data": {
+                "template_id": set_id,
+                "data": bytes_to_hex(bytes),
+            },
+        }),
+        Records::Unsupported { set_id, data } => json!({
+            "unsupported": {
+                "set_id": set_id,
+                "data": bytes_to_hex(data),
+            },
+        }),
+    }
+}
+
+fn set_from_json(
+    value: &Value,
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+) -> Result<Set, JsonError> {
+    let records = if let Some(records) = value.get("template") {
+        let records = records
+            .as_array()
+            .ok_or(JsonError::Schema("template"))?
+            .iter()
+            .map(|record| {
+                Ok(TemplateRecord {
+                    template_id: get_u16(record, "template_id")?,
+                    field_specifiers: fields_from_json(&record["fields"])?,
+                })
+            })
+            .collect::<Result<Vec<_>, JsonError>>()?;
+        templates.insert_template_records(&records, formatter)?;
+        Records::Template(records)
+    } else if let Some(records) = value.get("options_template") {
+        let records = records
+            .as_array()
+            .ok_or(JsonError::Schema("options_template"))?
+            .iter()
+            .map(|record| {
+                let template_id = get_u16(record, "template_id")?;
+                let scope_fields: Vec<_> = fields_from_json(&record["scope_fields"])?;
+                let fields: Vec<_> = fields_from_json(&record["fields"])?;
+                if scope_fields.is_empty() && fields.is_empty() {
+                    return Ok(OptionsTemplateRecord::withdrawal(template_id));
+                }
+                OptionsTemplateRecord::new(template_id, scope_fields, fields)
+                    .ok_or(JsonError::Schema("options_template"))
+            })
+            .collect::<Result<Vec<_>, JsonError>>()?;
+        templates.insert_options_template_records(&records, formatter)?;
+        Records::OptionsTemplate(records)
+    } else if let Some(data) = value.get("data") {
+        let set_id = get_u16(data, "template_id")?;
+        let template = templates
+            .get_template(set_id)
+            .ok_or(IpfixError::MissingTemplate(set_id))?;
+        let scope_field_count = template.scope_field_specifiers().len();
+        let data = data["records"]
+            .as_array()
+            .ok_or(JsonError::Schema("records"))?
+            .iter()
+            .map(|record| {
+                let mut values = DataRecordValues::default();
+                let mut scope_values = DataRecordValues::default();
+                for (i, field_spec) in template.field_specifiers().enumerate() {
+                    let value = record
+                        .get(field_spec.name.to_string())
+                        .ok_or_else(|| IpfixError::MissingData(field_spec.name.clone()))?;
+                    let value = value_from_json(value, field_spec)?;
+                    if i < scope_field_count {
+                        scope_values.insert(field_spec.name.clone(), value);
+                    } else {
+                        values.insert(field_spec.name.clone(), value);
+                    }
+                }
+                Ok(DataRecord {
+                    values,
+                    scope_values,
+                })
+            })
+            .collect::<Result<_, JsonError>>()?;
+        Records::Data { set_id, data }
+    } else if let Some(raw_data) = value.get("raw_data") {
+        Records::RawData {
+            set_id: get_u16(raw_data, "template_id")?,
+            bytes: raw_data["data"]
+                .as_str()
+                .and_then(bytes_from_hex)
+                .ok_or(JsonError::Schema("raw_data"))?,
+        }
+    } else if let Some(unsupported) = value.get("unsupported") {
+        Records::Unsupported {
+            set_id: get_u16(unsupported, "set_id")?,
+            data: unsupported["data"]
+                .as_str()
+                .and_then(bytes_from_hex)
+                .ok_or(JsonError::Schema("unsupported"))?,
+        }
+    } else {
+        return Err(JsonError::Schema("set"));
+    };
+    Ok(Set { records })
+}
+
+fn fields_to_json(field_specifiers: &[FieldSpecifier]) -> Vec<Value> {
+    field_specifiers
+        .iter()
+        .map(|field_spec| {
+            json!({
+                "enterprise_number": field_spec.enterprise_number,
+                "id": field_spec.information_element_identifier,
+                "length": field_spec.field_length,
+            })
+        })
+        .collect()
+}
+
+fn fields_from_json<T: FromIterator<FieldSpecifier>>(value: &Value) -> Result<T, JsonError> {
+    let Some(fields) = value.as_array() else {
+        return Err(JsonError::Schema("fields"));
+    };
+    fields
+        .iter()
+        .map(|field| {
+            let enterprise_number = match &field["enterprise_number"] {
+                Value::Null => None,
+                x => Some(
+                    x.as_u64()
+                        .and_then(|x| x.try_into().ok())
+                        .ok_or(JsonError::Schema("enterprise_number"))?,
+                ),
+            };
+            Ok(FieldSpecifier::new(
+                enterprise_number,
+                get_u16(field, "id")?,
+                get_u16(field, "length")?,
+            ))
+        })
+        .collect()
+}
+
+fn record_to_json(record: &DataRecord) -> Value {
+    Value::Object(
+        record
+            .scope_values
+            .iter()
+            .chain(&record.values)
+            .map(|(key, value)| (key.to_string(), value_to_json(value)))
+            .collect::<Map<_, _>>(),
+    )
+}
+
+fn value_to_json(value: &DataRecordValue) -> Value {
+    match value {
+        DataRecordValue::U8(x) => json!(x),
+        DataRecordValue::U16(x) => json!(x),
+        DataRecordValue::U32(x) => json!(x),
+        DataRecordValue::U64(x) => json!(x),
+        DataRecordValue::I8(x) => json!(x),
+        DataRecordValue::I16(x) => json!(x),
+        DataRecordValue::I32(x) => json!(x),
+        DataRecordValue::I64(x) => json!(x),
+        DataRecordValue::F32(x) => json!(x),
+        DataRecordValue::F64(x) => json!(x),
+        DataRecordValue::Bool(x) => json!(x),
+        DataRecordValue::MacAddress(x) => json!(x.to_string()),
+        DataRecordValue::Bytes(x) => json!(bytes_to_hex(x)),
+        DataRecordValue::String(x) => json!(x),
+        DataRecordValue::DateTimeSeconds(x) => json!(x),
+        DataRecordValue::DateTimeMilliseconds(x) => json!(x),
+        DataRecordValue::DateTimeMicroseconds(x) | DataRecordValue::DateTimeNanoseconds(x) => {
+            json!({ "seconds": x.seconds, "fraction": x.fraction })
+        }
+        DataRecordValue::Ipv4Addr(x) => json!(x.to_string()),
+        DataRecordValue::Ipv6Addr(x) => json!(x.to_string()),
+    }
+}
+
+fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn bytes_from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Decode a value as read with `field_spec`
+fn value_from_json(
+    value: &Value,
+    field_spec: &ExpandedFieldSpecifier,
+) -> Result<DataRecordValue, JsonError> {
+    let invalid = || JsonError::Schema(field_spec_name(field_spec));
+    let unsigned = || value.as_u64().ok_or_else(invalid);
+    let signed = || value.as_i64().ok_or_else(invalid);
+    let string = || value.as_str().ok_or_else(invalid);
+    let narrow = |x: Result<DataRecordValue, std::num::TryFromIntError>| x.map_err(|_| invalid());
+    Ok(match (field_spec.ty, field_spec.field_length) {
+        (DataRecordType::UnsignedInt, 1) => {
+            narrow(unsigned()?.try_into().map(DataRecordValue::U8))?
+        }
+        (DataRecordType::UnsignedInt, 2) => {
+            narrow(unsigned()?.try_into().map(DataRecordValue::U16))?
+        }
+        (DataRecordType::UnsignedInt, 3 | 4) => {
+            narrow(unsigned()?.try_into().map(DataRecordValue::U32))?
+        }
+        (DataRecordType::UnsignedInt, _) => DataRecordValue::U64(unsigned()?),
+        (DataRecordType::SignedInt, 1) => narrow(signed()?.try_into().map(DataRecordValue::I8))?,
+        (DataRecordType::SignedInt, 2) => narrow(signed()?.try_into().map(DataRecordValue::I16))?,
+        (DataRecordType::SignedInt, 3 | 4) => {
+            narrow(signed()?.try_into().map(DataRecordValue::I32))?
+        }
+        (DataRecordType::SignedInt, _) => DataRecordValue::I64(signed()?),
+        (DataRecordType::Float, 4) => {
+            DataRecordValue::F32(value.as_f64().ok_or_else(invalid)? as f32)
+        }
+        (DataRecordType::Float, _) => DataRecordValue::F64(value.as_f64().ok_or_else(invalid)?),
+        (DataRecordType::Bool, _) => DataRecordValue::Bool(value.as_bool().ok_or_else(invalid)?),
+        (DataRecordType::MacAddress, _) => {
+            DataRecordValue::MacAddress(string()?.parse().map_err(|_| invalid())?)
+        }
+        (DataRecordType::Bytes, _) => {
+            DataRecordValue::Bytes(bytes_from_hex(string()?).ok_or_else(invalid)?)
+        }
+        (DataRecordType::String, _) => DataRecordValue::String(string()?.to_string()),
+        (DataRecordType::DateTimeSeconds, _) => {
+            narrow(unsigned()?.try_into().map(DataRecordValue::DateTimeSeconds))?
+        }
+        (DataRecordType::DateTimeMilliseconds, _) => {
+            DataRecordValue::DateTimeMilliseconds(unsigned()?)
+        }
+        (DataRecordType::DateTimeMicroseconds | DataRecordType::DateTimeNanoseconds, _) => {
+            let timestamp = NtpTimestamp {
+                seconds: get_u32(value, "seconds").map_err(|_| invalid())?,
+                fraction: get_u32(value, "fraction").map_err(|_| invalid())?,
+            };
+            if field_spec.ty == DataRecordType::DateTimeMicroseconds {
+                DataRecordValue::DateTimeMicroseconds(timestamp)
+            } else {
+                DataRecordValue::DateTimeNanoseconds(timestamp)
+            }
+        }
+        (DataRecordType::Ipv4Addr, _) => {
+            DataRecordValue::Ipv4Addr(string()?.parse().map_err(|_| invalid())?)
+        }
+        (DataRecordType::Ipv6Addr, _) => {
+            DataRecordValue::Ipv6Addr(string()?.parse().map_err(|_| invalid())?)
+        }
+    })
+}
+
+fn field_spec_name(field_spec: &ExpandedFieldSpecifier) -> &'static str {
+    match field_spec.name {
+        DataRecordKey::Str(name) => name,
+        _ => "value",
+    }
+}
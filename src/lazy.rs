@@ -0,0 +1,91 @@
+//! Data records that are only decoded a field at a time, as fields are
+//! looked up. Reading a few fields of records with many is then much
+//! cheaper than decoding every field
+
+use binrw::BinResult;
+
+use crate::borrowed::{decode, next_field, BorrowedValue};
+use crate::parser::{DataRecord, DataRecordKey, DataRecordValues};
+use crate::template_store::{ExpandedFieldSpecifier, Template};
+
+/// The bytes of a data record with its template, from
+/// `BorrowedDataSet::lazy_records`
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct LazyDataRecord<'t, 'a> {
+    template: &'t Template,
+    bytes: &'a [u8],
+}
+
+impl<'t, 'a> LazyDataRecord<'t, 'a> {
+    /// A record of `template` spanning all of `bytes`
+    pub fn new(template: &'t Template, bytes: &'a [u8]) -> Self {
+        Self { template, bytes }
+    }
+
+    pub fn template(&self) -> &'t Template {
+        self.template
+    }
+
+    /// The encoded record
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Each field with its raw bytes, in template order, stopping at
+    /// the first field that doesn't fit in the record
+    pub fn fields(&self) -> impl Iterator<Item = (&'t ExpandedFieldSpecifier, &'a [u8])> {
+        let bytes = self.bytes;
+        let mut offset = 0;
+        self.template
+            .field_specifiers()
+            .map_while(move |field_spec| {
+                let field = next_field(bytes, &mut offset, field_spec.field_length).ok()?;
+                Some((field_spec, field))
+            })
+    }
+
+    /// The raw bytes of `key`, if the template has it
+    pub fn field(&self, key: impl Into<DataRecordKey>) -> Option<&'a [u8]> {
+        let key = key.into();
+        self.fields()
+            .find(|(field_spec, _)| field_spec.name == key)
+            .map(|(_, field)| field)
+    }
+
+    /// Decode the value of `key`, if the template has it. Fields before
+    /// it are skipped over without being decoded
+    pub fn get(&self, key: impl Into<DataRecordKey>) -> Option<BorrowedValue<'a>> {
+        let key = key.into();
+        self.fields()
+            .find(|(field_spec, _)| field_spec.name == key)
+            .map(|(field_spec, field)| decode(field_spec.ty, field))
+    }
+
+    /// Decode the value at `index` in template order
+    pub fn get_index(&self, index: usize) -> Option<BorrowedValue<'a>> {
+        self.fields()
+            .nth(index)
+            .map(|(field_spec, field)| decode(field_spec.ty, field))
+    }
+
+    /// Decode all fields into an owned `DataRecord`
+    pub fn to_owned(&self) -> BinResult<DataRecord> {
+        let scope_field_count = self.template.scope_field_specifiers().len();
+        let mut scope_values = DataRecordValues::default();
+        let mut values = DataRecordValues::default();
+        let mut offset = 0;
+        for (i, field_spec) in self.template.field_specifiers().enumerate() {
+            let field = next_field(self.bytes, &mut offset, field_spec.field_length)?;
+            let value = decode(field_spec.ty, field).to_owned();
+            if i < scope_field_count {
+                scope_values.insert(field_spec.name.clone(), value);
+            } else {
+                values.insert(field_spec.name.clone(), value);
+            }
+        }
+        Ok(DataRecord {
+            values,
+            scope_values,
+        })
+    }
+}
@@ -1,22 +1,205 @@
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod borrowed;
+pub mod collector;
+pub mod compact;
+mod convert;
+mod display;
+#[cfg(feature = "dtls")]
+pub mod dtls;
+pub mod export;
+pub mod file;
 pub mod information_elements;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod lazy;
+pub mod mediator;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod netflow;
+#[cfg(feature = "rayon")]
+pub mod parallel;
 pub mod parser;
+pub mod plan;
+mod query;
+pub mod record;
+pub mod registry;
+pub mod sctp;
+pub mod sequence;
+#[cfg(feature = "serde")]
+mod serialize;
+pub mod statistics;
+pub mod stream;
 pub mod template_store;
+mod time;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod types;
 mod util;
+pub mod view;
+pub mod visit;
 
-use std::{io::Cursor, rc::Rc};
+use std::{hash::Hash, io::Cursor};
+
+#[cfg(feature = "derive")]
+pub use ipfixrw_derive::IpfixRecord;
 
 use binrw::{BinRead, BinResult};
-use information_elements::Formatter;
-use template_store::TemplateStore;
+use information_elements::{learn_information_elements, Formatter};
+use template_store::{resolve_unrecognized_fields, ScopedTemplateStore, TemplateStorage};
 
-use crate::parser::Message;
+use crate::parser::{Message, ParseOptions, RawDataSet, WriteOptions};
 
 pub fn parse_ipfix_message<T: AsRef<[u8]>>(
     buf: &T,
-    templates: TemplateStore,
-    formatter: Rc<Formatter>,
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+) -> BinResult<Message> {
+    parse_ipfix_message_with_options(buf, templates, formatter, ParseOptions::default())
+}
+
+pub fn parse_ipfix_message_with_options<T: AsRef<[u8]>>(
+    buf: &T,
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+    options: ParseOptions,
 ) -> BinResult<Message> {
-    Message::read_args(&mut Cursor::new(buf), (templates, formatter))
+    Message::read_args(&mut Cursor::new(buf), (templates, formatter, options))
+}
+
+/// Encode a message, padding each set to a multiple of `alignment`
+/// bytes. Templates the message defines are added to `templates`
+pub fn write_ipfix_message(
+    message: &Message,
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+    alignment: u8,
+) -> BinResult<Vec<u8>> {
+    message.to_bytes(templates, formatter, WriteOptions::aligned(alignment))
+}
+
+/// Parse a single data set into raw field bytes, using only its template
+pub fn parse_raw_data_set<T: AsRef<[u8]>>(
+    buf: &T,
+    templates: &dyn TemplateStorage,
+) -> BinResult<RawDataSet> {
+    RawDataSet::read_args(&mut Cursor::new(buf), (templates,))
+}
+
+/// Parse a message, then learn any information elements it describes
+/// with Information Element Type Options records (RFC 5610), adding them
+/// to `formatter` and updating templates that use them. Records in the
+/// same message are not affected, only later messages
+pub fn parse_ipfix_message_learning<T: AsRef<[u8]>>(
+    buf: &T,
+    templates: &dyn TemplateStorage,
+    formatter: &mut Formatter,
+) -> BinResult<Message> {
+    let message = parse_ipfix_message(buf, templates, formatter)?;
+    if !learn_information_elements(&message, formatter).is_empty() {
+        resolve_unrecognized_fields(templates, formatter)
+            .map_err(|e| e.into_binrw_error(buf.as_ref().len() as u64))?;
+    }
+    Ok(message)
+}
+
+/// Parse a message using the templates scoped to `peer` and the
+/// message's Observation Domain ID
+pub fn parse_ipfix_message_scoped<T, P, S>(
+    buf: &T,
+    templates: &ScopedTemplateStore<P, S>,
+    peer: P,
+    formatter: &Formatter,
+) -> BinResult<Message>
+where
+    T: AsRef<[u8]>,
+    P: Hash + Eq,
+    S: TemplateStorage + 'static,
+{
+    Message::read_scoped(
+        &mut Cursor::new(buf),
+        templates,
+        peer,
+        formatter,
+        ParseOptions::default(),
+    )
+}
+
+/// Parse all of the back-to-back messages in `buf`, such as a TCP
+/// segment or a chunk of a file, split by their header lengths
+pub fn parse_ipfix_messages<T: AsRef<[u8]>>(
+    buf: &T,
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+) -> BinResult<Vec<Message>> {
+    iter_ipfix_messages(buf.as_ref(), templates, formatter, ParseOptions::default()).collect()
+}
+
+/// Iterate over the back-to-back messages in `buf`, parsing each as it
+/// is reached. Stops after the first error
+pub fn iter_ipfix_messages<'a>(
+    buf: &'a [u8],
+    templates: &'a dyn TemplateStorage,
+    formatter: &'a Formatter,
+    options: ParseOptions,
+) -> MessageIter<'a> {
+    MessageIter {
+        buf,
+        offset: 0,
+        templates,
+        formatter,
+        options,
+    }
+}
+
+/// Iterator over the messages in a buffer, from `iter_ipfix_messages`
+pub struct MessageIter<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    templates: &'a dyn TemplateStorage,
+    formatter: &'a Formatter,
+    options: ParseOptions,
+}
+
+impl<'a> MessageIter<'a> {
+    /// The bytes of the next message, using the length in its header
+    fn next_message_bytes(&self) -> BinResult<&'a [u8]> {
+        let rest = &self.buf[self.offset..];
+        let length = match rest.get(2..4) {
+            Some(length) => usize::from(u16::from_be_bytes([length[0], length[1]])),
+            None => 0,
+        };
+        if length < 16 || length > rest.len() {
+            return Err(binrw::Error::AssertFail {
+                pos: self.offset as u64,
+                message: format!(
+                    "invalid message length: [{length}], with {} bytes remaining",
+                    rest.len()
+                ),
+            });
+        }
+        Ok(&rest[..length])
+    }
+}
+
+impl Iterator for MessageIter<'_> {
+    type Item = BinResult<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.buf.len() {
+            return None;
+        }
+        let result = self.next_message_bytes().and_then(|bytes| {
+            self.offset += bytes.len();
+            parse_ipfix_message_with_options(&bytes, self.templates, self.formatter, self.options)
+        });
+        if result.is_err() {
+            self.offset = self.buf.len();
+        }
+        Some(result)
+    }
 }
@@ -0,0 +1,217 @@
+//! Re-exporting the sets of many upstream Transport Sessions on a single
+//! downstream one, as an IPFIX Mediator does. Upstream templates are
+//! given downstream IDs by an `Exporter`, so templates from different
+//! sessions that share an ID don't conflict, and identical ones share a
+//! downstream template
+//! <https://www.rfc-editor.org/rfc/rfc6183>
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use binrw::BinResult;
+
+use crate::export::Exporter;
+use crate::parser::{
+    IpfixError, Message, OptionsTemplateRecord, Records, TemplateRecord, OPTIONS_TEMPLATE_SET_ID,
+    TEMPLATE_SET_ID,
+};
+
+/// The downstream template of an upstream one
+#[derive(Clone, Copy, Debug)]
+struct Mapping {
+    template_id: u16,
+    options: bool,
+}
+
+/// Renumbers the templates of messages from many peers and queues their
+/// records on one `Exporter`. Decoded records must use the names of the
+/// exporter's formatter
+#[derive(Debug)]
+pub struct Mediator<P> {
+    exporter: Exporter,
+    /// Keyed by upstream (peer, observation_domain_id, template_id)
+    mappings: HashMap<(P, u32, u16), Mapping>,
+}
+
+impl<P: Hash + Eq + Clone> Mediator<P> {
+    pub fn new(exporter: Exporter) -> Self {
+        Self {
+            exporter,
+            mappings: HashMap::new(),
+        }
+    }
+
+    /// The downstream exporter, to encode the flushed messages with
+    pub fn exporter(&self) -> &Exporter {
+        &self.exporter
+    }
+
+    pub fn exporter_mut(&mut self) -> &mut Exporter {
+        &mut self.exporter
+    }
+
+    pub fn into_exporter(self) -> Exporter {
+        self.exporter
+    }
+
+    /// The downstream Template ID of an upstream template
+    pub fn template_id(
+        &self,
+        peer: &P,
+        observation_domain_id: u32,
+        template_id: u16,
+    ) -> Option<u16> {
+        self.mappings
+            .get(&(peer.clone(), observation_domain_id, template_id))
+            .map(|mapping| mapping.template_id)
+    }
+
+    /// Define, redefine or withdraw the templates of `message` downstream,
+    /// and queue its data records with their downstream template. Both
+    /// decoded and raw data sets are accepted
+    pub fn handle(&mut self, peer: P, message: &Message) -> Result<(), IpfixError> {
+        let observation_domain_id = message.observation_domain_id;
+        for set in &message.sets {
+            match &set.records {
+                Records::Template(records) => {
+                    for record in records {
+                        self.define_template(&peer, observation_domain_id, record)?;
+                    }
+                }
+                Records::OptionsTemplate(records) => {
+                    for record in records {
+                        self.define_options_template(&peer, observation_domain_id, record)?;
+                    }
+                }
+                Records::Data { set_id, data } => {
+                    let template_id = self
+                        .template_id(&peer, observation_domain_id, *set_id)
+                        .ok_or(IpfixError::MissingTemplate(*set_id))?;
+                    for record in data {
+                        self.exporter.push(template_id, record.clone())?;
+                    }
+                }
+                Records::RawData { set_id, bytes } => {
+                    let template_id = self
+                        .template_id(&peer, observation_domain_id, *set_id)
+                        .ok_or(IpfixError::MissingTemplate(*set_id))?;
+                    self.exporter.push_raw(template_id, bytes.clone())?;
+                }
+                // reserved sets mean nothing downstream
+                Records::Unsupported { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn define_template(
+        &mut self,
+        peer: &P,
+        observation_domain_id: u32,
+        record: &TemplateRecord,
+    ) -> Result<(), IpfixError> {
+        if record.field_specifiers.is_empty() {
+            self.withdraw(peer, observation_domain_id, record.template_id, false);
+            return Ok(());
+        }
+        let template_id = self
+            .exporter
+            .add_template(record.field_specifiers.to_vec())?;
+        self.map(
+            peer,
+            observation_domain_id,
+            record.template_id,
+            Mapping {
+                template_id,
+                options: false,
+            },
+        );
+        Ok(())
+    }
+
+    fn define_options_template(
+        &mut self,
+        peer: &P,
+        observation_domain_id: u32,
+        record: &OptionsTemplateRecord,
+    ) -> Result<(), IpfixError> {
+        if record.field_specifiers.is_empty() {
+            self.withdraw(peer, observation_domain_id, record.template_id, true);
+            return Ok(());
+        }
+        let template_id = self.exporter.add_options_template_record(record.clone())?;
+        self.map(
+            peer,
+            observation_domain_id,
+            record.template_id,
+            Mapping {
+                template_id,
+                options: true,
+            },
+        );
+        Ok(())
+    }
+
+    fn map(&mut self, peer: &P, observation_domain_id: u32, template_id: u16, mapping: Mapping) {
+        let old = self
+            .mappings
+            .insert((peer.clone(), observation_domain_id, template_id), mapping);
+        if let Some(old) = old.filter(|old| old.template_id != mapping.template_id) {
+            self.release(old.template_id);
+        }
+    }
+
+    /// Withdraw an upstream template, or all templates or options
+    /// templates of the Observation Domain for the All (Options)
+    /// Templates Withdrawal
+    fn withdraw(&mut self, peer: &P, observation_domain_id: u32, template_id: u16, options: bool) {
+        let withdraw_all = template_id == TEMPLATE_SET_ID || template_id == OPTIONS_TEMPLATE_SET_ID;
+        let removed: Vec<_> = self
+            .mappings
+            .iter()
+            .filter(|((p, domain, id), mapping)| {
+                p == peer
+                    && *domain == observation_domain_id
+                    && (*id == template_id || (withdraw_all && mapping.options == options))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in removed {
+            if let Some(mapping) = self.mappings.remove(&key) {
+                self.release(mapping.template_id);
+            }
+        }
+    }
+
+    /// Withdraw the templates of `peer`, such as when its Transport
+    /// Session ends
+    pub fn remove_peer(&mut self, peer: &P) {
+        let removed: Vec<_> = self
+            .mappings
+            .keys()
+            .filter(|(p, _, _)| p == peer)
+            .cloned()
+            .collect();
+        for key in removed {
+            if let Some(mapping) = self.mappings.remove(&key) {
+                self.release(mapping.template_id);
+            }
+        }
+    }
+
+    /// Withdraw a downstream template once no upstream one maps to it
+    fn release(&mut self, template_id: u16) {
+        if !self
+            .mappings
+            .values()
+            .any(|mapping| mapping.template_id == template_id)
+        {
+            self.exporter.withdraw_template(template_id);
+        }
+    }
+
+    /// `Exporter::flush` of the downstream exporter
+    pub fn flush(&mut self) -> BinResult<Vec<Message>> {
+        self.exporter.flush()
+    }
+}
@@ -0,0 +1,113 @@
+//! Counters of what collectors decode, reported through the `metrics`
+//! facade so that any of its exporters, such as Prometheus, can scrape
+//! them. `CollectorSession` updates them, so they cover every collector
+//! built on it, and `SessionManager` updates them in its place, once it
+//! has accepted or rejected each message
+
+use ::metrics::{counter, describe_counter};
+
+use crate::collector::{Collected, SessionEvent};
+use crate::parser::{IpfixError, Message, Records};
+use crate::sequence::SequenceEvent;
+
+/// Messages decoded
+pub const MESSAGES: &str = "ipfix_messages_total";
+/// Data records decoded, labelled by `template_id`
+pub const DATA_RECORDS: &str = "ipfix_data_records_total";
+/// Messages that failed to decode, labelled by `kind`
+pub const PARSE_ERRORS: &str = "ipfix_parse_errors_total";
+/// Templates added or redefined
+pub const TEMPLATES_LEARNED: &str = "ipfix_templates_learned_total";
+pub const TEMPLATES_WITHDRAWN: &str = "ipfix_templates_withdrawn_total";
+pub const TEMPLATES_EXPIRED: &str = "ipfix_templates_expired_total";
+/// Data sets dropped because their template was withdrawn
+pub const DATA_SETS_DISCARDED: &str = "ipfix_data_sets_discarded_total";
+/// Sequence Number gaps
+pub const SEQUENCE_GAPS: &str = "ipfix_sequence_gaps_total";
+/// Data records skipped over by Sequence Number gaps
+pub const LOST_DATA_RECORDS: &str = "ipfix_lost_data_records_total";
+
+/// Register descriptions of the counters, for exporters that show them
+pub fn describe() {
+    describe_counter!(MESSAGES, "Messages decoded");
+    describe_counter!(DATA_RECORDS, "Data records decoded, by template");
+    describe_counter!(PARSE_ERRORS, "Messages that failed to decode, by kind");
+    describe_counter!(TEMPLATES_LEARNED, "Templates added or redefined");
+    describe_counter!(TEMPLATES_WITHDRAWN, "Templates withdrawn");
+    describe_counter!(TEMPLATES_EXPIRED, "Templates expired");
+    describe_counter!(
+        DATA_SETS_DISCARDED,
+        "Data sets dropped because their template was withdrawn"
+    );
+    describe_counter!(SEQUENCE_GAPS, "Sequence Number gaps");
+    describe_counter!(
+        LOST_DATA_RECORDS,
+        "Data records skipped over by Sequence Number gaps"
+    );
+}
+
+/// The `kind` label of a decoding error
+pub fn error_kind(error: &binrw::Error) -> &'static str {
+    match error.root_cause() {
+        binrw::Error::BadMagic { .. } => "bad_version",
+        binrw::Error::Io(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => "truncated",
+        binrw::Error::Io(_) => "io",
+        error => match error.custom_err::<IpfixError>() {
+            Some(IpfixError::MissingTemplate(_)) => "missing_template",
+            Some(IpfixError::TemplateRedefinition(_)) => "template_redefinition",
+            Some(
+                IpfixError::InvalidFieldSpecLength { .. }
+                | IpfixError::InvalidBoolean(_)
+                | IpfixError::InvalidValueType { .. }
+                | IpfixError::InvalidValueLength { .. },
+            ) => "invalid_value",
+            _ => "malformed",
+        },
+    }
+}
+
+/// Record the outcome of decoding one message
+pub(crate) fn record_result(result: &binrw::BinResult<Collected>) {
+    match result {
+        Ok(collected) => {
+            record_message(&collected.message);
+            record_events(&collected.events);
+        }
+        Err(err) => record_error(err),
+    }
+}
+
+fn record_message(message: &Message) {
+    counter!(MESSAGES).increment(1);
+    for set in &message.sets {
+        if let Records::Data { set_id, data } = &set.records {
+            counter!(DATA_RECORDS, "template_id" => set_id.to_string())
+                .increment(data.len() as u64);
+        }
+    }
+}
+
+fn record_error(error: &binrw::Error) {
+    counter!(PARSE_ERRORS, "kind" => error_kind(error)).increment(1);
+}
+
+pub(crate) fn record_events(events: &[SessionEvent]) {
+    for event in events {
+        match event {
+            SessionEvent::TemplateAdded { .. } | SessionEvent::TemplateReplaced { .. } => {
+                counter!(TEMPLATES_LEARNED).increment(1)
+            }
+            SessionEvent::TemplateWithdrawn { .. } => counter!(TEMPLATES_WITHDRAWN).increment(1),
+            SessionEvent::TemplateExpired { .. } => counter!(TEMPLATES_EXPIRED).increment(1),
+            SessionEvent::DataSetDiscarded { .. } => counter!(DATA_SETS_DISCARDED).increment(1),
+            SessionEvent::Sequence {
+                event: SequenceEvent::Gap { missing, .. },
+                ..
+            } => {
+                counter!(SEQUENCE_GAPS).increment(1);
+                counter!(LOST_DATA_RECORDS).increment((*missing).into());
+            }
+            SessionEvent::Sequence { .. } => {}
+        }
+    }
+}
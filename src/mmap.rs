@@ -0,0 +1,73 @@
+//! Parsing files of back-to-back messages, such as RFC 5655 files,
+//! through a memory map rather than reading them into memory. With
+//! `BorrowedMessage`s, octet arrays and strings are borrowed from the
+//! mapped file
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use binrw::BinResult;
+use memmap2::Mmap;
+
+use crate::borrowed::{split_message, BorrowedMessage};
+use crate::information_elements::Formatter;
+use crate::parser::ParseOptions;
+use crate::template_store::TemplateStorage;
+use crate::{iter_ipfix_messages, MessageIter};
+
+/// A read-only memory map of a file of messages
+#[derive(Debug)]
+pub struct MappedFile {
+    mmap: Mmap,
+}
+
+impl MappedFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the map is read-only, but the file could still be
+        // changed by another process while it is mapped, which is
+        // undefined behaviour. Callers must not map files that are being
+        // written to
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(Self { mmap })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    /// Parse each message in the file as it is reached. Stops after the
+    /// first error
+    pub fn iter_messages<'a>(
+        &'a self,
+        templates: &'a dyn TemplateStorage,
+        formatter: &'a Formatter,
+        options: ParseOptions,
+    ) -> MessageIter<'a> {
+        iter_ipfix_messages(&self.mmap, templates, formatter, options)
+    }
+
+    /// Parse each message in the file as a `BorrowedMessage`, with data
+    /// sets borrowing from the file. Stops after the first error
+    pub fn iter_borrowed_messages<'a>(
+        &'a self,
+        templates: &'a dyn TemplateStorage,
+        formatter: &'a Formatter,
+    ) -> impl Iterator<Item = BinResult<BorrowedMessage<'a>>> {
+        let mut rest = self.bytes();
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done || rest.is_empty() {
+                return None;
+            }
+            let result = split_message(rest).and_then(|(message, _)| {
+                let parsed = BorrowedMessage::parse(message, templates, formatter)?;
+                rest = &rest[message.len()..];
+                Ok(parsed)
+            });
+            done = result.is_err();
+            Some(result)
+        })
+    }
+}
@@ -0,0 +1,5 @@
+//! NetFlow, the predecessor of IPFIX, converted to and from the IPFIX
+//! model so that one collector can accept both
+
+pub mod v5;
+pub mod v9;
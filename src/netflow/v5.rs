@@ -0,0 +1,189 @@
+//! NetFlow version 5, which has a fixed record format. Each record is
+//! converted to a `DataRecord` of the standard information elements in
+//! `template_fields`, leaving out the padding
+//! <https://www.cisco.com/c/en/us/td/docs/net_mgmt/netflow_collection_engine/3-6/user/guide/format.html>
+
+use std::io::Cursor;
+
+use binrw::{BinRead, BinResult};
+
+use crate::borrowed::{read_u16, read_u32, truncated};
+use crate::information_elements::Formatter;
+use crate::parser::{
+    DataRecord, FieldSpecifier, FieldSpecifiers, IpfixError, Message, ParseOptions, Records, Set,
+    TemplateRecord, TEMPLATE_SET_ID,
+};
+
+pub const VERSION: u16 = 5;
+/// The ID of the template in `template_fields`, in converted messages
+pub const TEMPLATE_ID: u16 = 256;
+
+const HEADER_LENGTH: usize = 24;
+const RECORD_LENGTH: usize = 48;
+
+/// The fields of a v5 record as (information element, field length),
+/// with `None` for padding
+const RECORD_FIELDS: [(Option<u16>, u16); 20] = [
+    (Some(8), 4),  // srcaddr: sourceIPv4Address
+    (Some(12), 4), // dstaddr: destinationIPv4Address
+    (Some(15), 4), // nexthop: ipNextHopIPv4Address
+    (Some(10), 2), // input: ingressInterface
+    (Some(14), 2), // output: egressInterface
+    (Some(2), 4),  // dPkts: packetDeltaCount
+    (Some(1), 4),  // dOctets: octetDeltaCount
+    (Some(22), 4), // first: flowStartSysUpTime
+    (Some(21), 4), // last: flowEndSysUpTime
+    (Some(7), 2),  // srcport: sourceTransportPort
+    (Some(11), 2), // dstport: destinationTransportPort
+    (None, 1),     // pad1
+    (Some(6), 1),  // tcp_flags: tcpControlBits
+    (Some(4), 1),  // prot: protocolIdentifier
+    (Some(5), 1),  // tos: ipClassOfService
+    (Some(16), 2), // src_as: bgpSourceAsNumber
+    (Some(17), 2), // dst_as: bgpDestinationAsNumber
+    (Some(9), 1),  // src_mask: sourceIPv4PrefixLength
+    (Some(13), 1), // dst_mask: destinationIPv4PrefixLength
+    (None, 2),     // pad2
+];
+
+/// The synthetic template describing converted v5 records
+pub fn template_fields() -> FieldSpecifiers {
+    RECORD_FIELDS
+        .iter()
+        .filter_map(|&(ie, field_length)| Some(FieldSpecifier::new(None, ie?, field_length)))
+        .collect()
+}
+
+/// A NetFlow v5 export packet, with its records in the IPFIX model
+#[derive(PartialEq, Clone, Debug)]
+pub struct V5Message {
+    /// Milliseconds since the device booted
+    pub sys_uptime: u32,
+    pub unix_secs: u32,
+    pub unix_nsecs: u32,
+    /// Counts flow records, as the Sequence Number does in IPFIX
+    pub flow_sequence: u32,
+    pub engine_type: u8,
+    pub engine_id: u8,
+    /// The sampling mode in the top 2 bits, and the interval in the rest
+    pub sampling_interval: u16,
+    pub records: Vec<DataRecord>,
+}
+
+impl V5Message {
+    /// Parse the packet in `buf`
+    pub fn parse(buf: &[u8], formatter: &Formatter, options: ParseOptions) -> BinResult<Self> {
+        let ipfix = to_ipfix_bytes(buf)?;
+        let templates = std::cell::RefCell::new(ahash::HashMap::default());
+        let message =
+            Message::read_args(&mut Cursor::new(ipfix), (&templates, formatter, options))?;
+        let records = message.iter_data_records().cloned().collect();
+        Ok(Self {
+            sys_uptime: read_u32(buf, 4).ok_or_else(|| truncated(4))?,
+            unix_secs: message.export_time,
+            unix_nsecs: read_u32(buf, 12).ok_or_else(|| truncated(12))?,
+            flow_sequence: message.sequence_number,
+            engine_type: buf[20],
+            engine_id: buf[21],
+            sampling_interval: read_u16(buf, 22).ok_or_else(|| truncated(22))?,
+            records,
+        })
+    }
+
+    /// The Observation Domain ID of converted messages, from the engine
+    /// type and ID
+    pub fn observation_domain_id(&self) -> u32 {
+        u32::from(self.engine_type) << 8 | u32::from(self.engine_id)
+    }
+
+    /// The IPFIX message with the synthetic template and a data set of
+    /// the records
+    pub fn into_ipfix(self) -> Message {
+        let observation_domain_id = self.observation_domain_id();
+        Message {
+            export_time: self.unix_secs,
+            sequence_number: self.flow_sequence,
+            observation_domain_id,
+            sets: [
+                Set {
+                    records: Records::Template(vec![TemplateRecord {
+                        template_id: TEMPLATE_ID,
+                        field_specifiers: template_fields(),
+                    }]),
+                },
+                Set {
+                    records: Records::Data {
+                        set_id: TEMPLATE_ID,
+                        data: self.records,
+                    },
+                },
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+}
+
+/// Convert a v5 packet to the bytes of the equivalent IPFIX message,
+/// with the synthetic template followed by the records
+pub fn to_ipfix_bytes(buf: &[u8]) -> BinResult<Vec<u8>> {
+    let version = read_u16(buf, 0).ok_or_else(|| truncated(0))?;
+    if version != VERSION {
+        return Err(binrw::Error::BadMagic {
+            pos: 0,
+            found: Box::new(version),
+        });
+    }
+    let count = usize::from(read_u16(buf, 2).ok_or_else(|| truncated(2))?);
+    let header = buf
+        .get(..HEADER_LENGTH)
+        .ok_or_else(|| truncated(buf.len()))?;
+    let records = buf
+        .get(HEADER_LENGTH..HEADER_LENGTH + count * RECORD_LENGTH)
+        .ok_or_else(|| truncated(buf.len()))?;
+
+    let fields = template_fields();
+    let mut out = Vec::with_capacity(buf.len() + fields.len() * 4 + 16);
+    out.extend(10u16.to_be_bytes());
+    out.extend([0, 0]);
+    // unix_secs and flow_sequence
+    out.extend(&header[8..12]);
+    out.extend(&header[16..20]);
+    out.extend([0, 0, header[20], header[21]]);
+
+    out.extend(TEMPLATE_SET_ID.to_be_bytes());
+    out.extend((8 + fields.len() as u16 * 4).to_be_bytes());
+    out.extend(TEMPLATE_ID.to_be_bytes());
+    out.extend((fields.len() as u16).to_be_bytes());
+    for field in &fields {
+        out.extend(field.information_element_identifier.to_be_bytes());
+        out.extend(field.field_length.to_be_bytes());
+    }
+
+    if count > 0 {
+        let start = out.len();
+        out.extend(TEMPLATE_ID.to_be_bytes());
+        out.extend([0, 0]);
+        for record in records.chunks_exact(RECORD_LENGTH) {
+            let mut offset = 0;
+            for &(ie, field_length) in &RECORD_FIELDS {
+                let end = offset + usize::from(field_length);
+                if ie.is_some() {
+                    out.extend(&record[offset..end]);
+                }
+                offset = end;
+            }
+        }
+        let length = (out.len() - start) as u16;
+        out[start + 2..start + 4].copy_from_slice(&length.to_be_bytes());
+    }
+
+    let length = u16::try_from(out.len()).map_err(|_| {
+        IpfixError::LengthOverflow {
+            length: out.len() as u64,
+        }
+        .into_binrw_error(0)
+    })?;
+    out[2..4].copy_from_slice(&length.to_be_bytes());
+    Ok(out)
+}
@@ -0,0 +1,360 @@
+//! NetFlow version 9, converted to and from IPFIX byte by byte. FlowSets
+//! 0 and 1 become template sets 2 and 3, and options scope types become
+//! the matching information elements. v9 has no enterprise bit, so field
+//! types from 32768 up become enterprise-specific elements of
+//! `VENDOR_ENTERPRISE_NUMBER`. Data FlowSets are the same in both, so
+//! templates are shared with IPFIX in any `TemplateStorage`
+//! <https://www.rfc-editor.org/rfc/rfc3954>
+
+use std::io::Cursor;
+
+use binrw::{BinRead, BinResult};
+
+use crate::borrowed::{read_u16, read_u32, split_message, split_sets, truncated};
+use crate::information_elements::Formatter;
+use crate::parser::{
+    IpfixError, Message, ParseOptions, Records, Sets, WriteOptions, OPTIONS_TEMPLATE_SET_ID,
+    TEMPLATE_SET_ID,
+};
+use crate::template_store::TemplateStorage;
+
+pub const VERSION: u16 = 9;
+pub const TEMPLATE_FLOWSET_ID: u16 = 0;
+pub const OPTIONS_TEMPLATE_FLOWSET_ID: u16 = 1;
+
+/// The enterprise number given to v9 field types from 32768 up, which
+/// vendors use for their own fields
+pub const VENDOR_ENTERPRISE_NUMBER: u32 = 0xFFFF_FFFE;
+
+const HEADER_LENGTH: usize = 20;
+
+/// Options scope field types (System, Interface, Line Card, Cache and
+/// Template), with the information elements they become
+const SCOPE_TYPES: [(u16, u16); 5] = [(1, 144), (2, 10), (3, 141), (4, 143), (5, 145)];
+
+fn invalid(pos: usize, message: String) -> binrw::Error {
+    binrw::Error::AssertFail {
+        pos: pos as u64,
+        message,
+    }
+}
+
+/// A NetFlow v9 export packet, with its FlowSets in the IPFIX model
+#[derive(PartialEq, Clone, Debug)]
+pub struct V9Message {
+    /// Milliseconds since the device booted
+    pub sys_uptime: u32,
+    pub unix_secs: u32,
+    /// Counts export packets, rather than data records as in IPFIX
+    pub sequence_number: u32,
+    pub source_id: u32,
+    pub sets: Sets,
+}
+
+impl V9Message {
+    /// Parse the packet in `buf`. Templates it defines are added to
+    /// `templates`, and data FlowSets must have a known template
+    pub fn parse(
+        buf: &[u8],
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+        options: ParseOptions,
+    ) -> BinResult<Self> {
+        let sys_uptime = read_u32(buf, 4).ok_or_else(|| truncated(4))?;
+        let ipfix = to_ipfix_bytes(buf)?;
+        let message = Message::read_args(&mut Cursor::new(ipfix), (templates, formatter, options))?;
+        Ok(Self::from_ipfix(message, sys_uptime))
+    }
+
+    /// Encode as a v9 packet. Template withdrawals are left out, as v9
+    /// has none
+    pub fn to_bytes(
+        &self,
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+    ) -> BinResult<Vec<u8>> {
+        let data_records = self
+            .sets
+            .iter()
+            .map(|set| match &set.records {
+                Records::Data { data, .. } => data.len(),
+                _ => 0,
+            })
+            .sum::<usize>();
+        let ipfix =
+            self.clone()
+                .into_ipfix()
+                .to_bytes(templates, formatter, WriteOptions::default())?;
+        from_ipfix_bytes(&ipfix, self.sys_uptime, data_records)
+    }
+
+    /// The IPFIX message with the same sets, with `unix_secs` as the
+    /// export time and `source_id` as the Observation Domain ID
+    pub fn into_ipfix(self) -> Message {
+        Message {
+            export_time: self.unix_secs,
+            sequence_number: self.sequence_number,
+            observation_domain_id: self.source_id,
+            sets: self.sets,
+        }
+    }
+
+    /// The v9 packet with the same sets as `message`
+    pub fn from_ipfix(message: Message, sys_uptime: u32) -> Self {
+        Self {
+            sys_uptime,
+            unix_secs: message.export_time,
+            sequence_number: message.sequence_number,
+            source_id: message.observation_domain_id,
+            sets: message.sets,
+        }
+    }
+}
+
+/// Append a field specifier, moving the top bit of `field_type` to an
+/// enterprise number
+fn push_field(out: &mut Vec<u8>, field_type: u16, field_length: u16) {
+    out.extend(field_type.to_be_bytes());
+    out.extend(field_length.to_be_bytes());
+    if field_type & 0x8000 != 0 {
+        out.extend(VENDOR_ENTERPRISE_NUMBER.to_be_bytes());
+    }
+}
+
+/// Set the 16 bit length at `at` to the length of `out` from `at`
+fn patch_length(out: &mut [u8], at: usize, length_offset: usize) -> BinResult<()> {
+    let length = out.len() - at;
+    let length = u16::try_from(length).map_err(|_| {
+        IpfixError::LengthOverflow {
+            length: length as u64,
+        }
+        .into_binrw_error(at as u64)
+    })?;
+    out[at + length_offset..at + length_offset + 2].copy_from_slice(&length.to_be_bytes());
+    Ok(())
+}
+
+/// Convert a v9 packet to the bytes of the equivalent IPFIX message
+pub fn to_ipfix_bytes(buf: &[u8]) -> BinResult<Vec<u8>> {
+    let version = read_u16(buf, 0).ok_or_else(|| truncated(0))?;
+    if version != VERSION {
+        return Err(binrw::Error::BadMagic {
+            pos: 0,
+            found: Box::new(version),
+        });
+    }
+    let header = buf
+        .get(..HEADER_LENGTH)
+        .ok_or_else(|| truncated(buf.len()))?;
+
+    let mut out = Vec::with_capacity(buf.len());
+    out.extend(10u16.to_be_bytes());
+    out.extend([0, 0]);
+    // unix_secs, sequence number and source ID
+    out.extend(&header[8..20]);
+
+    let mut offset = HEADER_LENGTH;
+    // anything shorter than a FlowSet header is padding
+    while buf.len() - offset >= 4 {
+        let flowset_id = read_u16(buf, offset).ok_or_else(|| truncated(offset))?;
+        let length = usize::from(read_u16(buf, offset + 2).ok_or_else(|| truncated(offset))?);
+        if length <= 4 {
+            return Err(invalid(
+                offset,
+                format!("invalid FlowSet length: [{length} <= 4]"),
+            ));
+        }
+        let flowset = buf
+            .get(offset..offset + length)
+            .ok_or_else(|| truncated(offset))?;
+        match flowset_id {
+            TEMPLATE_FLOWSET_ID => convert_templates(&flowset[4..], offset + 4, &mut out)?,
+            OPTIONS_TEMPLATE_FLOWSET_ID => {
+                convert_options_templates(&flowset[4..], offset + 4, &mut out)?
+            }
+            256.. => out.extend(flowset),
+            _ => {
+                return Err(invalid(
+                    offset,
+                    format!("FlowSet IDs 2-255 are reserved [flowset_id: {flowset_id}]"),
+                ))
+            }
+        }
+        offset += length;
+    }
+    patch_length(&mut out, 0, 2)?;
+    Ok(out)
+}
+
+/// Convert the records of a template FlowSet to a template set
+fn convert_templates(body: &[u8], pos: usize, out: &mut Vec<u8>) -> BinResult<()> {
+    let start = out.len();
+    out.extend(TEMPLATE_SET_ID.to_be_bytes());
+    out.extend([0, 0]);
+    let mut offset = 0;
+    while let (Some(template_id @ 256..), Some(field_count)) =
+        (read_u16(body, offset), read_u16(body, offset + 2))
+    {
+        let fields_length = usize::from(field_count) * 4;
+        let fields = body
+            .get(offset + 4..offset + 4 + fields_length)
+            .ok_or_else(|| truncated(pos + offset))?;
+        out.extend(template_id.to_be_bytes());
+        out.extend(field_count.to_be_bytes());
+        for field in fields.chunks_exact(4) {
+            push_field(
+                out,
+                u16::from_be_bytes([field[0], field[1]]),
+                u16::from_be_bytes([field[2], field[3]]),
+            );
+        }
+        offset += 4 + fields_length;
+    }
+    patch_length(out, start, 2)
+}
+
+/// Convert the records of an options template FlowSet to an options
+/// template set
+fn convert_options_templates(body: &[u8], pos: usize, out: &mut Vec<u8>) -> BinResult<()> {
+    let start = out.len();
+    out.extend(OPTIONS_TEMPLATE_SET_ID.to_be_bytes());
+    out.extend([0, 0]);
+    let mut offset = 0;
+    while let (Some(template_id @ 256..), Some(scope_length), Some(option_length)) = (
+        read_u16(body, offset),
+        read_u16(body, offset + 2),
+        read_u16(body, offset + 4),
+    ) {
+        let scope_length = usize::from(scope_length);
+        let fields_length = scope_length + usize::from(option_length);
+        let fields = body
+            .get(offset + 6..offset + 6 + fields_length)
+            .ok_or_else(|| truncated(pos + offset))?;
+        let scope_field_count = scope_length / 4;
+        let field_count = fields_length / 4;
+        out.extend(template_id.to_be_bytes());
+        out.extend((field_count as u16).to_be_bytes());
+        out.extend((scope_field_count as u16).to_be_bytes());
+        for (i, field) in fields.chunks_exact(4).enumerate() {
+            let mut field_type = u16::from_be_bytes([field[0], field[1]]);
+            let field_length = u16::from_be_bytes([field[2], field[3]]);
+            if i < scope_field_count {
+                let scope = SCOPE_TYPES.iter().find(|(scope, _)| *scope == field_type);
+                field_type = scope.map_or(field_type, |&(_, ie)| ie);
+            }
+            push_field(out, field_type, field_length);
+        }
+        offset += 6 + fields_length;
+    }
+    patch_length(out, start, 2)
+}
+
+/// Read the field specifier at `offset` of a template record, returning
+/// its v9 field type and length, and the offset after it
+fn read_field(set: &[u8], offset: usize) -> BinResult<(u16, u16, usize)> {
+    let ie = read_u16(set, offset).ok_or_else(|| truncated(offset))?;
+    let field_length = read_u16(set, offset + 2).ok_or_else(|| truncated(offset))?;
+    if ie & 0x8000 == 0 {
+        return Ok((ie, field_length, offset + 4));
+    }
+    let enterprise_number = read_u32(set, offset + 4).ok_or_else(|| truncated(offset))?;
+    if enterprise_number != VENDOR_ENTERPRISE_NUMBER {
+        return Err(invalid(
+            offset,
+            format!("enterprise-specific fields can't be sent in NetFlow v9 [enterprise_number: {enterprise_number}]"),
+        ));
+    }
+    Ok((ie, field_length, offset + 8))
+}
+
+/// Convert the bytes of an IPFIX message to a v9 packet with
+/// `data_records` data records. Template withdrawals are left out
+fn from_ipfix_bytes(message: &[u8], sys_uptime: u32, data_records: usize) -> BinResult<Vec<u8>> {
+    let (message, header) = split_message(message)?;
+    let mut out = Vec::with_capacity(message.len() + 4);
+    out.extend(VERSION.to_be_bytes());
+    out.extend([0, 0]);
+    out.extend(sys_uptime.to_be_bytes());
+    out.extend(header.export_time.to_be_bytes());
+    out.extend(header.sequence_number.to_be_bytes());
+    out.extend(header.observation_domain_id.to_be_bytes());
+
+    let mut count = data_records;
+    split_sets(message, |set_id, _, set| {
+        let start = out.len();
+        let mut offset = 4;
+        match set_id {
+            TEMPLATE_SET_ID => {
+                out.extend(TEMPLATE_FLOWSET_ID.to_be_bytes());
+                out.extend([0, 0]);
+                while let (Some(template_id), Some(field_count)) =
+                    (read_u16(set, offset), read_u16(set, offset + 2))
+                {
+                    offset += 4;
+                    if field_count == 0 {
+                        continue;
+                    }
+                    out.extend(template_id.to_be_bytes());
+                    out.extend(field_count.to_be_bytes());
+                    for _ in 0..field_count {
+                        let (field_type, field_length, next) = read_field(set, offset)?;
+                        out.extend(field_type.to_be_bytes());
+                        out.extend(field_length.to_be_bytes());
+                        offset = next;
+                    }
+                    count += 1;
+                }
+            }
+            OPTIONS_TEMPLATE_SET_ID => {
+                out.extend(OPTIONS_TEMPLATE_FLOWSET_ID.to_be_bytes());
+                out.extend([0, 0]);
+                while let (Some(template_id), Some(field_count)) =
+                    (read_u16(set, offset), read_u16(set, offset + 2))
+                {
+                    offset += 4;
+                    if field_count == 0 {
+                        continue;
+                    }
+                    let scope_field_count =
+                        read_u16(set, offset).ok_or_else(|| truncated(offset))?;
+                    offset += 2;
+                    out.extend(template_id.to_be_bytes());
+                    out.extend((scope_field_count * 4).to_be_bytes());
+                    out.extend(((field_count - scope_field_count) * 4).to_be_bytes());
+                    for i in 0..field_count {
+                        let (mut field_type, field_length, next) = read_field(set, offset)?;
+                        if i < scope_field_count {
+                            let scope = SCOPE_TYPES.iter().find(|(_, ie)| *ie == field_type);
+                            field_type = scope.map_or(field_type, |&(scope, _)| scope);
+                        }
+                        out.extend(field_type.to_be_bytes());
+                        out.extend(field_length.to_be_bytes());
+                        offset = next;
+                    }
+                    count += 1;
+                }
+                // pad to a multiple of 4 bytes
+                out.resize(out.len().next_multiple_of(4), 0);
+            }
+            _ => {
+                out.extend(set);
+                return Ok(());
+            }
+        }
+        // drop FlowSets left empty by withdrawals
+        if out.len() == start + 4 {
+            out.truncate(start);
+            return Ok(());
+        }
+        patch_length(&mut out, start, 2)
+    })?;
+
+    let count = u16::try_from(count).map_err(|_| {
+        invalid(
+            0,
+            format!("too many records for a v9 packet: [{count} > 65535]"),
+        )
+    })?;
+    out[2..4].copy_from_slice(&count.to_be_bytes());
+    Ok(out)
+}
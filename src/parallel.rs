@@ -0,0 +1,90 @@
+//! Decoding data sets on rayon's thread pool, for processing many
+//! messages offline, such as from a file or packet capture. Template
+//! sets are still read in order on the calling thread, since later sets
+//! depend on them, but data sets are independent once their template is
+//! known
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use binrw::{BinRead, BinResult};
+use rayon::prelude::*;
+
+use crate::borrowed::{BorrowedDataSet, BorrowedMessage, BorrowedSet};
+use crate::information_elements::Formatter;
+use crate::parser::{Message, ParseOptions, Records, Set};
+use crate::template_store::TemplateStorage;
+
+/// Parse the message at the start of each of `bufs`, in order, decoding
+/// their data sets in parallel. Templates are added to `templates` as
+/// they are read, and data sets must have a known template
+pub fn parse_ipfix_messages<B: AsRef<[u8]> + Sync>(
+    bufs: &[B],
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+    options: ParseOptions,
+) -> BinResult<Vec<Message>> {
+    let messages = bufs
+        .iter()
+        .map(|buf| BorrowedMessage::parse(buf.as_ref(), templates, formatter))
+        .collect::<BinResult<Vec<_>>>()?;
+    messages
+        .into_par_iter()
+        .map(|message| decode_message(message, formatter, options))
+        .collect()
+}
+
+/// Parse the message at the start of `buf`, decoding its data sets in
+/// parallel
+pub fn parse_ipfix_message(
+    buf: &[u8],
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+    options: ParseOptions,
+) -> BinResult<Message> {
+    let message = BorrowedMessage::parse(buf, templates, formatter)?;
+    decode_message(message, formatter, options)
+}
+
+fn decode_message(
+    message: BorrowedMessage<'_>,
+    formatter: &Formatter,
+    options: ParseOptions,
+) -> BinResult<Message> {
+    Ok(Message {
+        export_time: message.export_time,
+        sequence_number: message.sequence_number,
+        observation_domain_id: message.observation_domain_id,
+        sets: message
+            .sets
+            .into_par_iter()
+            .map(|set| match set {
+                BorrowedSet::Decoded(set) => Ok(set),
+                BorrowedSet::Data(data_set) => decode_data_set(data_set, formatter, options),
+            })
+            .collect::<BinResult<Vec<_>>>()?
+            .into_iter()
+            .collect(),
+    })
+}
+
+fn decode_data_set(
+    data_set: BorrowedDataSet<'_>,
+    formatter: &Formatter,
+    options: ParseOptions,
+) -> BinResult<Set> {
+    // a store of just this set's template, as it was when the set was read
+    let templates = RefCell::new(HashMap::from([(data_set.set_id, data_set.template)]));
+    let records = Records::read_args(
+        &mut Cursor::new(data_set.bytes),
+        (
+            data_set.set_id,
+            data_set.bytes.len() as u16,
+            &templates as &dyn TemplateStorage,
+            formatter,
+            options,
+        ),
+    )?;
+    Ok(Set { records })
+}
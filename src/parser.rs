@@ -1,20 +1,24 @@
 //! IPFIX reader/writer
 
 use std::{
+    hash::{Hash, Hasher},
+    io::Cursor,
     net::{Ipv4Addr, Ipv6Addr},
-    rc::Rc,
+    sync::Arc,
 };
 
-use ahash::{HashMap, HashMapExt};
 use binrw::{
-    binrw, binwrite, count,
-    io::{Read, Seek, Write},
-    until_eof, BinRead, BinReaderExt, BinResult, BinWrite, BinWriterExt, Endian,
+    binread, binrw, binwrite, count,
+    io::{Read, Seek, SeekFrom, Write},
+    BinRead, BinReaderExt, BinResult, BinWrite, BinWriterExt, Endian,
 };
 
-use crate::information_elements::Formatter;
-use crate::template_store::{Template, TemplateStore};
-use crate::util::{stream_position, until_limit, write_position_at};
+use crate::information_elements::{Formatter, FormatterExt};
+use crate::plan::FieldDecoder;
+use crate::template_store::{
+    ExpandedFieldSpecifier, ScopedTemplateStore, Template, TemplateStorage,
+};
+use crate::util::{stream_position, until_limit, until_padding, write_length_at, RelativeStream};
 
 #[derive(derive_more::Display, Debug)]
 pub enum IpfixError {
@@ -24,6 +28,45 @@ pub enum IpfixError {
     MissingData(DataRecordKey),
     #[display(fmt = "Invalid Length for Field Spec: {ty:?}, {length}")]
     InvalidFieldSpecLength { ty: DataRecordType, length: u16 },
+    #[display(fmt = "Template Redefined: {_0}")]
+    TemplateRedefinition(u16),
+    #[display(fmt = "Invalid Boolean: {_0}")]
+    InvalidBoolean(u8),
+    /// An Options Template with no scope fields, with
+    /// `ParseOptions::strict_scope_field_count`
+    #[display(fmt = "Options Template has no Scope Fields: {_0}")]
+    MissingScopeFields(u16),
+    #[display(fmt = "Field not in Template: {_0:?}")]
+    UnknownField(DataRecordKey),
+    #[display(fmt = "Value for {key:?} is not of type {ty:?}: {value:?}")]
+    InvalidValueType {
+        key: DataRecordKey,
+        value: DataRecordValue,
+        ty: DataRecordType,
+    },
+    #[display(fmt = "Value for {key:?} does not fit Field Length {length}: {value:?}")]
+    InvalidValueLength {
+        key: DataRecordKey,
+        value: DataRecordValue,
+        length: u16,
+    },
+    #[display(fmt = "Record of {size} bytes does not fit a message of {max_size} bytes")]
+    RecordTooLarge { size: usize, max_size: usize },
+    /// A message or set was too long for its 16 bit length field, so
+    /// should be split into smaller messages
+    #[display(fmt = "Length {length} exceeds the maximum of 65535 bytes")]
+    LengthOverflow { length: u64 },
+    /// All Template IDs from 256 to 65535 are in use
+    #[display(fmt = "No Template IDs left")]
+    TemplateIdsExhausted,
+    #[display(fmt = "IO Error: {_0}")]
+    Io(binrw::io::Error),
+}
+
+impl From<binrw::io::Error> for IpfixError {
+    fn from(e: binrw::io::Error) -> Self {
+        IpfixError::Io(e)
+    }
 }
 
 impl std::error::Error for IpfixError {}
@@ -37,32 +80,257 @@ impl IpfixError {
     }
 }
 
+/// A set that could not be decoded by `Message::read_lenient`
+#[derive(derive_more::Display, Debug)]
+#[display(fmt = "Set {index} at offset {offset}: {error}")]
+pub struct SetError {
+    /// Index of the set in the message, counting sets that failed
+    pub index: usize,
+    /// Position of the start of the set in the reader
+    pub offset: u64,
+    pub error: binrw::Error,
+}
+
+impl std::error::Error for SetError {}
+
+/// Options controlling how data records are decoded
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct ParseOptions {
+    /// Reject booleans other than 1 (true) and 2 (false), instead of
+    /// reading them as false
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-6.1.5>
+    pub strict_booleans: bool,
+    /// Read fields whose length doesn't fit their type (such as a 6 byte
+    /// sourceIPv4Address) as `Bytes` of the declared length, instead of
+    /// failing the whole message
+    pub lenient_field_lengths: bool,
+    /// Read sets with reserved Set IDs (0-1 and 4-255) as
+    /// `Records::Unsupported`, instead of failing the whole message
+    pub skip_reserved_sets: bool,
+    /// Read data sets with a missing template as `Records::RawData`,
+    /// instead of failing the whole message or buffering them in the
+    /// template store
+    pub keep_raw_sets: bool,
+    /// Reject Options Templates with a scope field count of 0, instead of
+    /// accepting them as some exporters send them
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.4.2.2>
+    pub strict_scope_field_count: bool,
+}
+
+/// Options controlling how messages are encoded
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct WriteOptions {
+    /// Pad each set to a multiple of this many bytes. 0 or 1 means no
+    /// padding
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.3.1>
+    pub alignment: u8,
+    /// Also pad Template and Options Template Sets to `alignment`, rather
+    /// than only Data Sets
+    pub pad_template_sets: bool,
+    /// Pad the last set so the whole message is a multiple of this many
+    /// bytes. 0 or 1 means no padding
+    pub message_alignment: u8,
+    /// Always use the 3 byte form (255 followed by a u16) for variable
+    /// length fields, even for values shorter than 255 bytes
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-7>
+    pub long_variable_length: bool,
+}
+
+impl WriteOptions {
+    /// Default options, padding each set to a multiple of `alignment` bytes
+    pub fn aligned(alignment: u8) -> Self {
+        Self {
+            alignment,
+            ..Default::default()
+        }
+    }
+
+    /// The alignment to pad a set with ID `set_id` to
+    pub(crate) fn set_alignment(&self, set_id: u16) -> u8 {
+        match set_id {
+            TEMPLATE_SET_ID | OPTIONS_TEMPLATE_SET_ID if !self.pad_template_sets => 1,
+            _ => self.alignment.max(1),
+        }
+    }
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            alignment: 1,
+            pad_template_sets: true,
+            message_alignment: 1,
+            long_variable_length: false,
+        }
+    }
+}
+
+/// Set ID of Template Sets, also used as the Template ID to withdraw all Templates
+pub const TEMPLATE_SET_ID: u16 = 2;
+/// Set ID of Options Template Sets
+pub const OPTIONS_TEMPLATE_SET_ID: u16 = 3;
+
+/// The sets of a `Message`
+#[cfg(not(feature = "smallvec"))]
+pub type Sets = Vec<Set>;
+/// The sets of a `Message`, stored inline for messages of up to 4 sets
+#[cfg(feature = "smallvec")]
+pub type Sets = smallvec::SmallVec<[Set; 4]>;
+
+/// The field specifiers of a template record
+#[cfg(not(feature = "smallvec"))]
+pub type FieldSpecifiers = Vec<FieldSpecifier>;
+/// The field specifiers of a template record, stored inline for templates
+/// of up to 32 fields
+#[cfg(feature = "smallvec")]
+pub type FieldSpecifiers = smallvec::SmallVec<[FieldSpecifier; 32]>;
+
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.1>
-#[binrw]
-#[brw(big, magic = 10u16)]
-#[br(import( templates: TemplateStore, formatter: Rc<Formatter>))]
-#[bw(import( templates: TemplateStore, formatter: Rc<Formatter>, alignment: u8))]
-#[bw(stream = s)]
+#[binread]
+#[br(big, magic = 10u16)]
+#[br(import( templates: &dyn TemplateStorage, formatter: &Formatter, options: ParseOptions))]
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Message {
     #[br(temp)]
-    // store offset for later updating
-    #[bw(try_calc = stream_position(s))]
     length: u16,
     pub export_time: u32,
     pub sequence_number: u32,
     pub observation_domain_id: u32,
-    #[br(parse_with = until_eof)]
-    #[br(args(templates, formatter))]
-    #[bw(args(templates, formatter, alignment))]
-    pub sets: Vec<Set>,
-    // jump back to length and set by current position
-    #[br(temp)]
-    #[bw(restore_position, try_calc = write_position_at(s, length, 0))]
-    _temp: (),
+    #[br(parse_with = read_sets)]
+    #[br(args(templates, formatter, options, export_time))]
+    pub sets: Sets,
+}
+
+/// Written by hand rather than derived, to pad the last set so the
+/// message is a multiple of `options.message_alignment` bytes
+impl BinWrite for Message {
+    type Args<'a> = (&'a dyn TemplateStorage, &'a Formatter, WriteOptions);
+
+    fn write_options<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        _: Endian,
+        (templates, formatter, options): Self::Args<'_>,
+    ) -> BinResult<()> {
+        let endian = Endian::Big;
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "write_message",
+            offset = writer.stream_position()?,
+            observation_domain_id = self.observation_domain_id,
+            sequence_number = self.sequence_number,
+        )
+        .entered();
+        let writer = &mut RelativeStream::new(writer);
+        // the length is filled in at the end
+        writer.write_type(&10u16, endian)?;
+        writer.write_type(&0u16, endian)?;
+        writer.write_type(&self.export_time, endian)?;
+        writer.write_type(&self.sequence_number, endian)?;
+        writer.write_type(&self.observation_domain_id, endian)?;
+
+        let mut last_set_start = None;
+        for set in &self.sets {
+            last_set_start = Some(writer.stream_position()?);
+            #[cfg(feature = "tracing")]
+            tracing::trace!(
+                offset = last_set_start,
+                set_id = set.records.set_id(),
+                "write set"
+            );
+            set.write_options(writer, endian, (templates, formatter, options))?;
+        }
+
+        let alignment = u64::from(options.message_alignment.max(1));
+        let end = writer.stream_position()?;
+        let padding = (alignment - end % alignment) % alignment;
+        if let Some(last_set_start) = last_set_start.filter(|_| padding > 0) {
+            writer.write_all(&vec![0; padding as usize])?;
+            let set_length = end + padding - last_set_start;
+            let set_length = u16::try_from(set_length).map_err(|_| {
+                IpfixError::LengthOverflow { length: set_length }.into_binrw_error(last_set_start)
+            })?;
+            writer.seek(SeekFrom::Start(last_set_start + 2))?;
+            writer.write_type(&set_length, endian)?;
+        }
+
+        write_length_at(writer, 2).map_err(|e| e.into_binrw_error(0))?;
+        writer.seek(SeekFrom::Start(end + padding))?;
+        Ok(())
+    }
+}
+
+impl binrw::meta::WriteEndian for Message {
+    const ENDIAN: binrw::meta::EndianKind = binrw::meta::EndianKind::Endian(Endian::Big);
 }
 
 impl Message {
+    /// Read a message using the templates scoped to `peer` and the
+    /// message's Observation Domain ID
+    pub fn read_scoped<R, P, S>(
+        reader: &mut R,
+        templates: &ScopedTemplateStore<P, S>,
+        peer: P,
+        formatter: &Formatter,
+        options: ParseOptions,
+    ) -> BinResult<Self>
+    where
+        R: Read + Seek,
+        P: std::hash::Hash + Eq,
+        S: TemplateStorage + 'static,
+    {
+        // peek at the observation domain id, after version, length,
+        // export time and sequence number
+        let start = reader.stream_position()?;
+        reader.seek(SeekFrom::Current(12))?;
+        let observation_domain_id: u32 = reader.read_be()?;
+        reader.seek(SeekFrom::Start(start))?;
+
+        let scope = templates.scope(peer, observation_domain_id);
+        Self::read_args(reader, (&*scope, formatter, options))
+    }
+
+    /// Read a message, skipping any sets that fail to decode and
+    /// returning their errors alongside the sets that did. Only a bad
+    /// message header, or a set length that makes the rest of the
+    /// message unreadable, stops reading
+    pub fn read_lenient<R: Read + Seek>(
+        reader: &mut R,
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+        options: ParseOptions,
+    ) -> BinResult<(Self, Vec<SetError>)> {
+        let endian = Endian::Big;
+        let pos = reader.stream_position()?;
+        let version: u16 = reader.read_type(endian)?;
+        if version != 10 {
+            return Err(binrw::Error::BadMagic {
+                pos,
+                found: Box::new(version),
+            });
+        }
+        let _length: u16 = reader.read_type(endian)?;
+        let export_time = reader.read_type(endian)?;
+        let sequence_number = reader.read_type(endian)?;
+        let observation_domain_id = reader.read_type(endian)?;
+
+        let mut errors = vec![];
+        let sets = read_sets_collecting(
+            reader,
+            endian,
+            (templates, formatter, options, export_time),
+            Some(&mut errors),
+        )?;
+        let message = Self {
+            export_time,
+            sequence_number,
+            observation_domain_id,
+            sets,
+        };
+        Ok((message, errors))
+    }
+
     pub fn iter_template_records(&self) -> impl Iterator<Item = &TemplateRecord> {
         self.sets
             .iter()
@@ -92,13 +360,218 @@ impl Message {
             })
             .flatten()
     }
+
+    /// Encode the message, adding any templates it defines to
+    /// `templates`
+    pub fn to_bytes(
+        &self,
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+        options: WriteOptions,
+    ) -> BinResult<Vec<u8>> {
+        let mut buf = vec![];
+        self.write_into(&mut buf, templates, formatter, options)?;
+        Ok(buf)
+    }
+
+    /// Like `to_bytes`, but replacing the contents of `buf`, to reuse its
+    /// allocation
+    pub fn write_into(
+        &self,
+        buf: &mut Vec<u8>,
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+        options: WriteOptions,
+    ) -> BinResult<()> {
+        buf.clear();
+        self.write_args(&mut Cursor::new(buf), (templates, formatter, options))
+    }
+
+    /// Data records, with the ID of the template each was decoded with
+    pub fn iter_data_records_with_template(&self) -> impl Iterator<Item = (u16, &DataRecord)> {
+        self.sets
+            .iter()
+            .filter_map(|set| match &set.records {
+                Records::Data { set_id, data } => Some(data.iter().map(|record| (*set_id, record))),
+                _ => None,
+            })
+            .flatten()
+    }
+
+    pub fn into_template_records(self) -> impl Iterator<Item = TemplateRecord> {
+        self.sets
+            .into_iter()
+            .filter_map(|set| match set.records {
+                Records::Template(templates) => Some(templates),
+                _ => None,
+            })
+            .flatten()
+    }
+
+    pub fn into_options_template_records(self) -> impl Iterator<Item = OptionsTemplateRecord> {
+        self.sets
+            .into_iter()
+            .filter_map(|set| match set.records {
+                Records::OptionsTemplate(templates) => Some(templates),
+                _ => None,
+            })
+            .flatten()
+    }
+
+    pub fn into_data_records(self) -> impl Iterator<Item = DataRecord> {
+        self.into_data_records_with_template()
+            .map(|(_, record)| record)
+    }
+
+    /// Data records, with the ID of the template each was decoded with
+    pub fn into_data_records_with_template(self) -> impl Iterator<Item = (u16, DataRecord)> {
+        self.sets
+            .into_iter()
+            .filter_map(|set| match set.records {
+                Records::Data { set_id, data } => {
+                    Some(data.into_iter().map(move |record| (set_id, record)))
+                }
+                _ => None,
+            })
+            .flatten()
+    }
+}
+
+/// Read sets until the end of the input. Data sets with a missing
+/// template are given to the template store to buffer, if it supports
+/// that, and buffered sets are decoded and added after the set
+/// containing their template. Usage of each template is reported to
+/// the template store
+fn read_sets<R: Read + Seek>(
+    reader: &mut R,
+    endian: Endian,
+    args: (&dyn TemplateStorage, &Formatter, ParseOptions, u32),
+) -> BinResult<Sets> {
+    read_sets_collecting(reader, endian, args, None)
+}
+
+/// Like `read_sets`, but if `errors` is given, sets that fail to decode
+/// are skipped and their errors added to it
+fn read_sets_collecting<R: Read + Seek>(
+    reader: &mut R,
+    endian: Endian,
+    (templates, formatter, options, export_time): (
+        &dyn TemplateStorage,
+        &Formatter,
+        ParseOptions,
+        u32,
+    ),
+    mut errors: Option<&mut Vec<SetError>>,
+) -> BinResult<Sets> {
+    // the header has already been read
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!(
+        "read_message",
+        offset = reader.stream_position()?.saturating_sub(16),
+        export_time,
+    )
+    .entered();
+    let mut sets = Sets::new();
+    for index in 0.. {
+        let start = reader.stream_position()?;
+        let set = match Set::read_options(reader, endian, (templates, formatter, options)) {
+            Ok(set) => set,
+            Err(err) if err.is_eof() => break,
+            Err(err) => {
+                reader.seek(SeekFrom::Start(start))?;
+                if buffer_set(reader, endian, templates)? {
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(
+                        offset = start,
+                        "buffered a data set until its template arrives"
+                    );
+                    continue;
+                }
+                let Some(errors) = errors.as_deref_mut() else {
+                    return Err(err);
+                };
+                #[cfg(feature = "tracing")]
+                tracing::warn!(offset = start, index, error = %err, "skipped a set that failed to decode");
+                errors.push(SetError {
+                    index,
+                    offset: start,
+                    error: err,
+                });
+                match skip_set(reader, endian, start)? {
+                    true => continue,
+                    false => break,
+                }
+            }
+        };
+
+        #[cfg(feature = "tracing")]
+        tracing::trace!(offset = start, set_id = set.records.set_id(), "read set");
+        let template_ids: Vec<u16> = match &set.records {
+            Records::Template(records) => records.iter().map(|t| t.template_id).collect(),
+            Records::OptionsTemplate(records) => records.iter().map(|t| t.template_id).collect(),
+            Records::Data { set_id, data } => {
+                let bytes = reader.stream_position()? - start;
+                templates.record_usage(*set_id, data.len(), bytes, export_time);
+                vec![]
+            }
+            Records::RawData { .. } | Records::Unsupported { .. } => vec![],
+        };
+        sets.push(set);
+
+        for template_id in template_ids {
+            for bytes in templates.take_buffered_sets(template_id) {
+                let length = u16::try_from(bytes.len()).unwrap_or(u16::MAX);
+                let records = Records::read_options(
+                    &mut Cursor::new(bytes),
+                    endian,
+                    (template_id, length, templates, formatter, options),
+                )?;
+                if let Records::Data { data, .. } = &records {
+                    // include the set header, as for unbuffered sets
+                    let bytes = u64::from(length) + 4;
+                    templates.record_usage(template_id, data.len(), bytes, export_time);
+                }
+                sets.push(Set { records });
+            }
+        }
+    }
+    Ok(sets)
+}
+
+/// Seek past the set starting at `start`, using its length. Returns
+/// false if the length is too short to find the next set
+fn skip_set<R: Read + Seek>(reader: &mut R, endian: Endian, start: u64) -> BinResult<bool> {
+    reader.seek(SeekFrom::Start(start))?;
+    let (_set_id, length): (u16, u16) = reader.read_type(endian)?;
+    if length < 4 {
+        return Ok(false);
+    }
+    reader.seek(SeekFrom::Start(start + u64::from(length)))?;
+    Ok(true)
+}
+
+/// If the set at the current position is a data set with a missing
+/// template, try to buffer it in the template store, skipping past it
+/// if successful
+fn buffer_set<R: Read + Seek>(
+    reader: &mut R,
+    endian: Endian,
+    templates: &dyn TemplateStorage,
+) -> BinResult<bool> {
+    let (set_id, length): (u16, u16) = reader.read_type(endian)?;
+    if set_id <= 255 || length <= 4 || templates.get_template(set_id).is_some() {
+        return Ok(false);
+    }
+    let bytes: Vec<u8> = count((length - 4).into())(reader, endian, ())?;
+    Ok(templates.buffer_set(set_id, bytes))
 }
 
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.3>
 #[binrw]
-#[br(big, import( templates: TemplateStore, formatter: Rc<Formatter> ))]
-#[bw(big, stream = s, import( templates: TemplateStore, formatter: Rc<Formatter>, alignment: u8 ))]
+#[br(big, import( templates: &dyn TemplateStorage, formatter: &Formatter, options: ParseOptions ))]
+#[bw(big, stream = s, map_stream = RelativeStream::new, import( templates: &dyn TemplateStorage, formatter: &Formatter, options: WriteOptions ))]
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Set {
     #[br(temp)]
     #[bw(calc = records.set_id())]
@@ -109,33 +582,36 @@ pub struct Set {
     #[bw(try_calc = stream_position(s))]
     length: u16,
     #[br(pad_size_to = length - 4)]
-    #[br(args(set_id, length - 4, templates, formatter))]
-    #[bw(align_after = alignment)]
-    #[bw(args(templates, formatter))]
+    #[br(args(set_id, length - 4, templates, formatter, options))]
+    #[bw(align_after = options.set_alignment(records.set_id()))]
+    #[bw(args(templates, formatter, options))]
     pub records: Records,
     // jump back to length and set by current position
     #[br(temp)]
-    #[bw(restore_position, try_calc = write_position_at(s, length, length - 2))]
+    #[bw(restore_position, try_calc = write_length_at(s, length))]
     _temp: (),
 }
 
 /// <https://www.rfc-editor.org/rfc/rfc7011.html#section-3.4>
 #[binrw]
 #[brw(big)]
-#[br(import ( set_id: u16, length: u16, templates: TemplateStore, formatter: Rc<Formatter> ))]
-#[bw(import ( templates: TemplateStore, formatter: Rc<Formatter> ))]
+#[br(import ( set_id: u16, length: u16, templates: &dyn TemplateStorage, formatter: &Formatter, options: ParseOptions ))]
+#[bw(import ( templates: &dyn TemplateStorage, formatter: &Formatter, options: WriteOptions ))]
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Records {
-    #[br(pre_assert(set_id == 2))]
+    #[br(pre_assert(set_id == TEMPLATE_SET_ID))]
     Template(
-        #[br(map = |x: Vec<TemplateRecord>| {templates.insert_template_records(x.as_slice(), &formatter); x})]
+        #[br(try_map = |x: Vec<TemplateRecord>| templates.insert_template_records(x.as_slice(), formatter).map(|_| x))]
         #[br(parse_with = until_limit(length.into()))]
+        #[bw(try_map = |x| templates.insert_template_records(x.as_slice(), formatter).map(|_| x))]
         Vec<TemplateRecord>,
     ),
-    #[br(pre_assert(set_id == 3))]
+    #[br(pre_assert(set_id == OPTIONS_TEMPLATE_SET_ID))]
     OptionsTemplate(
-        #[br(map = |x: Vec<OptionsTemplateRecord>| {templates.insert_options_template_records(x.as_slice(), &formatter); x})]
+        #[br(try_map = |x: Vec<OptionsTemplateRecord>| check_scope_field_counts(&x, options).and_then(|_| templates.insert_options_template_records(x.as_slice(), formatter)).map(|_| x))]
         #[br(parse_with = until_limit(length.into()))]
+        #[bw(try_map = |x| templates.insert_options_template_records(x.as_slice(), formatter).map(|_| x))]
         Vec<OptionsTemplateRecord>,
     ),
     #[br(pre_assert(set_id > 255, "Set IDs 0-1 and 4-255 are reserved [set_id: {set_id}]"))]
@@ -143,57 +619,248 @@ pub enum Records {
         #[br(calc = set_id)]
         #[bw(ignore)]
         set_id: u16,
-        #[br(parse_with = until_limit(length.into()))]
-        #[br(args(set_id, templates))]
-        #[bw(args(*set_id, templates))]
+        #[br(parse_with = read_data_records, args(length, set_id, templates, options))]
+        #[bw(args(*set_id, templates, options))]
         data: Vec<DataRecord>,
     },
+    /// A data set whose template was missing, kept as raw bytes to be
+    /// decoded later with `Records::decode`. Only read with
+    /// `ParseOptions::keep_raw_sets`
+    #[br(pre_assert(options.keep_raw_sets && set_id > 255 && templates.get_template(set_id).is_none()))]
+    RawData {
+        #[br(calc = set_id)]
+        #[bw(ignore)]
+        set_id: u16,
+        #[br(parse_with = count(length.into()))]
+        bytes: Vec<u8>,
+    },
+    /// A set with a reserved Set ID, kept as raw bytes. Only read with
+    /// `ParseOptions::skip_reserved_sets`
+    #[br(pre_assert(
+        options.skip_reserved_sets
+            && set_id < 256
+            && set_id != TEMPLATE_SET_ID
+            && set_id != OPTIONS_TEMPLATE_SET_ID
+    ))]
+    Unsupported {
+        #[br(calc = set_id)]
+        #[bw(ignore)]
+        set_id: u16,
+        #[br(parse_with = count(length.into()))]
+        data: Vec<u8>,
+    },
+}
+
+/// With `ParseOptions::strict_scope_field_count`, fail if any options
+/// template, other than a withdrawal, has no scope fields
+fn check_scope_field_counts(
+    records: &[OptionsTemplateRecord],
+    options: ParseOptions,
+) -> Result<(), IpfixError> {
+    if !options.strict_scope_field_count {
+        return Ok(());
+    }
+    match records
+        .iter()
+        .find(|record| !record.is_withdrawal() && record.scope_field_count == 0)
+    {
+        Some(record) => Err(IpfixError::MissingScopeFields(record.template_id)),
+        None => Ok(()),
+    }
+}
+
+/// The shortest possible record for the template `set_id`, so any
+/// shorter remainder of a set is padding
+fn min_record_length(templates: &dyn TemplateStorage, set_id: u16) -> u64 {
+    templates
+        .get_template(set_id)
+        .map_or(1, |template| template.min_record_length())
 }
 
 impl Records {
-    fn set_id(&self) -> u16 {
+    pub(crate) fn set_id(&self) -> u16 {
         match self {
-            Self::Template(_) => 2,
-            Self::OptionsTemplate(_) => 3,
-            Self::Data { set_id, data: _ } => *set_id,
+            Self::Template(_) => TEMPLATE_SET_ID,
+            Self::OptionsTemplate(_) => OPTIONS_TEMPLATE_SET_ID,
+            Self::Data { set_id, .. }
+            | Self::RawData { set_id, .. }
+            | Self::Unsupported { set_id, .. } => *set_id,
         }
     }
+
+    /// Template Withdrawals for each of `template_ids`
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-8.1>
+    pub fn template_withdrawal(template_ids: impl IntoIterator<Item = u16>) -> Self {
+        Self::Template(
+            template_ids
+                .into_iter()
+                .map(TemplateRecord::withdrawal)
+                .collect(),
+        )
+    }
+
+    /// All Templates Withdrawal
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-8.1>
+    pub fn withdraw_all() -> Self {
+        Self::template_withdrawal([TEMPLATE_SET_ID])
+    }
+
+    /// Options Template Withdrawals for each of `template_ids`
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-8.1>
+    pub fn options_template_withdrawal(template_ids: impl IntoIterator<Item = u16>) -> Self {
+        Self::OptionsTemplate(
+            template_ids
+                .into_iter()
+                .map(OptionsTemplateRecord::withdrawal)
+                .collect(),
+        )
+    }
+
+    /// Decode `RawData` records with the current `templates`, such as
+    /// after a missing template has arrived. Other records are returned
+    /// unchanged
+    pub fn decode(
+        self,
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+        options: ParseOptions,
+    ) -> BinResult<Self> {
+        let Self::RawData { set_id, bytes } = self else {
+            return Ok(self);
+        };
+        let length = u16::try_from(bytes.len()).unwrap_or(u16::MAX);
+        Self::read_options(
+            &mut Cursor::new(bytes),
+            Endian::Big,
+            (set_id, length, templates, formatter, options),
+        )
+    }
+
+    /// All Options Templates Withdrawal
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-8.1>
+    pub fn withdraw_all_options() -> Self {
+        Self::options_template_withdrawal([OPTIONS_TEMPLATE_SET_ID])
+    }
 }
 
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.4.1>
 #[binrw]
 #[brw(big)]
 #[derive(PartialEq, Clone, Debug)]
-#[br(assert(template_id > 255, "Template IDs 0-255 are reserved [template_id: {template_id}]"))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[br(assert(
+    template_id > 255 || (template_id == TEMPLATE_SET_ID && field_specifiers.is_empty()),
+    "Template IDs 0-255 are reserved [template_id: {template_id}]"
+))]
 pub struct TemplateRecord {
     pub template_id: u16,
     #[br(temp)]
     #[bw(try_calc = field_specifiers.len().try_into())]
     field_count: u16,
-    #[br(count = field_count)]
-    pub field_specifiers: Vec<FieldSpecifier>,
+    #[br(parse_with = count(field_count.into()))]
+    #[bw(map = |x| x.as_slice())]
+    pub field_specifiers: FieldSpecifiers,
+}
+
+impl TemplateRecord {
+    /// Template Withdrawal Record, with no field specifiers
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-8.1>
+    pub fn withdrawal(template_id: u16) -> Self {
+        Self {
+            template_id,
+            field_specifiers: FieldSpecifiers::new(),
+        }
+    }
+
+    pub fn is_withdrawal(&self) -> bool {
+        self.field_specifiers.is_empty()
+    }
 }
 
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.4.2>
 #[binrw]
 #[brw(big)]
 #[derive(PartialEq, Clone, Debug)]
-#[br(assert(template_id > 255, "Template IDs 0-255 are reserved [template_id: {template_id}]"))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[br(assert(
+    template_id > 255 || (template_id == OPTIONS_TEMPLATE_SET_ID && field_specifiers.is_empty()),
+    "Template IDs 0-255 are reserved [template_id: {template_id}]"
+))]
+// a scope field count of 0 is invalid, but is accepted from existing
+// exporters, e.g. pskreporter, unless `ParseOptions::strict_scope_field_count`
+#[br(assert(
+    usize::from(scope_field_count) <= field_specifiers.len(),
+    "Scope field count exceeds field count [scope_field_count: {scope_field_count}]"
+))]
+#[bw(assert(
+    usize::from(*scope_field_count) <= field_specifiers.len(),
+    "Scope field count exceeds field count [scope_field_count: {scope_field_count}]"
+))]
 pub struct OptionsTemplateRecord {
     pub template_id: u16,
     #[br(temp)]
     #[bw(try_calc = field_specifiers.len().try_into())]
     field_count: u16,
-    // TODO
+    /// The number of leading `field_specifiers` that are scope fields.
+    /// Not present in withdrawals
+    #[br(if(field_count > 0))]
+    #[bw(if(!field_specifiers.is_empty()))]
     pub scope_field_count: u16,
-    #[br(count = field_count)]
-    pub field_specifiers: Vec<FieldSpecifier>,
+    #[br(parse_with = count(field_count.into()))]
+    #[bw(map = |x| x.as_slice())]
+    pub field_specifiers: FieldSpecifiers,
+}
+
+impl OptionsTemplateRecord {
+    /// Options Template Record with `scope_field_specifiers` followed by
+    /// `field_specifiers`, with a matching `scope_field_count`. Returns
+    /// None if there are no scope fields or too many scope fields
+    pub fn new(
+        template_id: u16,
+        scope_field_specifiers: Vec<FieldSpecifier>,
+        field_specifiers: Vec<FieldSpecifier>,
+    ) -> Option<Self> {
+        if scope_field_specifiers.is_empty() {
+            return None;
+        }
+        let scope_field_count = scope_field_specifiers.len().try_into().ok()?;
+        Some(Self {
+            template_id,
+            scope_field_count,
+            field_specifiers: scope_field_specifiers
+                .into_iter()
+                .chain(field_specifiers)
+                .collect(),
+        })
+    }
+
+    /// The leading scope fields of `field_specifiers`
+    pub fn scope_field_specifiers(&self) -> &[FieldSpecifier] {
+        let scope_field_count =
+            usize::from(self.scope_field_count).min(self.field_specifiers.len());
+        &self.field_specifiers[..scope_field_count]
+    }
+
+    /// Options Template Withdrawal Record, with no field specifiers
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-8.1>
+    pub fn withdrawal(template_id: u16) -> Self {
+        Self {
+            template_id,
+            scope_field_count: 0,
+            field_specifiers: FieldSpecifiers::new(),
+        }
+    }
+
+    pub fn is_withdrawal(&self) -> bool {
+        self.field_specifiers.is_empty()
+    }
 }
 
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.2>
 #[binrw]
 #[brw(big)]
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FieldSpecifier {
     #[br(temp)]
     #[bw(calc = information_element_identifier | (u16::from(enterprise_number.is_some()) << 15))]
@@ -218,95 +885,446 @@ impl FieldSpecifier {
             enterprise_number,
         }
     }
+
+    /// A field for the element named `name` in `formatter`, or None if
+    /// there isn't one
+    pub fn by_name(name: &str, field_length: u16, formatter: &Formatter) -> Option<Self> {
+        let (enterprise_number, id, _) = formatter.resolve(name)?;
+        let enterprise_number = (enterprise_number != 0).then_some(enterprise_number);
+        Some(Self::new(enterprise_number, id, field_length))
+    }
+}
+
+impl From<&ExpandedFieldSpecifier> for FieldSpecifier {
+    fn from(field_spec: &ExpandedFieldSpecifier) -> Self {
+        Self::new(
+            field_spec.enterprise_number,
+            field_spec.information_element_identifier,
+            field_spec.field_length,
+        )
+    }
 }
 
+/// The values of a `DataRecord`
+#[cfg(not(feature = "indexmap"))]
+pub type DataRecordValues = ahash::HashMap<DataRecordKey, DataRecordValue>;
+/// The values of a `DataRecord`, iterated in template order
+#[cfg(feature = "indexmap")]
+pub type DataRecordValues = indexmap::IndexMap<DataRecordKey, DataRecordValue, ahash::RandomState>;
+
 /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.4.3>
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DataRecord {
-    pub values: HashMap<DataRecordKey, DataRecordValue>,
+    pub values: DataRecordValues,
+    /// Values of scope fields, for records described by an options
+    /// template. These identify what the `values` apply to
+    pub scope_values: DataRecordValues,
 }
 
-/// slightly nicer syntax to make a `DataRecord`
+/// slightly nicer syntax to make a `DataRecord`. Keys are either a name,
+/// `(enterprise_number, information_element_identifier)` or
+/// `(enterprise_number, information_element_identifier, field_length)`
+/// for an unrecognized field, with enterprise number 0 meaning none, or
+/// `[expr]` for anything that converts to a `DataRecordKey`
 #[macro_export]
 macro_rules! data_record {
-    { $($key:literal: $type:ident($value:expr)),+ $(,)? } => {
+    { $($key:tt: $type:ident($value:expr)),+ $(,)? } => {
         DataRecord {
-            values: HashMap::from_iter([
-                $( ((DataRecordKey::Str($key), DataRecordValue::$type($value))), )+
-            ])
+            values: ::core::iter::FromIterator::from_iter([
+                $( $crate::data_record_entry!($key, DataRecordValue::$type($value)), )+
+            ]),
+            scope_values: Default::default(),
         }
     };
 }
 
+#[doc(hidden)]
+#[macro_export]
+macro_rules! data_record_entry {
+    ($key:literal, $value:expr) => {
+        (DataRecordKey::Str($key), $value)
+    };
+    (($pen:expr, $id:expr), $value:expr) => {{
+        let value: $crate::parser::DataRecordValue = $value;
+        $crate::data_record_entry!(($pen, $id, value.natural_field_length()), value)
+    }};
+    (($pen:expr, $id:expr, $length:expr), $value:expr) => {
+        (
+            DataRecordKey::Unrecognized($crate::parser::FieldSpecifier::new(
+                Some($pen).filter(|&pen: &u32| pen != 0),
+                $id,
+                $length,
+            )),
+            $value,
+        )
+    };
+    ([$key:expr], $value:expr) => {
+        (DataRecordKey::from($key), $value)
+    };
+}
+
+impl DataRecord {
+    /// Widen all integer values, as with `DataRecordValue::normalized`
+    pub fn normalize(&mut self) {
+        for value in self
+            .values
+            .values_mut()
+            .chain(self.scope_values.values_mut())
+        {
+            *value = std::mem::replace(value, DataRecordValue::U8(0)).normalized();
+        }
+    }
+
+    /// Look up the value of an unrecognized field by information element,
+    /// whatever its field length, in either the values or scope values
+    pub fn get_by_ie(
+        &self,
+        enterprise_number: Option<u32>,
+        information_element_identifier: u16,
+    ) -> Option<&DataRecordValue> {
+        self.values
+            .iter()
+            .chain(&self.scope_values)
+            .find_map(|(key, value)| match key {
+                DataRecordKey::Unrecognized(field_spec)
+                    if field_spec.enterprise_number == enterprise_number
+                        && field_spec.information_element_identifier
+                            == information_element_identifier =>
+                {
+                    Some(value)
+                }
+                _ => None,
+            })
+    }
+}
+
+/// Always read big endian, as IPFIX is
 impl BinRead for DataRecord {
-    type Args<'a> = (u16, TemplateStore);
+    type Args<'a> = (u16, &'a dyn TemplateStorage, ParseOptions);
 
     fn read_options<R: Read + Seek>(
         reader: &mut R,
-        endian: Endian,
-        (set_id, templates): Self::Args<'_>,
+        _: Endian,
+        (set_id, templates, options): Self::Args<'_>,
     ) -> BinResult<Self> {
         let template = templates.get_template(set_id).ok_or(
             IpfixError::MissingTemplate(set_id).into_binrw_error(reader.stream_position()?),
         )?;
+        template.plan().read_record(reader, options)
+    }
+}
 
-        // TODO: should these be handled differently?
-        let field_specifiers = match template {
-            Template::Template(field_specifiers) => field_specifiers,
-            Template::OptionsTemplate(field_specifiers) => field_specifiers,
-        };
-
-        let mut values = HashMap::with_capacity(field_specifiers.len());
-        for field_spec in field_specifiers.iter() {
-            // TODO: should read whole field length according to template, regardless of type
-            let value = reader.read_type_args(endian, (field_spec.ty, field_spec.field_length))?;
+/// Read the records of a data set of `length` bytes. The set is read in
+/// one go, and its records decoded from slices of it with the `DecodePlan`
+/// stored with the set's template
+fn read_data_records<R: Read + Seek>(
+    reader: &mut R,
+    endian: Endian,
+    (length, set_id, templates, options): (u16, u16, &dyn TemplateStorage, ParseOptions),
+) -> BinResult<Vec<DataRecord>> {
+    let template = templates
+        .get_template(set_id)
+        .ok_or(IpfixError::MissingTemplate(set_id).into_binrw_error(reader.stream_position()?))?;
+    let start = reader.stream_position()?;
+    let bytes: Vec<u8> = count(length.into())(reader, endian, ())?;
+    if template.field_specifiers().next().is_none() {
+        return Ok(vec![]);
+    }
 
-            values.insert(field_spec.name.clone(), value);
+    let plan = template.plan();
+    if let Some(record_length) = plan.record_length().filter(|&length| length > 0) {
+        // records of a fixed length are decoded without checking for the
+        // end of the set, the rest of which is padding
+        return bytes
+            .chunks_exact(record_length)
+            .enumerate()
+            .map(|(i, record)| {
+                plan.decode_record(record, &mut 0, start + (i * record_length) as u64, options)
+            })
+            .collect();
+    }
+    let min_length = template.min_record_length().max(1) as usize;
+    let mut records = vec![];
+    let mut offset = 0;
+    while bytes.len() - offset >= min_length {
+        let record_start = offset;
+        match plan.decode_record(&bytes, &mut offset, start, options) {
+            // a template of only zero length fields makes records of no
+            // bytes, which would repeat forever
+            Ok(_) if offset == record_start => break,
+            Ok(record) => records.push(record),
+            // the rest of the set is padding
+            Err(err) if err.is_eof() => break,
+            Err(err) => return Err(err),
         }
-        Ok(Self { values })
     }
+    Ok(records)
 }
 
 impl BinWrite for DataRecord {
-    type Args<'a> = (u16, TemplateStore);
+    type Args<'a> = (u16, &'a dyn TemplateStorage, WriteOptions);
 
     fn write_options<W: Write + Seek>(
         &self,
         writer: &mut W,
         endian: Endian,
-        (set_id, templates): Self::Args<'_>,
+        (set_id, templates, options): Self::Args<'_>,
     ) -> BinResult<()> {
         let template = templates.get_template(set_id).ok_or(
             IpfixError::MissingTemplate(set_id).into_binrw_error(writer.stream_position()?),
         )?;
 
-        let field_specifiers = match template {
-            Template::Template(field_specifiers) => field_specifiers,
-            Template::OptionsTemplate(field_specifiers) => field_specifiers,
-        };
-
+        let scope_field_count = template.scope_field_specifiers().len();
         // TODO: should check if all keys are used?
-        for field_spec in field_specifiers {
+        for (i, field_spec) in template.field_specifiers().enumerate() {
             // TODO: check template type vs actual type?
-            let value = self.values.get(&field_spec.name).ok_or(
-                IpfixError::MissingData(field_spec.name)
+            // scope fields may also be given with the other values
+            let value = if i < scope_field_count {
+                self.scope_values.get(&field_spec.name)
+            } else {
+                None
+            }
+            .or_else(|| self.values.get(&field_spec.name))
+            .ok_or(
+                IpfixError::MissingData(field_spec.name.clone())
                     .into_binrw_error(writer.stream_position()?),
             )?;
+            if !value.fits_field_length(field_spec.field_length) {
+                return Err(IpfixError::InvalidValueLength {
+                    key: field_spec.name.clone(),
+                    value: value.clone(),
+                    length: field_spec.field_length,
+                }
+                .into_binrw_error(writer.stream_position()?));
+            }
 
-            writer.write_type_args(value, endian, (field_spec.field_length,))?;
+            writer.write_type_args(
+                value,
+                endian,
+                (field_spec.field_length, options.long_variable_length),
+            )?;
         }
         Ok(())
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+impl DataRecord {
+    /// Build a record with exactly the fields of `template`
+    pub fn builder(template: &Template) -> DataRecordBuilder<'_> {
+        DataRecordBuilder {
+            template,
+            record: Self::default(),
+        }
+    }
+}
+
+/// Builds a `DataRecord` for a template, checking each value as it is
+/// set, so that it can always be written with that template
+#[derive(Debug)]
+pub struct DataRecordBuilder<'a> {
+    template: &'a Template,
+    record: DataRecord,
+}
+
+impl DataRecordBuilder<'_> {
+    /// Set the value of a field, which must be in the template, with a
+    /// matching type that fits its field length
+    pub fn set(
+        mut self,
+        key: impl Into<DataRecordKey>,
+        value: DataRecordValue,
+    ) -> Result<Self, IpfixError> {
+        let key = key.into();
+        let scope_field_count = self.template.scope_field_specifiers().len();
+        let (i, field_spec) = self
+            .template
+            .field_specifiers()
+            .enumerate()
+            .find(|(_, field_spec)| field_spec.name == key)
+            .ok_or_else(|| IpfixError::UnknownField(key.clone()))?;
+        if value.data_type() != field_spec.ty {
+            return Err(IpfixError::InvalidValueType {
+                key,
+                value,
+                ty: field_spec.ty,
+            });
+        }
+        if !value.fits_field_length(field_spec.field_length) {
+            return Err(IpfixError::InvalidValueLength {
+                key,
+                value,
+                length: field_spec.field_length,
+            });
+        }
+        if i < scope_field_count {
+            self.record.scope_values.insert(key, value);
+        } else {
+            self.record.values.insert(key, value);
+        }
+        Ok(self)
+    }
+
+    /// The finished record, if every field in the template has been set
+    pub fn build(self) -> Result<DataRecord, IpfixError> {
+        let scope_field_count = self.template.scope_field_specifiers().len();
+        for (i, field_spec) in self.template.field_specifiers().enumerate() {
+            let values = if i < scope_field_count {
+                &self.record.scope_values
+            } else {
+                &self.record.values
+            };
+            if !values.contains_key(&field_spec.name) {
+                return Err(IpfixError::MissingData(field_spec.name.clone()));
+            }
+        }
+        Ok(self.record)
+    }
+}
+
+/// A Data Set decoded with only its template, leaving each field as raw
+/// bytes, so no Formatter is needed to read or write it
+#[binrw]
+#[br(big, import( templates: &dyn TemplateStorage ))]
+#[bw(big, stream = s, map_stream = RelativeStream::new)]
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RawDataSet {
+    #[br(assert(set_id > 255, "Set IDs 0-255 are not data sets [set_id: {set_id}]"))]
+    pub set_id: u16,
+    #[br(temp)]
+    #[br(assert(length > 4, "invalid set length: [{length} <= 4]"))]
+    // store offset for later updating
+    #[bw(try_calc = stream_position(s))]
+    length: u16,
+    #[br(pad_size_to = length - 4)]
+    #[br(parse_with = until_padding((length - 4).into(), min_record_length(templates, set_id)))]
+    #[br(args(set_id, templates))]
+    pub records: Vec<RawDataRecord>,
+    // jump back to length and set by current position
+    #[br(temp)]
+    #[bw(restore_position, try_calc = write_length_at(s, length))]
+    _temp: (),
+}
+
+/// A data record as the raw bytes of each field, in template order.
+/// Variable length fields do not include their length prefix
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RawDataRecord {
+    pub fields: Vec<(FieldSpecifier, Vec<u8>)>,
+}
+
+impl BinRead for RawDataRecord {
+    type Args<'a> = (u16, &'a dyn TemplateStorage);
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        (set_id, templates): Self::Args<'_>,
+    ) -> BinResult<Self> {
+        let template = templates.get_template(set_id).ok_or(
+            IpfixError::MissingTemplate(set_id).into_binrw_error(reader.stream_position()?),
+        )?;
+
+        let fields = template
+            .field_specifiers()
+            .map(|field_spec| {
+                let bytes = read_variable_length(reader, endian, field_spec.field_length)?;
+                Ok((FieldSpecifier::from(field_spec), bytes))
+            })
+            .collect::<BinResult<_>>()?;
+        Ok(Self { fields })
+    }
+}
+
+impl BinWrite for RawDataRecord {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        endian: Endian,
+        _: Self::Args<'_>,
+    ) -> BinResult<()> {
+        for (field_spec, bytes) in &self.fields {
+            if field_spec.field_length == u16::MAX {
+                if bytes.len() < 255 {
+                    (bytes.len() as u8).write_options(writer, endian, ())?;
+                } else {
+                    let length = u16::try_from(bytes.len()).map_err(|e| binrw::Error::Custom {
+                        pos: writer.stream_position().unwrap_or_default(),
+                        err: Box::new(e),
+                    })?;
+                    255u8.write_options(writer, endian, ())?;
+                    length.write_options(writer, endian, ())?;
+                }
+            }
+            bytes.write_options(writer, endian, ())?;
+        }
+        Ok(())
+    }
+}
+
+/// `Str` and `Owned` keys with the same name are equal, so either can
+/// be used to look up values
+#[derive(Clone, Debug)]
 pub enum DataRecordKey {
     Str(&'static str),
+    /// A name only known at runtime, such as one learned from an exporter
+    Owned(Arc<str>),
     Unrecognized(FieldSpecifier),
     Err(String),
 }
 
+impl DataRecordKey {
+    /// The information element name, for `Str` and `Owned` keys
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            DataRecordKey::Str(name) => Some(name),
+            DataRecordKey::Owned(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for DataRecordKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (DataRecordKey::Unrecognized(a), DataRecordKey::Unrecognized(b)) => a == b,
+            (DataRecordKey::Err(a), DataRecordKey::Err(b)) => a == b,
+            _ => matches!((self.name(), other.name()), (Some(a), Some(b)) if a == b),
+        }
+    }
+}
+
+impl Eq for DataRecordKey {}
+
+impl Hash for DataRecordKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            DataRecordKey::Str(name) => (0u8, name).hash(state),
+            DataRecordKey::Owned(name) => (0u8, &**name).hash(state),
+            DataRecordKey::Unrecognized(field_spec) => (1u8, field_spec).hash(state),
+            DataRecordKey::Err(err) => (2u8, err).hash(state),
+        }
+    }
+}
+
+impl From<&'static str> for DataRecordKey {
+    fn from(name: &'static str) -> Self {
+        DataRecordKey::Str(name)
+    }
+}
+
+impl From<String> for DataRecordKey {
+    fn from(name: String) -> Self {
+        DataRecordKey::Owned(name.into())
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataRecordType {
     UnsignedInt,
     SignedInt,
@@ -325,49 +1343,50 @@ pub enum DataRecordType {
 
 #[binwrite]
 #[bw(big)]
-#[bw(import( length: u16 ))]
+#[bw(import( length: u16, long_variable_length: bool ))]
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataRecordValue {
-    U8(u8),
-    U16(u16),
-    U32(u32),
-    U64(u64),
-    I8(i8),
-    I16(i16),
-    I32(i32),
-    I64(i64),
-    F32(f32),
-    F64(f64),
+    U8(#[bw(write_with = write_unsigned, args(length))] u8),
+    U16(#[bw(write_with = write_unsigned, args(length))] u16),
+    U32(#[bw(write_with = write_unsigned, args(length))] u32),
+    U64(#[bw(write_with = write_unsigned, args(length))] u64),
+    I8(#[bw(write_with = write_signed, args(length))] i8),
+    I16(#[bw(write_with = write_signed, args(length))] i16),
+    I32(#[bw(write_with = write_signed, args(length))] i32),
+    I64(#[bw(write_with = write_signed, args(length))] i64),
+    F32(#[bw(write_with = write_float, args(length))] f32),
+    F64(#[bw(write_with = write_float, args(length))] f64),
     Bool(#[bw(map = |&x| -> u8 {if x {1} else {2} })] bool),
 
-    MacAddress([u8; 6]),
+    MacAddress(MacAddress),
 
     // TODO: same logic as variable length string
     Bytes(
-        #[bw(if(length == u16::MAX), calc = if self_2.len() < 255 { self_2.len() as u8 } else { 255 })]
+        #[bw(if(length == u16::MAX), calc = if self_2.len() < 255 && !long_variable_length { self_2.len() as u8 } else { 255 })]
          u8,
-        #[bw(if(length == u16::MAX && self_2.len() >= 255), try_calc = self_2.len().try_into())]
-        u16,
+        #[bw(if(length == u16::MAX && (self_2.len() >= 255 || long_variable_length)), try_calc = self_2.len().try_into())]
+         u16,
         Vec<u8>,
     ),
     String(
-        #[bw(if(length == u16::MAX), calc = if self_2.len() < 255 { self_2.len() as u8 } else { 255 })]
+        #[bw(if(length == u16::MAX), calc = if self_2.len() < 255 && !long_variable_length { self_2.len() as u8 } else { 255 })]
          u8,
-        #[bw(if(length == u16::MAX && self_2.len() >= 255), try_calc = self_2.len().try_into())]
-        u16,
+        #[bw(if(length == u16::MAX && (self_2.len() >= 255 || long_variable_length)), try_calc = self_2.len().try_into())]
+         u16,
         #[bw(map = |x| x.as_bytes())] String,
     ),
 
     DateTimeSeconds(u32),
     DateTimeMilliseconds(u64),
-    DateTimeMicroseconds(u64),
-    DateTimeNanoseconds(u64),
+    DateTimeMicroseconds(NtpTimestamp),
+    DateTimeNanoseconds(NtpTimestamp),
 
     Ipv4Addr(#[bw(map = |&x| -> u32 {x.into()})] Ipv4Addr),
     Ipv6Addr(#[bw(map = |&x| -> u128 {x.into()})] Ipv6Addr),
 }
 
-fn read_variable_length<R: Read + Seek>(
+pub(crate) fn read_variable_length<R: Read + Seek>(
     reader: &mut R,
     endian: Endian,
     length: u16,
@@ -386,72 +1405,340 @@ fn read_variable_length<R: Read + Seek>(
     count(actual_length.into())(reader, endian, ())
 }
 
-impl BinRead for DataRecordValue {
-    type Args<'a> = (DataRecordType, u16);
+/// Error converting a `DataRecordValue` to another type, when it is the
+/// wrong variant or out of range
+#[derive(derive_more::Display, PartialEq, Eq, Clone, Copy, Debug)]
+#[display(fmt = "Invalid DataRecordValue conversion")]
+pub struct ValueConversionError;
 
-    fn read_options<R: Read + Seek>(
-        reader: &mut R,
-        endian: Endian,
-        (ty, length): Self::Args<'_>,
-    ) -> BinResult<Self> {
-        // TODO: length shouldn't actually change the data type, technically
-        Ok(match (ty, length) {
-            (DataRecordType::UnsignedInt, 1) => DataRecordValue::U8(reader.read_type(endian)?),
-            (DataRecordType::UnsignedInt, 2) => DataRecordValue::U16(reader.read_type(endian)?),
-            (DataRecordType::UnsignedInt, 4) => DataRecordValue::U32(reader.read_type(endian)?),
-            (DataRecordType::UnsignedInt, 8) => DataRecordValue::U64(reader.read_type(endian)?),
-            (DataRecordType::SignedInt, 1) => DataRecordValue::I8(reader.read_type(endian)?),
-            (DataRecordType::SignedInt, 2) => DataRecordValue::I16(reader.read_type(endian)?),
-            (DataRecordType::SignedInt, 4) => DataRecordValue::I32(reader.read_type(endian)?),
-            (DataRecordType::SignedInt, 8) => DataRecordValue::I64(reader.read_type(endian)?),
-            (DataRecordType::Float, 4) => DataRecordValue::F32(reader.read_type(endian)?),
-            (DataRecordType::Float, 8) => DataRecordValue::F64(reader.read_type(endian)?),
-            // TODO: technically 1=>true, 2=>false, others undefined
-            (DataRecordType::Bool, 1) => DataRecordValue::Bool(u8::read(reader).map(|x| x == 1)?),
-            (DataRecordType::MacAddress, 6) => {
-                DataRecordValue::MacAddress(reader.read_type(endian)?)
+impl std::error::Error for ValueConversionError {}
+
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-6.1.4>
+#[binrw]
+#[brw(big)]
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl From<[u8; 6]> for MacAddress {
+    fn from(bytes: [u8; 6]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<MacAddress> for [u8; 6] {
+    fn from(mac: MacAddress) -> Self {
+        mac.0
+    }
+}
+
+/// Formats as "aa:bb:cc:dd:ee:ff"
+impl std::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(derive_more::Display, PartialEq, Eq, Clone, Copy, Debug)]
+#[display(fmt = "Invalid MAC address")]
+pub struct ParseMacAddressError;
+
+impl std::error::Error for ParseMacAddressError {}
+
+/// Parses six hex octets separated by ':' or '-'
+impl std::str::FromStr for MacAddress {
+    type Err = ParseMacAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; 6];
+        let mut octets = s.split([':', '-']);
+        for byte in bytes.iter_mut() {
+            let octet = octets.next().ok_or(ParseMacAddressError)?;
+            if octet.len() != 2 {
+                return Err(ParseMacAddressError);
             }
+            *byte = u8::from_str_radix(octet, 16).map_err(|_| ParseMacAddressError)?;
+        }
+        match octets.next() {
+            Some(_) => Err(ParseMacAddressError),
+            None => Ok(Self(bytes)),
+        }
+    }
+}
+
+/// 64-bit NTP timestamp, used for dateTimeMicroseconds and
+/// dateTimeNanoseconds values
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-6.1.9>
+#[binrw]
+#[brw(big)]
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NtpTimestamp {
+    /// Seconds since 1900-01-01 00:00 UTC
+    pub seconds: u32,
+    /// Fraction of a second, in units of 2^-32 seconds
+    pub fraction: u32,
+}
+
+impl NtpTimestamp {
+    /// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+    pub const UNIX_EPOCH_OFFSET: i64 = 2_208_988_800;
+
+    /// Seconds since the NTP epoch, accounting for the wrap in 2036 by
+    /// treating timestamps with the most significant bit clear as NTP
+    /// era 1, giving a range of 1968 to 2104
+    /// <https://www.rfc-editor.org/rfc/rfc4330#section-3>
+    fn ntp_seconds(&self) -> i64 {
+        if self.seconds & 0x8000_0000 == 0 {
+            i64::from(self.seconds) + (1 << 32)
+        } else {
+            i64::from(self.seconds)
+        }
+    }
+
+    /// Nanoseconds since the Unix epoch, which may be negative
+    pub fn to_unix_nanos(&self) -> i64 {
+        let seconds = self.ntp_seconds() - Self::UNIX_EPOCH_OFFSET;
+        let nanos = (u64::from(self.fraction) * 1_000_000_000) >> 32;
+        seconds * 1_000_000_000 + nanos as i64
+    }
+
+    /// Convert nanoseconds since the Unix epoch, returning None if out
+    /// of the representable range (1968 to 2104)
+    pub fn from_unix_nanos(nanos: i64) -> Option<Self> {
+        let seconds = nanos.div_euclid(1_000_000_000) + Self::UNIX_EPOCH_OFFSET;
+        if !(0x8000_0000..0x1_8000_0000).contains(&seconds) {
+            return None;
+        }
+        let nanos = nanos.rem_euclid(1_000_000_000) as u64;
+        Some(Self {
+            seconds: seconds as u32,
+            // round up, so converting back gives the same nanoseconds
+            fraction: (nanos << 32).div_ceil(1_000_000_000) as u32,
+        })
+    }
+}
+
+impl DataRecordValue {
+    /// The value of any unsigned integer variant, widened to u64
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            DataRecordValue::U8(x) => Some(x.into()),
+            DataRecordValue::U16(x) => Some(x.into()),
+            DataRecordValue::U32(x) => Some(x.into()),
+            DataRecordValue::U64(x) => Some(x),
+            _ => None,
+        }
+    }
 
-            (DataRecordType::Bytes, _) => {
-                DataRecordValue::Bytes(read_variable_length(reader, endian, length)?)
+    /// The value of any signed integer variant, widened to i64
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            DataRecordValue::I8(x) => Some(x.into()),
+            DataRecordValue::I16(x) => Some(x.into()),
+            DataRecordValue::I32(x) => Some(x.into()),
+            DataRecordValue::I64(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// The information element type this value is read as
+    pub fn data_type(&self) -> DataRecordType {
+        match self {
+            DataRecordValue::U8(_)
+            | DataRecordValue::U16(_)
+            | DataRecordValue::U32(_)
+            | DataRecordValue::U64(_) => DataRecordType::UnsignedInt,
+            DataRecordValue::I8(_)
+            | DataRecordValue::I16(_)
+            | DataRecordValue::I32(_)
+            | DataRecordValue::I64(_) => DataRecordType::SignedInt,
+            DataRecordValue::F32(_) | DataRecordValue::F64(_) => DataRecordType::Float,
+            DataRecordValue::Bool(_) => DataRecordType::Bool,
+            DataRecordValue::MacAddress(_) => DataRecordType::MacAddress,
+            DataRecordValue::Bytes(_) => DataRecordType::Bytes,
+            DataRecordValue::String(_) => DataRecordType::String,
+            DataRecordValue::DateTimeSeconds(_) => DataRecordType::DateTimeSeconds,
+            DataRecordValue::DateTimeMilliseconds(_) => DataRecordType::DateTimeMilliseconds,
+            DataRecordValue::DateTimeMicroseconds(_) => DataRecordType::DateTimeMicroseconds,
+            DataRecordValue::DateTimeNanoseconds(_) => DataRecordType::DateTimeNanoseconds,
+            DataRecordValue::Ipv4Addr(_) => DataRecordType::Ipv4Addr,
+            DataRecordValue::Ipv6Addr(_) => DataRecordType::Ipv6Addr,
+        }
+    }
+
+    /// Whether this value can be written to a field of `length` bytes.
+    /// Integers of any width can be written to any integer field their
+    /// value fits in, using reduced-length encodings if needed
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-6.2>
+    pub fn fits_field_length(&self, length: u16) -> bool {
+        let width = self.encoded_width();
+        match *self {
+            DataRecordValue::U8(_)
+            | DataRecordValue::U16(_)
+            | DataRecordValue::U32(_)
+            | DataRecordValue::U64(_) => {
+                (1..=8).contains(&length)
+                    && self
+                        .as_u64()
+                        .is_some_and(|x| length >= 8 || x >> (8 * length) == 0)
             }
-            (DataRecordType::String, _) => DataRecordValue::String(
-                match String::from_utf8(read_variable_length(reader, endian, length)?) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        return Err(binrw::Error::Custom {
-                            pos: reader.stream_position()?,
-                            err: Box::new(e),
-                        });
-                    }
-                },
-            ),
-
-            (DataRecordType::DateTimeSeconds, 4) => {
-                DataRecordValue::DateTimeSeconds(reader.read_type(endian)?)
+            DataRecordValue::I8(_)
+            | DataRecordValue::I16(_)
+            | DataRecordValue::I32(_)
+            | DataRecordValue::I64(_) => {
+                (1..=8).contains(&length)
+                    && self.as_i64().is_some_and(|x| {
+                        let shift = 64 - 8 * u32::from(length);
+                        x << shift >> shift == x
+                    })
             }
+            DataRecordValue::F32(_) | DataRecordValue::F64(_) => length == 4 || length == 8,
+            DataRecordValue::Bytes(ref x) => length == u16::MAX || usize::from(length) == x.len(),
+            DataRecordValue::String(ref x) => length == u16::MAX || usize::from(length) == x.len(),
+            _ => length == width,
+        }
+    }
 
-            (DataRecordType::DateTimeMilliseconds, 8) => {
-                DataRecordValue::DateTimeMilliseconds(reader.read_type(endian)?)
-            }
+    /// The field length of this value without reduced-size encoding:
+    /// the full width of fixed-length types, or the length of octet
+    /// arrays and strings
+    pub fn natural_field_length(&self) -> u16 {
+        match self {
+            DataRecordValue::Bytes(x) => x.len().try_into().unwrap_or(u16::MAX),
+            DataRecordValue::String(x) => x.len().try_into().unwrap_or(u16::MAX),
+            _ => self.encoded_width(),
+        }
+    }
 
-            (DataRecordType::DateTimeMicroseconds, 8) => {
-                DataRecordValue::DateTimeMicroseconds(reader.read_type(endian)?)
-            }
+    /// The full width in bytes of fixed-length values
+    fn encoded_width(&self) -> u16 {
+        match self {
+            DataRecordValue::U8(_) | DataRecordValue::I8(_) | DataRecordValue::Bool(_) => 1,
+            DataRecordValue::U16(_) | DataRecordValue::I16(_) => 2,
+            DataRecordValue::U32(_)
+            | DataRecordValue::I32(_)
+            | DataRecordValue::F32(_)
+            | DataRecordValue::DateTimeSeconds(_)
+            | DataRecordValue::Ipv4Addr(_) => 4,
+            DataRecordValue::U64(_)
+            | DataRecordValue::I64(_)
+            | DataRecordValue::F64(_)
+            | DataRecordValue::DateTimeMilliseconds(_)
+            | DataRecordValue::DateTimeMicroseconds(_)
+            | DataRecordValue::DateTimeNanoseconds(_) => 8,
+            DataRecordValue::MacAddress(_) => 6,
+            DataRecordValue::Ipv6Addr(_) => 16,
+            DataRecordValue::Bytes(_) | DataRecordValue::String(_) => u16::MAX,
+        }
+    }
 
-            (DataRecordType::DateTimeNanoseconds, 8) => {
-                DataRecordValue::DateTimeNanoseconds(reader.read_type(endian)?)
-            }
+    /// The increase from `previous` to this value of a totalCounter,
+    /// allowing for the counter wrapping around at the width of its
+    /// field, `field_length` bytes, which may be a reduced-length
+    /// encoding. Returns None if the values aren't the same unsigned
+    /// variant, or `field_length` isn't 1 to 8
+    /// <https://www.rfc-editor.org/rfc/rfc7012#section-3.2.2>
+    pub fn counter_delta(&self, previous: &DataRecordValue, field_length: u16) -> Option<u64> {
+        if std::mem::discriminant(self) != std::mem::discriminant(previous) {
+            return None;
+        }
+        let delta = self.as_u64()?.wrapping_sub(previous.as_u64()?);
+        match field_length {
+            1..=7 => Some(delta & ((1 << (8 * field_length)) - 1)),
+            8 => Some(delta),
+            _ => None,
+        }
+    }
 
-            (DataRecordType::Ipv4Addr, 4) => {
-                DataRecordValue::Ipv4Addr(u32::read_be(reader)?.into())
-            }
+    /// Widen unsigned integers to `U64` and signed integers to `I64`,
+    /// leaving other values unchanged. These are still written with the
+    /// width given by the template
+    pub fn normalized(self) -> Self {
+        match (self.as_u64(), self.as_i64()) {
+            (Some(x), _) => DataRecordValue::U64(x),
+            (_, Some(x)) => DataRecordValue::I64(x),
+            _ => self,
+        }
+    }
+}
 
-            (DataRecordType::Ipv6Addr, 16) => {
-                DataRecordValue::Ipv6Addr(u128::read_be(reader)?.into())
-            }
-            _ => Err(IpfixError::InvalidFieldSpecLength { ty, length }
-                .into_binrw_error(reader.stream_position()?))?,
-        })
+/// Write an unsigned integer using `length` bytes, either a
+/// reduced-length encoding or zero-extended to a wider field. Other
+/// lengths use its full width
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-6.2>
+fn write_unsigned<W: Write + Seek, T: Copy + Into<u64>>(
+    value: &T,
+    writer: &mut W,
+    endian: Endian,
+    (length,): (u16,),
+) -> BinResult<()> {
+    let length = match usize::from(length) {
+        length @ 1..=8 => length,
+        _ => std::mem::size_of::<T>(),
+    };
+    let value: u64 = (*value).into();
+    match endian {
+        Endian::Big => writer.write_all(&value.to_be_bytes()[8 - length..])?,
+        Endian::Little => writer.write_all(&value.to_le_bytes()[..length])?,
+    }
+    Ok(())
+}
+
+/// Write a signed integer using `length` bytes, either a reduced-length
+/// encoding or sign-extended to a wider field. Other lengths use its
+/// full width
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-6.2>
+fn write_signed<W: Write + Seek, T: Copy + Into<i64>>(
+    value: &T,
+    writer: &mut W,
+    endian: Endian,
+    (length,): (u16,),
+) -> BinResult<()> {
+    let length = match usize::from(length) {
+        length @ 1..=8 => length,
+        _ => std::mem::size_of::<T>(),
+    };
+    let value: i64 = (*value).into();
+    match endian {
+        Endian::Big => writer.write_all(&value.to_be_bytes()[8 - length..])?,
+        Endian::Little => writer.write_all(&value.to_le_bytes()[..length])?,
+    }
+    Ok(())
+}
+
+/// Write a float as float32 or float64 according to `length`, since
+/// float64 information elements may use a reduced-length float32
+/// encoding. Writing a float64 value as float32 loses precision
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-6.2>
+fn write_float<W: Write + Seek, T: Copy + Into<f64>>(
+    value: &T,
+    writer: &mut W,
+    endian: Endian,
+    (length,): (u16,),
+) -> BinResult<()> {
+    let value: f64 = (*value).into();
+    let single_precision = match length {
+        4 => true,
+        8 => false,
+        _ => std::mem::size_of::<T>() == 4,
+    };
+    if single_precision {
+        (value as f32).write_options(writer, endian, ())
+    } else {
+        value.write_options(writer, endian, ())
+    }
+}
+
+/// Always read big endian, as IPFIX is
+impl BinRead for DataRecordValue {
+    type Args<'a> = (DataRecordType, u16, ParseOptions);
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        _: Endian,
+        (ty, length, options): Self::Args<'_>,
+    ) -> BinResult<Self> {
+        FieldDecoder::new(ty, length).read(reader, options)
     }
 }
@@ -0,0 +1,389 @@
+//! Templates compiled into the steps to decode their data records, so
+//! the type and length of each field is only matched once per template
+//! rather than once per record
+
+use binrw::io::{Read, Seek};
+use binrw::{BinRead, BinResult};
+
+use crate::parser::{
+    DataRecord, DataRecordKey, DataRecordType, DataRecordValue, DataRecordValues, IpfixError,
+    MacAddress, NtpTimestamp, ParseOptions,
+};
+use crate::template_store::ExpandedFieldSpecifier;
+
+/// How to decode one field, from its type and length
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum FieldDecoder {
+    U8,
+    U16,
+    U32,
+    U64,
+    /// A reduced-length unsigned integer of 3 or 5 to 7 bytes
+    ReducedUnsigned(u16),
+    I8,
+    I16,
+    I32,
+    I64,
+    /// A reduced-length signed integer of 3 or 5 to 7 bytes
+    ReducedSigned(u16),
+    F32,
+    F64,
+    Bool,
+    MacAddress,
+    Bytes(u16),
+    String(u16),
+    DateTimeSeconds,
+    DateTimeMilliseconds,
+    DateTimeMicroseconds,
+    DateTimeNanoseconds,
+    Ipv4Addr,
+    Ipv6Addr,
+    /// Read as `Bytes` with `lenient_field_lengths`, otherwise an error
+    InvalidLength(DataRecordType, u16),
+}
+
+impl FieldDecoder {
+    pub(crate) fn new(ty: DataRecordType, length: u16) -> Self {
+        // TODO: length shouldn't actually change the data type, technically
+        match (ty, length) {
+            (DataRecordType::UnsignedInt, 1) => FieldDecoder::U8,
+            (DataRecordType::UnsignedInt, 2) => FieldDecoder::U16,
+            (DataRecordType::UnsignedInt, 4) => FieldDecoder::U32,
+            (DataRecordType::UnsignedInt, 8) => FieldDecoder::U64,
+            (DataRecordType::UnsignedInt, 3 | 5..=7) => FieldDecoder::ReducedUnsigned(length),
+            (DataRecordType::SignedInt, 1) => FieldDecoder::I8,
+            (DataRecordType::SignedInt, 2) => FieldDecoder::I16,
+            (DataRecordType::SignedInt, 4) => FieldDecoder::I32,
+            (DataRecordType::SignedInt, 8) => FieldDecoder::I64,
+            (DataRecordType::SignedInt, 3 | 5..=7) => FieldDecoder::ReducedSigned(length),
+            // also a reduced-length float64
+            (DataRecordType::Float, 4) => FieldDecoder::F32,
+            (DataRecordType::Float, 8) => FieldDecoder::F64,
+            (DataRecordType::Bool, 1) => FieldDecoder::Bool,
+            (DataRecordType::MacAddress, 6) => FieldDecoder::MacAddress,
+            (DataRecordType::Bytes, _) => FieldDecoder::Bytes(length),
+            (DataRecordType::String, _) => FieldDecoder::String(length),
+            (DataRecordType::DateTimeSeconds, 4) => FieldDecoder::DateTimeSeconds,
+            (DataRecordType::DateTimeMilliseconds, 8) => FieldDecoder::DateTimeMilliseconds,
+            (DataRecordType::DateTimeMicroseconds, 8) => FieldDecoder::DateTimeMicroseconds,
+            (DataRecordType::DateTimeNanoseconds, 8) => FieldDecoder::DateTimeNanoseconds,
+            (DataRecordType::Ipv4Addr, 4) => FieldDecoder::Ipv4Addr,
+            (DataRecordType::Ipv6Addr, 16) => FieldDecoder::Ipv6Addr,
+            _ => FieldDecoder::InvalidLength(ty, length),
+        }
+    }
+
+    /// The number of bytes the field takes, or `u16::MAX` for a
+    /// variable length field
+    fn length(self, options: ParseOptions) -> u16 {
+        match self {
+            FieldDecoder::U8 | FieldDecoder::I8 | FieldDecoder::Bool => 1,
+            FieldDecoder::U16 | FieldDecoder::I16 => 2,
+            FieldDecoder::U32
+            | FieldDecoder::I32
+            | FieldDecoder::F32
+            | FieldDecoder::DateTimeSeconds
+            | FieldDecoder::Ipv4Addr => 4,
+            FieldDecoder::MacAddress => 6,
+            FieldDecoder::U64
+            | FieldDecoder::I64
+            | FieldDecoder::F64
+            | FieldDecoder::DateTimeMilliseconds
+            | FieldDecoder::DateTimeMicroseconds
+            | FieldDecoder::DateTimeNanoseconds => 8,
+            FieldDecoder::Ipv6Addr => 16,
+            FieldDecoder::ReducedUnsigned(length)
+            | FieldDecoder::ReducedSigned(length)
+            | FieldDecoder::Bytes(length)
+            | FieldDecoder::String(length) => length,
+            FieldDecoder::InvalidLength(_, length) if options.lenient_field_lengths => length,
+            // fails in `decode` before reading anything
+            FieldDecoder::InvalidLength(..) => 0,
+        }
+    }
+
+    /// Read the bytes of the field, including the length of a variable
+    /// length field, and `decode` them
+    pub(crate) fn read<R: Read + Seek>(
+        self,
+        reader: &mut R,
+        options: ParseOptions,
+    ) -> BinResult<DataRecordValue> {
+        let base = reader.stream_position()?;
+        let mut bytes = vec![];
+        let length = match self.length(options) {
+            u16::MAX => match u8::read(reader)? {
+                255 => {
+                    let length = u16::read_be(reader)?;
+                    bytes.push(255);
+                    bytes.extend(length.to_be_bytes());
+                    length
+                }
+                length => {
+                    bytes.push(length);
+                    length.into()
+                }
+            },
+            length => length,
+        };
+        let start = bytes.len();
+        bytes.resize(start + usize::from(length), 0);
+        reader.read_exact(&mut bytes[start..])?;
+        self.decode(&bytes, &mut 0, base, options)
+    }
+
+    /// Decode the big endian field at `*offset` in `bytes`, moving
+    /// `offset` past it. `base` is the position of `bytes` in the input,
+    /// for errors
+    pub(crate) fn decode(
+        self,
+        bytes: &[u8],
+        offset: &mut usize,
+        base: u64,
+        options: ParseOptions,
+    ) -> BinResult<DataRecordValue> {
+        Ok(match self {
+            FieldDecoder::U8 => DataRecordValue::U8(take_array::<1>(bytes, offset)?[0]),
+            FieldDecoder::U16 => {
+                DataRecordValue::U16(u16::from_be_bytes(take_array(bytes, offset)?))
+            }
+            FieldDecoder::U32 => {
+                DataRecordValue::U32(u32::from_be_bytes(take_array(bytes, offset)?))
+            }
+            FieldDecoder::U64 => {
+                DataRecordValue::U64(u64::from_be_bytes(take_array(bytes, offset)?))
+            }
+            FieldDecoder::ReducedUnsigned(length) => {
+                let value = decode_unsigned(take(bytes, offset, length.into())?);
+                if length == 3 {
+                    DataRecordValue::U32(value as u32)
+                } else {
+                    DataRecordValue::U64(value)
+                }
+            }
+            FieldDecoder::I8 => DataRecordValue::I8(i8::from_be_bytes(take_array(bytes, offset)?)),
+            FieldDecoder::I16 => {
+                DataRecordValue::I16(i16::from_be_bytes(take_array(bytes, offset)?))
+            }
+            FieldDecoder::I32 => {
+                DataRecordValue::I32(i32::from_be_bytes(take_array(bytes, offset)?))
+            }
+            FieldDecoder::I64 => {
+                DataRecordValue::I64(i64::from_be_bytes(take_array(bytes, offset)?))
+            }
+            FieldDecoder::ReducedSigned(length) => {
+                let shift = 64 - 8 * u32::from(length);
+                let value =
+                    (decode_unsigned(take(bytes, offset, length.into())?) << shift) as i64 >> shift;
+                if length == 3 {
+                    DataRecordValue::I32(value as i32)
+                } else {
+                    DataRecordValue::I64(value)
+                }
+            }
+            FieldDecoder::F32 => {
+                DataRecordValue::F32(f32::from_be_bytes(take_array(bytes, offset)?))
+            }
+            FieldDecoder::F64 => {
+                DataRecordValue::F64(f64::from_be_bytes(take_array(bytes, offset)?))
+            }
+            FieldDecoder::Bool => DataRecordValue::Bool(match take_array::<1>(bytes, offset)?[0] {
+                1 => true,
+                2 => false,
+                x if options.strict_booleans => {
+                    Err(IpfixError::InvalidBoolean(x).into_binrw_error(base + *offset as u64 - 1))?
+                }
+                _ => false,
+            }),
+            FieldDecoder::MacAddress => {
+                DataRecordValue::MacAddress(MacAddress(take_array(bytes, offset)?))
+            }
+            FieldDecoder::Bytes(length) => {
+                DataRecordValue::Bytes(take_variable_length(bytes, offset, length)?.to_vec())
+            }
+            FieldDecoder::String(length) => {
+                let field = take_variable_length(bytes, offset, length)?;
+                match std::str::from_utf8(field) {
+                    Ok(s) => DataRecordValue::String(s.to_string()),
+                    Err(e) => {
+                        return Err(binrw::Error::Custom {
+                            pos: base + *offset as u64,
+                            err: Box::new(e),
+                        });
+                    }
+                }
+            }
+            FieldDecoder::DateTimeSeconds => {
+                DataRecordValue::DateTimeSeconds(u32::from_be_bytes(take_array(bytes, offset)?))
+            }
+            FieldDecoder::DateTimeMilliseconds => DataRecordValue::DateTimeMilliseconds(
+                u64::from_be_bytes(take_array(bytes, offset)?),
+            ),
+            FieldDecoder::DateTimeMicroseconds => {
+                DataRecordValue::DateTimeMicroseconds(decode_ntp(take_array(bytes, offset)?))
+            }
+            FieldDecoder::DateTimeNanoseconds => {
+                DataRecordValue::DateTimeNanoseconds(decode_ntp(take_array(bytes, offset)?))
+            }
+            FieldDecoder::Ipv4Addr => {
+                DataRecordValue::Ipv4Addr(u32::from_be_bytes(take_array(bytes, offset)?).into())
+            }
+            FieldDecoder::Ipv6Addr => {
+                DataRecordValue::Ipv6Addr(u128::from_be_bytes(take_array(bytes, offset)?).into())
+            }
+            FieldDecoder::InvalidLength(ty, length) => {
+                if options.lenient_field_lengths {
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(?ty, length, "reading a field of the wrong length as bytes");
+                    return FieldDecoder::Bytes(length).decode(bytes, offset, base, options);
+                }
+                Err(IpfixError::InvalidFieldSpecLength { ty, length }
+                    .into_binrw_error(base + *offset as u64))?
+            }
+        })
+    }
+}
+
+/// `n` bytes of `bytes` at `*offset`, moving `offset` past them
+fn take<'b>(bytes: &'b [u8], offset: &mut usize, n: usize) -> BinResult<&'b [u8]> {
+    let taken = bytes
+        .get(*offset..*offset + n)
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+    *offset += n;
+    Ok(taken)
+}
+
+fn take_array<const N: usize>(bytes: &[u8], offset: &mut usize) -> BinResult<[u8; N]> {
+    Ok(take(bytes, offset, N)?.try_into().unwrap())
+}
+
+fn decode_ntp(bytes: [u8; 8]) -> NtpTimestamp {
+    NtpTimestamp {
+        seconds: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        fraction: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+    }
+}
+
+/// A big endian unsigned integer of up to 8 bytes
+fn decode_unsigned(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf[8 - bytes.len()..].copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
+}
+
+/// A field of `length`, or of the length before it for variable length
+/// fields
+fn take_variable_length<'b>(
+    bytes: &'b [u8],
+    offset: &mut usize,
+    length: u16,
+) -> BinResult<&'b [u8]> {
+    let length = match length {
+        u16::MAX => match take_array::<1>(bytes, offset)?[0] {
+            255 => u16::from_be_bytes(take_array(bytes, offset)?),
+            length => length.into(),
+        },
+        length => length,
+    };
+    take(bytes, offset, length.into())
+}
+
+/// The steps to decode the data records of a template, with the key of
+/// each field resolved up front. Built once when the template is
+/// inserted, see `Template::plan`
+#[derive(PartialEq, Clone, Debug)]
+pub struct DecodePlan {
+    fields: Vec<(DataRecordKey, FieldDecoder)>,
+    scope_field_count: usize,
+    record_length: Option<usize>,
+}
+
+impl DecodePlan {
+    pub fn new(
+        scope_field_specifiers: &[ExpandedFieldSpecifier],
+        field_specifiers: &[ExpandedFieldSpecifier],
+    ) -> Self {
+        Self {
+            fields: scope_field_specifiers
+                .iter()
+                .chain(field_specifiers)
+                .map(|field_spec| {
+                    (
+                        field_spec.name.clone(),
+                        FieldDecoder::new(field_spec.ty, field_spec.field_length),
+                    )
+                })
+                .collect(),
+            scope_field_count: scope_field_specifiers.len(),
+            record_length: scope_field_specifiers
+                .iter()
+                .chain(field_specifiers)
+                .map(|field_spec| match field_spec.field_length {
+                    u16::MAX => None,
+                    length => Some(usize::from(length)),
+                })
+                .sum(),
+        }
+    }
+
+    /// The length of every data record, if the template has no variable
+    /// length fields
+    pub fn record_length(&self) -> Option<usize> {
+        self.record_length
+    }
+
+    /// Read and decode one data record
+    pub fn read_record<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        options: ParseOptions,
+    ) -> BinResult<DataRecord> {
+        let mut scope_values =
+            DataRecordValues::with_capacity_and_hasher(self.scope_field_count, Default::default());
+        let mut values = DataRecordValues::with_capacity_and_hasher(
+            self.fields.len() - self.scope_field_count,
+            Default::default(),
+        );
+        for (i, (key, decoder)) in self.fields.iter().enumerate() {
+            let value = decoder.read(reader, options)?;
+            if i < self.scope_field_count {
+                scope_values.insert(key.clone(), value);
+            } else {
+                values.insert(key.clone(), value);
+            }
+        }
+        Ok(DataRecord {
+            values,
+            scope_values,
+        })
+    }
+
+    /// Decode the data record at `*offset` in `bytes`, moving `offset` to
+    /// its end. `base` is the position of `bytes` in the input, for errors
+    pub(crate) fn decode_record(
+        &self,
+        bytes: &[u8],
+        offset: &mut usize,
+        base: u64,
+        options: ParseOptions,
+    ) -> BinResult<DataRecord> {
+        let mut scope_values =
+            DataRecordValues::with_capacity_and_hasher(self.scope_field_count, Default::default());
+        let mut values = DataRecordValues::with_capacity_and_hasher(
+            self.fields.len() - self.scope_field_count,
+            Default::default(),
+        );
+        for (i, (key, decoder)) in self.fields.iter().enumerate() {
+            let value = decoder.decode(bytes, offset, base, options)?;
+            if i < self.scope_field_count {
+                scope_values.insert(key.clone(), value);
+            } else {
+                values.insert(key.clone(), value);
+            }
+        }
+        Ok(DataRecord {
+            values,
+            scope_values,
+        })
+    }
+}
@@ -0,0 +1,107 @@
+//! Looking up, filtering and projecting the values of data records
+
+use std::net::IpAddr;
+
+use crate::parser::{
+    DataRecord, DataRecordKey, DataRecordValue, DataRecordValues, MacAddress, Message, Records,
+};
+
+/// Typed getters, converting with the `TryFrom<&DataRecordValue>`
+/// implementations
+macro_rules! impl_typed_getters {
+    ($($name:ident => $ty:ty),+ $(,)?) => {
+        $(
+            #[doc = concat!("The value of `key` as ", stringify!($ty), ", if present and convertible")]
+            pub fn $name(&self, key: impl Into<DataRecordKey>) -> Option<$ty> {
+                self.get_as(key)
+            }
+        )+
+    };
+}
+
+impl DataRecord {
+    /// Look up a value in either the values or scope values
+    pub fn get(&self, key: impl Into<DataRecordKey>) -> Option<&DataRecordValue> {
+        self.get_key(&key.into())
+    }
+
+    fn get_key(&self, key: &DataRecordKey) -> Option<&DataRecordValue> {
+        self.values.get(key).or_else(|| self.scope_values.get(key))
+    }
+
+    /// Look up a value and convert it to `T`, returning None if it is
+    /// missing or can't be converted
+    pub fn get_as<T>(&self, key: impl Into<DataRecordKey>) -> Option<T>
+    where
+        T: for<'a> TryFrom<&'a DataRecordValue>,
+    {
+        T::try_from(self.get(key)?).ok()
+    }
+
+    impl_typed_getters! {
+        get_u8 => u8,
+        get_u16 => u16,
+        get_u32 => u32,
+        get_u64 => u64,
+        get_i64 => i64,
+        get_f64 => f64,
+        get_bool => bool,
+        get_mac_address => MacAddress,
+        get_ip_addr => IpAddr,
+    }
+
+    /// The value of a string field
+    pub fn get_str(&self, key: impl Into<DataRecordKey>) -> Option<&str> {
+        match self.get(key)? {
+            DataRecordValue::String(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// A record with only the values and scope values for `keys`
+    pub fn project(&self, keys: &[DataRecordKey]) -> DataRecord {
+        let select = |values: &DataRecordValues| {
+            values
+                .iter()
+                .filter(|(key, _)| keys.contains(key))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        };
+        DataRecord {
+            values: select(&self.values),
+            scope_values: select(&self.scope_values),
+        }
+    }
+}
+
+impl Message {
+    /// Data records for which `predicate` returns true
+    pub fn records_where<'a, F>(&'a self, mut predicate: F) -> impl Iterator<Item = &'a DataRecord>
+    where
+        F: FnMut(&DataRecord) -> bool + 'a,
+    {
+        self.iter_data_records()
+            .filter(move |record| predicate(record))
+    }
+
+    /// Data records described by the template `template_id`
+    pub fn records_for_template(&self, template_id: u16) -> impl Iterator<Item = &DataRecord> {
+        self.sets
+            .iter()
+            .filter_map(move |set| match &set.records {
+                Records::Data { set_id, data } if *set_id == template_id => Some(data),
+                _ => None,
+            })
+            .flatten()
+    }
+
+    /// The values of `keys` in each data record, in the same order as
+    /// `keys`
+    pub fn project<'a>(
+        &'a self,
+        keys: &'a [DataRecordKey],
+    ) -> impl Iterator<Item = Vec<Option<&'a DataRecordValue>>> {
+        self.iter_data_records()
+            .map(move |record| keys.iter().map(|key| record.get_key(key)).collect())
+    }
+}
@@ -0,0 +1,147 @@
+//! Mapping Rust structs to templates and data records, usually with
+//! `#[derive(IpfixRecord)]` from the `derive` feature
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::parser::{
+    DataRecord, DataRecordKey, DataRecordValue, FieldSpecifier, IpfixError, MacAddress, Records,
+    Set, TemplateRecord,
+};
+use crate::template_store::{ExpandedFieldSpecifier, Template};
+
+/// A struct with a field for each information element of a template
+pub trait IpfixRecord: Sized {
+    /// The field specifiers of the template, in order
+    fn field_specifiers() -> Vec<FieldSpecifier>;
+
+    /// Convert to a data record, named according to `template`
+    fn to_data_record(&self, template: &Template) -> Result<DataRecord, IpfixError>;
+
+    /// Convert from a data record read with `template`
+    fn from_data_record(record: &DataRecord, template: &Template) -> Result<Self, IpfixError>;
+
+    /// The template record describing this struct
+    fn template_record(template_id: u16) -> TemplateRecord {
+        TemplateRecord {
+            template_id,
+            field_specifiers: Self::field_specifiers().into_iter().collect(),
+        }
+    }
+
+    /// A data set of `records`, for the template `template_id`
+    fn data_set(
+        records: &[Self],
+        template_id: u16,
+        template: &Template,
+    ) -> Result<Set, IpfixError> {
+        Ok(Set {
+            records: Records::Data {
+                set_id: template_id,
+                data: records
+                    .iter()
+                    .map(|record| record.to_data_record(template))
+                    .collect::<Result<_, _>>()?,
+            },
+        })
+    }
+}
+
+/// Types that can be a field of an `IpfixRecord`
+pub trait IpfixValue: Into<DataRecordValue> + for<'a> TryFrom<&'a DataRecordValue> {
+    /// Field length used when none is given
+    const FIELD_LENGTH: u16;
+}
+
+macro_rules! impl_ipfix_value {
+    ($($ty:ty => $length:expr),+ $(,)?) => {
+        $(
+            impl IpfixValue for $ty {
+                const FIELD_LENGTH: u16 = $length;
+            }
+        )+
+    };
+}
+
+impl_ipfix_value! {
+    u8 => 1,
+    u16 => 2,
+    u32 => 4,
+    u64 => 8,
+    i8 => 1,
+    i16 => 2,
+    i32 => 4,
+    i64 => 8,
+    f32 => 4,
+    f64 => 8,
+    bool => 1,
+    MacAddress => 6,
+    String => u16::MAX,
+    Vec<u8> => u16::MAX,
+    Ipv4Addr => 4,
+    Ipv6Addr => 16,
+}
+
+/// The position and field specifier of an information element in
+/// `template`
+fn find_field_spec(
+    template: &Template,
+    enterprise_number: Option<u32>,
+    information_element_identifier: u16,
+) -> Result<(usize, &ExpandedFieldSpecifier), IpfixError> {
+    template
+        .field_specifiers()
+        .enumerate()
+        .find(|(_, field_spec)| {
+            field_spec.enterprise_number == enterprise_number
+                && field_spec.information_element_identifier == information_element_identifier
+        })
+        .ok_or_else(|| {
+            IpfixError::UnknownField(DataRecordKey::Unrecognized(FieldSpecifier::new(
+                enterprise_number,
+                information_element_identifier,
+                0,
+            )))
+        })
+}
+
+#[doc(hidden)]
+pub fn insert_value<T: IpfixValue>(
+    record: &mut DataRecord,
+    template: &Template,
+    enterprise_number: Option<u32>,
+    information_element_identifier: u16,
+    value: T,
+) -> Result<(), IpfixError> {
+    let (i, field_spec) =
+        find_field_spec(template, enterprise_number, information_element_identifier)?;
+    // scope fields of an options template go in `scope_values`
+    let values = if i < template.scope_field_specifiers().len() {
+        &mut record.scope_values
+    } else {
+        &mut record.values
+    };
+    values.insert(field_spec.name.clone(), value.into());
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn get_value<T: IpfixValue>(
+    record: &DataRecord,
+    template: &Template,
+    enterprise_number: Option<u32>,
+    information_element_identifier: u16,
+) -> Result<T, IpfixError> {
+    let (_, field_spec) =
+        find_field_spec(template, enterprise_number, information_element_identifier)?;
+    let key = &field_spec.name;
+    let value = record
+        .values
+        .get(key)
+        .or_else(|| record.scope_values.get(key))
+        .ok_or_else(|| IpfixError::MissingData(key.clone()))?;
+    T::try_from(value).map_err(|_| IpfixError::InvalidValueType {
+        key: key.clone(),
+        value: value.clone(),
+        ty: field_spec.ty,
+    })
+}
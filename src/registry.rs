@@ -0,0 +1,312 @@
+//! Information element definitions loaded at runtime, so deployments can
+//! add vendor elements to a `Formatter` without recompiling
+//!
+//! Supported formats are:
+//!
+//! - the iana registry csv, `ipfix-information-elements.csv` from
+//!   <https://www.iana.org/assignments/ipfix/ipfix.xhtml>, using its
+//!   `ElementID`, `Name` and `Abstract Data Type` columns (`csv` feature)
+//! - the iana registry xml, `ipfix.xml` from
+//!   <https://www.iana.org/assignments/ipfix/ipfix.xml> (`xml` feature)
+//! - a list of elements, as csv with `enterprise,id,name,type` columns
+//!   (`csv` feature), or as TOML (`toml` feature):
+//!
+//! ```toml
+//! [[element]]
+//! enterprise = 29305
+//! id = 1
+//! name = "exampleCounter"
+//! type = "unsigned64"
+//! ```
+//!
+//! Types are abstract data type names like `unsigned32` or `ipv4Address`.
+//! Loaded elements replace any with the same enterprise number and ID.
+
+#[cfg(any(feature = "csv", feature = "toml", feature = "xml"))]
+use std::borrow::Cow;
+#[cfg(feature = "csv")]
+use std::io::Read;
+
+#[cfg(any(feature = "csv", feature = "toml", feature = "xml"))]
+use crate::information_elements::{data_type_from_name, Formatter};
+#[cfg(any(feature = "csv", feature = "toml"))]
+use crate::parser::DataRecordType;
+
+#[derive(derive_more::Display, Debug)]
+pub enum RegistryError {
+    #[display(fmt = "Missing column {_0}")]
+    MissingColumn(&'static str),
+    #[display(fmt = "Missing registry {_0}")]
+    MissingRegistry(&'static str),
+    /// `element` counts from 1, in the order elements appear
+    #[display(fmt = "Invalid {field} for element {element}: {value:?}")]
+    InvalidValue {
+        element: usize,
+        field: &'static str,
+        value: String,
+    },
+    /// The input couldn't be parsed as csv, TOML or xml
+    #[display(fmt = "{_0}")]
+    Syntax(String),
+}
+
+impl std::error::Error for RegistryError {}
+
+#[cfg(feature = "csv")]
+impl From<csv::Error> for RegistryError {
+    fn from(e: csv::Error) -> Self {
+        RegistryError::Syntax(e.to_string())
+    }
+}
+
+#[cfg(feature = "toml")]
+impl From<toml::de::Error> for RegistryError {
+    fn from(e: toml::de::Error) -> Self {
+        RegistryError::Syntax(e.to_string())
+    }
+}
+
+#[cfg(feature = "xml")]
+impl From<roxmltree::Error> for RegistryError {
+    fn from(e: roxmltree::Error) -> Self {
+        RegistryError::Syntax(e.to_string())
+    }
+}
+
+/// Add the elements of an iana format registry csv to `formatter`, under
+/// `enterprise_number`. Ranges, unnamed elements and elements with
+/// unsupported types are skipped. Returns the number of elements added
+#[cfg(feature = "csv")]
+pub fn load_iana_csv<R: Read>(
+    reader: R,
+    enterprise_number: u32,
+    formatter: &mut Formatter,
+) -> Result<usize, RegistryError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers()?;
+    let column = |name| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or(RegistryError::MissingColumn(name))
+    };
+    let id_pos = column("ElementID")?;
+    let name_pos = column("Name")?;
+    let data_type_pos = column("Abstract Data Type")?;
+
+    let mut added = 0;
+    for result in csv_reader.records() {
+        let record = result?;
+        let (Ok(id), name, Some(ty)) = (
+            record[id_pos].parse::<u16>(),
+            &record[name_pos],
+            data_type_from_name(&record[data_type_pos]),
+        ) else {
+            continue;
+        };
+        if name.is_empty() {
+            continue;
+        }
+        formatter.insert((enterprise_number, id), (Cow::Owned(name.to_string()), ty));
+        added += 1;
+    }
+    Ok(added)
+}
+
+/// Update `formatter` from the information elements of the iana registry
+/// xml, so it includes elements assigned since this crate was built.
+/// Ranges and elements with unsupported types are skipped. Returns the
+/// (enterprise_number, information_element_identifier) of each element
+/// that was added or changed, like `learn_information_elements`
+#[cfg(feature = "xml")]
+pub fn load_iana_xml(
+    xml: &str,
+    formatter: &mut Formatter,
+) -> Result<Vec<(u32, u16)>, RegistryError> {
+    let document = roxmltree::Document::parse(xml)?;
+    let Some(registry) = document.descendants().find(|node| {
+        node.has_tag_name("registry") && node.attribute("id") == Some("ipfix-information-elements")
+    }) else {
+        return Err(RegistryError::MissingRegistry("ipfix-information-elements"));
+    };
+
+    let mut updated = vec![];
+    for record in registry
+        .children()
+        .filter(|node| node.has_tag_name("record"))
+    {
+        let text = |name| {
+            record
+                .children()
+                .find(|node| node.has_tag_name(name))
+                .and_then(|node| node.text())
+                .map(str::trim)
+        };
+        let (Some(Ok(id)), Some(name), Some(Some(ty))) = (
+            text("elementId").map(str::parse::<u16>),
+            text("name"),
+            text("dataType").map(data_type_from_name),
+        ) else {
+            continue;
+        };
+        let key = (0, id);
+        if formatter
+            .get(&key)
+            .is_some_and(|(old_name, old_ty)| old_name == name && *old_ty == ty)
+        {
+            continue;
+        }
+        formatter.insert(key, (Cow::Owned(name.to_string()), ty));
+        updated.push(key);
+    }
+    Ok(updated)
+}
+
+/// Add the elements of a csv with `enterprise,id,name,type` columns to
+/// `formatter`. Returns the number of elements added
+#[cfg(feature = "csv")]
+pub fn load_elements_csv<R: Read>(
+    reader: R,
+    formatter: &mut Formatter,
+) -> Result<usize, RegistryError> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let headers = csv_reader.headers()?;
+    let column = |name| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or(RegistryError::MissingColumn(name))
+    };
+    let enterprise_pos = column("enterprise")?;
+    let id_pos = column("id")?;
+    let name_pos = column("name")?;
+    let type_pos = column("type")?;
+
+    let mut elements = vec![];
+    for (i, result) in csv_reader.records().enumerate() {
+        let record = result?;
+        let element = i + 1;
+        let invalid = |field, value: &str| RegistryError::InvalidValue {
+            element,
+            field,
+            value: value.to_string(),
+        };
+        let enterprise = &record[enterprise_pos];
+        let enterprise_number = match enterprise {
+            "" => 0,
+            _ => enterprise
+                .parse()
+                .map_err(|_| invalid("enterprise", enterprise))?,
+        };
+        let id = record[id_pos]
+            .parse()
+            .map_err(|_| invalid("id", &record[id_pos]))?;
+        elements.push(element_definition(
+            element,
+            enterprise_number,
+            id,
+            &record[name_pos],
+            &record[type_pos],
+        )?);
+    }
+    Ok(insert_elements(formatter, elements))
+}
+
+/// Add the `[[element]]` tables of a TOML document to `formatter`.
+/// `enterprise` may be left out for iana elements. Returns the number of
+/// elements added
+#[cfg(feature = "toml")]
+pub fn load_elements_toml(toml: &str, formatter: &mut Formatter) -> Result<usize, RegistryError> {
+    let document: toml::Table = toml.parse()?;
+    let tables = match document.get("element") {
+        Some(toml::Value::Array(tables)) => tables.as_slice(),
+        Some(value) => {
+            return Err(RegistryError::InvalidValue {
+                element: 0,
+                field: "element",
+                value: value.to_string(),
+            })
+        }
+        None => &[],
+    };
+
+    let mut elements = vec![];
+    for (i, table) in tables.iter().enumerate() {
+        let element = i + 1;
+        let field = |name| -> Result<&toml::Value, RegistryError> {
+            table.get(name).ok_or_else(|| RegistryError::InvalidValue {
+                element,
+                field: name,
+                value: String::new(),
+            })
+        };
+        let invalid = |name, value: &toml::Value| RegistryError::InvalidValue {
+            element,
+            field: name,
+            value: value.to_string(),
+        };
+        let integer = |name| -> Result<i64, RegistryError> {
+            let value = field(name)?;
+            value.as_integer().ok_or_else(|| invalid(name, value))
+        };
+        let string = |name| -> Result<&str, RegistryError> {
+            let value = field(name)?;
+            value.as_str().ok_or_else(|| invalid(name, value))
+        };
+        let enterprise_number = match table.get("enterprise") {
+            Some(value) => value
+                .as_integer()
+                .and_then(|x| u32::try_from(x).ok())
+                .ok_or_else(|| invalid("enterprise", value))?,
+            None => 0,
+        };
+        let id = integer("id")?;
+        let id = u16::try_from(id).map_err(|_| invalid("id", &toml::Value::Integer(id)))?;
+        elements.push(element_definition(
+            element,
+            enterprise_number,
+            id,
+            string("name")?,
+            string("type")?,
+        )?);
+    }
+    Ok(insert_elements(formatter, elements))
+}
+
+/// Check one element of a list of elements
+#[cfg(any(feature = "csv", feature = "toml"))]
+fn element_definition(
+    element: usize,
+    enterprise_number: u32,
+    id: u16,
+    name: &str,
+    data_type: &str,
+) -> Result<((u32, u16), String, DataRecordType), RegistryError> {
+    let invalid = |field, value: &str| RegistryError::InvalidValue {
+        element,
+        field,
+        value: value.to_string(),
+    };
+    if name.is_empty() {
+        return Err(invalid("name", name));
+    }
+    let ty = data_type_from_name(data_type).ok_or_else(|| invalid("type", data_type))?;
+    Ok(((enterprise_number, id), name.to_string(), ty))
+}
+
+/// Add checked elements, so a list with an invalid element adds none
+#[cfg(any(feature = "csv", feature = "toml"))]
+fn insert_elements(
+    formatter: &mut Formatter,
+    elements: Vec<((u32, u16), String, DataRecordType)>,
+) -> usize {
+    let added = elements.len();
+    formatter.extend(
+        elements
+            .into_iter()
+            .map(|(key, name, ty)| (key, (Cow::Owned(name), ty))),
+    );
+    added
+}
@@ -0,0 +1,202 @@
+//! Mapping messages onto the streams of an SCTP association
+//! <https://www.rfc-editor.org/rfc/rfc7011#section-10.2>
+//!
+//! Each template is given a stream, and its Template Records and Data
+//! Records are sent on it, so that per-stream ordering keeps templates
+//! ahead of their data. Templates are always sent reliably, while data
+//! may be sent partially reliably (PR-SCTP, RFC 3758). On the collecting
+//! side all streams of an association share one `CollectorSession` peer
+//!
+//! `StreamMapper` plans which stream each part of a message goes on.
+//! With the `sctp` feature on Linux, `socket` sends and receives them on
+//! kernel SCTP associations
+
+#[cfg(all(feature = "sctp", target_os = "linux"))]
+pub mod socket;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::borrowed::record_count;
+use crate::parser::{
+    Message, OptionsTemplateRecord, Records, Set, TemplateRecord, OPTIONS_TEMPLATE_SET_ID,
+    TEMPLATE_SET_ID,
+};
+use crate::template_store::TemplateStorage;
+
+/// How a message is to be sent on its stream
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Reliability {
+    Reliable,
+    /// Abandoned if not delivered within `lifetime`
+    PartiallyReliable {
+        lifetime: Duration,
+    },
+}
+
+/// Part of a message, to be sent on `stream`
+#[derive(PartialEq, Clone, Debug)]
+pub struct SctpMessage {
+    pub stream: u16,
+    pub reliability: Reliability,
+    pub message: Message,
+}
+
+/// Assigns templates to the outbound streams of an association, and
+/// splits messages by stream
+#[derive(Debug)]
+pub struct StreamMapper {
+    streams: u16,
+    data_lifetime: Option<Duration>,
+    /// Stream of each (observation_domain_id, template_id)
+    assigned: HashMap<(u32, u16), u16>,
+    next_stream: u16,
+}
+
+impl StreamMapper {
+    /// Use `streams` outbound streams, which must be at least one
+    pub fn new(streams: u16) -> Self {
+        assert!(streams > 0, "an association has at least one stream");
+        Self {
+            streams,
+            data_lifetime: None,
+            assigned: HashMap::new(),
+            next_stream: 0,
+        }
+    }
+
+    /// Send data sets partially reliably, abandoning them after `lifetime`
+    pub fn data_lifetime(mut self, lifetime: Duration) -> Self {
+        self.data_lifetime = Some(lifetime);
+        self
+    }
+
+    /// The stream of `template_id`, assigning the next one round-robin
+    /// if it doesn't have one yet
+    pub fn stream_for(&mut self, observation_domain_id: u32, template_id: u16) -> u16 {
+        *self
+            .assigned
+            .entry((observation_domain_id, template_id))
+            .or_insert_with(|| {
+                let stream = self.next_stream;
+                self.next_stream = (self.next_stream + 1) % self.streams;
+                stream
+            })
+    }
+
+    /// Streams used by `observation_domain_id`, or just stream 0 if none
+    fn domain_streams(&self, observation_domain_id: u32) -> Vec<u16> {
+        let mut streams: Vec<u16> = self
+            .assigned
+            .iter()
+            .filter(|((domain, _), _)| *domain == observation_domain_id)
+            .map(|(_, stream)| *stream)
+            .collect();
+        streams.sort_unstable();
+        streams.dedup();
+        if streams.is_empty() {
+            streams.push(0);
+        }
+        streams
+    }
+
+    /// Where each record of a template set goes. Withdrawals of all
+    /// templates go on every stream of the domain, after which its
+    /// templates are reassigned
+    fn template_streams(&mut self, observation_domain_id: u32, template_id: u16) -> Vec<u16> {
+        if template_id == TEMPLATE_SET_ID || template_id == OPTIONS_TEMPLATE_SET_ID {
+            let streams = self.domain_streams(observation_domain_id);
+            self.assigned
+                .retain(|(domain, _), _| *domain != observation_domain_id);
+            streams
+        } else {
+            vec![self.stream_for(observation_domain_id, template_id)]
+        }
+    }
+
+    /// Split `message` into messages for each stream, in order.
+    /// Consecutive sets for the same stream stay in one message, and the
+    /// Sequence Number of each part counts the data records before it,
+    /// using `templates` to count the records of raw data sets
+    pub fn split(
+        &mut self,
+        message: &Message,
+        templates: &dyn TemplateStorage,
+    ) -> Vec<SctpMessage> {
+        let domain = message.observation_domain_id;
+        let mut parts: Vec<(u16, Reliability, Set)> = vec![];
+        for set in &message.sets {
+            match &set.records {
+                Records::Template(records) => {
+                    for record in records {
+                        for stream in self.template_streams(domain, record.template_id) {
+                            parts.push((stream, Reliability::Reliable, template_set(record)));
+                        }
+                    }
+                }
+                Records::OptionsTemplate(records) => {
+                    for record in records {
+                        for stream in self.template_streams(domain, record.template_id) {
+                            parts.push((
+                                stream,
+                                Reliability::Reliable,
+                                options_template_set(record),
+                            ));
+                        }
+                    }
+                }
+                Records::Data { set_id, .. } | Records::RawData { set_id, .. } => {
+                    let reliability = match self.data_lifetime {
+                        Some(lifetime) => Reliability::PartiallyReliable { lifetime },
+                        None => Reliability::Reliable,
+                    };
+                    parts.push((self.stream_for(domain, *set_id), reliability, set.clone()));
+                }
+                Records::Unsupported { .. } => {
+                    parts.push((0, Reliability::Reliable, set.clone()));
+                }
+            }
+        }
+
+        let mut messages: Vec<SctpMessage> = vec![];
+        let mut sequence_number = message.sequence_number;
+        for (stream, reliability, set) in parts {
+            let data_records = match &set.records {
+                Records::Data { data, .. } => data.len() as u32,
+                Records::RawData { set_id, bytes } => templates
+                    .get_template(*set_id)
+                    .map_or(0, |template| record_count(&template, bytes) as u32),
+                _ => 0,
+            };
+            match messages.last_mut() {
+                Some(last) if last.stream == stream && last.reliability == reliability => {
+                    last.message.sets.push(set);
+                }
+                _ => messages.push(SctpMessage {
+                    stream,
+                    reliability,
+                    message: Message {
+                        export_time: message.export_time,
+                        sequence_number,
+                        observation_domain_id: domain,
+                        sets: [set].into_iter().collect(),
+                    },
+                }),
+            }
+            sequence_number = sequence_number.wrapping_add(data_records);
+        }
+        messages
+    }
+}
+
+fn template_set(record: &TemplateRecord) -> Set {
+    Set {
+        records: Records::Template(vec![record.clone()]),
+    }
+}
+
+fn options_template_set(record: &OptionsTemplateRecord) -> Set {
+    Set {
+        records: Records::OptionsTemplate(vec![record.clone()]),
+    }
+}
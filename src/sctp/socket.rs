@@ -0,0 +1,384 @@
+//! Sending and receiving messages on SCTP associations, with the kernel
+//! SCTP of Linux. Associations are one-to-one style sockets, and each
+//! message is one SCTP message on its stream, sent partially reliably
+//! with the `SCTP_PR_SCTP_TTL` policy if its `Reliability` says so
+//! <https://www.rfc-editor.org/rfc/rfc6458>
+
+use std::io::{self, IoSlice};
+use std::mem::{size_of, MaybeUninit};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use binrw::BinResult;
+use socket2::{Domain, MaybeUninitSlice, MsgHdr, MsgHdrMut, Protocol, Socket, Type};
+
+use super::{Reliability, StreamMapper};
+use crate::collector::{Collected, CollectorSession};
+use crate::information_elements::Formatter;
+use crate::parser::{Message, ParseOptions, WriteOptions};
+use crate::template_store::TemplateStorage;
+
+/// The largest possible message, as the Length field is 16 bits
+const MAX_MESSAGE_LENGTH: usize = u16::MAX as usize;
+
+/// Room for the ancillary data of one send or receive
+const CONTROL_LENGTH: usize = 128;
+
+/// A one-to-one style SCTP socket, asking for `streams` streams each way
+fn sctp_socket(addr: &SocketAddr, streams: u16) -> io::Result<Socket> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::from(libc::IPPROTO_SCTP)),
+    )?;
+    let init = libc::sctp_initmsg {
+        sinit_num_ostreams: streams,
+        sinit_max_instreams: streams,
+        sinit_max_attempts: 0,
+        sinit_max_init_timeo: 0,
+    };
+    set_option(&socket, libc::SCTP_INITMSG, &init)?;
+    Ok(socket)
+}
+
+fn set_option<T>(socket: &Socket, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: `value` is a valid `T` of the given size for the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_SCTP,
+            name,
+            (value as *const T).cast(),
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Append an SCTP control message of type `ty` holding `data` to
+/// `control`
+fn push_control<T: Copy>(control: &mut Vec<u8>, ty: libc::c_int, data: &T) {
+    let offset = control.len();
+    // SAFETY: the CMSG macros only compute lengths
+    let (space, len) = unsafe {
+        (
+            libc::CMSG_SPACE(size_of::<T>() as u32) as usize,
+            libc::CMSG_LEN(size_of::<T>() as u32) as usize,
+        )
+    };
+    control.resize(offset + space, 0);
+    let header = libc::cmsghdr {
+        cmsg_len: len as _,
+        cmsg_level: libc::IPPROTO_SCTP,
+        cmsg_type: ty,
+    };
+    let data_offset = offset + len - size_of::<T>();
+    // SAFETY: both writes are within `control`, which was just resized
+    unsafe {
+        let ptr = control.as_mut_ptr();
+        std::ptr::write_unaligned(ptr.add(offset).cast(), header);
+        std::ptr::write_unaligned(ptr.add(data_offset).cast(), *data);
+    }
+}
+
+/// The stream in the `SCTP_RCVINFO` control message of `control`
+fn received_stream(control: &[u8]) -> Option<u16> {
+    // SAFETY: the CMSG macros only compute lengths
+    let header_len = unsafe { libc::CMSG_LEN(0) } as usize;
+    let mut offset = 0;
+    while offset + size_of::<libc::cmsghdr>() <= control.len() {
+        // SAFETY: the header is within `control`
+        let header: libc::cmsghdr =
+            unsafe { std::ptr::read_unaligned(control.as_ptr().add(offset).cast()) };
+        let len = header.cmsg_len as usize;
+        if len < header_len || offset + len > control.len() {
+            return None;
+        }
+        if header.cmsg_level == libc::IPPROTO_SCTP
+            && header.cmsg_type == libc::SCTP_RCVINFO
+            && len - header_len >= size_of::<libc::sctp_rcvinfo>()
+        {
+            // SAFETY: the data is within `control`, and large enough
+            let info: libc::sctp_rcvinfo = unsafe {
+                std::ptr::read_unaligned(control.as_ptr().add(offset + header_len).cast())
+            };
+            return Some(info.rcv_sid);
+        }
+        // SAFETY: the CMSG macros only compute lengths
+        offset += unsafe { libc::CMSG_SPACE((len - header_len) as u32) } as usize;
+    }
+    None
+}
+
+/// One end of an SCTP association
+#[derive(Debug)]
+pub struct SctpStream {
+    socket: Socket,
+}
+
+impl SctpStream {
+    fn new(socket: Socket) -> io::Result<Self> {
+        // report the stream of each message received
+        set_option(&socket, libc::SCTP_RECVRCVINFO, &1 as &libc::c_int)?;
+        Ok(Self { socket })
+    }
+
+    /// Open an association to `addr` with up to `streams` streams
+    pub fn connect(addr: SocketAddr, streams: u16) -> io::Result<Self> {
+        let socket = sctp_socket(&addr, streams)?;
+        socket.connect(&addr.into())?;
+        Self::new(socket)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket
+            .peer_addr()?
+            .as_socket()
+            .ok_or_else(|| io::ErrorKind::Unsupported.into())
+    }
+
+    /// Send `bytes` as one message on `stream`
+    pub fn send(&self, bytes: &[u8], stream: u16, reliability: Reliability) -> io::Result<()> {
+        let mut control = Vec::with_capacity(CONTROL_LENGTH);
+        let info = libc::sctp_sndinfo {
+            snd_sid: stream,
+            snd_flags: 0,
+            snd_ppid: 0,
+            snd_context: 0,
+            snd_assoc_id: 0,
+        };
+        push_control(&mut control, libc::SCTP_SNDINFO, &info);
+        if let Reliability::PartiallyReliable { lifetime } = reliability {
+            let info = libc::sctp_prinfo {
+                pr_policy: libc::SCTP_PR_SCTP_TTL as u16,
+                pr_value: lifetime.as_millis().try_into().unwrap_or(u32::MAX),
+            };
+            push_control(&mut control, libc::SCTP_PRINFO, &info);
+        }
+        let buffers = [IoSlice::new(bytes)];
+        let message = MsgHdr::new().with_buffers(&buffers).with_control(&control);
+        let sent = self.socket.sendmsg(&message, 0)?;
+        if sent != bytes.len() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        Ok(())
+    }
+
+    /// Receive the next message into `buf`, returning its stream, or
+    /// `None` once the association is shut down
+    pub fn recv(&self, buf: &mut Vec<u8>) -> io::Result<Option<u16>> {
+        buf.resize(MAX_MESSAGE_LENGTH, 0);
+        let mut control = [MaybeUninit::<u8>::uninit(); CONTROL_LENGTH];
+        // SAFETY: initialized bytes are valid `MaybeUninit<u8>`s
+        let data: &mut [MaybeUninit<u8>] =
+            unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast(), buf.len()) };
+        let mut buffers = [MaybeUninitSlice::new(data)];
+        let mut message = MsgHdrMut::new()
+            .with_buffers(&mut buffers)
+            .with_control(&mut control);
+        let received = self.socket.recvmsg(&mut message, 0)?;
+        let (flags, control_len) = (message.flags(), message.control_len());
+        if received == 0 {
+            buf.clear();
+            return Ok(None);
+        }
+        if !flags.is_end_of_record() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SCTP message longer than the maximum message length",
+            ));
+        }
+        buf.truncate(received);
+        // SAFETY: the kernel initialized the first `control_len` bytes
+        let control: &[u8] =
+            unsafe { std::slice::from_raw_parts(control.as_ptr().cast(), control_len) };
+        Ok(Some(received_stream(control).unwrap_or(0)))
+    }
+}
+
+/// Listens for SCTP associations
+#[derive(Debug)]
+pub struct SctpListener {
+    socket: Socket,
+}
+
+impl SctpListener {
+    /// Accept associations on `addr` with up to `streams` streams
+    pub fn bind(addr: impl ToSocketAddrs, streams: u16) -> io::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
+        let socket = sctp_socket(&addr, streams)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(128)?;
+        Ok(Self { socket })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn accept(&self) -> io::Result<(SctpStream, SocketAddr)> {
+        let (socket, peer) = self.socket.accept()?;
+        let peer = peer
+            .as_socket()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))?;
+        Ok((SctpStream::new(socket)?, peer))
+    }
+}
+
+/// Sends messages on an association, each part on the stream a
+/// `StreamMapper` assigns
+#[derive(Debug)]
+pub struct SctpExporter {
+    stream: SctpStream,
+    mapper: StreamMapper,
+}
+
+impl SctpExporter {
+    pub fn new(stream: SctpStream, mapper: StreamMapper) -> Self {
+        Self { stream, mapper }
+    }
+
+    pub fn connect(addr: SocketAddr, mapper: StreamMapper) -> io::Result<Self> {
+        let stream = SctpStream::connect(addr, mapper.streams)?;
+        Ok(Self::new(stream, mapper))
+    }
+
+    pub fn stream(&self) -> &SctpStream {
+        &self.stream
+    }
+
+    /// Split `message` by stream and send each part, encoding them with
+    /// `templates`
+    pub fn send_message(
+        &mut self,
+        message: &Message,
+        templates: &dyn TemplateStorage,
+        formatter: &Formatter,
+        options: WriteOptions,
+    ) -> BinResult<()> {
+        for part in self.mapper.split(message, templates) {
+            let bytes = part.message.to_bytes(templates, formatter, options)?;
+            self.stream.send(&bytes, part.stream, part.reliability)?;
+        }
+        Ok(())
+    }
+}
+
+/// Something that happened on an `SctpCollector`
+#[derive(Debug)]
+pub enum SctpEvent {
+    Connected(SocketAddr),
+    /// A message from `peer` on `stream`, and the result of decoding it
+    Message {
+        peer: SocketAddr,
+        stream: u16,
+        result: BinResult<Collected>,
+    },
+    /// The association closed, and its templates were dropped
+    Disconnected(SocketAddr),
+}
+
+/// Accepts associations from many Exporters, reading each in its own
+/// thread. All streams of an association share one `CollectorSession`
+/// peer, and templates last until the association closes (RFC 7011
+/// section 10.2)
+pub struct SctpCollector {
+    listener: SctpListener,
+    formatter: Formatter,
+    options: ParseOptions,
+}
+
+impl SctpCollector {
+    pub fn new(listener: SctpListener, formatter: Formatter) -> Self {
+        Self {
+            listener,
+            formatter,
+            options: ParseOptions::default(),
+        }
+    }
+
+    pub fn bind(addr: impl ToSocketAddrs, streams: u16, formatter: Formatter) -> io::Result<Self> {
+        Ok(Self::new(SctpListener::bind(addr, streams)?, formatter))
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept associations, sending the events of all of them to
+    /// `sender`. Returns when accepting fails, or on the next association
+    /// after the receiver is dropped
+    pub fn run(&self, sender: Sender<SctpEvent>) -> io::Result<()> {
+        loop {
+            let (stream, peer) = self.listener.accept()?;
+            if sender.send(SctpEvent::Connected(peer)).is_err() {
+                return Ok(());
+            }
+            let formatter = self.formatter.clone();
+            let options = self.options;
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                let session = CollectorSession::new(formatter, Duration::MAX).options(options);
+                send_messages(&stream, peer, session, &sender);
+                let _ = sender.send(SctpEvent::Disconnected(peer));
+            });
+        }
+    }
+
+    /// `run` in a new thread
+    pub fn spawn(self, sender: Sender<SctpEvent>) -> JoinHandle<io::Result<()>> {
+        std::thread::spawn(move || self.run(sender))
+    }
+}
+
+/// Send the messages of an association to `sender`, until it closes,
+/// fails, or the receiver is dropped
+fn send_messages(
+    stream: &SctpStream,
+    peer: SocketAddr,
+    mut session: CollectorSession<SocketAddr>,
+    sender: &Sender<SctpEvent>,
+) {
+    let mut buf = vec![];
+    loop {
+        let event = match stream.recv(&mut buf) {
+            Ok(Some(stream_id)) => SctpEvent::Message {
+                peer,
+                stream: stream_id,
+                result: session.handle_datagram(peer, &buf),
+            },
+            Ok(None) => return,
+            Err(err) => {
+                let result = Err(err.into());
+                let _ = sender.send(SctpEvent::Message {
+                    peer,
+                    stream: 0,
+                    result,
+                });
+                return;
+            }
+        };
+        if sender.send(event).is_err() {
+            return;
+        }
+    }
+}
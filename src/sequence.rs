@@ -0,0 +1,195 @@
+//! Checking Sequence Numbers of received messages, to detect lost,
+//! duplicated and reordered data records
+//! <https://www.rfc-editor.org/rfc/rfc7011#section-3.1>
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::parser::Message;
+
+/// How a message's Sequence Number compares to the one expected
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum SequenceEvent {
+    /// The first message from this peer and Observation Domain
+    First,
+    /// The expected Sequence Number
+    InOrder,
+    /// Ahead of the expected Sequence Number, so `missing` data records
+    /// were lost (or are still to arrive, out of order)
+    Gap {
+        expected: u32,
+        received: u32,
+        missing: u32,
+    },
+    /// Behind the expected Sequence Number, so a repeated or late message
+    Duplicate { expected: u32, received: u32 },
+    /// Too far from the expected Sequence Number to be loss or
+    /// reordering, such as after the Exporting Process restarted
+    Reset { expected: u32, received: u32 },
+}
+
+/// Counts for one peer and Observation Domain, or a total of them
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct SequenceStatistics {
+    pub messages: u64,
+    pub data_records: u64,
+    /// Data records skipped over by gaps
+    pub lost_data_records: u64,
+    pub gaps: u64,
+    pub duplicates: u64,
+    pub resets: u64,
+    /// Message octets, by the Length in their headers. Only counted by
+    /// `CollectorSession`, which has the encoded messages
+    pub octets: u64,
+}
+
+impl SequenceStatistics {
+    /// Fraction of data records lost, from 0 to 1
+    pub fn loss_ratio(&self) -> f64 {
+        let total = self.data_records + self.lost_data_records;
+        if total == 0 {
+            return 0.0;
+        }
+        self.lost_data_records as f64 / total as f64
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.messages += other.messages;
+        self.data_records += other.data_records;
+        self.lost_data_records += other.lost_data_records;
+        self.gaps += other.gaps;
+        self.duplicates += other.duplicates;
+        self.resets += other.resets;
+        self.octets += other.octets;
+    }
+}
+
+#[derive(Debug)]
+struct SequenceState {
+    expected: u32,
+    statistics: SequenceStatistics,
+}
+
+/// Tracks the Sequence Numbers of messages from each (peer,
+/// observation_domain_id) pair. The Sequence Number of each message
+/// should be that of the previous message plus its number of data
+/// records, modulo 2^32
+#[derive(Debug)]
+pub struct SequenceTracker<P> {
+    streams: HashMap<(P, u32), SequenceState>,
+    max_gap: u32,
+}
+
+impl<P: Hash + Eq> Default for SequenceTracker<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Hash + Eq> SequenceTracker<P> {
+    pub fn new() -> Self {
+        Self {
+            streams: HashMap::new(),
+            max_gap: 1 << 20,
+        }
+    }
+
+    /// Treat Sequence Numbers more than `max_gap` data records ahead of
+    /// or behind the expected one as a reset, rather than a gap or
+    /// duplicate
+    pub fn max_gap(mut self, max_gap: u32) -> Self {
+        self.max_gap = max_gap;
+        self
+    }
+
+    /// Check the Sequence Number of `message`, received from `peer`
+    pub fn observe(&mut self, peer: P, message: &Message) -> SequenceEvent {
+        let received = message.sequence_number;
+        let data_records = message.iter_data_records().count() as u32;
+
+        let mut first = false;
+        let state = self
+            .streams
+            .entry((peer, message.observation_domain_id))
+            .or_insert_with(|| {
+                first = true;
+                SequenceState {
+                    expected: received,
+                    statistics: SequenceStatistics::default(),
+                }
+            });
+        let expected = state.expected;
+        let ahead = received.wrapping_sub(expected);
+        let behind = expected.wrapping_sub(received);
+        let event = if first {
+            SequenceEvent::First
+        } else if ahead == 0 {
+            SequenceEvent::InOrder
+        } else if ahead <= self.max_gap {
+            SequenceEvent::Gap {
+                expected,
+                received,
+                missing: ahead,
+            }
+        } else if behind <= self.max_gap {
+            SequenceEvent::Duplicate { expected, received }
+        } else {
+            SequenceEvent::Reset { expected, received }
+        };
+
+        let statistics = &mut state.statistics;
+        statistics.messages += 1;
+        statistics.data_records += u64::from(data_records);
+        match event {
+            SequenceEvent::Gap { missing, .. } => {
+                statistics.gaps += 1;
+                statistics.lost_data_records += u64::from(missing);
+            }
+            SequenceEvent::Duplicate { .. } => statistics.duplicates += 1,
+            SequenceEvent::Reset { .. } => statistics.resets += 1,
+            SequenceEvent::First | SequenceEvent::InOrder => {}
+        }
+        // a late message doesn't move the expected Sequence Number back
+        if !matches!(event, SequenceEvent::Duplicate { .. }) {
+            state.expected = received.wrapping_add(data_records);
+        }
+        event
+    }
+
+    /// Count `octets` for the last message from `peer` with
+    /// `observation_domain_id`
+    pub(crate) fn add_octets(&mut self, peer: P, observation_domain_id: u32, octets: u64) {
+        if let Some(state) = self.streams.get_mut(&(peer, observation_domain_id)) {
+            state.statistics.octets += octets;
+        }
+    }
+
+    /// Counts for messages from `peer` with `observation_domain_id`
+    pub fn statistics(&self, peer: P, observation_domain_id: u32) -> Option<SequenceStatistics> {
+        self.streams
+            .get(&(peer, observation_domain_id))
+            .map(|state| state.statistics)
+    }
+
+    /// Counts for all peers and Observation Domains
+    pub fn total_statistics(&self) -> SequenceStatistics {
+        let mut total = SequenceStatistics::default();
+        for state in self.streams.values() {
+            total.add(&state.statistics);
+        }
+        total
+    }
+
+    /// Stop tracking `peer` and `observation_domain_id`, such as when its
+    /// Transport Session ends. Returns its final counts
+    pub fn remove(&mut self, peer: P, observation_domain_id: u32) -> Option<SequenceStatistics> {
+        self.streams
+            .remove(&(peer, observation_domain_id))
+            .map(|state| state.statistics)
+    }
+
+    /// Stop tracking all Observation Domains of `peer`
+    pub fn remove_peer(&mut self, peer: &P) {
+        self.streams.retain(|(p, _), _| p != peer);
+    }
+}
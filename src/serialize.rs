@@ -0,0 +1,62 @@
+//! serde implementations for types that need a custom representation
+//!
+//! `DataRecordKey`s are written as strings, so records can be maps in
+//! formats like JSON: either the information element name, or
+//! `"{enterprise_number}:{information_element_identifier}:{field_length}"`
+//! for unrecognized fields, with enterprise number 0 meaning none
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::parser::{DataRecordKey, FieldSpecifier, MacAddress};
+
+const ERR_PREFIX: &str = "error:";
+
+impl Serialize for DataRecordKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DataRecordKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        if let Some(err) = key.strip_prefix(ERR_PREFIX) {
+            return Ok(DataRecordKey::Err(err.to_string()));
+        }
+        if key.starts_with(|c: char| c.is_ascii_digit()) {
+            let parts = key
+                .split(':')
+                .map(str::parse)
+                .collect::<Result<Vec<u32>, _>>()
+                .map_err(de::Error::custom)?;
+            let [enterprise_number, id, length] = parts[..] else {
+                return Err(de::Error::invalid_value(
+                    de::Unexpected::Str(&key),
+                    &"enterprise_number:information_element_identifier:field_length",
+                ));
+            };
+            let to_u16 = |x: u32| u16::try_from(x).map_err(de::Error::custom);
+            return Ok(DataRecordKey::Unrecognized(FieldSpecifier::new(
+                Some(enterprise_number).filter(|&e| e != 0),
+                to_u16(id)?,
+                to_u16(length)?,
+            )));
+        }
+        Ok(DataRecordKey::Owned(key.into()))
+    }
+}
+
+/// Written as "aa:bb:cc:dd:ee:ff"
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
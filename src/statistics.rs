@@ -0,0 +1,142 @@
+//! Options templates and data records for exporting statistics about
+//! the Metering and Exporting Processes, with counts from
+//! `Exporter::statistics` and `Exporter::not_sent_statistics`, or from
+//! `CollectorSession::metering_statistics` for what was received
+//! <https://www.rfc-editor.org/rfc/rfc7011#section-4>
+
+use crate::parser::{
+    DataRecord, DataRecordKey, DataRecordValue, DataRecordValues, FieldSpecifier,
+    OptionsTemplateRecord, Records, Set,
+};
+
+/// Metering Process Statistics, scoped to an Observation Domain
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-4.1>
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct MeteringProcessStatistics {
+    pub observation_domain_id: u32,
+    pub exported_message_total_count: u64,
+    pub exported_flow_record_total_count: u64,
+    pub exported_octet_total_count: u64,
+}
+
+impl MeteringProcessStatistics {
+    /// The options template describing these statistics
+    pub fn options_template(template_id: u16) -> OptionsTemplateRecord {
+        OptionsTemplateRecord {
+            template_id,
+            scope_field_count: 1,
+            field_specifiers: [
+                // observationDomainId
+                FieldSpecifier::new(None, 149, 4),
+                // exportedMessageTotalCount
+                FieldSpecifier::new(None, 41, 8),
+                // exportedFlowRecordTotalCount
+                FieldSpecifier::new(None, 42, 8),
+                // exportedOctetTotalCount
+                FieldSpecifier::new(None, 40, 8),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    pub fn data_record(&self) -> DataRecord {
+        statistics_record(
+            ("observationDomainId", self.observation_domain_id),
+            [
+                (
+                    "exportedMessageTotalCount",
+                    self.exported_message_total_count,
+                ),
+                (
+                    "exportedFlowRecordTotalCount",
+                    self.exported_flow_record_total_count,
+                ),
+                ("exportedOctetTotalCount", self.exported_octet_total_count),
+            ],
+        )
+    }
+
+    /// A data set with the current statistics, for the options
+    /// template `template_id` from `options_template`
+    pub fn data_set(&self, template_id: u16) -> Set {
+        Set {
+            records: Records::Data {
+                set_id: template_id,
+                data: vec![self.data_record()],
+            },
+        }
+    }
+}
+
+/// Exporting Process Reliability Statistics, scoped to an Exporting Process
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-4.3>
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct ExportingProcessStatistics {
+    pub exporting_process_id: u32,
+    pub not_sent_flow_total_count: u64,
+    pub not_sent_packet_total_count: u64,
+    pub not_sent_octet_total_count: u64,
+}
+
+impl ExportingProcessStatistics {
+    /// The options template describing these statistics
+    pub fn options_template(template_id: u16) -> OptionsTemplateRecord {
+        OptionsTemplateRecord {
+            template_id,
+            scope_field_count: 1,
+            field_specifiers: [
+                // exportingProcessId
+                FieldSpecifier::new(None, 144, 4),
+                // notSentFlowTotalCount
+                FieldSpecifier::new(None, 166, 8),
+                // notSentPacketTotalCount
+                FieldSpecifier::new(None, 167, 8),
+                // notSentOctetTotalCount
+                FieldSpecifier::new(None, 168, 8),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    pub fn data_record(&self) -> DataRecord {
+        statistics_record(
+            ("exportingProcessId", self.exporting_process_id),
+            [
+                ("notSentFlowTotalCount", self.not_sent_flow_total_count),
+                ("notSentPacketTotalCount", self.not_sent_packet_total_count),
+                ("notSentOctetTotalCount", self.not_sent_octet_total_count),
+            ],
+        )
+    }
+
+    /// A data set with the current statistics, for the options
+    /// template `template_id` from `options_template`
+    pub fn data_set(&self, template_id: u16) -> Set {
+        Set {
+            records: Records::Data {
+                set_id: template_id,
+                data: vec![self.data_record()],
+            },
+        }
+    }
+}
+
+fn statistics_record<const N: usize>(
+    (scope_name, scope_value): (&'static str, u32),
+    counters: [(&'static str, u64); N],
+) -> DataRecord {
+    let mut scope_values = DataRecordValues::with_capacity_and_hasher(1, Default::default());
+    scope_values.insert(
+        DataRecordKey::Str(scope_name),
+        DataRecordValue::U32(scope_value),
+    );
+    DataRecord {
+        values: counters
+            .into_iter()
+            .map(|(name, count)| (DataRecordKey::Str(name), DataRecordValue::U64(count)))
+            .collect(),
+        scope_values,
+    }
+}
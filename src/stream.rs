@@ -0,0 +1,169 @@
+//! Reading and writing messages one at a time on a stream, such as a
+//! TCP socket, pipe or file
+
+use std::io::{Cursor, ErrorKind, Read, Write};
+
+use binrw::{BinRead, BinResult};
+
+use crate::information_elements::Formatter;
+use crate::parser::{Message, ParseOptions, WriteOptions};
+use crate::template_store::TemplateStorage;
+
+/// Iterator over the messages read from `reader`, framed by the version
+/// and length at the start of each message header. Only one message is
+/// held in memory at a time. Ends at the end of `reader`, or after the
+/// first error
+pub struct MessageStream<'a, R> {
+    reader: R,
+    templates: &'a dyn TemplateStorage,
+    formatter: &'a Formatter,
+    options: ParseOptions,
+    /// Bytes read from `reader` so far, for error positions
+    position: u64,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<'a, R: Read> MessageStream<'a, R> {
+    pub fn new(reader: R, templates: &'a dyn TemplateStorage, formatter: &'a Formatter) -> Self {
+        Self {
+            reader,
+            templates,
+            formatter,
+            options: ParseOptions::default(),
+            position: 0,
+            buf: vec![],
+            done: false,
+        }
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for MessageStream<'_, R> {
+    type Item = BinResult<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = match read_message_frame(&mut self.reader, &mut self.buf, self.position) {
+            Ok(false) => {
+                self.done = true;
+                return None;
+            }
+            Ok(true) => {
+                self.position += self.buf.len() as u64;
+                Message::read_args(
+                    &mut Cursor::new(&self.buf),
+                    (self.templates, self.formatter, self.options),
+                )
+            }
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            self.done = true;
+        }
+        Some(result)
+    }
+}
+
+/// Check the version and length at the start of a message header,
+/// returning the length. `position` is only used for errors
+pub(crate) fn message_length(header: [u8; 4], position: u64) -> BinResult<u16> {
+    let version = u16::from_be_bytes([header[0], header[1]]);
+    if version != 10 {
+        return Err(binrw::Error::BadMagic {
+            pos: position,
+            found: Box::new(version),
+        });
+    }
+    let length = u16::from_be_bytes([header[2], header[3]]);
+    if length < 16 {
+        return Err(binrw::Error::AssertFail {
+            pos: position,
+            message: format!("invalid message length: [{length} < 16]"),
+        });
+    }
+    Ok(length)
+}
+
+/// Read the next message from `reader` into `buf`, framed by the length
+/// in its header, or return false at the end of the stream. `position`
+/// is only used for errors
+pub(crate) fn read_message_frame(
+    reader: &mut impl Read,
+    buf: &mut Vec<u8>,
+    position: u64,
+) -> BinResult<bool> {
+    let mut header = [0; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let length = message_length(header, position)?;
+    buf.clear();
+    buf.extend_from_slice(&header);
+    buf.resize(length.into(), 0);
+    reader.read_exact(&mut buf[header.len()..])?;
+    Ok(true)
+}
+
+/// Writes messages to `writer`, which doesn't need to implement `Seek`.
+/// Each message is encoded into a buffer first, to fill in its lengths,
+/// and then written in one piece
+pub struct MessageWriter<'a, W> {
+    writer: W,
+    templates: &'a dyn TemplateStorage,
+    formatter: &'a Formatter,
+    options: WriteOptions,
+    buf: Vec<u8>,
+}
+
+impl<'a, W: Write> MessageWriter<'a, W> {
+    pub fn new(writer: W, templates: &'a dyn TemplateStorage, formatter: &'a Formatter) -> Self {
+        Self {
+            writer,
+            templates,
+            formatter,
+            options: WriteOptions::default(),
+            buf: vec![],
+        }
+    }
+
+    /// Write messages with `options`
+    pub fn options(mut self, options: WriteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Encode and write `message`, adding any templates it defines
+    pub fn write(&mut self, message: &Message) -> BinResult<()> {
+        message.write_into(&mut self.buf, self.templates, self.formatter, self.options)?;
+        self.writer.write_all(&self.buf)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> BinResult<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
@@ -1,18 +1,31 @@
 use std::{
+    borrow::Cow,
     cell::RefCell,
     collections::HashMap,
+    hash::Hash,
     rc::Rc,
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use binrw::{
+    io::{Cursor, Read, Seek, Write},
+    BinRead, BinResult, BinWrite,
 };
 
 use crate::{
+    export::ExportSession,
     information_elements::Formatter,
     parser::{
-        DataRecordKey, DataRecordType, FieldSpecifier, OptionsTemplateRecord, TemplateRecord,
+        DataRecordKey, DataRecordType, FieldSpecifier, IpfixError, Message, OptionsTemplateRecord,
+        ParseOptions, Records, Set, Sets, TemplateRecord, WriteOptions, OPTIONS_TEMPLATE_SET_ID,
+        TEMPLATE_SET_ID,
     },
+    plan::DecodePlan,
+    stream::read_message_frame,
 };
 
-#[derive(Clone, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub struct ExpandedFieldSpecifier {
     pub name: DataRecordKey,
     pub ty: DataRecordType,
@@ -30,7 +43,8 @@ impl ExpandedFieldSpecifier {
             field_spec.enterprise_number.unwrap_or(0),
             field_spec.information_element_identifier,
         )) {
-            Some((name, ty)) => (DataRecordKey::Str(name), ty),
+            Some((Cow::Borrowed(name), ty)) => (DataRecordKey::Str(name), ty),
+            Some((Cow::Owned(name), ty)) => (DataRecordKey::Owned(name.as_str().into()), ty),
             None => (
                 DataRecordKey::Unrecognized(field_spec.clone()),
                 // TODO: this is probably not technically correct
@@ -48,19 +62,142 @@ impl ExpandedFieldSpecifier {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum Template {
-    Template(Vec<ExpandedFieldSpecifier>),
-    OptionsTemplate(Vec<ExpandedFieldSpecifier>),
+    Template {
+        field_specifiers: Vec<ExpandedFieldSpecifier>,
+        /// Compiled from the field specifiers by `Template::new`
+        plan: Arc<DecodePlan>,
+    },
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-3.4.2.2>
+    OptionsTemplate {
+        /// Fields identifying what the options data describes, which
+        /// come first in each record
+        scope_field_specifiers: Vec<ExpandedFieldSpecifier>,
+        field_specifiers: Vec<ExpandedFieldSpecifier>,
+        /// Compiled from the field specifiers by `Template::options`
+        plan: Arc<DecodePlan>,
+    },
+}
+
+impl Template {
+    pub fn new(field_specifiers: Vec<ExpandedFieldSpecifier>) -> Self {
+        Template::Template {
+            plan: Arc::new(DecodePlan::new(&[], &field_specifiers)),
+            field_specifiers,
+        }
+    }
+
+    pub fn options(
+        scope_field_specifiers: Vec<ExpandedFieldSpecifier>,
+        field_specifiers: Vec<ExpandedFieldSpecifier>,
+    ) -> Self {
+        Template::OptionsTemplate {
+            plan: Arc::new(DecodePlan::new(&scope_field_specifiers, &field_specifiers)),
+            scope_field_specifiers,
+            field_specifiers,
+        }
+    }
+
+    /// The plan to decode data records with this template
+    pub fn plan(&self) -> &DecodePlan {
+        match self {
+            Template::Template { plan, .. } | Template::OptionsTemplate { plan, .. } => plan,
+        }
+    }
+
+    /// All field specifiers in the order they appear in data records,
+    /// starting with any scope fields
+    pub fn field_specifiers(&self) -> impl Iterator<Item = &ExpandedFieldSpecifier> {
+        let (scope_field_specifiers, field_specifiers) = match self {
+            Template::Template {
+                field_specifiers, ..
+            } => (&[][..], field_specifiers),
+            Template::OptionsTemplate {
+                scope_field_specifiers,
+                field_specifiers,
+                ..
+            } => (&scope_field_specifiers[..], field_specifiers),
+        };
+        scope_field_specifiers.iter().chain(field_specifiers)
+    }
+
+    /// Scope field specifiers, which are always empty for a non-options template
+    pub fn scope_field_specifiers(&self) -> &[ExpandedFieldSpecifier] {
+        match self {
+            Template::Template { .. } => &[],
+            Template::OptionsTemplate {
+                scope_field_specifiers,
+                ..
+            } => scope_field_specifiers,
+        }
+    }
+
+    /// The length of the shortest possible data record, counting one
+    /// byte for each variable length field
+    pub fn min_record_length(&self) -> u64 {
+        self.field_specifiers()
+            .map(|field_spec| match field_spec.field_length {
+                u16::MAX => 1,
+                length => u64::from(length),
+            })
+            .sum()
+    }
 }
 
 pub trait TemplateStorage: std::fmt::Debug {
     fn get_template(&self, template_id: u16) -> Option<Template>;
-    fn insert_template(&self, template_id: u16, template: Template);
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError>;
+    fn remove_template(&self, template_id: u16) -> Option<Template>;
+    /// Keep only the templates for which `f` returns true
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool);
+    /// All templates currently in the store
+    fn templates(&self) -> Vec<(u16, Template)>;
+    /// Remove a template that has outlived its lifetime
+    fn expire_template(&self, template_id: u16) -> Option<Template> {
+        self.remove_template(template_id)
+    }
+    /// Hold on to the contents of a data set that arrived before its
+    /// template, returning false if the set is not buffered
+    fn buffer_set(&self, _set_id: u16, _bytes: Vec<u8>) -> bool {
+        false
+    }
+    /// Remove and return all buffered data sets for `template_id`
+    fn take_buffered_sets(&self, _template_id: u16) -> Vec<Vec<u8>> {
+        vec![]
+    }
+    /// Called for each data set decoded with `template_id`, with the
+    /// number of records, size of the set in bytes, and export time of
+    /// the message containing it
+    fn record_usage(&self, _template_id: u16, _records: usize, _bytes: u64, _export_time: u32) {}
 
-    fn insert_template_records(&self, template_records: &[TemplateRecord], formatter: &Formatter) {
+    /// Insert templates from template records, handling template
+    /// withdrawals (records with no field specifiers)
+    /// <https://www.rfc-editor.org/rfc/rfc7011#section-8.1>
+    fn insert_template_records(
+        &self,
+        template_records: &[TemplateRecord],
+        formatter: &Formatter,
+    ) -> Result<(), IpfixError> {
         for template in template_records {
-            let expanded_template = Template::Template(
+            if template.is_withdrawal() {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(template_id = template.template_id, "template withdrawn");
+                if template.template_id == TEMPLATE_SET_ID {
+                    self.retain_templates(&mut |_, t| !matches!(t, Template::Template { .. }));
+                } else {
+                    self.remove_template(template.template_id);
+                }
+                continue;
+            }
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                template_id = template.template_id,
+                field_count = template.field_specifiers.len(),
+                "template inserted"
+            );
+
+            let expanded_template = Template::new(
                 template
                     .field_specifiers
                     .iter()
@@ -70,28 +207,55 @@ pub trait TemplateStorage: std::fmt::Debug {
                     .collect(),
             );
 
-            self.insert_template(template.template_id, expanded_template);
+            self.insert_template(template.template_id, expanded_template)?;
         }
+        Ok(())
     }
 
-    // TODO: these should probably be treated differently
+    /// Insert templates from options template records, splitting off
+    /// the first `scope_field_count` fields as scope fields, and
+    /// handling options template withdrawals
     fn insert_options_template_records(
         &self,
         template_records: &[OptionsTemplateRecord],
         formatter: &Formatter,
-    ) {
+    ) -> Result<(), IpfixError> {
         for template in template_records {
-            let expanded_template = Template::OptionsTemplate(
-                template
-                    .field_specifiers
-                    .iter()
-                    .map(|field_spec| {
-                        ExpandedFieldSpecifier::from_field_spec(field_spec, formatter)
-                    })
-                    .collect(),
+            if template.is_withdrawal() {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    template_id = template.template_id,
+                    "options template withdrawn"
+                );
+                if template.template_id == OPTIONS_TEMPLATE_SET_ID {
+                    self.retain_templates(&mut |_, t| {
+                        !matches!(t, Template::OptionsTemplate { .. })
+                    });
+                } else {
+                    self.remove_template(template.template_id);
+                }
+                continue;
+            }
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                template_id = template.template_id,
+                field_count = template.field_specifiers.len(),
+                scope_field_count = template.scope_field_count,
+                "options template inserted"
             );
-            self.insert_template(template.template_id, expanded_template);
+
+            let mut field_specifiers: Vec<_> = template
+                .field_specifiers
+                .iter()
+                .map(|field_spec| ExpandedFieldSpecifier::from_field_spec(field_spec, formatter))
+                .collect();
+            let scope_field_count =
+                usize::from(template.scope_field_count).min(field_specifiers.len());
+            let scope_field_specifiers = field_specifiers.drain(..scope_field_count).collect();
+            let expanded_template = Template::options(scope_field_specifiers, field_specifiers);
+            self.insert_template(template.template_id, expanded_template)?;
         }
+        Ok(())
     }
 }
 
@@ -99,18 +263,1083 @@ impl<S: ::std::hash::BuildHasher> TemplateStorage for RefCell<HashMap<u16, Templ
     fn get_template(&self, template_id: u16) -> Option<Template> {
         self.borrow().get(&template_id).cloned()
     }
-    fn insert_template(&self, template_id: u16, template: Template) {
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
         self.borrow_mut().insert(template_id, template);
+        Ok(())
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        self.borrow_mut().remove(&template_id)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        self.borrow_mut()
+            .retain(|&template_id, template| f(template_id, template));
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        self.borrow()
+            .iter()
+            .map(|(&template_id, template)| (template_id, template.clone()))
+            .collect()
     }
 }
 
-impl<S: ::std::hash::BuildHasher> TemplateStorage for Arc<RwLock<HashMap<u16, Template, S>>> {
+impl<S: ::std::hash::BuildHasher> TemplateStorage for RwLock<HashMap<u16, Template, S>> {
     fn get_template(&self, template_id: u16) -> Option<Template> {
         self.read().unwrap().get(&template_id).cloned()
     }
-    fn insert_template(&self, template_id: u16, template: Template) {
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
         self.write().unwrap().insert(template_id, template);
+        Ok(())
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        self.write().unwrap().remove(&template_id)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        self.write()
+            .unwrap()
+            .retain(|&template_id, template| f(template_id, template));
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        self.read()
+            .unwrap()
+            .iter()
+            .map(|(&template_id, template)| (template_id, template.clone()))
+            .collect()
+    }
+}
+
+/// Lock-free alternative to `Arc<RwLock<HashMap>>` for sharing templates between threads
+#[cfg(feature = "dashmap")]
+impl<S: ::std::hash::BuildHasher + Clone> TemplateStorage for dashmap::DashMap<u16, Template, S> {
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        self.get(&template_id).map(|template| template.clone())
+    }
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
+        self.insert(template_id, template);
+        Ok(())
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        self.remove(&template_id).map(|(_, template)| template)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        self.retain(|&template_id, template| f(template_id, template));
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        self.iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect()
+    }
+}
+
+impl<S: TemplateStorage + ?Sized> TemplateStorage for Rc<S> {
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        (**self).get_template(template_id)
+    }
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
+        (**self).insert_template(template_id, template)
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        (**self).remove_template(template_id)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        (**self).retain_templates(f)
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        (**self).templates()
+    }
+    fn expire_template(&self, template_id: u16) -> Option<Template> {
+        (**self).expire_template(template_id)
+    }
+    fn buffer_set(&self, set_id: u16, bytes: Vec<u8>) -> bool {
+        (**self).buffer_set(set_id, bytes)
+    }
+    fn take_buffered_sets(&self, template_id: u16) -> Vec<Vec<u8>> {
+        (**self).take_buffered_sets(template_id)
+    }
+    fn record_usage(&self, template_id: u16, records: usize, bytes: u64, export_time: u32) {
+        (**self).record_usage(template_id, records, bytes, export_time)
+    }
+}
+
+impl<S: TemplateStorage + ?Sized> TemplateStorage for Arc<S> {
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        (**self).get_template(template_id)
+    }
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
+        (**self).insert_template(template_id, template)
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        (**self).remove_template(template_id)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        (**self).retain_templates(f)
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        (**self).templates()
+    }
+    fn expire_template(&self, template_id: u16) -> Option<Template> {
+        (**self).expire_template(template_id)
+    }
+    fn buffer_set(&self, set_id: u16, bytes: Vec<u8>) -> bool {
+        (**self).buffer_set(set_id, bytes)
+    }
+    fn take_buffered_sets(&self, template_id: u16) -> Vec<Vec<u8>> {
+        (**self).take_buffered_sets(template_id)
+    }
+    fn record_usage(&self, template_id: u16, records: usize, bytes: u64, export_time: u32) {
+        (**self).record_usage(template_id, records, bytes, export_time)
+    }
+}
+
+/// Look up fields that were not in the formatter when their template
+/// was inserted again, e.g. after learning new information elements,
+/// and replace the templates that now have recognized fields
+pub fn resolve_unrecognized_fields(
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+) -> Result<(), IpfixError> {
+    let resolve = |field_specifiers: &[ExpandedFieldSpecifier]| -> Vec<ExpandedFieldSpecifier> {
+        field_specifiers
+            .iter()
+            .map(|field_spec| match &field_spec.name {
+                DataRecordKey::Unrecognized(unrecognized) => {
+                    ExpandedFieldSpecifier::from_field_spec(unrecognized, formatter)
+                }
+                _ => field_spec.clone(),
+            })
+            .collect()
+    };
+
+    for (template_id, template) in templates.templates() {
+        let resolved = match &template {
+            Template::Template {
+                field_specifiers, ..
+            } => Template::new(resolve(field_specifiers)),
+            Template::OptionsTemplate {
+                scope_field_specifiers,
+                field_specifiers,
+                ..
+            } => Template::options(resolve(scope_field_specifiers), resolve(field_specifiers)),
+        };
+        if resolved != template {
+            templates.insert_template(template_id, resolved)?;
+        }
+    }
+    Ok(())
+}
+
+/// Export all templates in `templates` as template records and
+/// options template records, sorted by template id
+pub fn template_records(
+    templates: &dyn TemplateStorage,
+) -> (Vec<TemplateRecord>, Vec<OptionsTemplateRecord>) {
+    let mut template_records = vec![];
+    let mut options_template_records = vec![];
+    for (template_id, template) in templates.templates() {
+        match template {
+            Template::Template {
+                field_specifiers, ..
+            } => template_records.push(TemplateRecord {
+                template_id,
+                field_specifiers: field_specifiers.iter().map(Into::into).collect(),
+            }),
+            Template::OptionsTemplate {
+                scope_field_specifiers,
+                field_specifiers,
+                ..
+            } => options_template_records.push(OptionsTemplateRecord {
+                template_id,
+                // always fits, as the scope fields were read from a record
+                scope_field_count: scope_field_specifiers.len() as u16,
+                field_specifiers: scope_field_specifiers
+                    .iter()
+                    .chain(&field_specifiers)
+                    .map(Into::into)
+                    .collect(),
+            }),
+        }
+    }
+    // sort for stable output
+    template_records.sort_by_key(|t| t.template_id);
+    options_template_records.sort_by_key(|t| t.template_id);
+    (template_records, options_template_records)
+}
+
+/// Export all templates in `templates` as a Template Set and an
+/// Options Template Set, omitting empty sets, e.g. to re-announce them
+pub fn template_sets(templates: &dyn TemplateStorage) -> Sets {
+    let (template_records, options_template_records) = template_records(templates);
+    into_template_sets(template_records, options_template_records)
+}
+
+fn into_template_sets(
+    template_records: Vec<TemplateRecord>,
+    options_template_records: Vec<OptionsTemplateRecord>,
+) -> Sets {
+    let mut sets = Sets::new();
+    if !template_records.is_empty() {
+        sets.push(Set {
+            records: Records::Template(template_records),
+        });
+    }
+    if !options_template_records.is_empty() {
+        sets.push(Set {
+            records: Records::OptionsTemplate(options_template_records),
+        });
+    }
+    sets
+}
+
+/// Save all templates in `templates` to `writer`, as IPFIX messages
+/// containing a Template Set and an Options Template Set, split into
+/// as many messages as needed to fit their 65535 byte limit. These can
+/// be loaded again with `load_templates`
+pub fn save_templates<W: Write + Seek>(
+    templates: &dyn TemplateStorage,
+    writer: &mut W,
+) -> BinResult<()> {
+    let mut session = ExportSession::new(0);
+    let mut message = session.message().export_time(0).max_size(u16::MAX.into());
+    for set in template_sets(templates) {
+        message = message.set(set);
+    }
+
+    // write with a scratch store, so writing doesn't touch `templates`
+    let scratch_templates = RefCell::new(HashMap::new());
+    let formatter = Formatter::default();
+    let options = WriteOptions::default();
+    for message in message.build_messages(&scratch_templates, &formatter, options)? {
+        message.write_args(writer, (&scratch_templates, &formatter, options))?;
+    }
+    Ok(())
+}
+
+/// Load templates saved with `save_templates` into `templates`,
+/// reading messages until the end of `reader` and looking up their
+/// names and types in `formatter`
+pub fn load_templates<R: Read + Seek>(
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+    reader: &mut R,
+) -> BinResult<()> {
+    let mut buf = Vec::new();
+    loop {
+        let position = reader.stream_position()?;
+        if !read_message_frame(reader, &mut buf, position)? {
+            return Ok(());
+        }
+        Message::read_args(
+            &mut Cursor::new(&buf),
+            (templates, formatter, ParseOptions::default()),
+        )?;
+    }
+}
+
+/// Templates are only unique within a Transport Session and
+/// Observation Domain, so this keeps a separate store for each (peer,
+/// observation_domain_id) pair, effectively keying templates by (peer,
+/// observation_domain_id, template_id)
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-8>
+pub struct ScopedTemplateStore<P, S = RefCell<ahash::HashMap<u16, Template>>> {
+    scopes: RefCell<HashMap<(P, u32), Rc<S>>>,
+    new_scope: Box<dyn Fn() -> S>,
+}
+
+impl<P: std::fmt::Debug, S: std::fmt::Debug> std::fmt::Debug for ScopedTemplateStore<P, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScopedTemplateStore")
+            .field("scopes", &self.scopes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P, S: Default + 'static> Default for ScopedTemplateStore<P, S> {
+    fn default() -> Self {
+        Self::with_factory(S::default)
+    }
+}
+
+impl<P, S> ScopedTemplateStore<P, S> {
+    /// Create a store where each new scope is created by calling `new_scope`
+    pub fn with_factory(new_scope: impl Fn() -> S + 'static) -> Self {
+        Self {
+            scopes: RefCell::new(HashMap::new()),
+            new_scope: Box::new(new_scope),
+        }
+    }
+}
+
+impl<P: Hash + Eq, S: TemplateStorage + Default + 'static> ScopedTemplateStore<P, S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<P: Hash + Eq, S: TemplateStorage> ScopedTemplateStore<P, S> {
+    /// Get the template store for `peer` and `observation_domain_id`,
+    /// creating an empty one if it does not exist yet
+    pub fn scope(&self, peer: P, observation_domain_id: u32) -> Rc<S> {
+        self.scopes
+            .borrow_mut()
+            .entry((peer, observation_domain_id))
+            .or_insert_with(|| Rc::new((self.new_scope)()))
+            .clone()
+    }
+
+    /// All existing scopes, as ((peer, observation_domain_id), store)
+    pub fn scopes(&self) -> Vec<((P, u32), Rc<S>)>
+    where
+        P: Clone,
+    {
+        self.scopes
+            .borrow()
+            .iter()
+            .map(|(key, store)| (key.clone(), store.clone()))
+            .collect()
     }
+
+    /// Drop all templates for `peer`, such as when its Transport Session is closed
+    pub fn remove_peer(&self, peer: &P) {
+        self.scopes.borrow_mut().retain(|(p, _), _| p != peer);
+    }
+}
+
+/// Wrapper around a template store that tracks when each template
+/// was last refreshed, so that templates received over UDP can be
+/// expired after their lifetime. Expired templates are not returned
+/// from `get_template`, even before they are removed with `sweep`
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-8.4>
+#[derive(Debug)]
+pub struct ExpiringTemplateStore<S = RefCell<ahash::HashMap<u16, Template>>, O = ()> {
+    templates: S,
+    lifetime: Duration,
+    options_lifetime: Duration,
+    /// When each template was last refreshed, with its lifetime
+    refreshed: RwLock<HashMap<u16, (Instant, Duration)>>,
+    observer: O,
 }
 
-pub type TemplateStore = Rc<dyn TemplateStorage>;
+impl<S: TemplateStorage> ExpiringTemplateStore<S> {
+    pub fn new(templates: S, lifetime: Duration) -> Self {
+        Self {
+            templates,
+            lifetime,
+            options_lifetime: lifetime,
+            refreshed: RwLock::new(HashMap::new()),
+            observer: (),
+        }
+    }
+}
+
+impl<S: TemplateStorage, O: TemplateObserver + std::fmt::Debug> ExpiringTemplateStore<S, O> {
+    /// Call `observer` when templates are added, replaced, withdrawn or
+    /// expire
+    pub fn with_observer<P: TemplateObserver>(self, observer: P) -> ExpiringTemplateStore<S, P> {
+        ExpiringTemplateStore {
+            templates: self.templates,
+            lifetime: self.lifetime,
+            options_lifetime: self.options_lifetime,
+            refreshed: self.refreshed,
+            observer,
+        }
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Expire options templates after `lifetime` rather than the
+    /// lifetime of other templates
+    pub fn options_lifetime(mut self, lifetime: Duration) -> Self {
+        self.options_lifetime = lifetime;
+        self
+    }
+
+    /// The wrapped template store
+    pub fn inner(&self) -> &S {
+        &self.templates
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Time that `template_id` was last inserted or refreshed
+    pub fn last_refreshed(&self, template_id: u16) -> Option<Instant> {
+        self.refreshed
+            .read()
+            .unwrap()
+            .get(&template_id)
+            .map(|&(refreshed, _)| refreshed)
+    }
+
+    /// Remove all templates last refreshed before `instant`, returning their IDs
+    pub fn expire_older_than(&self, instant: Instant) -> Vec<u16> {
+        let expired: Vec<u16> = self
+            .refreshed
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, &(refreshed, _))| refreshed < instant)
+            .map(|(&template_id, _)| template_id)
+            .collect();
+
+        for template_id in &expired {
+            self.expire_template(*template_id);
+        }
+        expired
+    }
+
+    /// Remove all templates that have outlived their lifetime, returning their IDs
+    pub fn sweep(&self) -> Vec<u16> {
+        let expired: Vec<u16> = self
+            .refreshed
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, &(refreshed, lifetime))| refreshed.elapsed() >= lifetime)
+            .map(|(&template_id, _)| template_id)
+            .collect();
+
+        for template_id in &expired {
+            self.expire_template(*template_id);
+        }
+        expired
+    }
+}
+
+impl<S: TemplateStorage, O: TemplateObserver + std::fmt::Debug> TemplateStorage
+    for ExpiringTemplateStore<S, O>
+{
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        let (refreshed, lifetime) = *self.refreshed.read().unwrap().get(&template_id)?;
+        if refreshed.elapsed() >= lifetime {
+            return None;
+        }
+        self.templates.get_template(template_id)
+    }
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
+        let lifetime = match template {
+            Template::Template { .. } => self.lifetime,
+            Template::OptionsTemplate { .. } => self.options_lifetime,
+        };
+        observe_insert(self, &self.observer, template_id, || {
+            self.templates.insert_template(template_id, template)?;
+            self.refreshed
+                .write()
+                .unwrap()
+                .insert(template_id, (Instant::now(), lifetime));
+            Ok(())
+        })
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        self.refreshed.write().unwrap().remove(&template_id);
+        let template = self.templates.remove_template(template_id)?;
+        self.observer.on_withdraw(template_id, &template);
+        Some(template)
+    }
+    fn expire_template(&self, template_id: u16) -> Option<Template> {
+        self.refreshed.write().unwrap().remove(&template_id);
+        let template = self.templates.expire_template(template_id)?;
+        self.observer.on_expire(template_id, &template);
+        Some(template)
+    }
+    fn buffer_set(&self, set_id: u16, bytes: Vec<u8>) -> bool {
+        self.templates.buffer_set(set_id, bytes)
+    }
+    fn take_buffered_sets(&self, template_id: u16) -> Vec<Vec<u8>> {
+        self.templates.take_buffered_sets(template_id)
+    }
+    fn record_usage(&self, template_id: u16, records: usize, bytes: u64, export_time: u32) {
+        self.templates
+            .record_usage(template_id, records, bytes, export_time)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        let mut refreshed = self.refreshed.write().unwrap();
+        self.templates
+            .retain_templates(&mut |template_id, template| {
+                let keep = f(template_id, template);
+                if !keep {
+                    refreshed.remove(&template_id);
+                    self.observer.on_withdraw(template_id, template);
+                }
+                keep
+            });
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        self.templates
+            .templates()
+            .into_iter()
+            .filter(|(template_id, _)| self.get_template(*template_id).is_some())
+            .collect()
+    }
+}
+
+/// How to handle a Template ID being redefined with a different
+/// definition than the one already in the store
+pub enum RedefinitionPolicy {
+    /// Always replace the existing template
+    Overwrite,
+    /// Skip re-announcements identical to the existing template,
+    /// replacing it otherwise
+    IgnoreIfIdentical,
+    /// Return an error if the new definition differs from the existing template
+    Error,
+    /// Call the function with the template id, existing template and
+    /// new template, replacing the existing template if it returns true
+    Callback(RedefinitionCallback),
+}
+
+pub type RedefinitionCallback = Box<dyn Fn(u16, &Template, &Template) -> bool + Send + Sync>;
+
+impl std::fmt::Debug for RedefinitionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overwrite => write!(f, "Overwrite"),
+            Self::IgnoreIfIdentical => write!(f, "IgnoreIfIdentical"),
+            Self::Error => write!(f, "Error"),
+            Self::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+/// Wrapper around a template store that applies a
+/// `RedefinitionPolicy` when a template is inserted with the same ID
+/// as an existing one
+#[derive(Debug)]
+pub struct PolicyTemplateStore<S = RefCell<ahash::HashMap<u16, Template>>, O = ()> {
+    templates: S,
+    policy: RedefinitionPolicy,
+    observer: O,
+}
+
+impl<S: TemplateStorage> PolicyTemplateStore<S> {
+    pub fn new(templates: S, policy: RedefinitionPolicy) -> Self {
+        Self {
+            templates,
+            policy,
+            observer: (),
+        }
+    }
+}
+
+impl<S: TemplateStorage, O: TemplateObserver> PolicyTemplateStore<S, O> {
+    /// Call `observer` when templates are added, replaced, withdrawn or
+    /// expire
+    pub fn with_observer<P: TemplateObserver>(self, observer: P) -> PolicyTemplateStore<S, P> {
+        PolicyTemplateStore {
+            templates: self.templates,
+            policy: self.policy,
+            observer,
+        }
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// The wrapped template store
+    pub fn inner(&self) -> &S {
+        &self.templates
+    }
+}
+
+impl<S: TemplateStorage, O: TemplateObserver + std::fmt::Debug> TemplateStorage
+    for PolicyTemplateStore<S, O>
+{
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        self.templates.get_template(template_id)
+    }
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
+        observe_insert(self, &self.observer, template_id, || {
+            self.insert_with_policy(template_id, template)
+        })
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        let template = self.templates.remove_template(template_id)?;
+        self.observer.on_withdraw(template_id, &template);
+        Some(template)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        self.templates
+            .retain_templates(&mut observe_retain(&self.observer, f))
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        self.templates.templates()
+    }
+    fn expire_template(&self, template_id: u16) -> Option<Template> {
+        let template = self.templates.expire_template(template_id)?;
+        self.observer.on_expire(template_id, &template);
+        Some(template)
+    }
+    fn buffer_set(&self, set_id: u16, bytes: Vec<u8>) -> bool {
+        self.templates.buffer_set(set_id, bytes)
+    }
+    fn take_buffered_sets(&self, template_id: u16) -> Vec<Vec<u8>> {
+        self.templates.take_buffered_sets(template_id)
+    }
+    fn record_usage(&self, template_id: u16, records: usize, bytes: u64, export_time: u32) {
+        self.templates
+            .record_usage(template_id, records, bytes, export_time)
+    }
+}
+
+impl<S: TemplateStorage, O> PolicyTemplateStore<S, O> {
+    fn insert_with_policy(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
+        if let Some(existing) = self.templates.get_template(template_id) {
+            let identical = existing == template;
+            match &self.policy {
+                RedefinitionPolicy::Overwrite => (),
+                RedefinitionPolicy::IgnoreIfIdentical if identical => return Ok(()),
+                RedefinitionPolicy::IgnoreIfIdentical => (),
+                RedefinitionPolicy::Error if identical => (),
+                RedefinitionPolicy::Error => {
+                    return Err(IpfixError::TemplateRedefinition(template_id))
+                }
+                RedefinitionPolicy::Callback(_) if identical => (),
+                RedefinitionPolicy::Callback(f) => {
+                    if !f(template_id, &existing, &template) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        self.templates.insert_template(template_id, template)
+    }
+}
+
+/// Callbacks for changes to the templates in a store. All methods
+/// default to doing nothing
+pub trait TemplateObserver {
+    /// A new template was added
+    fn on_insert(&self, _template_id: u16, _template: &Template) {}
+    /// An existing template was replaced with a different definition
+    fn on_replace(&self, _template_id: u16, _old: &Template, _new: &Template) {}
+    /// A template was withdrawn or otherwise removed
+    fn on_withdraw(&self, _template_id: u16, _template: &Template) {}
+    /// A template was removed after outliving its lifetime
+    fn on_expire(&self, _template_id: u16, _template: &Template) {}
+}
+
+/// No observer, the default for the built-in stores
+impl TemplateObserver for () {}
+
+/// Run `insert`, then tell `observer` how it changed `template_id` in
+/// `templates`. This compares what was actually stored, as a store may
+/// ignore an insert
+fn observe_insert(
+    templates: &dyn TemplateStorage,
+    observer: &dyn TemplateObserver,
+    template_id: u16,
+    insert: impl FnOnce() -> Result<(), IpfixError>,
+) -> Result<(), IpfixError> {
+    let old = templates.get_template(template_id);
+    insert()?;
+    match (old, templates.get_template(template_id)) {
+        (None, Some(new)) => observer.on_insert(template_id, &new),
+        (Some(old), Some(new)) if old != new => observer.on_replace(template_id, &old, &new),
+        _ => (),
+    }
+    Ok(())
+}
+
+/// `f` for `retain_templates`, telling `observer` of each template it
+/// removes
+fn observe_retain<'a>(
+    observer: &'a dyn TemplateObserver,
+    f: &'a mut dyn FnMut(u16, &Template) -> bool,
+) -> impl FnMut(u16, &Template) -> bool + 'a {
+    move |template_id, template| {
+        let keep = f(template_id, template);
+        if !keep {
+            observer.on_withdraw(template_id, template);
+        }
+        keep
+    }
+}
+
+/// Attach a `TemplateObserver` to any template store, to be called
+/// whenever its templates change. The built-in wrapper stores take an
+/// observer directly with `with_observer`, so this is mainly for plain
+/// maps and other stores
+#[derive(Debug)]
+pub struct ObservedTemplateStore<S, O> {
+    templates: S,
+    observer: O,
+}
+
+impl<S: TemplateStorage, O: TemplateObserver> ObservedTemplateStore<S, O> {
+    pub fn new(templates: S, observer: O) -> Self {
+        Self {
+            templates,
+            observer,
+        }
+    }
+
+    /// The wrapped template store
+    pub fn inner(&self) -> &S {
+        &self.templates
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+}
+
+impl<S: TemplateStorage, O: TemplateObserver + std::fmt::Debug> TemplateStorage
+    for ObservedTemplateStore<S, O>
+{
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        self.templates.get_template(template_id)
+    }
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
+        observe_insert(&self.templates, &self.observer, template_id, || {
+            self.templates.insert_template(template_id, template)
+        })
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        let template = self.templates.remove_template(template_id)?;
+        self.observer.on_withdraw(template_id, &template);
+        Some(template)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        self.templates
+            .retain_templates(&mut observe_retain(&self.observer, f))
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        self.templates.templates()
+    }
+    fn expire_template(&self, template_id: u16) -> Option<Template> {
+        let template = self.templates.expire_template(template_id)?;
+        self.observer.on_expire(template_id, &template);
+        Some(template)
+    }
+    fn buffer_set(&self, set_id: u16, bytes: Vec<u8>) -> bool {
+        self.templates.buffer_set(set_id, bytes)
+    }
+    fn take_buffered_sets(&self, template_id: u16) -> Vec<Vec<u8>> {
+        self.templates.take_buffered_sets(template_id)
+    }
+    fn record_usage(&self, template_id: u16, records: usize, bytes: u64, export_time: u32) {
+        self.templates
+            .record_usage(template_id, records, bytes, export_time)
+    }
+}
+
+/// Wrapper around a template store that buffers the contents of data
+/// sets that arrive before their template (common over UDP), so they
+/// can be decoded once the template arrives. Use with a
+/// `ScopedTemplateStore` to buffer sets per (peer, observation domain)
+#[derive(Debug)]
+pub struct BufferingTemplateStore<S = RefCell<ahash::HashMap<u16, Template>>, O = ()> {
+    templates: S,
+    max_buffered_sets: usize,
+    buffered: RwLock<HashMap<u16, Vec<Vec<u8>>>>,
+    observer: O,
+}
+
+impl<S: TemplateStorage> BufferingTemplateStore<S> {
+    /// Buffer at most `max_buffered_sets` sets at a time, after which
+    /// data sets with missing templates are errors as usual
+    pub fn new(templates: S, max_buffered_sets: usize) -> Self {
+        Self {
+            templates,
+            max_buffered_sets,
+            buffered: RwLock::new(HashMap::new()),
+            observer: (),
+        }
+    }
+}
+
+impl<S: TemplateStorage, O: TemplateObserver> BufferingTemplateStore<S, O> {
+    /// Call `observer` when templates are added, replaced, withdrawn or
+    /// expire
+    pub fn with_observer<P: TemplateObserver>(self, observer: P) -> BufferingTemplateStore<S, P> {
+        BufferingTemplateStore {
+            templates: self.templates,
+            max_buffered_sets: self.max_buffered_sets,
+            buffered: self.buffered,
+            observer,
+        }
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// The wrapped template store
+    pub fn inner(&self) -> &S {
+        &self.templates
+    }
+
+    /// Number of sets currently buffered
+    pub fn buffered_sets(&self) -> usize {
+        self.buffered.read().unwrap().values().map(Vec::len).sum()
+    }
+
+    /// Drop all buffered sets
+    pub fn clear_buffered_sets(&self) {
+        self.buffered.write().unwrap().clear();
+    }
+}
+
+impl<S: TemplateStorage, O: TemplateObserver + std::fmt::Debug> TemplateStorage
+    for BufferingTemplateStore<S, O>
+{
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        self.templates.get_template(template_id)
+    }
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
+        observe_insert(&self.templates, &self.observer, template_id, || {
+            self.templates.insert_template(template_id, template)
+        })
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        let template = self.templates.remove_template(template_id)?;
+        self.observer.on_withdraw(template_id, &template);
+        Some(template)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        self.templates
+            .retain_templates(&mut observe_retain(&self.observer, f))
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        self.templates.templates()
+    }
+    fn expire_template(&self, template_id: u16) -> Option<Template> {
+        let template = self.templates.expire_template(template_id)?;
+        self.observer.on_expire(template_id, &template);
+        Some(template)
+    }
+    fn buffer_set(&self, set_id: u16, bytes: Vec<u8>) -> bool {
+        // check the cap and push under one lock, so concurrent callers can't overshoot it
+        let mut buffered = self.buffered.write().unwrap();
+        if buffered.values().map(Vec::len).sum::<usize>() >= self.max_buffered_sets {
+            return false;
+        }
+        buffered.entry(set_id).or_default().push(bytes);
+        true
+    }
+    fn take_buffered_sets(&self, template_id: u16) -> Vec<Vec<u8>> {
+        self.buffered
+            .write()
+            .unwrap()
+            .remove(&template_id)
+            .unwrap_or_default()
+    }
+    fn record_usage(&self, template_id: u16, records: usize, bytes: u64, export_time: u32) {
+        self.templates
+            .record_usage(template_id, records, bytes, export_time)
+    }
+}
+
+/// Usage counters for a single template
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct TemplateUsage {
+    /// Number of data records decoded
+    pub records: u64,
+    /// Total size of data sets decoded, including set headers
+    pub bytes: u64,
+    /// Export time of the first message with data for this template
+    pub first_seen: u32,
+    /// Export time of the most recent message with data for this template
+    pub last_seen: u32,
+}
+
+/// Wrapper around a template store that counts how each template is
+/// used. Counters are reset when a template is withdrawn or expires,
+/// so templates that are announced but never used have no usage
+#[derive(Debug)]
+pub struct UsageTemplateStore<S = RefCell<ahash::HashMap<u16, Template>>, O = ()> {
+    templates: S,
+    usage: RwLock<HashMap<u16, TemplateUsage>>,
+    observer: O,
+}
+
+impl<S: TemplateStorage> UsageTemplateStore<S> {
+    pub fn new(templates: S) -> Self {
+        Self {
+            templates,
+            usage: RwLock::new(HashMap::new()),
+            observer: (),
+        }
+    }
+}
+
+impl<S: TemplateStorage, O: TemplateObserver> UsageTemplateStore<S, O> {
+    /// Call `observer` when templates are added, replaced, withdrawn or
+    /// expire
+    pub fn with_observer<P: TemplateObserver>(self, observer: P) -> UsageTemplateStore<S, P> {
+        UsageTemplateStore {
+            templates: self.templates,
+            usage: self.usage,
+            observer,
+        }
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// The wrapped template store
+    pub fn inner(&self) -> &S {
+        &self.templates
+    }
+
+    pub fn usage(&self, template_id: u16) -> Option<TemplateUsage> {
+        self.usage.read().unwrap().get(&template_id).copied()
+    }
+
+    /// Usage of every template that has been used
+    pub fn all_usage(&self) -> Vec<(u16, TemplateUsage)> {
+        self.usage
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&template_id, &usage)| (template_id, usage))
+            .collect()
+    }
+
+    /// IDs of templates in the store that have not been used
+    pub fn unused_templates(&self) -> Vec<u16> {
+        let usage = self.usage.read().unwrap();
+        self.templates
+            .templates()
+            .into_iter()
+            .map(|(template_id, _)| template_id)
+            .filter(|template_id| !usage.contains_key(template_id))
+            .collect()
+    }
+}
+
+impl<S: TemplateStorage, O: TemplateObserver + std::fmt::Debug> TemplateStorage
+    for UsageTemplateStore<S, O>
+{
+    fn get_template(&self, template_id: u16) -> Option<Template> {
+        self.templates.get_template(template_id)
+    }
+    fn insert_template(&self, template_id: u16, template: Template) -> Result<(), IpfixError> {
+        observe_insert(&self.templates, &self.observer, template_id, || {
+            self.templates.insert_template(template_id, template)
+        })
+    }
+    fn remove_template(&self, template_id: u16) -> Option<Template> {
+        self.usage.write().unwrap().remove(&template_id);
+        let template = self.templates.remove_template(template_id)?;
+        self.observer.on_withdraw(template_id, &template);
+        Some(template)
+    }
+    fn retain_templates(&self, f: &mut dyn FnMut(u16, &Template) -> bool) {
+        let mut usage = self.usage.write().unwrap();
+        self.templates
+            .retain_templates(&mut |template_id, template| {
+                let keep = f(template_id, template);
+                if !keep {
+                    usage.remove(&template_id);
+                    self.observer.on_withdraw(template_id, template);
+                }
+                keep
+            });
+    }
+    fn templates(&self) -> Vec<(u16, Template)> {
+        self.templates.templates()
+    }
+    fn expire_template(&self, template_id: u16) -> Option<Template> {
+        self.usage.write().unwrap().remove(&template_id);
+        let template = self.templates.expire_template(template_id)?;
+        self.observer.on_expire(template_id, &template);
+        Some(template)
+    }
+    fn buffer_set(&self, set_id: u16, bytes: Vec<u8>) -> bool {
+        self.templates.buffer_set(set_id, bytes)
+    }
+    fn take_buffered_sets(&self, template_id: u16) -> Vec<Vec<u8>> {
+        self.templates.take_buffered_sets(template_id)
+    }
+    fn record_usage(&self, template_id: u16, records: usize, bytes: u64, export_time: u32) {
+        self.usage
+            .write()
+            .unwrap()
+            .entry(template_id)
+            .and_modify(|usage| {
+                usage.records += records as u64;
+                usage.bytes += bytes;
+                usage.last_seen = export_time;
+            })
+            .or_insert(TemplateUsage {
+                records: records as u64,
+                bytes,
+                first_seen: export_time,
+                last_seen: export_time,
+            });
+        self.templates
+            .record_usage(template_id, records, bytes, export_time)
+    }
+}
+
+/// Tracks when each template was last sent by an exporter, so
+/// templates can be periodically resent as required over UDP
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-10.3.6>
+#[derive(Debug)]
+pub struct TemplateRefresher {
+    interval: Duration,
+    last_sent: HashMap<u16, Instant>,
+}
+
+impl TemplateRefresher {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn last_sent(&self, template_id: u16) -> Option<Instant> {
+        self.last_sent.get(&template_id).copied()
+    }
+
+    /// Record that `template_id` was sent at `now`
+    pub fn mark_sent(&mut self, template_id: u16, now: Instant) {
+        self.last_sent.insert(template_id, now);
+    }
+
+    /// Forget when `template_id` was sent, so it is sent again with
+    /// the next message, e.g. after it was redefined
+    pub fn reset(&mut self, template_id: u16) {
+        self.last_sent.remove(&template_id);
+    }
+
+    /// Template and Options Template sets for the templates in
+    /// `templates` that have never been sent or were last sent at least
+    /// `interval` before `now`, to be prepended to the next message.
+    /// These templates are marked as sent at `now`
+    pub fn due_sets(&mut self, templates: &dyn TemplateStorage, now: Instant) -> Sets {
+        let (mut template_records, mut options_template_records) = template_records(templates);
+
+        // forget templates that are no longer in the store
+        self.last_sent.retain(|template_id, _| {
+            template_records
+                .iter()
+                .any(|t| t.template_id == *template_id)
+                || options_template_records
+                    .iter()
+                    .any(|t| t.template_id == *template_id)
+        });
+
+        let interval = self.interval;
+        let mut is_due = |template_id: u16| match self.last_sent.get(&template_id) {
+            Some(&sent) if now.saturating_duration_since(sent) < interval => false,
+            _ => {
+                self.last_sent.insert(template_id, now);
+                true
+            }
+        };
+        template_records.retain(|t| is_due(t.template_id));
+        options_template_records.retain(|t| is_due(t.template_id));
+
+        into_template_sets(template_records, options_template_records)
+    }
+}
@@ -0,0 +1,181 @@
+//! Conversions between timestamp values and other time types
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "chrono")]
+use chrono::{DateTime, TimeZone, Utc};
+
+#[cfg(feature = "chrono")]
+use crate::parser::Message;
+use crate::parser::{DataRecordType, DataRecordValue, NtpTimestamp, ValueConversionError};
+
+fn system_time_from_unix_nanos(nanos: i64) -> Option<SystemTime> {
+    if nanos >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_nanos(nanos as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_nanos(nanos.unsigned_abs()))
+    }
+}
+
+fn unix_nanos_from_system_time(time: SystemTime) -> Option<i64> {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos().try_into().ok(),
+        Err(before) => i64::try_from(before.duration().as_nanos()).ok().map(|x| -x),
+    }
+}
+
+impl From<NtpTimestamp> for SystemTime {
+    fn from(timestamp: NtpTimestamp) -> Self {
+        // always in range, as NTP timestamps only cover 1968 to 2104
+        system_time_from_unix_nanos(timestamp.to_unix_nanos()).unwrap()
+    }
+}
+
+impl TryFrom<SystemTime> for NtpTimestamp {
+    type Error = ValueConversionError;
+
+    fn try_from(time: SystemTime) -> Result<Self, Self::Error> {
+        unix_nanos_from_system_time(time)
+            .and_then(NtpTimestamp::from_unix_nanos)
+            .ok_or(ValueConversionError)
+    }
+}
+
+impl DataRecordValue {
+    /// The time represented by any of the DateTime variants
+    pub fn try_as_system_time(&self) -> Option<SystemTime> {
+        match self {
+            DataRecordValue::DateTimeSeconds(seconds) => {
+                UNIX_EPOCH.checked_add(Duration::from_secs((*seconds).into()))
+            }
+            DataRecordValue::DateTimeMilliseconds(millis) => {
+                UNIX_EPOCH.checked_add(Duration::from_millis(*millis))
+            }
+            DataRecordValue::DateTimeMicroseconds(timestamp)
+            | DataRecordValue::DateTimeNanoseconds(timestamp) => Some((*timestamp).into()),
+            _ => None,
+        }
+    }
+
+    /// Make a value of the DateTime variant for `ty`. Returns None if
+    /// `ty` isn't a DateTime type or `time` is out of its range.
+    /// Precision beyond that of `ty` is truncated
+    pub fn from_system_time(ty: DataRecordType, time: SystemTime) -> Option<Self> {
+        let since_epoch = time.duration_since(UNIX_EPOCH);
+        Some(match ty {
+            DataRecordType::DateTimeSeconds => {
+                DataRecordValue::DateTimeSeconds(since_epoch.ok()?.as_secs().try_into().ok()?)
+            }
+            DataRecordType::DateTimeMilliseconds => DataRecordValue::DateTimeMilliseconds(
+                since_epoch.ok()?.as_millis().try_into().ok()?,
+            ),
+            DataRecordType::DateTimeMicroseconds => {
+                let nanos = unix_nanos_from_system_time(time)?;
+                DataRecordValue::DateTimeMicroseconds(NtpTimestamp::from_unix_nanos(
+                    nanos - nanos.rem_euclid(1000),
+                )?)
+            }
+            DataRecordType::DateTimeNanoseconds => {
+                DataRecordValue::DateTimeNanoseconds(time.try_into().ok()?)
+            }
+            _ => return None,
+        })
+    }
+}
+
+impl TryFrom<&DataRecordValue> for SystemTime {
+    type Error = ValueConversionError;
+
+    fn try_from(value: &DataRecordValue) -> Result<Self, Self::Error> {
+        value.try_as_system_time().ok_or(ValueConversionError)
+    }
+}
+
+impl TryFrom<DataRecordValue> for SystemTime {
+    type Error = ValueConversionError;
+
+    fn try_from(value: DataRecordValue) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+/// The time since the Unix epoch, failing for times before it
+impl TryFrom<&DataRecordValue> for Duration {
+    type Error = ValueConversionError;
+
+    fn try_from(value: &DataRecordValue) -> Result<Self, Self::Error> {
+        SystemTime::try_from(value)?
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| ValueConversionError)
+    }
+}
+
+impl TryFrom<DataRecordValue> for Duration {
+    type Error = ValueConversionError;
+
+    fn try_from(value: DataRecordValue) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl NtpTimestamp {
+    pub fn to_datetime(&self) -> DateTime<Utc> {
+        Utc.timestamp_nanos(self.to_unix_nanos())
+    }
+
+    /// Returns None if `datetime` is outside of the range 1968 to 2104
+    pub fn from_datetime(datetime: &DateTime<Utc>) -> Option<Self> {
+        Self::from_unix_nanos(datetime.timestamp_nanos_opt()?)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl DataRecordValue {
+    /// The time represented by any of the DateTime variants
+    pub fn to_datetime(&self) -> Option<DateTime<Utc>> {
+        match self {
+            DataRecordValue::DateTimeSeconds(seconds) => {
+                Utc.timestamp_opt((*seconds).into(), 0).single()
+            }
+            DataRecordValue::DateTimeMilliseconds(millis) => Utc
+                .timestamp_millis_opt((*millis).try_into().ok()?)
+                .single(),
+            DataRecordValue::DateTimeMicroseconds(timestamp)
+            | DataRecordValue::DateTimeNanoseconds(timestamp) => Some(timestamp.to_datetime()),
+            _ => None,
+        }
+    }
+
+    /// Make a value of the DateTime variant for `ty`. Returns None if
+    /// `ty` isn't a DateTime type or `datetime` is out of its range.
+    /// Precision beyond that of `ty` is truncated
+    pub fn from_datetime(ty: DataRecordType, datetime: &DateTime<Utc>) -> Option<Self> {
+        Some(match ty {
+            DataRecordType::DateTimeSeconds => {
+                DataRecordValue::DateTimeSeconds(datetime.timestamp().try_into().ok()?)
+            }
+            DataRecordType::DateTimeMilliseconds => {
+                DataRecordValue::DateTimeMilliseconds(datetime.timestamp_millis().try_into().ok()?)
+            }
+            DataRecordType::DateTimeMicroseconds => {
+                let micros = datetime.timestamp_micros();
+                DataRecordValue::DateTimeMicroseconds(NtpTimestamp::from_unix_nanos(
+                    micros.checked_mul(1000)?,
+                )?)
+            }
+            DataRecordType::DateTimeNanoseconds => {
+                DataRecordValue::DateTimeNanoseconds(NtpTimestamp::from_datetime(datetime)?)
+            }
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl Message {
+    pub fn export_datetime(&self) -> DateTime<Utc> {
+        // a u32 of seconds is always in range
+        Utc.timestamp_opt(self.export_time.into(), 0).unwrap()
+    }
+}
@@ -0,0 +1,89 @@
+//! IPFIX over TLS, with rustls (RFC 7011 section 11). Exporters and
+//! Collectors should authenticate each other, so both configs take an
+//! optional certificate chain for the peer to check. Connections are
+//! plain `Read + Write` streams, for `MessageWriter` and `TcpSession`
+//! <https://www.rfc-editor.org/rfc/rfc7011#section-11>
+
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+
+pub use rustls;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+
+/// A TLS connection to a Collector
+pub type ClientStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// A TLS connection from an Exporter
+pub type ServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// A certificate chain, starting with the end-entity certificate, and
+/// its private key
+pub type CertifiedKey = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Config for Collectors presenting `identity`. With `client_roots`,
+/// Exporters must present a certificate issued by one of them
+pub fn server_config(
+    identity: CertifiedKey,
+    client_roots: Option<RootCertStore>,
+) -> Result<Arc<ServerConfig>, rustls::Error> {
+    let builder =
+        ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match client_roots {
+        Some(roots) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+                .build()
+                .map_err(|err| rustls::Error::General(err.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let (cert_chain, key) = identity;
+    Ok(Arc::new(builder.with_single_cert(cert_chain, key)?))
+}
+
+/// Config for Exporters trusting Collectors issued by `roots`,
+/// presenting `identity` if given
+pub fn client_config(
+    roots: RootCertStore,
+    identity: Option<CertifiedKey>,
+) -> Result<Arc<ClientConfig>, rustls::Error> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert_chain, key)) => builder.with_client_auth_cert(cert_chain, key)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Connect to the Collector at `addr`, which must have a certificate
+/// for `server_name`, which is also sent as SNI. The handshake happens
+/// on the first read or write
+pub fn connect(
+    addr: impl ToSocketAddrs,
+    config: Arc<ClientConfig>,
+    server_name: &str,
+) -> io::Result<ClientStream> {
+    let server_name = ServerName::try_from(server_name.to_owned())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let connection = ClientConnection::new(config, server_name).map_err(io::Error::other)?;
+    Ok(StreamOwned::new(connection, TcpStream::connect(addr)?))
+}
+
+/// Accept TLS on a connection from an Exporter. The handshake happens on
+/// the first read or write
+pub fn accept(stream: TcpStream, config: Arc<ServerConfig>) -> io::Result<ServerStream> {
+    let connection = ServerConnection::new(config).map_err(io::Error::other)?;
+    Ok(StreamOwned::new(connection, stream))
+}
@@ -0,0 +1,223 @@
+//! Typed views of the values of specific information elements
+
+use std::net::IpAddr;
+
+use bitflags::bitflags;
+
+use crate::parser::{DataRecord, DataRecordValue};
+
+bitflags! {
+    /// Value of tcpControlBits (IE 6)
+    /// <https://www.iana.org/assignments/ipfix/ipfix.xhtml#ipfix-tcpcontrolbits-flags>
+    #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+    pub struct TcpControlBits: u16 {
+        const FIN = 0x0001;
+        const SYN = 0x0002;
+        const RST = 0x0004;
+        const PSH = 0x0008;
+        const ACK = 0x0010;
+        const URG = 0x0020;
+        const ECE = 0x0040;
+        const CWR = 0x0080;
+        const NS = 0x0100;
+    }
+}
+
+impl TryFrom<&DataRecordValue> for TcpControlBits {
+    type Error = crate::parser::ValueConversionError;
+
+    /// From a U8 or U16 value, keeping any unassigned bits
+    fn try_from(value: &DataRecordValue) -> Result<Self, Self::Error> {
+        u16::try_from(value).map(Self::from_bits_retain)
+    }
+}
+
+impl From<TcpControlBits> for DataRecordValue {
+    fn from(bits: TcpControlBits) -> Self {
+        DataRecordValue::U16(bits.bits())
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/protocol-numbers.rs"));
+include!(concat!(env!("OUT_DIR"), "/ipfix-subregistries.rs"));
+
+/// Conversions between registry enums and DataRecordValue, for enums of
+/// the unsigned type `$repr`
+macro_rules! impl_enum_value {
+    ($repr:ty, $variant:ident: $($ty:ty),+ $(,)?) => {
+        $(
+            impl TryFrom<&DataRecordValue> for $ty {
+                type Error = crate::parser::ValueConversionError;
+
+                /// From an unsigned value that fits in the enum's type
+                fn try_from(value: &DataRecordValue) -> Result<Self, Self::Error> {
+                    <$repr>::try_from(value).map(Self::from)
+                }
+            }
+
+            impl From<$ty> for DataRecordValue {
+                fn from(value: $ty) -> Self {
+                    DataRecordValue::$variant(value.into())
+                }
+            }
+        )+
+    };
+}
+
+impl_enum_value!(
+    u8,
+    U8: ProtocolIdentifier,
+    FlowEndReason,
+    ForwardingStatus,
+    NatEvent,
+    FirewallEvent,
+);
+// selectorAlgorithm is unsigned16
+impl_enum_value!(u16, U16: SelectorAlgorithm);
+
+impl DataRecord {
+    /// tcpControlBits, if present and an unsigned integer
+    pub fn tcp_control_bits(&self) -> Option<TcpControlBits> {
+        self.get("tcpControlBits")?.try_into().ok()
+    }
+
+    /// protocolIdentifier, if present and an unsigned integer
+    pub fn protocol_identifier(&self) -> Option<ProtocolIdentifier> {
+        self.get("protocolIdentifier")?.try_into().ok()
+    }
+
+    /// flowEndReason, if present and an unsigned integer
+    pub fn flow_end_reason(&self) -> Option<FlowEndReason> {
+        self.get("flowEndReason")?.try_into().ok()
+    }
+
+    /// forwardingStatus, if present and an unsigned integer
+    pub fn forwarding_status(&self) -> Option<ForwardingStatus> {
+        self.get("forwardingStatus")?.try_into().ok()
+    }
+
+    /// natEvent, if present and an unsigned integer
+    pub fn nat_event(&self) -> Option<NatEvent> {
+        self.get("natEvent")?.try_into().ok()
+    }
+
+    /// firewallEvent, if present and an unsigned integer
+    pub fn firewall_event(&self) -> Option<FirewallEvent> {
+        self.get("firewallEvent")?.try_into().ok()
+    }
+
+    /// selectorAlgorithm, if present and an unsigned integer
+    pub fn selector_algorithm(&self) -> Option<SelectorAlgorithm> {
+        self.get("selectorAlgorithm")?.try_into().ok()
+    }
+}
+
+/// The five-tuple identifying a flow, for aggregating and correlating
+/// records
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct FlowKey {
+    pub source_address: IpAddr,
+    pub destination_address: IpAddr,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub protocol: ProtocolIdentifier,
+}
+
+impl FlowKey {
+    /// Extract the flow key from a record, using either the IPv4 or IPv6
+    /// addresses, and sourceTransportPort, tcpSourcePort or udpSourcePort
+    /// (and their destination equivalents). Ports are 0 if not present,
+    /// as for ICMP. Returns None if the addresses or protocolIdentifier
+    /// are missing
+    pub fn from_record(record: &DataRecord) -> Option<Self> {
+        let first = |names: &[&'static str]| names.iter().find_map(|&name| record.get(name));
+        let address = |names| IpAddr::try_from(first(names)?).ok();
+        let port = |names| first(names).and_then(|value| u16::try_from(value).ok());
+        Some(Self {
+            source_address: address(&["sourceIPv4Address", "sourceIPv6Address"])?,
+            destination_address: address(&["destinationIPv4Address", "destinationIPv6Address"])?,
+            source_port: port(&["sourceTransportPort", "tcpSourcePort", "udpSourcePort"])
+                .unwrap_or(0),
+            destination_port: port(&[
+                "destinationTransportPort",
+                "tcpDestinationPort",
+                "udpDestinationPort",
+            ])
+            .unwrap_or(0),
+            protocol: record.protocol_identifier()?,
+        })
+    }
+
+    /// The key of the flow in the opposite direction
+    pub fn reversed(&self) -> Self {
+        Self {
+            source_address: self.destination_address,
+            destination_address: self.source_address,
+            source_port: self.destination_port,
+            destination_port: self.source_port,
+            protocol: self.protocol,
+        }
+    }
+}
+
+/// Pair a prefix address with its prefix length, such as sourceIPv4Prefix
+/// and sourceIPv4PrefixLength
+#[cfg(feature = "ipnet")]
+pub fn ip_prefix(
+    address: &DataRecordValue,
+    prefix_length: &DataRecordValue,
+) -> Option<ipnet::IpNet> {
+    let prefix_length = u8::try_from(prefix_length).ok()?;
+    match *address {
+        DataRecordValue::Ipv4Addr(address) => ipnet::Ipv4Net::new(address, prefix_length)
+            .ok()
+            .map(Into::into),
+        DataRecordValue::Ipv6Addr(address) => ipnet::Ipv6Net::new(address, prefix_length)
+            .ok()
+            .map(Into::into),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "ipnet")]
+impl DataRecord {
+    fn get_prefix(
+        &self,
+        address: &'static str,
+        prefix_length: &'static str,
+    ) -> Option<ipnet::IpNet> {
+        ip_prefix(self.get(address)?, self.get(prefix_length)?)
+    }
+
+    /// sourceIPv4Prefix with sourceIPv4PrefixLength
+    pub fn source_ipv4_prefix(&self) -> Option<ipnet::Ipv4Net> {
+        match self.get_prefix("sourceIPv4Prefix", "sourceIPv4PrefixLength")? {
+            ipnet::IpNet::V4(net) => Some(net),
+            ipnet::IpNet::V6(_) => None,
+        }
+    }
+
+    /// destinationIPv4Prefix with destinationIPv4PrefixLength
+    pub fn destination_ipv4_prefix(&self) -> Option<ipnet::Ipv4Net> {
+        match self.get_prefix("destinationIPv4Prefix", "destinationIPv4PrefixLength")? {
+            ipnet::IpNet::V4(net) => Some(net),
+            ipnet::IpNet::V6(_) => None,
+        }
+    }
+
+    /// sourceIPv6Prefix with sourceIPv6PrefixLength
+    pub fn source_ipv6_prefix(&self) -> Option<ipnet::Ipv6Net> {
+        match self.get_prefix("sourceIPv6Prefix", "sourceIPv6PrefixLength")? {
+            ipnet::IpNet::V6(net) => Some(net),
+            ipnet::IpNet::V4(_) => None,
+        }
+    }
+
+    /// destinationIPv6Prefix with destinationIPv6PrefixLength
+    pub fn destination_ipv6_prefix(&self) -> Option<ipnet::Ipv6Net> {
+        match self.get_prefix("destinationIPv6Prefix", "destinationIPv6PrefixLength")? {
+            ipnet::IpNet::V6(net) => Some(net),
+            ipnet::IpNet::V4(_) => None,
+        }
+    }
+}
@@ -1,41 +1,132 @@
-use std::num::TryFromIntError;
+use binrw::io::{Read, Seek, SeekFrom, TakeSeekExt, Write};
+use binrw::{helpers::until_eof_with, BinRead, BinResult, Endian};
 
-use binrw::io::{Read, Seek, TakeSeekExt, Write};
-use binrw::{until_eof, BinRead, BinResult, BinWriterExt, Endian};
+use crate::parser::IpfixError;
 
-#[derive(derive_more::From, derive_more::Error, derive_more::Display, Debug)]
-pub enum WritePositionError {
-    Io(binrw::io::Error),
-    TryFromInt(TryFromIntError),
-    BinRw(binrw::Error),
+/// A stream with positions relative to where it was first used, so
+/// lengths can be calculated from positions wherever a struct is written
+pub(crate) struct RelativeStream<S> {
+    inner: S,
+    start: Option<u64>,
 }
 
-pub(crate) fn stream_position<S: Seek>(s: &mut S) -> Result<u16, WritePositionError> {
-    Ok(u16::try_from(s.stream_position()?)?)
+impl<S: Seek> RelativeStream<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Self { inner, start: None }
+    }
+
+    fn start(&mut self) -> binrw::io::Result<u64> {
+        match self.start {
+            Some(start) => Ok(start),
+            None => {
+                let start = self.inner.stream_position()?;
+                self.start = Some(start);
+                Ok(start)
+            }
+        }
+    }
+}
+
+impl<S: Write + Seek> Write for RelativeStream<S> {
+    fn write(&mut self, buf: &[u8]) -> binrw::io::Result<usize> {
+        self.start()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> binrw::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Seek> Seek for RelativeStream<S> {
+    fn seek(&mut self, pos: SeekFrom) -> binrw::io::Result<u64> {
+        let start = self.start()?;
+        let pos = match pos {
+            SeekFrom::Start(offset) => SeekFrom::Start(start + offset),
+            pos => pos,
+        };
+        self.inner
+            .seek(pos)?
+            .checked_sub(start)
+            .ok_or_else(|| binrw::io::Error::from(binrw::io::ErrorKind::InvalidInput))
+    }
 }
 
-/// Write the current position of the `writer` at `output_position`, minus `offset`
-/// This is used to get the length of a struct, via an empty field at the end
-pub(crate) fn write_position_at<W: Write + Seek>(
+/// The current position, as a placeholder for a length field
+pub(crate) fn stream_position<S: Seek>(s: &mut S) -> Result<u16, IpfixError> {
+    let position = s.stream_position()?;
+    u16::try_from(position).map_err(|_| IpfixError::LengthOverflow { length: position })
+}
+
+/// Write the current position of the `writer` at `output_position`.
+/// Used with a `RelativeStream` to write the length of a struct, via an
+/// empty field at the end
+pub(crate) fn write_length_at<W: Write + Seek>(
     writer: &mut W,
     output_position: u16,
-    offset: u16,
-) -> Result<(), WritePositionError> {
-    // TODO: avoid unwrap
-    let current_position = u16::try_from(writer.stream_position()?)?;
-    writer.seek(std::io::SeekFrom::Start(output_position.into()))?;
-    writer.write_be(&(current_position - offset))?;
+) -> Result<(), IpfixError> {
+    let length = writer.stream_position()?;
+    let length = u16::try_from(length).map_err(|_| IpfixError::LengthOverflow { length })?;
+    writer.seek(SeekFrom::Start(output_position.into()))?;
+    writer.write_all(&length.to_be_bytes())?;
     Ok(())
 }
 
-pub(crate) fn until_limit<Reader, T, Arg, Ret>(
+/// Like `binrw::helpers::until_eof`, but allowing arguments that borrow
+pub(crate) fn until_eof<'a, Reader, T, Ret>(
+    reader: &mut Reader,
+    endian: Endian,
+    args: T::Args<'a>,
+) -> BinResult<Ret>
+where
+    T: BinRead,
+    T::Args<'a>: Clone,
+    Reader: Read + Seek,
+    Ret: FromIterator<T>,
+{
+    until_eof_with(|reader: &mut Reader, endian, args| T::read_options(reader, endian, args))(
+        reader, endian, args,
+    )
+}
+
+pub(crate) fn until_limit<'a, Reader, T, Ret>(
     limit: u64,
-) -> impl Fn(&mut Reader, Endian, Arg) -> BinResult<Ret> + Copy
+) -> impl Fn(&mut Reader, Endian, T::Args<'a>) -> BinResult<Ret> + Copy
 where
-    T: for<'a> BinRead<Args<'a> = Arg>,
+    T: BinRead,
+    T::Args<'a>: Clone,
     Reader: Read + Seek,
-    Arg: Clone,
     Ret: FromIterator<T>,
 {
     move |reader, endian, args| until_eof(&mut reader.take_seek(limit), endian, args)
 }
+
+/// Like `until_limit`, but stopping when fewer than `min_length` bytes
+/// remain, which are padding
+/// <https://www.rfc-editor.org/rfc/rfc7011#section-3.3.1>
+pub(crate) fn until_padding<'a, Reader, T, Ret>(
+    limit: u64,
+    min_length: u64,
+) -> impl Fn(&mut Reader, Endian, T::Args<'a>) -> BinResult<Ret> + Copy
+where
+    T: BinRead,
+    T::Args<'a>: Clone,
+    Reader: Read + Seek,
+    Ret: FromIterator<T>,
+{
+    move |reader, endian, args| {
+        let mut reader = reader.take_seek(limit);
+        let mut items = vec![];
+        while reader.limit() >= min_length.max(1) {
+            let remaining = reader.limit();
+            match T::read_options(&mut reader, endian, args.clone()) {
+                // items of no bytes would repeat forever
+                Ok(_) if reader.limit() == remaining => break,
+                Ok(item) => items.push(item),
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(items.into_iter().collect())
+    }
+}
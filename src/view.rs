@@ -0,0 +1,212 @@
+//! Parsing many messages with one `Parser`, which keeps the buffers it
+//! decodes into between messages, and the templates of the data sets it
+//! has seen. Each `MessageView` borrows from the parser until the next
+//! message is parsed, so steady-state parsing of data sets doesn't
+//! allocate beyond the values themselves
+
+use std::io::Cursor;
+use std::ops::Range;
+use std::rc::Rc;
+
+use ahash::HashMap;
+use binrw::{BinRead, BinResult};
+
+use crate::borrowed::{split_message, split_sets};
+use crate::compact::{decode_values, CompactRecord};
+use crate::information_elements::Formatter;
+use crate::parser::{
+    DataRecordKey, DataRecordValue, IpfixError, ParseOptions, Set, OPTIONS_TEMPLATE_SET_ID,
+    TEMPLATE_SET_ID,
+};
+use crate::template_store::{Template, TemplateStorage};
+
+#[derive(Debug)]
+enum ParsedSet {
+    Decoded(Set),
+    Data {
+        set_id: u16,
+        template: Rc<Template>,
+        values: Range<usize>,
+    },
+}
+
+/// Reusable state for parsing messages one after another. Templates are
+/// fetched from the template storage once, and cached until a message
+/// with a Template or Options Template Set, so call
+/// `clear_template_cache` if templates change or expire in the storage
+/// by other means
+#[derive(Debug)]
+pub struct Parser<'a> {
+    templates: &'a dyn TemplateStorage,
+    formatter: &'a Formatter,
+    options: ParseOptions,
+    sets: Vec<ParsedSet>,
+    /// Values of the data records of all sets, in template field order
+    values: Vec<DataRecordValue>,
+    cached_templates: HashMap<u16, Rc<Template>>,
+}
+
+/// A message decoded by `Parser::parse_into`
+#[derive(Debug)]
+pub struct MessageView<'p> {
+    pub export_time: u32,
+    pub sequence_number: u32,
+    pub observation_domain_id: u32,
+    sets: &'p [ParsedSet],
+    values: &'p [DataRecordValue],
+}
+
+#[derive(Debug)]
+pub enum SetView<'p> {
+    /// A Template or Options Template Set
+    Decoded(&'p Set),
+    Data(DataSetView<'p>),
+}
+
+/// The records of a data set in a `MessageView`
+#[derive(Clone, Copy, Debug)]
+pub struct DataSetView<'p> {
+    pub set_id: u16,
+    pub template: &'p Template,
+    values: &'p [DataRecordValue],
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(templates: &'a dyn TemplateStorage, formatter: &'a Formatter) -> Self {
+        Self {
+            templates,
+            formatter,
+            options: ParseOptions::default(),
+            sets: vec![],
+            values: vec![],
+            cached_templates: HashMap::default(),
+        }
+    }
+
+    /// Parse messages with `options`
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Forget the cached templates, fetching them from the template
+    /// storage again
+    pub fn clear_template_cache(&mut self) {
+        self.cached_templates.clear();
+    }
+
+    /// Parse the message at the start of `bytes`, replacing the previous
+    /// message. Templates it defines are added to the parser's templates,
+    /// and data sets must have a known template
+    pub fn parse_into(&mut self, bytes: &[u8]) -> BinResult<MessageView<'_>> {
+        self.sets.clear();
+        self.values.clear();
+        let (bytes, header) = split_message(bytes)?;
+        split_sets(bytes, |set_id, offset, set_bytes| {
+            let set = match set_id {
+                TEMPLATE_SET_ID | OPTIONS_TEMPLATE_SET_ID => {
+                    // the set may redefine or withdraw cached templates
+                    self.cached_templates.clear();
+                    ParsedSet::Decoded(Set::read_args(
+                        &mut Cursor::new(set_bytes),
+                        (self.templates, self.formatter, self.options),
+                    )?)
+                }
+                _ => {
+                    let template = match self.cached_templates.get(&set_id) {
+                        Some(template) => template.clone(),
+                        None => {
+                            let template =
+                                Rc::new(self.templates.get_template(set_id).ok_or_else(|| {
+                                    IpfixError::MissingTemplate(set_id)
+                                        .into_binrw_error(offset as u64)
+                                })?);
+                            self.cached_templates.insert(set_id, template.clone());
+                            template
+                        }
+                    };
+                    let start = self.values.len();
+                    decode_values(&template, &set_bytes[4..], self.options, &mut self.values)?;
+                    ParsedSet::Data {
+                        set_id,
+                        template,
+                        values: start..self.values.len(),
+                    }
+                }
+            };
+            self.sets.push(set);
+            Ok(())
+        })?;
+        Ok(MessageView {
+            export_time: header.export_time,
+            sequence_number: header.sequence_number,
+            observation_domain_id: header.observation_domain_id,
+            sets: &self.sets,
+            values: &self.values,
+        })
+    }
+}
+
+impl<'p> MessageView<'p> {
+    pub fn sets(&self) -> impl Iterator<Item = SetView<'p>> + '_ {
+        let values = self.values;
+        self.sets.iter().map(move |set| match set {
+            ParsedSet::Decoded(set) => SetView::Decoded(set),
+            ParsedSet::Data {
+                set_id,
+                template,
+                values: range,
+            } => SetView::Data(DataSetView {
+                set_id: *set_id,
+                template,
+                values: &values[range.clone()],
+            }),
+        })
+    }
+
+    pub fn iter_data_sets(&self) -> impl Iterator<Item = DataSetView<'p>> + '_ {
+        self.sets().filter_map(|set| match set {
+            SetView::Data(data_set) => Some(data_set),
+            SetView::Decoded(_) => None,
+        })
+    }
+
+    pub fn iter_data_records(&self) -> impl Iterator<Item = CompactRecord<'p>> + '_ {
+        self.iter_data_sets()
+            .flat_map(|data_set| data_set.records())
+    }
+}
+
+impl<'p> DataSetView<'p> {
+    /// The position of `key` in the template, to look values up with
+    /// `CompactRecord::value`
+    pub fn field_index(&self, key: impl Into<DataRecordKey>) -> Option<usize> {
+        let key = key.into();
+        self.template
+            .field_specifiers()
+            .position(|field_spec| field_spec.name == key)
+    }
+
+    fn field_count(&self) -> usize {
+        self.template.field_specifiers().count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values
+            .len()
+            .checked_div(self.field_count())
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = CompactRecord<'p>> {
+        let template = self.template;
+        // a template with no fields has no values to split
+        self.values
+            .chunks(self.field_count().max(1))
+            .map(move |values| CompactRecord::new(template, values))
+    }
+}
@@ -0,0 +1,117 @@
+//! Decoding by callbacks: a `Visitor` is called with each template and
+//! each field of each data record as the message is scanned, without
+//! building a `Message`, its sets or its records. Octet arrays and
+//! strings are borrowed from the input, as with `borrowed`
+
+use std::io::Cursor;
+
+use binrw::{BinRead, BinResult};
+
+use crate::borrowed::{decode, split_fields, split_message, split_sets, BorrowedValue};
+use crate::information_elements::Formatter;
+use crate::parser::{IpfixError, ParseOptions, Records, Set};
+use crate::template_store::{ExpandedFieldSpecifier, Template, TemplateStorage};
+
+/// Callbacks for `visit_ipfix_message`, which all do nothing by default
+pub trait Visitor {
+    /// Called with the header of the message, before any of its sets
+    fn on_message(
+        &mut self,
+        _export_time: u32,
+        _sequence_number: u32,
+        _observation_domain_id: u32,
+    ) {
+    }
+
+    /// Called after a template is added or replaced
+    fn on_template(&mut self, _template_id: u16, _template: &Template) {}
+
+    /// Called after a template, or all templates of a type for the
+    /// template set IDs, are withdrawn
+    fn on_template_withdrawn(&mut self, _template_id: u16) {}
+
+    /// Called before the fields of each data record
+    fn on_record_start(&mut self, _set_id: u16, _template: &Template) {}
+
+    /// Called with each field of a data record, in template order
+    fn on_record_field(&mut self, _field_spec: &ExpandedFieldSpecifier, _value: BorrowedValue<'_>) {
+    }
+
+    /// Called after the fields of each data record
+    fn on_record_end(&mut self, _set_id: u16) {}
+}
+
+/// Scan the message at the start of `buf`, calling `visitor` with its
+/// templates and records. Templates it defines are added to
+/// `templates`, and data sets must have a known template. Returns the
+/// length of the message, where the next message in `buf` starts
+pub fn visit_ipfix_message(
+    buf: &[u8],
+    templates: &dyn TemplateStorage,
+    formatter: &Formatter,
+    visitor: &mut impl Visitor,
+) -> BinResult<usize> {
+    let (buf, header) = split_message(buf)?;
+    visitor.on_message(
+        header.export_time,
+        header.sequence_number,
+        header.observation_domain_id,
+    );
+    split_sets(buf, |set_id, offset, set_bytes| {
+        if set_id > 255 {
+            let template = templates.get_template(set_id).ok_or_else(|| {
+                IpfixError::MissingTemplate(set_id).into_binrw_error(offset as u64)
+            })?;
+            return visit_records(set_id, &template, &set_bytes[4..], visitor);
+        }
+
+        let set = Set::read_args(
+            &mut Cursor::new(set_bytes),
+            (templates, formatter, ParseOptions::default()),
+        )?;
+        let template_ids: Vec<(u16, bool)> = match &set.records {
+            Records::Template(records) => records
+                .iter()
+                .map(|record| (record.template_id, record.is_withdrawal()))
+                .collect(),
+            Records::OptionsTemplate(records) => records
+                .iter()
+                .map(|record| (record.template_id, record.is_withdrawal()))
+                .collect(),
+            _ => vec![],
+        };
+        for (template_id, withdrawal) in template_ids {
+            match templates.get_template(template_id) {
+                Some(template) if !withdrawal => visitor.on_template(template_id, &template),
+                _ => visitor.on_template_withdrawn(template_id),
+            }
+        }
+        Ok(())
+    })?;
+    Ok(buf.len())
+}
+
+/// Call `visitor` with each record of a data set, stopping at padding
+/// shorter than a record
+fn visit_records(
+    set_id: u16,
+    template: &Template,
+    bytes: &[u8],
+    visitor: &mut impl Visitor,
+) -> BinResult<()> {
+    // a template of only zero length fields makes records of no bytes,
+    // which would repeat forever
+    let min_length = match template.min_record_length() {
+        0 => return Ok(()),
+        min_length => min_length as usize,
+    };
+    let mut offset = 0;
+    while bytes.len() - offset >= min_length {
+        visitor.on_record_start(set_id, template);
+        offset = split_fields(template, bytes, offset, |field_spec, field| {
+            visitor.on_record_field(field_spec, decode(field_spec.ty, field));
+        })?;
+        visitor.on_record_end(set_id);
+    }
+    Ok(())
+}
@@ -0,0 +1,187 @@
+#![cfg(feature = "derive")]
+
+use std::cell::RefCell;
+use std::io::Cursor;
+use std::net::Ipv4Addr;
+
+use ahash::{HashMap, HashMapExt};
+use binrw::{BinRead, BinWrite};
+
+use ipfixrw::information_elements::get_default_formatter;
+use ipfixrw::parser::{
+    DataRecordKey, DataRecordType, DataRecordValue, FieldSpecifier, IpfixError,
+    OptionsTemplateRecord, ParseOptions, Records, Set, TemplateRecord, WriteOptions,
+};
+use ipfixrw::record::IpfixRecord;
+use ipfixrw::template_store::TemplateStorage;
+
+#[derive(ipfixrw::IpfixRecord, PartialEq, Clone, Debug)]
+struct Flow {
+    #[ipfix(id = 8)]
+    source: Ipv4Addr,
+    #[ipfix(id = 7)]
+    source_port: u16,
+    #[ipfix(id = 1, length = 4)]
+    octets: u64,
+    #[ipfix(id = 82)]
+    interface: String,
+    #[ipfix(pen = 30351, id = 11)]
+    information_source: u8,
+}
+
+#[derive(ipfixrw::IpfixRecord, PartialEq, Clone, Debug)]
+struct MeteringStatistics {
+    #[ipfix(id = 149)]
+    observation_domain_id: u32,
+    #[ipfix(id = 41)]
+    exported_messages: u64,
+}
+
+#[test]
+fn derive_ipfix_record() {
+    assert_eq!(
+        Flow::template_record(256),
+        TemplateRecord {
+            template_id: 256,
+            field_specifiers: [
+                FieldSpecifier::new(None, 8, 4),
+                FieldSpecifier::new(None, 7, 2),
+                FieldSpecifier::new(None, 1, 4),
+                FieldSpecifier::new(None, 82, u16::MAX),
+                FieldSpecifier::new(Some(30351), 11, 1),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    );
+
+    let templates = RefCell::new(HashMap::new());
+    let mut formatter = get_default_formatter();
+    formatter.insert(
+        (30351, 11),
+        ("informationSource".into(), DataRecordType::UnsignedInt),
+    );
+    let mut writer = Cursor::new(Vec::new());
+    Set {
+        records: Records::Template(vec![Flow::template_record(256)]),
+    }
+    .write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::default()),
+    )
+    .unwrap();
+    let template = templates.get_template(256).unwrap();
+
+    let flows = vec![
+        Flow {
+            source: Ipv4Addr::new(10, 0, 0, 1),
+            source_port: 443,
+            octets: 1500,
+            interface: "eth0".into(),
+            information_source: 1,
+        },
+        Flow {
+            source: Ipv4Addr::new(10, 0, 0, 2),
+            source_port: 80,
+            octets: 40,
+            interface: "eth1".into(),
+            information_source: 2,
+        },
+    ];
+    let set = Flow::data_set(&flows, 256, &template).unwrap();
+    let mut writer = Cursor::new(Vec::new());
+    set.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::default()),
+    )
+    .unwrap();
+
+    let read_set = Set::read_args(
+        &mut Cursor::new(writer.into_inner()),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    let Records::Data { data, .. } = read_set.records else {
+        panic!("expected a data set");
+    };
+    let read_flows = data
+        .iter()
+        .map(|record| Flow::from_data_record(record, &template))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(read_flows, flows);
+
+    // a template without all of the fields
+    templates
+        .insert_template_records(
+            &[TemplateRecord {
+                template_id: 257,
+                field_specifiers: [FieldSpecifier::new(None, 8, 4)].into_iter().collect(),
+            }],
+            &formatter,
+        )
+        .unwrap();
+    let other_template = templates.get_template(257).unwrap();
+    assert!(matches!(
+        flows[0].to_data_record(&other_template),
+        Err(IpfixError::UnknownField(_))
+    ));
+}
+
+#[test]
+fn derive_options_record() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let record = OptionsTemplateRecord::new(
+        300,
+        vec![FieldSpecifier::new(None, 149, 4)],
+        vec![FieldSpecifier::new(None, 41, 8)],
+    )
+    .unwrap();
+    templates
+        .insert_options_template_records(&[record], &formatter)
+        .unwrap();
+    let template = templates.get_template(300).unwrap();
+
+    // scope fields go in the scope values
+    let statistics = MeteringStatistics {
+        observation_domain_id: 7,
+        exported_messages: 10,
+    };
+    let data_record = statistics.to_data_record(&template).unwrap();
+    assert_eq!(
+        data_record
+            .scope_values
+            .get(&DataRecordKey::Str("observationDomainId")),
+        Some(&DataRecordValue::U32(7))
+    );
+    assert_eq!(
+        data_record
+            .values
+            .get(&DataRecordKey::Str("exportedMessageTotalCount")),
+        Some(&DataRecordValue::U64(10))
+    );
+    assert_eq!(data_record.values.len(), 1);
+
+    let set =
+        MeteringStatistics::data_set(std::slice::from_ref(&statistics), 300, &template).unwrap();
+    let mut writer = Cursor::new(Vec::new());
+    set.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::default()),
+    )
+    .unwrap();
+    let read_set = Set::read_args(
+        &mut Cursor::new(writer.into_inner()),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(read_set, set);
+    let Records::Data { data, .. } = read_set.records else {
+        panic!("expected a data set");
+    };
+    assert_eq!(
+        MeteringStatistics::from_data_record(&data[0], &template).unwrap(),
+        statistics
+    );
+}
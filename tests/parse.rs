@@ -1,24 +1,59 @@
 use std::cell::RefCell;
-use std::net::Ipv4Addr;
-use std::rc::Rc;
+use std::io::Cursor;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use ahash::{HashMap, HashMapExt};
+use binrw::{BinRead, BinWrite, Endian};
 
-use ipfixrw::information_elements::get_default_formatter;
-use ipfixrw::parse_ipfix_message;
-use ipfixrw::parser::{DataRecord, DataRecordKey, DataRecordType, DataRecordValue};
-use ipfixrw::template_store::Template;
+use ipfixrw::borrowed::{BorrowedMessage, BorrowedValue};
+use ipfixrw::collector::tcp::{TcpCollector, TcpEvent, TcpSession};
+use ipfixrw::collector::udp::{UdpCollector, UdpEvent};
+use ipfixrw::collector::{CollectorSession, SessionEvent, SessionManager, Transport};
+use ipfixrw::compact::CompactMessage;
+use ipfixrw::information_elements::{
+    default_information_element, get_default_formatter, get_default_semantics, DataTypeSemantics,
+    Formatter, FormatterExt,
+};
+use ipfixrw::netflow::v5::{self, V5Message};
+use ipfixrw::netflow::v9::{V9Message, VENDOR_ENTERPRISE_NUMBER};
+use ipfixrw::parser::{
+    DataRecord, DataRecordKey, DataRecordType, DataRecordValue, DataRecordValues, FieldSpecifier,
+    IpfixError, MacAddress, Message, NtpTimestamp, OptionsTemplateRecord, ParseMacAddressError,
+    ParseOptions, RawDataRecord, RawDataSet, Records, Set, TemplateRecord, ValueConversionError,
+    WriteOptions,
+};
+use ipfixrw::sctp::{Reliability, StreamMapper};
+use ipfixrw::sequence::{SequenceEvent, SequenceStatistics, SequenceTracker};
+use ipfixrw::statistics::MeteringProcessStatistics;
+use ipfixrw::stream::MessageStream;
+use ipfixrw::template_store::{
+    load_templates, save_templates, template_records, template_sets, BufferingTemplateStore,
+    ExpiringTemplateStore, ObservedTemplateStore, PolicyTemplateStore, RedefinitionPolicy,
+    ScopedTemplateStore, TemplateObserver, TemplateRefresher, UsageTemplateStore,
+};
+use ipfixrw::template_store::{ExpandedFieldSpecifier, Template, TemplateStorage};
+use ipfixrw::types::{
+    FirewallEvent, FlowEndReason, FlowKey, ForwardingStatus, NatEvent, ProtocolIdentifier,
+    SelectorAlgorithm, TcpControlBits,
+};
+use ipfixrw::view::Parser;
+use ipfixrw::visit::{visit_ipfix_message, Visitor};
+use ipfixrw::{
+    data_record, iter_ipfix_messages, parse_ipfix_message, parse_ipfix_message_learning,
+    parse_ipfix_message_scoped, parse_ipfix_messages, parse_raw_data_set,
+};
 
 // shall not cause infinite loop
 #[test]
 fn looper_01() {
     let b = include_bytes!("../resources/tests/looper_01.bin");
 
-    let templates = Rc::new(RefCell::new(HashMap::new()));
-    let formatter = Rc::new(get_default_formatter());
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
 
-    let m = parse_ipfix_message(b, templates, formatter);
+    let m = parse_ipfix_message(b, &templates, &formatter);
     assert!(m.is_err());
 }
 
@@ -30,18 +65,18 @@ fn test_parse() {
     // contains data sets for templates 999, 500, 999
     let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
 
-    let templates = Rc::new(RefCell::new(HashMap::new()));
-    let formatter = Rc::new(get_default_formatter());
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
 
-    let msg = parse_ipfix_message(template_bytes, templates.clone(), formatter.clone()).unwrap();
+    let msg = parse_ipfix_message(template_bytes, &templates, &formatter).unwrap();
     assert_eq!(msg.sets.len(), 1);
     assert_eq!(templates.borrow().len(), 3);
     assert!(templates.borrow().contains_key(&500));
     assert!(templates.borrow().contains_key(&999));
     assert!(templates.borrow().contains_key(&501));
-    assert!(parse_ipfix_message(template_bytes, templates.clone(), formatter.clone(),).is_ok());
+    assert!(parse_ipfix_message(template_bytes, &templates, &formatter,).is_ok());
 
-    let data_message = parse_ipfix_message(data_bytes, templates, formatter.clone()).unwrap();
+    let data_message = parse_ipfix_message(data_bytes, &templates, &formatter).unwrap();
     let datarecords: Vec<&DataRecord> = data_message.iter_data_records().collect();
     assert_eq!(datarecords.len(), 21);
 
@@ -82,19 +117,16 @@ fn test_parse_template_enterprise_fields() {
     // 261, 262
     let temp_2 = include_bytes!("../resources/tests/parse_temp_2.bin");
 
-    let templates = Rc::new(RefCell::new(HashMap::new()));
-    let formatter = Rc::new(get_default_formatter());
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
 
-    let _ = parse_ipfix_message(temp_1, templates.clone(), formatter.clone()).unwrap();
-    let _ = parse_ipfix_message(temp_2, templates.clone(), formatter.clone()).unwrap();
+    let _ = parse_ipfix_message(temp_1, &templates, &formatter).unwrap();
+    let _ = parse_ipfix_message(temp_2, &templates, &formatter).unwrap();
     // sum the number of parsed enterprise fields
     let enterprise_fields = templates
         .borrow()
         .values()
-        .flat_map(|t| match t {
-            Template::Template(field_specifiers) => field_specifiers,
-            Template::OptionsTemplate(field_specifiers) => field_specifiers,
-        })
+        .flat_map(|t| t.field_specifiers())
         .filter(|fs| fs.enterprise_number.is_some())
         .count();
 
@@ -115,7 +147,7 @@ fn test_parse_data_variable_fields() {
     // http sample
     let d2 = include_bytes!("../resources/tests/http_samp.bin");
 
-    let templates = Rc::new(RefCell::new(HashMap::new()));
+    let templates = RefCell::new(HashMap::new());
     let mut formatter = get_default_formatter();
 
     // add custom fields for ntop pen
@@ -159,12 +191,11 @@ fn test_parse_data_variable_fields() {
         (35632, 509) => ("L7_PROTO_RISK", UnsignedInt),
         (35632, 527) => ("L7_RISK_SCORE", UnsignedInt)
     });
-    let formatter = Rc::new(formatter);
 
-    assert!(parse_ipfix_message(temp_1, templates.clone(), formatter.clone()).is_ok());
-    assert!(parse_ipfix_message(temp_2, templates.clone(), formatter.clone()).is_ok());
+    assert!(parse_ipfix_message(temp_1, &templates, &formatter).is_ok());
+    assert!(parse_ipfix_message(temp_2, &templates, &formatter).is_ok());
 
-    let dns = parse_ipfix_message(d1, templates.clone(), formatter.clone()).unwrap();
+    let dns = parse_ipfix_message(d1, &templates, &formatter).unwrap();
     println!("{dns:#?}");
     let records: Vec<&DataRecord> = dns.iter_data_records().collect();
     assert!(!records.is_empty());
@@ -178,7 +209,7 @@ fn test_parse_data_variable_fields() {
     }
 
     // http
-    let http = parse_ipfix_message(d2, templates, formatter.clone()).unwrap();
+    let http = parse_ipfix_message(d2, &templates, &formatter).unwrap();
     let records: Vec<&DataRecord> = http.iter_data_records().collect();
     assert!(!records.is_empty());
     let record = records[0];
@@ -191,32 +222,4229 @@ fn test_parse_data_variable_fields() {
     }
 }
 
+#[cfg(feature = "ntop")]
 #[test]
-fn concurrency() {
-    // A state to be shared between parsing threads
-    let templates = Arc::new(RwLock::new(HashMap::new()));
+fn ntop_elements() {
+    use ipfixrw::information_elements::ntop;
 
-    // First thread to parse a template test
-    let t1 = templates.clone();
-    let j1 = std::thread::spawn(move || {
-        // contains templates 500, 999, 501
-        let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
-        let formatter = Rc::new(get_default_formatter());
-        let _m = parse_ipfix_message(template_bytes, Rc::new(t1), formatter.clone());
-    });
+    let temp_1 = include_bytes!("../resources/tests/parse_temp_1.bin");
+    let temp_2 = include_bytes!("../resources/tests/parse_temp_2.bin");
+    let dns_bytes = include_bytes!("../resources/tests/dns_samp.bin");
 
-    // Second thread to parse data set
-    let t2 = templates.clone();
-    let j2 = std::thread::spawn(move || {
-        // contains data sets for templates 999, 500, 999
-        let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
-        let formatter = Rc::new(get_default_formatter());
-        let _m = parse_ipfix_message(data_bytes, Rc::new(t2), formatter.clone());
-    });
+    let templates = RefCell::new(HashMap::new());
+    let mut formatter = get_default_formatter();
+    formatter.extend(ntop::elements());
+    assert_eq!(
+        formatter.resolve("SRC_FRAGMENTS"),
+        Some((ntop::ENTERPRISE_NUMBER, 80, DataRecordType::UnsignedInt))
+    );
 
-    let _r1 = j1.join();
-    let _r2 = j2.join();
+    parse_ipfix_message(temp_1, &templates, &formatter).unwrap();
+    parse_ipfix_message(temp_2, &templates, &formatter).unwrap();
+    let dns = parse_ipfix_message(dns_bytes, &templates, &formatter).unwrap();
+    let record = dns.iter_data_records().next().unwrap();
+    assert!(record
+        .values
+        .keys()
+        .all(|key| !matches!(key, DataRecordKey::Unrecognized(_))));
+    assert_eq!(
+        record.values.get(&DataRecordKey::Str("DNS_QUERY")),
+        Some(&DataRecordValue::String(
+            "asimov.vortex.data.trafficmanager.net".to_string()
+        ))
+    );
+}
 
-    // Assert state mutated from threads
-    assert!(templates.read().unwrap().len() == 3);
+#[test]
+fn template_withdrawal() {
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    parse_ipfix_message(template_bytes, &templates, &formatter).unwrap();
+    assert_eq!(templates.borrow().len(), 3);
+
+    // withdraw 999 on read
+    let withdrawal_bytes = hex::decode("0002000803E70000").unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&withdrawal_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(
+        set,
+        Set {
+            records: Records::template_withdrawal([999])
+        }
+    );
+    assert_eq!(templates.borrow().len(), 2);
+    assert!(!templates.borrow().contains_key(&999));
+
+    // withdraw all on write
+    let mut writer = Cursor::new(Vec::new());
+    Set {
+        records: Records::withdraw_all(),
+    }
+    .write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::aligned(4)),
+    )
+    .unwrap();
+    assert_eq!(
+        writer.into_inner(),
+        hex::decode("0002000800020000").unwrap()
+    );
+    assert!(templates.borrow().is_empty());
+}
+
+#[test]
+fn reduced_length_unsigned() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // template 256: octetDeltaCount (3 bytes), packetDeltaCount (6 bytes)
+    let template_bytes = hex::decode("00020010010000020001000300020006").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    let data_bytes = hex::decode("0100000D010203040506070809").unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(
+        set,
+        Set {
+            records: Records::Data {
+                set_id: 256,
+                data: vec![data_record! {
+                    "octetDeltaCount": U32(0x010203),
+                    "packetDeltaCount": U64(0x040506070809),
+                }],
+            },
+        }
+    );
+
+    // written with the same reduced lengths
+    let mut writer = Cursor::new(Vec::new());
+    set.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::aligned(1)),
+    )
+    .unwrap();
+    assert_eq!(writer.into_inner(), data_bytes);
+}
+
+#[test]
+fn write_value_width_validation() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // template 256: octetDeltaCount (3 bytes), sourceIPv4Address
+    let template_bytes = hex::decode("00020010010000020001000300080004").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    for (octets, source) in [
+        // too large for a reduced-length field
+        (
+            DataRecordValue::U32(0x01000000),
+            DataRecordValue::Ipv4Addr(Ipv4Addr::LOCALHOST),
+        ),
+        // not an integer
+        (
+            DataRecordValue::Bool(true),
+            DataRecordValue::Ipv4Addr(Ipv4Addr::LOCALHOST),
+        ),
+        // wrong address family
+        (
+            DataRecordValue::U32(1),
+            DataRecordValue::Ipv6Addr(Ipv6Addr::LOCALHOST),
+        ),
+    ] {
+        let mut record = DataRecord::default();
+        record
+            .values
+            .insert(DataRecordKey::Str("octetDeltaCount"), octets);
+        record
+            .values
+            .insert(DataRecordKey::Str("sourceIPv4Address"), source);
+        let set = Set {
+            records: Records::Data {
+                set_id: 256,
+                data: vec![record],
+            },
+        };
+        let mut writer = Cursor::new(Vec::new());
+        let err = set
+            .write_args(
+                &mut writer,
+                (&templates, &formatter, WriteOptions::default()),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("does not fit Field Length"));
+    }
+
+    assert!(DataRecordValue::U32(0xFFFFFF).fits_field_length(3));
+    assert!(!DataRecordValue::U32(0xFFFFFF).fits_field_length(2));
+    assert!(DataRecordValue::I32(-0x800000).fits_field_length(3));
+    assert!(!DataRecordValue::I32(-0x800001).fits_field_length(3));
+    assert!(DataRecordValue::String("eth0".into()).fits_field_length(u16::MAX));
+    assert!(!DataRecordValue::String("eth0".into()).fits_field_length(8));
+}
+
+#[test]
+fn write_integer_width_coercion() {
+    let templates = RefCell::new(HashMap::new());
+    let mut formatter = get_default_formatter();
+    formatter.insert((0, 1000), ("signedA".into(), DataRecordType::SignedInt));
+
+    // template 256: octetDeltaCount (8 bytes), packetDeltaCount (3 bytes), signedA (4 bytes)
+    let template_bytes = hex::decode("0002001401000003000100080002000303E80004").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    // narrower values are extended to the field length
+    let set = Set {
+        records: Records::Data {
+            set_id: 256,
+            data: vec![data_record! {
+                "octetDeltaCount": U8(5),
+                "packetDeltaCount": U16(0x0102),
+                "signedA": I8(-2),
+            }],
+        },
+    };
+    let data_bytes = hex::decode("010000130000000000000005000102FFFFFFFE").unwrap();
+    let mut writer = Cursor::new(Vec::new());
+    set.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(writer.into_inner(), data_bytes);
+
+    let read_set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(
+        read_set.records,
+        Records::Data {
+            set_id: 256,
+            data: vec![data_record! {
+                "octetDeltaCount": U64(5),
+                "packetDeltaCount": U32(0x0102),
+                "signedA": I32(-2),
+            }],
+        }
+    );
+
+    assert!(DataRecordValue::U8(1).fits_field_length(8));
+    assert!(!DataRecordValue::U8(1).fits_field_length(16));
+}
+
+#[cfg(feature = "indexmap")]
+#[test]
+fn values_in_template_order() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // template 256: destinationTransportPort, sourceTransportPort,
+    // protocolIdentifier, octetDeltaCount
+    let template_bytes = hex::decode("0002001801000004000B0002000700020004000100010004").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    let data_bytes = hex::decode("0100000D0050C35006000003E8").unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    let Records::Data { data, .. } = set.records else {
+        panic!("expected a data set");
+    };
+    let names: Vec<_> = data[0].values.keys().cloned().collect();
+    assert_eq!(
+        names,
+        [
+            DataRecordKey::Str("destinationTransportPort"),
+            DataRecordKey::Str("sourceTransportPort"),
+            DataRecordKey::Str("protocolIdentifier"),
+            DataRecordKey::Str("octetDeltaCount"),
+        ]
+    );
+}
+
+#[test]
+fn data_record_builder() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // template 256: sourceIPv4Address, octetDeltaCount (3 bytes)
+    let template_bytes = hex::decode("00020010010000020008000400010003").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    let template = templates.get_template(256).unwrap();
+
+    let record = DataRecord::builder(&template)
+        .set(
+            "sourceIPv4Address",
+            DataRecordValue::Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+        )
+        .unwrap()
+        .set("octetDeltaCount", DataRecordValue::U64(1000))
+        .unwrap()
+        .build()
+        .unwrap();
+    assert_eq!(
+        record,
+        data_record! {
+            "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+            "octetDeltaCount": U64(1000),
+        }
+    );
+    let set = Set {
+        records: Records::Data {
+            set_id: 256,
+            data: vec![record],
+        },
+    };
+    let mut writer = Cursor::new(Vec::new());
+    set.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(
+        writer.into_inner(),
+        hex::decode("0100000B0A0000010003E8").unwrap()
+    );
+
+    // unknown fields, wrong types, values too large, and missing fields
+    let builder = || DataRecord::builder(&template);
+    assert!(matches!(
+        builder().set("packetDeltaCount", DataRecordValue::U64(1)),
+        Err(IpfixError::UnknownField(_))
+    ));
+    assert!(matches!(
+        builder().set("octetDeltaCount", DataRecordValue::I64(1)),
+        Err(IpfixError::InvalidValueType { .. })
+    ));
+    assert!(matches!(
+        builder().set("octetDeltaCount", DataRecordValue::U64(1 << 24)),
+        Err(IpfixError::InvalidValueLength { .. })
+    ));
+    assert!(matches!(
+        builder()
+            .set("octetDeltaCount", DataRecordValue::U8(1))
+            .unwrap()
+            .build(),
+        Err(IpfixError::MissingData(DataRecordKey::Str(
+            "sourceIPv4Address"
+        )))
+    ));
+}
+
+#[test]
+fn reduced_length_signed() {
+    let templates = RefCell::new(HashMap::new());
+    let mut formatter = get_default_formatter();
+    formatter.insert((0, 1000), ("signedA".into(), DataRecordType::SignedInt));
+    formatter.insert((0, 1001), ("signedB".into(), DataRecordType::SignedInt));
+
+    // template 256: signedA (3 bytes), signedB (5 bytes)
+    let template_bytes = hex::decode("000200100100000203E8000303E90005").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    let data_bytes = hex::decode("0100000CFFFFFE0000000001").unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(
+        set,
+        Set {
+            records: Records::Data {
+                set_id: 256,
+                data: vec![data_record! {
+                    "signedA": I32(-2),
+                    "signedB": I64(1),
+                }],
+            },
+        }
+    );
+
+    // written with the same reduced lengths
+    let mut writer = Cursor::new(Vec::new());
+    set.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::aligned(1)),
+    )
+    .unwrap();
+    assert_eq!(writer.into_inner(), data_bytes);
+}
+
+#[test]
+fn reduced_length_float() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // template 256: samplingProbability (float64, as 4 bytes)
+    let template_bytes = hex::decode("0002000C0100000101370004").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    let data_bytes = hex::decode("010000083F000000").unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    let expected = Set {
+        records: Records::Data {
+            set_id: 256,
+            data: vec![data_record! { "samplingProbability": F32(0.5) }],
+        },
+    };
+    assert_eq!(set, expected);
+
+    // float64 values are written as float32 to match the template
+    let set = Set {
+        records: Records::Data {
+            set_id: 256,
+            data: vec![data_record! { "samplingProbability": F64(0.5) }],
+        },
+    };
+    let mut writer = Cursor::new(Vec::new());
+    set.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::aligned(1)),
+    )
+    .unwrap();
+    assert_eq!(writer.into_inner(), data_bytes);
+}
+
+#[test]
+fn normalized_integers() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // template 256: octetDeltaCount (2 bytes), packetDeltaCount (4 bytes)
+    let template_bytes = hex::decode("00020010010000020001000200020004").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    let data_bytes = hex::decode("0100000A000100000002").unwrap();
+    let Set {
+        records: Records::Data { set_id, mut data },
+    } = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap()
+    else {
+        panic!("expected data set");
+    };
+    assert_eq!(
+        data[0].values[&DataRecordKey::Str("octetDeltaCount")].as_u64(),
+        Some(1)
+    );
+    assert_eq!(DataRecordValue::I8(-1).as_i64(), Some(-1));
+    assert_eq!(DataRecordValue::I8(-1).as_u64(), None);
+
+    data[0].normalize();
+    assert_eq!(
+        data[0],
+        data_record! {
+            "octetDeltaCount": U64(1),
+            "packetDeltaCount": U64(2),
+        }
+    );
+
+    // the template still controls the width written
+    let mut writer = Cursor::new(Vec::new());
+    Set {
+        records: Records::Data { set_id, data },
+    }
+    .write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::aligned(1)),
+    )
+    .unwrap();
+    assert_eq!(writer.into_inner(), data_bytes);
+}
+
+#[test]
+fn booleans() {
+    let templates = RefCell::new(HashMap::new());
+    let mut formatter = get_default_formatter();
+    formatter.insert((0, 1000), ("flag".into(), DataRecordType::Bool));
+    let strict = ParseOptions {
+        strict_booleans: true,
+        ..Default::default()
+    };
+
+    // template 256: flag
+    let template_bytes = hex::decode("0002000C0100000103E80001").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, strict),
+    )
+    .unwrap();
+
+    for (value, flag) in [(true, "01"), (false, "02")] {
+        let data_bytes = hex::decode("01000005".to_owned() + flag).unwrap();
+        let set = Set {
+            records: Records::Data {
+                set_id: 256,
+                data: vec![data_record! { "flag": Bool(value) }],
+            },
+        };
+        let read_set = Set::read_args(
+            &mut Cursor::new(&data_bytes),
+            (&templates, &formatter, strict),
+        )
+        .unwrap();
+        assert_eq!(read_set, set);
+
+        let mut writer = Cursor::new(Vec::new());
+        set.write_args(
+            &mut writer,
+            (&templates, &formatter, WriteOptions::aligned(1)),
+        )
+        .unwrap();
+        assert_eq!(writer.into_inner(), data_bytes);
+    }
+
+    // other values are false by default, and rejected in strict mode
+    let data_bytes = hex::decode("0100000503").unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(
+        set.records,
+        Records::Data {
+            set_id: 256,
+            data: vec![data_record! { "flag": Bool(false) }],
+        }
+    );
+    assert!(Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, strict)
+    )
+    .is_err());
+}
+
+#[test]
+fn lenient_field_lengths() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let lenient = ParseOptions {
+        lenient_field_lengths: true,
+        ..Default::default()
+    };
+
+    // template 256: sourceIPv4Address (6 bytes), destinationTransportPort
+    let template_bytes = hex::decode("000200100100000200080006000B0002").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    let data_bytes = hex::decode("0100000C0A00000100000050").unwrap();
+    assert!(Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default())
+    )
+    .is_err());
+
+    let set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, lenient),
+    )
+    .unwrap();
+    let expected = Set {
+        records: Records::Data {
+            set_id: 256,
+            data: vec![data_record! {
+                "sourceIPv4Address": Bytes(vec![10, 0, 0, 1, 0, 0]),
+                "destinationTransportPort": U16(80),
+            }],
+        },
+    };
+    assert_eq!(set, expected);
+
+    let mut writer = Cursor::new(Vec::new());
+    expected
+        .write_args(
+            &mut writer,
+            (&templates, &formatter, WriteOptions::aligned(1)),
+        )
+        .unwrap();
+    assert_eq!(writer.into_inner(), data_bytes);
+}
+
+#[test]
+fn long_variable_length() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // template 256: interfaceName (variable length)
+    let template_bytes = hex::decode("0002000C010000010052FFFF").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    let set = Set {
+        records: Records::Data {
+            set_id: 256,
+            data: vec![data_record! { "interfaceName": String("eth0".into()) }],
+        },
+    };
+    for (long_variable_length, data_bytes) in [
+        (false, "010000090465746830"),
+        (true, "0100000BFF000465746830"),
+    ] {
+        let data_bytes = hex::decode(data_bytes).unwrap();
+        let options = WriteOptions {
+            long_variable_length,
+            ..Default::default()
+        };
+        let mut writer = Cursor::new(Vec::new());
+        set.write_args(&mut writer, (&templates, &formatter, options))
+            .unwrap();
+        assert_eq!(writer.into_inner(), data_bytes);
+
+        let read_set = Set::read_args(
+            &mut Cursor::new(&data_bytes),
+            (&templates, &formatter, ParseOptions::default()),
+        )
+        .unwrap();
+        assert_eq!(read_set, set);
+    }
+}
+
+#[test]
+fn raw_data_sets() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = Formatter::default();
+
+    // template 256: sourceIPv4Address, interfaceName (variable length)
+    let template_bytes = hex::decode("0002001001000002000800040052FFFF").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    let data_bytes = hex::decode("010000120A0000010465746830C0A8000100").unwrap();
+    let set = parse_raw_data_set(&data_bytes, &templates).unwrap();
+    let ip = FieldSpecifier::new(None, 8, 4);
+    let name = FieldSpecifier::new(None, 82, u16::MAX);
+    assert_eq!(
+        set,
+        RawDataSet {
+            set_id: 256,
+            records: vec![
+                RawDataRecord {
+                    fields: vec![
+                        (ip.clone(), vec![10, 0, 0, 1]),
+                        (name.clone(), b"eth0".to_vec()),
+                    ],
+                },
+                RawDataRecord {
+                    fields: vec![(ip, vec![192, 168, 0, 1]), (name, vec![])],
+                },
+            ],
+        }
+    );
+
+    let mut writer = Cursor::new(Vec::new());
+    set.write(&mut writer).unwrap();
+    assert_eq!(writer.into_inner(), data_bytes);
+}
+
+#[test]
+fn default_information_elements() {
+    assert_eq!(
+        default_information_element(8),
+        Some(("sourceIPv4Address", DataRecordType::Ipv4Addr))
+    );
+    assert_eq!(default_information_element(u16::MAX), None);
+
+    let formatter = get_default_formatter();
+    for ((enterprise_number, id), (name, ty)) in &formatter {
+        assert_eq!(enterprise_number, 0);
+        assert_eq!(default_information_element(id), Some((&name[..], *ty)));
+    }
+
+    // inserted elements replace the static ones
+    let mut formatter = get_default_formatter();
+    let known = formatter.len();
+    assert_eq!(
+        formatter.insert((0, 1), ("bytes".into(), DataRecordType::UnsignedInt)),
+        Some(("octetDeltaCount".into(), DataRecordType::UnsignedInt))
+    );
+    assert_eq!(
+        formatter.get(&(0, 1)),
+        Some(&("bytes".into(), DataRecordType::UnsignedInt))
+    );
+    assert_eq!(formatter.len(), known);
+    assert_ne!(formatter, get_default_formatter());
+    assert_eq!(Formatter::new().get(&(0, 1)), None);
+}
+
+#[test]
+fn resolve_names() {
+    let mut formatter = get_default_formatter();
+    assert_eq!(
+        formatter.resolve("sourceIPv4Address"),
+        Some((0, 8, DataRecordType::Ipv4Addr))
+    );
+    assert_eq!(formatter.resolve("noSuchElement"), None);
+
+    // iana elements win over enterprise elements of the same name
+    formatter.insert(
+        (9, 8),
+        ("sourceIPv4Address".into(), DataRecordType::Ipv4Addr),
+    );
+    formatter.insert((9, 1), ("myCounter".into(), DataRecordType::UnsignedInt));
+    formatter.insert((5, 2), ("myCounter".into(), DataRecordType::UnsignedInt));
+    assert_eq!(
+        formatter.resolve("sourceIPv4Address"),
+        Some((0, 8, DataRecordType::Ipv4Addr))
+    );
+    assert_eq!(
+        formatter.resolve("myCounter"),
+        Some((5, 2, DataRecordType::UnsignedInt))
+    );
+
+    assert_eq!(
+        FieldSpecifier::by_name("sourceIPv4Address", 4, &formatter),
+        Some(FieldSpecifier::new(None, 8, 4))
+    );
+    assert_eq!(
+        FieldSpecifier::by_name("myCounter", 8, &formatter),
+        Some(FieldSpecifier::new(Some(5), 2, 8))
+    );
+    assert_eq!(
+        FieldSpecifier::by_name("noSuchElement", 4, &formatter),
+        None
+    );
+}
+
+#[test]
+fn counter_semantics() {
+    let semantics = get_default_semantics();
+    // octetDeltaCount, octetTotalCount, sourceTransportPort, sourceIPv4Address
+    assert_eq!(
+        semantics.get(&(0, 1)),
+        Some(&DataTypeSemantics::DeltaCounter)
+    );
+    assert_eq!(
+        semantics.get(&(0, 85)),
+        Some(&DataTypeSemantics::TotalCounter)
+    );
+    assert_eq!(semantics.get(&(0, 7)), Some(&DataTypeSemantics::Identifier));
+    assert_eq!(semantics.get(&(0, 8)), None);
+
+    assert_eq!(
+        DataRecordValue::U32(150).counter_delta(&DataRecordValue::U32(100), 4),
+        Some(50)
+    );
+    assert_eq!(
+        DataRecordValue::U32(5).counter_delta(&DataRecordValue::U32(u32::MAX - 4), 4),
+        Some(10)
+    );
+    // a reduced-length counter wraps at the width of its field
+    assert_eq!(
+        DataRecordValue::U32(5).counter_delta(&DataRecordValue::U32(0xff_fffb), 3),
+        Some(10)
+    );
+    assert_eq!(
+        DataRecordValue::U64(5).counter_delta(&DataRecordValue::U64(0xff_fffb), 3),
+        Some(10)
+    );
+    assert_eq!(
+        DataRecordValue::U64(5).counter_delta(&DataRecordValue::U64(u64::MAX), 8),
+        Some(6)
+    );
+    assert_eq!(
+        DataRecordValue::U64(5).counter_delta(&DataRecordValue::U32(1), 8),
+        None
+    );
+}
+
+#[cfg(feature = "ipnet")]
+#[test]
+fn ip_prefixes() {
+    use ipfixrw::types::ip_prefix;
+
+    let record = data_record! {
+        "sourceIPv4Prefix": Ipv4Addr(Ipv4Addr::new(10, 1, 0, 0)),
+        "sourceIPv4PrefixLength": U8(16),
+        "destinationIPv6Prefix": Ipv6Addr("2001:db8::".parse().unwrap()),
+        "destinationIPv6PrefixLength": U8(32),
+    };
+    assert_eq!(
+        record.source_ipv4_prefix(),
+        Some("10.1.0.0/16".parse().unwrap())
+    );
+    assert_eq!(
+        record.destination_ipv6_prefix(),
+        Some("2001:db8::/32".parse().unwrap())
+    );
+    assert_eq!(record.destination_ipv4_prefix(), None);
+    assert_eq!(record.source_ipv6_prefix(), None);
+
+    // invalid prefix lengths
+    assert_eq!(
+        ip_prefix(
+            &DataRecordValue::Ipv4Addr(Ipv4Addr::new(10, 1, 0, 0)),
+            &DataRecordValue::U8(33)
+        ),
+        None
+    );
+}
+
+#[test]
+fn ntp_timestamps() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // template 256: flowStartMicroseconds, flowStartNanoseconds
+    let template_bytes = hex::decode("0002001001000002009A0008009C0008").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+
+    // 1970-01-01 00:00:00.5 and 2023-01-01 00:00:00.000000001
+    let data_bytes =
+        hex::decode(concat!("01000014", "83AA7E8080000000", "E75B4B8000000005")).unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    let Records::Data { data, .. } = &set.records else {
+        panic!("expected data set");
+    };
+    let micros = &data[0].values[&DataRecordKey::Str("flowStartMicroseconds")];
+    let nanos = &data[0].values[&DataRecordKey::Str("flowStartNanoseconds")];
+    assert_eq!(
+        micros,
+        &DataRecordValue::DateTimeMicroseconds(NtpTimestamp {
+            seconds: 0x83AA7E80,
+            fraction: 0x80000000,
+        })
+    );
+    let DataRecordValue::DateTimeMicroseconds(micros) = micros else {
+        unreachable!()
+    };
+    let DataRecordValue::DateTimeNanoseconds(nanos) = nanos else {
+        panic!("expected nanoseconds");
+    };
+    assert_eq!(micros.to_unix_nanos(), 500_000_000);
+    assert_eq!(nanos.to_unix_nanos(), 1_672_531_200_000_000_001);
+    assert_eq!(
+        NtpTimestamp::from_unix_nanos(1_672_531_200_000_000_001),
+        Some(*nanos)
+    );
+    assert_eq!(NtpTimestamp::from_unix_nanos(i64::MAX), None);
+
+    // after the wrap in 2036
+    let era_1 = NtpTimestamp {
+        seconds: 0,
+        fraction: 0,
+    };
+    assert_eq!(era_1.to_unix_nanos(), 2_085_978_496_000_000_000);
+    assert_eq!(
+        NtpTimestamp::from_unix_nanos(era_1.to_unix_nanos()),
+        Some(era_1)
+    );
+
+    let mut writer = Cursor::new(Vec::new());
+    set.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::aligned(1)),
+    )
+    .unwrap();
+    assert_eq!(writer.into_inner(), data_bytes);
+}
+
+#[test]
+fn value_conversions() {
+    assert_eq!(u64::try_from(DataRecordValue::U8(1)), Ok(1));
+    assert_eq!(u8::try_from(&DataRecordValue::U64(255)), Ok(255));
+    assert_eq!(
+        u8::try_from(&DataRecordValue::U64(256)),
+        Err(ValueConversionError)
+    );
+    assert_eq!(i64::try_from(&DataRecordValue::I16(-2)), Ok(-2));
+    assert_eq!(i64::try_from(&DataRecordValue::U32(2)), Ok(2));
+    assert_eq!(
+        u32::try_from(&DataRecordValue::I32(-2)),
+        Err(ValueConversionError)
+    );
+    assert_eq!(f64::try_from(DataRecordValue::F32(0.5)), Ok(0.5));
+    assert_eq!(
+        f32::try_from(DataRecordValue::F64(0.5)),
+        Err(ValueConversionError)
+    );
+    assert_eq!(bool::try_from(DataRecordValue::Bool(true)), Ok(true));
+
+    let string = DataRecordValue::String("abc".to_string());
+    assert_eq!(<&str>::try_from(&string), Ok("abc"));
+    assert_eq!(String::try_from(string), Ok("abc".to_string()));
+    let bytes = DataRecordValue::Bytes(vec![1, 2]);
+    assert_eq!(<&[u8]>::try_from(&bytes), Ok(&[1, 2][..]));
+    assert_eq!(Vec::<u8>::try_from(bytes), Ok(vec![1, 2]));
+    assert_eq!(
+        String::try_from(DataRecordValue::U8(0)),
+        Err(ValueConversionError)
+    );
+
+    let ip = DataRecordValue::Ipv4Addr(Ipv4Addr::new(127, 0, 0, 1));
+    assert_eq!(Ipv4Addr::try_from(&ip), Ok(Ipv4Addr::new(127, 0, 0, 1)));
+    assert_eq!(
+        IpAddr::try_from(ip),
+        Ok(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))
+    );
+    assert_eq!(
+        Ipv6Addr::try_from(DataRecordValue::Ipv6Addr(Ipv6Addr::LOCALHOST)),
+        Ok(Ipv6Addr::LOCALHOST)
+    );
+    assert_eq!(
+        <[u8; 6]>::try_from(DataRecordValue::MacAddress([1, 2, 3, 4, 5, 6].into())),
+        Ok([1, 2, 3, 4, 5, 6])
+    );
+
+    // e.g. collecting values from records
+    let records = [
+        data_record! { "octetDeltaCount": U16(10) },
+        data_record! { "octetDeltaCount": U64(20) },
+    ];
+    let total: u64 = records
+        .iter()
+        .filter_map(|r| r.values.get(&DataRecordKey::Str("octetDeltaCount")))
+        .map(|v| u64::try_from(v).unwrap())
+        .sum();
+    assert_eq!(total, 30);
+}
+
+#[test]
+fn mac_addresses() {
+    let mac = MacAddress([0xaa, 0xbb, 0xcc, 0x01, 0x02, 0x03]);
+    assert_eq!(mac.to_string(), "aa:bb:cc:01:02:03");
+    assert_eq!("aa:bb:cc:01:02:03".parse(), Ok(mac));
+    assert_eq!("AA-BB-CC-01-02-03".parse(), Ok(mac));
+    for invalid in [
+        "",
+        "aa:bb:cc:01:02",
+        "aa:bb:cc:01:02:03:04",
+        "aa:bb:cc:1:02:03",
+        "aa:bb:cc:01:02:0g",
+    ] {
+        assert_eq!(invalid.parse::<MacAddress>(), Err(ParseMacAddressError));
+    }
+
+    let record = data_record! {
+        "sourceMacAddress": MacAddress("aa:bb:cc:01:02:03".parse().unwrap()),
+    };
+    assert_eq!(
+        MacAddress::try_from(&record.values[&DataRecordKey::Str("sourceMacAddress")]),
+        Ok(mac)
+    );
+}
+
+#[cfg(feature = "macaddr")]
+#[test]
+fn macaddr_interop() {
+    let mac = MacAddress([0xaa, 0xbb, 0xcc, 0x01, 0x02, 0x03]);
+    let other: macaddr::MacAddr6 = mac.into();
+    assert_eq!(
+        other,
+        macaddr::MacAddr6::new(0xaa, 0xbb, 0xcc, 0x01, 0x02, 0x03)
+    );
+    assert_eq!(MacAddress::from(other), mac);
+}
+
+#[test]
+fn tcp_control_bits() {
+    let record = data_record! { "tcpControlBits": U8(0x12) };
+    let bits = record.tcp_control_bits().unwrap();
+    assert_eq!(bits, TcpControlBits::SYN | TcpControlBits::ACK);
+    assert!(bits.contains(TcpControlBits::SYN));
+    assert!(!bits.contains(TcpControlBits::FIN));
+
+    // unassigned bits are kept
+    let record = data_record! { "tcpControlBits": U16(0x8001) };
+    let bits = record.tcp_control_bits().unwrap();
+    assert!(bits.contains(TcpControlBits::FIN));
+    assert_eq!(DataRecordValue::from(bits), DataRecordValue::U16(0x8001));
+
+    assert_eq!(
+        data_record! { "octetDeltaCount": U8(1) }.tcp_control_bits(),
+        None
+    );
+}
+
+#[test]
+fn protocol_identifier() {
+    let record = data_record! { "protocolIdentifier": U8(6) };
+    assert_eq!(record.protocol_identifier(), Some(ProtocolIdentifier::Tcp));
+
+    assert_eq!(ProtocolIdentifier::from(17), ProtocolIdentifier::Udp);
+    assert_eq!(ProtocolIdentifier::from(58), ProtocolIdentifier::Ipv6Icmp);
+    assert_eq!(
+        ProtocolIdentifier::from(200),
+        ProtocolIdentifier::Other(200)
+    );
+    for value in 0..=u8::MAX {
+        assert_eq!(u8::from(ProtocolIdentifier::from(value)), value);
+    }
+    assert_eq!(
+        DataRecordValue::from(ProtocolIdentifier::Gre),
+        DataRecordValue::U8(47)
+    );
+
+    assert_eq!(
+        data_record! { "protocolIdentifier": U16(256) }.protocol_identifier(),
+        None
+    );
+}
+
+#[test]
+fn flow_key() {
+    let tcp = data_record! {
+        "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+        "destinationIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 2)),
+        "tcpSourcePort": U16(40000),
+        "tcpDestinationPort": U16(443),
+        "protocolIdentifier": U8(6),
+    };
+    let key = FlowKey::from_record(&tcp).unwrap();
+    assert_eq!(
+        key,
+        FlowKey {
+            source_address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            destination_address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            source_port: 40000,
+            destination_port: 443,
+            protocol: ProtocolIdentifier::Tcp,
+        }
+    );
+    let reply = data_record! {
+        "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 2)),
+        "destinationIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+        "sourceTransportPort": U16(443),
+        "destinationTransportPort": U16(40000),
+        "protocolIdentifier": U8(6),
+    };
+    assert_eq!(FlowKey::from_record(&reply), Some(key.reversed()));
+
+    let icmp = data_record! {
+        "sourceIPv6Address": Ipv6Addr(Ipv6Addr::LOCALHOST),
+        "destinationIPv6Address": Ipv6Addr(Ipv6Addr::LOCALHOST),
+        "protocolIdentifier": U8(58),
+    };
+    let key = FlowKey::from_record(&icmp).unwrap();
+    assert_eq!((key.source_port, key.destination_port), (0, 0));
+    assert_eq!(key.protocol, ProtocolIdentifier::Ipv6Icmp);
+
+    assert_eq!(
+        FlowKey::from_record(&data_record! { "protocolIdentifier": U8(6) }),
+        None
+    );
+}
+
+#[test]
+fn subregistry_values() {
+    let record = data_record! {
+        "flowEndReason": U8(2),
+        "forwardingStatus": U8(0x81),
+        "natEvent": U8(4),
+        "firewallEvent": U8(3),
+        "selectorAlgorithm": U8(1),
+    };
+    assert_eq!(record.flow_end_reason(), Some(FlowEndReason::ActiveTimeout));
+    assert_eq!(
+        record.forwarding_status(),
+        Some(ForwardingStatus::DropAclDeny)
+    );
+    assert_eq!(record.nat_event(), Some(NatEvent::Nat44SessionCreate));
+    assert_eq!(record.firewall_event(), Some(FirewallEvent::FlowDenied));
+    assert_eq!(
+        record.selector_algorithm(),
+        Some(SelectorAlgorithm::SystematicCountBasedSampling)
+    );
+
+    assert_eq!(FlowEndReason::from(0), FlowEndReason::Unknown(0));
+    assert_eq!(u8::from(FlowEndReason::Unknown(99)), 99);
+    assert_eq!(ForwardingStatus::from(0), ForwardingStatus::Unspecified);
+    assert_eq!(
+        DataRecordValue::from(NatEvent::QuotaExceeded),
+        DataRecordValue::U8(13)
+    );
+
+    // selectorAlgorithm is unsigned16, so values beyond 255 are kept
+    let record = data_record! { "selectorAlgorithm": U16(300) };
+    assert_eq!(
+        record.selector_algorithm(),
+        Some(SelectorAlgorithm::Unknown(300))
+    );
+    assert_eq!(
+        DataRecordValue::from(SelectorAlgorithm::SystematicCountBasedSampling),
+        DataRecordValue::U16(1)
+    );
+}
+
+#[test]
+fn system_time_timestamps() {
+    let time = UNIX_EPOCH + Duration::from_nanos(1_672_531_200_001_001_001);
+
+    for (ty, truncated) in [
+        (DataRecordType::DateTimeSeconds, 1_672_531_200_000_000_000),
+        (
+            DataRecordType::DateTimeMilliseconds,
+            1_672_531_200_001_000_000,
+        ),
+        (
+            DataRecordType::DateTimeMicroseconds,
+            1_672_531_200_001_001_000,
+        ),
+        (
+            DataRecordType::DateTimeNanoseconds,
+            1_672_531_200_001_001_001,
+        ),
+    ] {
+        let value = DataRecordValue::from_system_time(ty, time).unwrap();
+        let truncated = UNIX_EPOCH + Duration::from_nanos(truncated);
+        assert_eq!(value.try_as_system_time(), Some(truncated));
+        assert_eq!(SystemTime::try_from(&value), Ok(truncated));
+        assert_eq!(
+            Duration::try_from(value),
+            Ok(truncated.duration_since(UNIX_EPOCH).unwrap())
+        );
+    }
+
+    let timestamp = NtpTimestamp::try_from(time).unwrap();
+    assert_eq!(SystemTime::from(timestamp), time);
+
+    assert_eq!(DataRecordValue::U32(0).try_as_system_time(), None);
+    assert_eq!(
+        SystemTime::try_from(DataRecordValue::U32(0)),
+        Err(ValueConversionError)
+    );
+    assert_eq!(
+        DataRecordValue::from_system_time(
+            DataRecordType::DateTimeSeconds,
+            UNIX_EPOCH - Duration::from_secs(1)
+        ),
+        None
+    );
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn chrono_timestamps() {
+    use chrono::{TimeZone, Utc};
+
+    let datetime = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap()
+        + chrono::Duration::nanoseconds(1_001_001);
+
+    for (ty, truncated) in [
+        (
+            DataRecordType::DateTimeSeconds,
+            Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
+        ),
+        (
+            DataRecordType::DateTimeMilliseconds,
+            Utc.timestamp_nanos(1_672_531_200_001_000_000),
+        ),
+        (
+            DataRecordType::DateTimeMicroseconds,
+            Utc.timestamp_nanos(1_672_531_200_001_001_000),
+        ),
+        (DataRecordType::DateTimeNanoseconds, datetime),
+    ] {
+        let value = DataRecordValue::from_datetime(ty, &datetime).unwrap();
+        assert_eq!(value.to_datetime(), Some(truncated));
+    }
+    assert_eq!(
+        DataRecordValue::from_datetime(DataRecordType::UnsignedInt, &datetime),
+        None
+    );
+    assert_eq!(DataRecordValue::U32(0).to_datetime(), None);
+
+    let message = Message {
+        export_time: 1_672_531_200,
+        sequence_number: 0,
+        observation_domain_id: 0,
+        sets: [].into_iter().collect(),
+    };
+    assert_eq!(
+        message.export_datetime(),
+        Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap()
+    );
+}
+
+#[test]
+fn options_template_withdrawal() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // options templates 300 and 301, with meteringProcessId as scope
+    let options_template_bytes = hex::decode(concat!(
+        "0003001C",
+        "012C00020001008F000400290008",
+        "012D00010001008F0004",
+    ))
+    .unwrap();
+    Set::read_args(
+        &mut Cursor::new(&options_template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(templates.borrow().len(), 2);
+
+    // withdraw 300 on read, with no scope field count
+    let withdrawal_bytes = hex::decode("00030008012C0000").unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&withdrawal_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(
+        set,
+        Set {
+            records: Records::options_template_withdrawal([300])
+        }
+    );
+    assert!(!templates.borrow().contains_key(&300));
+    assert!(templates.borrow().contains_key(&301));
+
+    // withdraw all options templates on write, leaving other templates
+    templates
+        .insert_template(500, Template::new(vec![]))
+        .unwrap();
+    let mut writer = Cursor::new(Vec::new());
+    Set {
+        records: Records::withdraw_all_options(),
+    }
+    .write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::aligned(4)),
+    )
+    .unwrap();
+    assert_eq!(
+        writer.into_inner(),
+        hex::decode("0003000800030000").unwrap()
+    );
+    assert_eq!(templates.borrow().keys().collect::<Vec<_>>(), vec![&500]);
+}
+
+#[test]
+fn scoped_templates() {
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+
+    // contains data sets for templates 999, 500, 999
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let templates: ScopedTemplateStore<&str> = ScopedTemplateStore::new();
+    let formatter = get_default_formatter();
+
+    let msg = parse_ipfix_message_scoped(template_bytes, &templates, "a", &formatter).unwrap();
+    let scope = templates.scope("a", msg.observation_domain_id);
+    assert_eq!(scope.borrow().len(), 3);
+
+    assert!(parse_ipfix_message_scoped(data_bytes, &templates, "a", &formatter).is_ok());
+    // templates from peer "a" are not visible to peer "b"
+    assert!(parse_ipfix_message_scoped(data_bytes, &templates, "b", &formatter).is_err());
+
+    templates.remove_peer(&"a");
+    assert!(parse_ipfix_message_scoped(data_bytes, &templates, "a", &formatter).is_err());
+}
+
+#[test]
+fn template_expiry() {
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+
+    // contains data sets for templates 999, 500, 999
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let formatter = get_default_formatter();
+
+    let templates =
+        ExpiringTemplateStore::new(RefCell::new(HashMap::new()), Duration::from_secs(1800));
+    parse_ipfix_message(template_bytes, &templates, &formatter).unwrap();
+    assert!(parse_ipfix_message(data_bytes, &templates, &formatter).is_ok());
+    assert!(templates.sweep().is_empty());
+
+    let mut expired = templates.expire_older_than(Instant::now());
+    expired.sort();
+    assert_eq!(expired, vec![500, 501, 999]);
+    assert!(parse_ipfix_message(data_bytes, &templates, &formatter).is_err());
+
+    // stale templates are not used, even before being swept
+    let templates = ExpiringTemplateStore::new(RefCell::new(HashMap::new()), Duration::ZERO);
+    parse_ipfix_message(template_bytes, &templates, &formatter).unwrap();
+    assert!(parse_ipfix_message(data_bytes, &templates, &formatter).is_err());
+}
+
+#[test]
+fn template_redefinition_policy() {
+    // template 256: sourceIPv4Address
+    let template_bytes = hex::decode("0002000C0100000100080004").unwrap();
+    // template 256: destinationIPv4Address
+    let redefined_bytes = hex::decode("0002000C01000001000C0004").unwrap();
+
+    let formatter = get_default_formatter();
+    let read_set = |bytes: &[u8], templates: &dyn TemplateStorage| {
+        Set::read_args(
+            &mut Cursor::new(bytes),
+            (templates, &formatter, ParseOptions::default()),
+        )
+    };
+
+    let templates =
+        PolicyTemplateStore::new(RefCell::new(HashMap::new()), RedefinitionPolicy::Error);
+    assert!(read_set(&template_bytes, &templates).is_ok());
+    // identical re-announcement is fine
+    assert!(read_set(&template_bytes, &templates).is_ok());
+    assert!(read_set(&redefined_bytes, &templates).is_err());
+
+    let templates = PolicyTemplateStore::new(
+        RefCell::new(HashMap::new()),
+        RedefinitionPolicy::Callback(Box::new(|template_id, _, _| template_id != 256)),
+    );
+    read_set(&template_bytes, &templates).unwrap();
+    read_set(&redefined_bytes, &templates).unwrap();
+    let Some(Template::Template {
+        field_specifiers, ..
+    }) = templates.get_template(256)
+    else {
+        panic!("missing template");
+    };
+    assert_eq!(
+        field_specifiers[0].name,
+        DataRecordKey::Str("sourceIPv4Address")
+    );
+}
+
+#[derive(Default, Debug)]
+struct EventLog(RefCell<Vec<String>>);
+
+impl TemplateObserver for EventLog {
+    fn on_insert(&self, template_id: u16, _template: &Template) {
+        self.0.borrow_mut().push(format!("insert {template_id}"));
+    }
+    fn on_replace(&self, template_id: u16, _old: &Template, _new: &Template) {
+        self.0.borrow_mut().push(format!("replace {template_id}"));
+    }
+    fn on_withdraw(&self, template_id: u16, _template: &Template) {
+        self.0.borrow_mut().push(format!("withdraw {template_id}"));
+    }
+    fn on_expire(&self, template_id: u16, _template: &Template) {
+        self.0.borrow_mut().push(format!("expire {template_id}"));
+    }
+}
+
+#[test]
+fn template_observer() {
+    // template 256: sourceIPv4Address
+    let template_bytes = hex::decode("0002000C0100000100080004").unwrap();
+    // template 256: destinationIPv4Address
+    let redefined_bytes = hex::decode("0002000C01000001000C0004").unwrap();
+    // withdraw template 256
+    let withdrawal_bytes = hex::decode("0002000801000000").unwrap();
+
+    let formatter = get_default_formatter();
+    let templates =
+        ExpiringTemplateStore::new(RefCell::new(HashMap::new()), Duration::from_secs(1800))
+            .with_observer(EventLog::default());
+    for bytes in [
+        &template_bytes,
+        &template_bytes,
+        &redefined_bytes,
+        &withdrawal_bytes,
+        &template_bytes,
+    ] {
+        Set::read_args(
+            &mut Cursor::new(bytes),
+            (&templates, &formatter, ParseOptions::default()),
+        )
+        .unwrap();
+    }
+    templates.expire_older_than(Instant::now());
+
+    assert_eq!(
+        *templates.observer().0.borrow(),
+        [
+            "insert 256",
+            "replace 256",
+            "withdraw 256",
+            "insert 256",
+            "expire 256"
+        ]
+    );
+
+    // any store can be observed by wrapping it
+    let templates = ObservedTemplateStore::new(RefCell::new(HashMap::new()), EventLog::default());
+    for bytes in [&template_bytes, &redefined_bytes, &withdrawal_bytes] {
+        Set::read_args(
+            &mut Cursor::new(bytes),
+            (&templates, &formatter, ParseOptions::default()),
+        )
+        .unwrap();
+    }
+    assert_eq!(
+        *templates.observer().0.borrow(),
+        ["insert 256", "replace 256", "withdraw 256"]
+    );
+}
+
+#[test]
+fn buffered_data_sets() {
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+
+    // contains data sets for templates 999, 500, 999
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let templates = BufferingTemplateStore::new(RefCell::new(HashMap::new()), 16);
+    let formatter = get_default_formatter();
+
+    let data_message = parse_ipfix_message(data_bytes, &templates, &formatter).unwrap();
+    assert!(data_message.sets.is_empty());
+    assert_eq!(templates.buffered_sets(), 3);
+
+    // buffered sets are decoded once the templates arrive
+    let template_message = parse_ipfix_message(template_bytes, &templates, &formatter).unwrap();
+    assert_eq!(template_message.sets.len(), 4);
+    assert_eq!(template_message.iter_data_records().count(), 21);
+    assert_eq!(templates.buffered_sets(), 0);
+
+    // without room to buffer, missing templates are still errors
+    let templates = BufferingTemplateStore::new(RefCell::new(HashMap::new()), 0);
+    assert!(parse_ipfix_message(data_bytes, &templates, &formatter).is_err());
+}
+
+#[test]
+fn template_usage() {
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    // contains data sets for templates 999, 500, 999
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let templates = UsageTemplateStore::new(RefCell::new(HashMap::new()));
+    let formatter = get_default_formatter();
+
+    parse_ipfix_message(template_bytes, &templates, &formatter).unwrap();
+    assert!(templates.all_usage().is_empty());
+    let mut unused = templates.unused_templates();
+    unused.sort();
+    assert_eq!(unused, vec![500, 501, 999]);
+
+    let message = parse_ipfix_message(data_bytes, &templates, &formatter).unwrap();
+    assert_eq!(templates.unused_templates(), vec![501]);
+
+    let records: u64 = templates.all_usage().iter().map(|(_, u)| u.records).sum();
+    assert_eq!(records, message.iter_data_records().count() as u64);
+    let bytes: u64 = templates.all_usage().iter().map(|(_, u)| u.bytes).sum();
+    assert_eq!(bytes, data_bytes.len() as u64 - 16);
+
+    let usage = templates.usage(999).unwrap();
+    assert_eq!(usage.first_seen, message.export_time);
+    assert_eq!(usage.last_seen, message.export_time);
+
+    // withdrawal resets usage
+    templates.remove_template(999);
+    assert_eq!(templates.usage(999), None);
+}
+
+#[test]
+fn options_template_scope_fields() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // scope: meteringProcessId; fields: exportedMessageTotalCount, exportedFlowRecordTotalCount
+    let record = OptionsTemplateRecord {
+        template_id: 300,
+        scope_field_count: 1,
+        field_specifiers: [
+            FieldSpecifier::new(None, 143, 4),
+            FieldSpecifier::new(None, 41, 8),
+            FieldSpecifier::new(None, 42, 8),
+        ]
+        .into_iter()
+        .collect(),
+    };
+    templates
+        .insert_options_template_records(std::slice::from_ref(&record), &formatter)
+        .unwrap();
+
+    let template = templates.get_template(300).unwrap();
+    let scope_names: Vec<_> = template
+        .scope_field_specifiers()
+        .iter()
+        .map(|f| f.name.clone())
+        .collect();
+    assert_eq!(scope_names, vec![DataRecordKey::Str("meteringProcessId")]);
+    assert_eq!(template.field_specifiers().count(), 3);
+
+    // the scope field count survives exporting the template again
+    let (_, options_records) = template_records(&templates);
+    assert_eq!(options_records, vec![record.clone()]);
+
+    // the scope field count can be derived from the scope fields
+    assert_eq!(
+        OptionsTemplateRecord::new(
+            300,
+            vec![FieldSpecifier::new(None, 143, 4)],
+            vec![
+                FieldSpecifier::new(None, 41, 8),
+                FieldSpecifier::new(None, 42, 8)
+            ],
+        ),
+        Some(record.clone())
+    );
+    assert_eq!(
+        record.scope_field_specifiers(),
+        &[FieldSpecifier::new(None, 143, 4)]
+    );
+    assert_eq!(OptionsTemplateRecord::new(300, vec![], vec![]), None);
+
+    // scope field counts larger than the field count are rejected
+    let invalid_bytes = hex::decode("0003000E012C00010003008F0004").unwrap();
+    assert!(Set::read_args(
+        &mut Cursor::new(&invalid_bytes),
+        (&templates, &formatter, ParseOptions::default())
+    )
+    .is_err());
+    let invalid_record = OptionsTemplateRecord {
+        scope_field_count: 4,
+        ..record
+    };
+    assert!(invalid_record.write(&mut Cursor::new(Vec::new())).is_err());
+
+    // scope values are kept separate in decoded records
+    let mut data_record = data_record! {
+        "exportedMessageTotalCount": U64(10),
+        "exportedFlowRecordTotalCount": U64(200),
+    };
+    data_record.scope_values.insert(
+        DataRecordKey::Str("meteringProcessId"),
+        DataRecordValue::U32(7),
+    );
+    let mut bytes = Cursor::new(Vec::new());
+    data_record
+        .write_options(
+            &mut bytes,
+            Endian::Big,
+            (300, &templates, WriteOptions::default()),
+        )
+        .unwrap();
+    assert_eq!(bytes.get_ref().len(), 20);
+
+    bytes.set_position(0);
+    let decoded = DataRecord::read_options(
+        &mut bytes,
+        Endian::Big,
+        (300, &templates, ParseOptions::default()),
+    )
+    .unwrap();
+    assert_eq!(decoded, data_record);
+}
+
+#[test]
+fn options_template_zero_scope_fields() {
+    let formatter = get_default_formatter();
+    let strict = ParseOptions {
+        strict_scope_field_count: true,
+        ..Default::default()
+    };
+
+    // template 301: no scope fields; meteringProcessId
+    let bytes = hex::decode("0003000E012D00010000008F0004").unwrap();
+
+    // accepted by default
+    let templates = RefCell::new(HashMap::new());
+    Set::read_args(
+        &mut Cursor::new(&bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    assert!(templates.get_template(301).is_some());
+
+    // rejected with strict_scope_field_count, without being stored
+    let templates = RefCell::new(HashMap::new());
+    let err =
+        Set::read_args(&mut Cursor::new(&bytes), (&templates, &formatter, strict)).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains(&IpfixError::MissingScopeFields(301).to_string()));
+    assert!(templates.get_template(301).is_none());
+
+    // withdrawals have no scope field count, so are still accepted
+    let withdrawal_bytes = hex::decode("00030008012D0000").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&withdrawal_bytes),
+        (&templates, &formatter, strict),
+    )
+    .unwrap();
+}
+
+#[test]
+fn learn_information_elements() {
+    // exporter describing its enterprise field (12345, 1) as "myCounter"
+    let export_templates = RefCell::new(HashMap::new());
+    let mut export_formatter = get_default_formatter();
+    export_formatter.insert(
+        (12345, 1),
+        ("myCounter".into(), DataRecordType::UnsignedInt),
+    );
+
+    let mut type_record = data_record! {
+        "informationElementDataType": U8(3),
+        "informationElementName": String("myCounter".to_string()),
+    };
+    type_record.scope_values = DataRecordValues::from_iter([
+        (
+            DataRecordKey::Str("informationElementId"),
+            DataRecordValue::U16(1),
+        ),
+        (
+            DataRecordKey::Str("privateEnterpriseNumber"),
+            DataRecordValue::U32(12345),
+        ),
+    ]);
+    let messages = [
+        vec![
+            Set {
+                records: Records::OptionsTemplate(vec![OptionsTemplateRecord {
+                    template_id: 400,
+                    scope_field_count: 2,
+                    field_specifiers: [
+                        FieldSpecifier::new(None, 303, 2),
+                        FieldSpecifier::new(None, 346, 4),
+                        FieldSpecifier::new(None, 339, 1),
+                        FieldSpecifier::new(None, 341, u16::MAX),
+                    ]
+                    .into_iter()
+                    .collect(),
+                }]),
+            },
+            Set {
+                records: Records::Template(vec![TemplateRecord {
+                    template_id: 401,
+                    field_specifiers: [FieldSpecifier::new(Some(12345), 1, 4)]
+                        .into_iter()
+                        .collect(),
+                }]),
+            },
+            Set {
+                records: Records::Data {
+                    set_id: 400,
+                    data: vec![type_record],
+                },
+            },
+        ],
+        vec![Set {
+            records: Records::Data {
+                set_id: 401,
+                data: vec![data_record! { "myCounter": U32(42) }],
+            },
+        }],
+    ]
+    .map(|sets| {
+        let mut bytes = Cursor::new(Vec::new());
+        Message {
+            export_time: 0,
+            sequence_number: 0,
+            observation_domain_id: 0,
+            sets: sets.into_iter().collect(),
+        }
+        .write_args(
+            &mut bytes,
+            (
+                &export_templates,
+                &export_formatter,
+                WriteOptions::aligned(4),
+            ),
+        )
+        .unwrap();
+        bytes.into_inner()
+    });
+
+    let templates = RefCell::new(HashMap::new());
+    let mut formatter = get_default_formatter();
+    parse_ipfix_message_learning(&messages[0], &templates, &mut formatter).unwrap();
+    assert_eq!(
+        formatter.get(&(12345, 1)),
+        Some(&("myCounter".into(), DataRecordType::UnsignedInt))
+    );
+
+    let message = parse_ipfix_message_learning(&messages[1], &templates, &mut formatter).unwrap();
+    let records: Vec<&DataRecord> = message.iter_data_records().collect();
+    assert_eq!(records, vec![&data_record! { "myCounter": U32(42) }]);
+
+    // learned names are owned, but can be looked up either way
+    let (key, _) = records[0].values.iter().next().unwrap();
+    assert!(matches!(key, DataRecordKey::Owned(name) if &**name == "myCounter"));
+    assert_eq!(
+        records[0].values.get(&DataRecordKey::Str("myCounter")),
+        Some(&DataRecordValue::U32(42))
+    );
+    assert_eq!(
+        records[0]
+            .values
+            .get(&DataRecordKey::from("myCounter".to_string())),
+        Some(&DataRecordValue::U32(42))
+    );
+}
+
+#[cfg(feature = "csv")]
+#[test]
+fn registry_csv() -> Result<(), Box<dyn std::error::Error>> {
+    use ipfixrw::registry::{load_elements_csv, load_iana_csv, RegistryError};
+
+    // the registry the default formatter is built from
+    let mut formatter = Formatter::new();
+    let registry = std::fs::File::open("resources/ipfix-information-elements.csv")?;
+    let added = load_iana_csv(registry, 0, &mut formatter)?;
+    assert_eq!(added, formatter.len());
+    assert_eq!(formatter, get_default_formatter());
+
+    let elements = "\
+enterprise, id, name, type
+29305, 1, exampleCounter, unsigned64
+29305, 2, exampleAddress, ipv6Address
+, 1000, futureElement, string
+";
+    assert_eq!(load_elements_csv(elements.as_bytes(), &mut formatter)?, 3);
+    assert_eq!(
+        formatter.get(&(29305, 2)),
+        Some(&("exampleAddress".into(), DataRecordType::Ipv6Addr))
+    );
+    assert_eq!(
+        formatter.get(&(0, 1000)),
+        Some(&("futureElement".into(), DataRecordType::String))
+    );
+
+    // nothing is added from a list with an invalid element
+    let elements = "enterprise,id,name,type\n1,1,a,unsigned8\n1,2,b,basicList\n";
+    assert!(matches!(
+        load_elements_csv(elements.as_bytes(), &mut formatter),
+        Err(RegistryError::InvalidValue {
+            element: 2,
+            field: "type",
+            ..
+        })
+    ));
+    assert_eq!(formatter.get(&(1, 1)), None);
+    assert!(matches!(
+        load_elements_csv("id,name,type\n".as_bytes(), &mut formatter),
+        Err(RegistryError::MissingColumn("enterprise"))
+    ));
+    Ok(())
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn registry_toml() -> Result<(), Box<dyn std::error::Error>> {
+    use ipfixrw::registry::{load_elements_toml, RegistryError};
+
+    let mut formatter = get_default_formatter();
+    let elements = r#"
+        [[element]]
+        enterprise = 29305
+        id = 1
+        name = "exampleCounter"
+        type = "unsigned64"
+
+        # replaces octetDeltaCount
+        [[element]]
+        id = 1
+        name = "bytes"
+        type = "unsigned64"
+    "#;
+    assert_eq!(load_elements_toml(elements, &mut formatter)?, 2);
+    assert_eq!(
+        formatter.get(&(29305, 1)),
+        Some(&("exampleCounter".into(), DataRecordType::UnsignedInt))
+    );
+    assert_eq!(
+        formatter.get(&(0, 1)),
+        Some(&("bytes".into(), DataRecordType::UnsignedInt))
+    );
+
+    let elements = "[[element]]\nid = 70000\nname = \"a\"\ntype = \"string\"\n";
+    assert!(matches!(
+        load_elements_toml(elements, &mut formatter),
+        Err(RegistryError::InvalidValue {
+            element: 1,
+            field: "id",
+            ..
+        })
+    ));
+    assert!(matches!(
+        load_elements_toml("[[element]\n", &mut formatter),
+        Err(RegistryError::Syntax(_))
+    ));
+    Ok(())
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn registry_xml() -> Result<(), Box<dyn std::error::Error>> {
+    use ipfixrw::registry::{load_iana_xml, RegistryError};
+
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<registry xmlns="http://www.iana.org/assignments" id="ipfix">
+  <title>IP Flow Information Export (IPFIX) Entities</title>
+  <registry id="ipfix-information-elements">
+    <record>
+      <name>octetDeltaCount</name>
+      <dataType>unsigned64</dataType>
+      <elementId>1</elementId>
+    </record>
+    <record>
+      <name>Assigned for NetFlow v9 compatibility</name>
+      <elementId>105-127</elementId>
+    </record>
+    <record>
+      <name>newlyAssignedElement</name>
+      <dataType>ipv4Address</dataType>
+      <elementId>1000</elementId>
+    </record>
+    <record>
+      <name>newlyAssignedList</name>
+      <dataType>basicList</dataType>
+      <elementId>1001</elementId>
+    </record>
+  </registry>
+  <registry id="ipfix-version-numbers">
+    <record>
+      <value>10</value>
+    </record>
+  </registry>
+</registry>"#;
+
+    let mut formatter = get_default_formatter();
+    let known = formatter.len();
+    assert_eq!(load_iana_xml(xml, &mut formatter)?, vec![(0, 1000)]);
+    assert_eq!(formatter.len(), known + 1);
+    assert_eq!(
+        formatter.get(&(0, 1000)),
+        Some(&("newlyAssignedElement".into(), DataRecordType::Ipv4Addr))
+    );
+
+    assert!(matches!(
+        load_iana_xml("<registry id=\"ipfix\"/>", &mut formatter),
+        Err(RegistryError::MissingRegistry(_))
+    ));
+    assert!(matches!(
+        load_iana_xml("<registry>", &mut formatter),
+        Err(RegistryError::Syntax(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn template_refresher() {
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    parse_ipfix_message(template_bytes, &templates, &formatter).unwrap();
+
+    let mut refresher = TemplateRefresher::new(Duration::from_secs(60));
+    let start = Instant::now();
+
+    // everything is sent the first time
+    let sets = refresher.due_sets(&templates, start);
+    assert_eq!(sets.len(), 1);
+    let Records::Template(records) = &sets[0].records else {
+        panic!("expected template set");
+    };
+    assert_eq!(records.len(), 3);
+
+    // nothing is due within the interval
+    assert!(refresher
+        .due_sets(&templates, start + Duration::from_secs(30))
+        .is_empty());
+
+    // a reset template is sent again
+    refresher.reset(999);
+    let sets = refresher.due_sets(&templates, start + Duration::from_secs(30));
+    let Records::Template(records) = &sets[0].records else {
+        panic!("expected template set");
+    };
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].template_id, 999);
+
+    // everything else is due after the interval
+    let sets = refresher.due_sets(&templates, start + Duration::from_secs(60));
+    let Records::Template(records) = &sets[0].records else {
+        panic!("expected template set");
+    };
+    let ids: Vec<u16> = records.iter().map(|t| t.template_id).collect();
+    assert_eq!(ids, vec![500, 501]);
+}
+
+#[test]
+fn save_and_load_templates() {
+    // 257, 258, 259, 260
+    let temp_1 = include_bytes!("../resources/tests/parse_temp_1.bin");
+    // 261, 262
+    let temp_2 = include_bytes!("../resources/tests/parse_temp_2.bin");
+
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    parse_ipfix_message(temp_1, &templates, &formatter).unwrap();
+    parse_ipfix_message(temp_2, &templates, &formatter).unwrap();
+
+    let mut saved = Cursor::new(Vec::new());
+    save_templates(&templates, &mut saved).unwrap();
+
+    let loaded_templates = RefCell::new(HashMap::new());
+    saved.set_position(0);
+    load_templates(&loaded_templates, &formatter, &mut saved).unwrap();
+
+    assert_eq!(loaded_templates.borrow().len(), 6);
+    assert_eq!(*loaded_templates.borrow(), *templates.borrow());
+}
+
+#[test]
+fn save_and_load_many_templates() {
+    // 300 templates of 60 fields each don't fit in one message
+    let formatter = get_default_formatter();
+    let records: Vec<TemplateRecord> = (256..556)
+        .map(|template_id| TemplateRecord {
+            template_id,
+            field_specifiers: (1..=60)
+                .map(|ie| FieldSpecifier::new(None, ie, 4))
+                .collect(),
+        })
+        .collect();
+    let templates = RefCell::new(HashMap::new());
+    templates
+        .insert_template_records(&records, &formatter)
+        .unwrap();
+
+    let mut saved = Cursor::new(Vec::new());
+    save_templates(&templates, &mut saved).unwrap();
+    assert!(saved.get_ref().len() > usize::from(u16::MAX));
+
+    let loaded_templates = RefCell::new(HashMap::new());
+    saved.set_position(0);
+    load_templates(&loaded_templates, &formatter, &mut saved).unwrap();
+
+    assert_eq!(loaded_templates.borrow().len(), 300);
+    assert_eq!(*loaded_templates.borrow(), *templates.borrow());
+}
+
+#[test]
+fn export_template_records() {
+    // contains templates 500, 999, 501
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let message = parse_ipfix_message(template_bytes, &templates, &formatter).unwrap();
+
+    let (records, options_records) = template_records(&templates);
+    let ids: Vec<u16> = records.iter().map(|t| t.template_id).collect();
+    assert_eq!(ids, vec![500, 501, 999]);
+    assert!(options_records.is_empty());
+
+    let mut announced: Vec<_> = message.iter_template_records().cloned().collect();
+    announced.sort_by_key(|t| t.template_id);
+    assert_eq!(records, announced);
+
+    // re-announcing into a fresh store gives the same templates
+    let sets = template_sets(&templates);
+    assert_eq!(sets.len(), 1);
+    let reannounced = RefCell::new(HashMap::new());
+    for set in &sets {
+        let Records::Template(records) = &set.records else {
+            panic!("expected template set");
+        };
+        reannounced
+            .insert_template_records(records, &formatter)
+            .unwrap();
+    }
+    assert_eq!(*reannounced.borrow(), *templates.borrow());
+}
+
+#[test]
+fn concurrency() {
+    // A state to be shared between parsing threads
+    let templates = Arc::new(RwLock::new(HashMap::new()));
+
+    // First thread to parse a template test
+    let t1 = templates.clone();
+    let j1 = std::thread::spawn(move || {
+        // contains templates 500, 999, 501
+        let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+        let formatter = get_default_formatter();
+        let _m = parse_ipfix_message(template_bytes, &t1, &formatter);
+    });
+
+    // Second thread to parse data set
+    let t2 = templates.clone();
+    let j2 = std::thread::spawn(move || {
+        // contains data sets for templates 999, 500, 999
+        let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+        let formatter = get_default_formatter();
+        let _m = parse_ipfix_message(data_bytes, &t2, &formatter);
+    });
+
+    let _r1 = j1.join();
+    let _r2 = j2.join();
+
+    // Assert state mutated from threads
+    assert!(templates.read().unwrap().len() == 3);
+}
+
+#[cfg(feature = "dashmap")]
+#[test]
+fn concurrency_dashmap() {
+    let templates = Arc::new(dashmap::DashMap::new());
+
+    let t1 = templates.clone();
+    std::thread::spawn(move || {
+        // contains templates 500, 999, 501
+        let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+        let formatter = get_default_formatter();
+        parse_ipfix_message(template_bytes, &t1, &formatter).unwrap();
+    })
+    .join()
+    .unwrap();
+
+    let t2 = templates.clone();
+    std::thread::spawn(move || {
+        // contains data sets for templates 999, 500, 999
+        let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+        let formatter = get_default_formatter();
+        parse_ipfix_message(data_bytes, &t2, &formatter).unwrap();
+    })
+    .join()
+    .unwrap();
+
+    assert_eq!(templates.len(), 3);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn parallel_decoding() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let templates = RefCell::new(HashMap::new());
+    let expected = vec![
+        parse_ipfix_message(template_bytes, &templates, &formatter)?,
+        parse_ipfix_message(data_bytes, &templates, &formatter)?,
+        parse_ipfix_message(data_bytes, &templates, &formatter)?,
+    ];
+
+    // templates are read in order, so the first message provides them
+    let templates = RefCell::new(HashMap::new());
+    let messages = ipfixrw::parallel::parse_ipfix_messages(
+        &[&template_bytes[..], data_bytes, data_bytes],
+        &templates,
+        &formatter,
+        ParseOptions::default(),
+    )?;
+    similar_asserts::assert_eq!(expected: expected, actual: messages);
+
+    let message = ipfixrw::parallel::parse_ipfix_message(
+        data_bytes,
+        &templates,
+        &formatter,
+        ParseOptions::default(),
+    )?;
+    assert_eq!(message, expected[1]);
+
+    Ok(())
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mapped_file() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+    let path = std::env::temp_dir().join(format!("ipfixrw-mapped-{}.ipfix", std::process::id()));
+    std::fs::write(
+        &path,
+        [&template_bytes[..], data_bytes, data_bytes].concat(),
+    )?;
+    let file = ipfixrw::mmap::MappedFile::open(&path)?;
+
+    let templates = RefCell::new(HashMap::new());
+    let messages: Vec<Message> = file
+        .iter_messages(&templates, &formatter, ParseOptions::default())
+        .collect::<Result<_, _>>()?;
+    assert_eq!(messages.len(), 3);
+
+    let templates = RefCell::new(HashMap::new());
+    let mut record_count = 0;
+    for message in file.iter_borrowed_messages(&templates, &formatter) {
+        for data_set in message?.iter_data_sets() {
+            record_count += data_set.records().count();
+        }
+    }
+    assert_eq!(
+        record_count,
+        messages
+            .iter()
+            .map(|message| message.iter_data_records().count())
+            .sum::<usize>()
+    );
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_keys_and_values() {
+    let mut record = data_record! {
+        "sourceMacAddress": MacAddress(MacAddress([0x00, 0x11, 0x22, 0xaa, 0xbb, 0xcc])),
+    };
+    record.values.insert(
+        DataRecordKey::Unrecognized(FieldSpecifier::new(Some(30351), 11, 1)),
+        DataRecordValue::Bytes(vec![1]),
+    );
+    let json = serde_json::to_value(&record).unwrap();
+    assert_eq!(
+        json["values"]["sourceMacAddress"],
+        serde_json::json!({ "MacAddress": "00:11:22:aa:bb:cc" })
+    );
+    assert_eq!(
+        json["values"]["30351:11:1"],
+        serde_json::json!({ "Bytes": [1] })
+    );
+    let read_record: DataRecord = serde_json::from_value(json).unwrap();
+    assert_eq!(read_record, record);
+}
+
+#[test]
+fn display() {
+    use DataRecordValue::*;
+
+    let message = Message {
+        export_time: 1_700_000_000,
+        sequence_number: 7,
+        observation_domain_id: 1,
+        sets: [
+            Set {
+                records: Records::Template(vec![TemplateRecord {
+                    template_id: 256,
+                    field_specifiers: [
+                        FieldSpecifier::new(None, 8, 4),
+                        FieldSpecifier::new(Some(29305), 1, u16::MAX),
+                    ]
+                    .into_iter()
+                    .collect(),
+                }]),
+            },
+            Set {
+                records: Records::Data {
+                    set_id: 256,
+                    data: vec![data_record! {
+                        "sourceIPv4Address": Ipv4Addr(std::net::Ipv4Addr::new(10, 0, 0, 1)),
+                    }],
+                },
+            },
+        ]
+        .into_iter()
+        .collect(),
+    };
+    assert_eq!(
+        message.to_string(),
+        "Message exported 2023-11-14T22:13:20Z, sequence number 7, observation domain 1\n\
+         \x20 Template Set (2)\n\
+         \x20   Template 256: 8[4], 29305:1[variable]\n\
+         \x20 Data Set (256)\n\
+         \x20   sourceIPv4Address=10.0.0.1\n"
+    );
+
+    assert_eq!(
+        DataRecordKey::Unrecognized(FieldSpecifier::new(None, 400, 2)).to_string(),
+        "0:400:2"
+    );
+    assert_eq!(Bytes(vec![0xab, 0x01]).to_string(), "0xab01");
+    assert_eq!(String("a b".into()).to_string(), "\"a b\"");
+    assert_eq!(
+        DateTimeMilliseconds(951_782_400_123).to_string(),
+        "2000-02-29T00:00:00.123Z"
+    );
+    assert_eq!(
+        DateTimeNanoseconds(NtpTimestamp::from_unix_nanos(-1).unwrap()).to_string(),
+        "1969-12-31T23:59:59.999999999Z"
+    );
+    assert_eq!(
+        MacAddress(ipfixrw::parser::MacAddress::from([
+            0, 0x11, 0x22, 0xaa, 0xbb, 0xcc
+        ]))
+        .to_string(),
+        "00:11:22:aa:bb:cc"
+    );
+}
+
+#[test]
+fn get_by_ie() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // template 256: unknown element 32512 (2 bytes), enterprise 30351 element 11 (1 byte)
+    let template_bytes = hex::decode("00020014010000027F000002800B00010000768F").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    let data_bytes = hex::decode("01000007123405").unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )
+    .unwrap();
+    let Records::Data { data, .. } = &set.records else {
+        panic!("expected data set");
+    };
+
+    let record = &data[0];
+    assert_eq!(
+        record.get_by_ie(None, 32512),
+        Some(&DataRecordValue::Bytes(vec![0x12, 0x34]))
+    );
+    assert_eq!(
+        record.get_by_ie(Some(30351), 11),
+        Some(&DataRecordValue::Bytes(vec![0x05]))
+    );
+    assert_eq!(record.get_by_ie(Some(30351), 32512), None);
+    assert_eq!(record.get_by_ie(None, 11), None);
+}
+
+#[test]
+fn data_record_macro_keys() {
+    let name = "octetDeltaCount".to_string();
+    let record = data_record! {
+        "packetDeltaCount": U64(1),
+        (0, 32512): Bytes(vec![0x12, 0x34]),
+        (30351, 11, 4): U8(5),
+        [name]: U64(2),
+        [DataRecordKey::Err("oops".into())]: U8(0),
+    };
+    assert_eq!(record.values.len(), 5);
+    assert_eq!(
+        record.get_by_ie(None, 32512),
+        Some(&DataRecordValue::Bytes(vec![0x12, 0x34]))
+    );
+    assert_eq!(
+        record
+            .values
+            .get(&DataRecordKey::Unrecognized(FieldSpecifier::new(
+                None, 32512, 2
+            ))),
+        Some(&DataRecordValue::Bytes(vec![0x12, 0x34]))
+    );
+    assert_eq!(
+        record
+            .values
+            .get(&DataRecordKey::Unrecognized(FieldSpecifier::new(
+                Some(30351),
+                11,
+                4
+            ))),
+        Some(&DataRecordValue::U8(5))
+    );
+    assert_eq!(
+        record.values.get(&DataRecordKey::Str("octetDeltaCount")),
+        Some(&DataRecordValue::U64(2))
+    );
+}
+
+#[test]
+fn query_records() {
+    use DataRecordValue::*;
+
+    let message = Message {
+        export_time: 0,
+        sequence_number: 0,
+        observation_domain_id: 0,
+        sets: [
+            Set {
+                records: Records::Data {
+                    set_id: 256,
+                    data: vec![
+                        data_record! {
+                            "destinationTransportPort": U16(443),
+                            "sourceIPv4Address": Ipv4Addr(std::net::Ipv4Addr::new(10, 0, 0, 1)),
+                        },
+                        data_record! {
+                            "destinationTransportPort": U16(53),
+                            "sourceIPv4Address": Ipv4Addr(std::net::Ipv4Addr::new(10, 0, 0, 2)),
+                        },
+                    ],
+                },
+            },
+            Set {
+                records: Records::Data {
+                    set_id: 257,
+                    data: vec![data_record! { "interfaceName": String("eth0".into()) }],
+                },
+            },
+        ]
+        .into_iter()
+        .collect(),
+    };
+
+    let https: Vec<_> = message
+        .records_where(|r| r.get_u16("destinationTransportPort") == Some(443))
+        .collect();
+    assert_eq!(https.len(), 1);
+    assert_eq!(
+        https[0].get_ip_addr("sourceIPv4Address"),
+        Some(IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1)))
+    );
+    assert_eq!(https[0].get_u8("destinationTransportPort"), None);
+    assert_eq!(https[0].get_u64("destinationTransportPort"), Some(443));
+    assert_eq!(message.records_for_template(257).count(), 1);
+    assert_eq!(
+        message
+            .records_for_template(257)
+            .next()
+            .unwrap()
+            .get_str("interfaceName"),
+        Some("eth0")
+    );
+
+    let keys = ["destinationTransportPort".into(), "interfaceName".into()];
+    let rows: Vec<_> = message.project(&keys).collect();
+    assert_eq!(
+        rows,
+        vec![
+            vec![Some(&U16(443)), None],
+            vec![Some(&U16(53)), None],
+            vec![None, Some(&String("eth0".into()))],
+        ]
+    );
+    assert_eq!(
+        https[0].project(&keys),
+        data_record! { "destinationTransportPort": U16(443) }
+    );
+}
+
+/// Template 256 of only a zero length octetArray (IE 313), and a data
+/// set with one byte for it
+const ZERO_LENGTH_RECORDS: &str = concat!(
+    "000a0021000000000000000000000000",
+    "0002000c010000010139000001000005",
+    "00",
+);
+
+#[test]
+fn zero_length_records() -> binrw::BinResult<()> {
+    let bytes = hex::decode(ZERO_LENGTH_RECORDS).unwrap();
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let message = parse_ipfix_message(&bytes, &templates, &formatter)?;
+    assert_eq!(message.iter_data_records().count(), 0);
+    // the data set follows the 16 byte header and 12 byte template set
+    let set = parse_raw_data_set(&bytes[28..].to_vec(), &templates)?;
+    assert!(set.records.is_empty());
+
+    let templates = RefCell::new(HashMap::new());
+    let message = CompactMessage::parse(&bytes, &templates, &formatter, ParseOptions::default())?;
+    assert!(message.iter_data_sets().all(|set| set.is_empty()));
+
+    let templates = RefCell::new(HashMap::new());
+    let message = BorrowedMessage::parse(&bytes, &templates, &formatter)?;
+    let data_set = message.iter_data_sets().next().unwrap();
+    assert_eq!(data_set.records().count(), 0);
+    assert_eq!(data_set.lazy_records().count(), 0);
+
+    struct CountRecords(usize);
+    impl Visitor for CountRecords {
+        fn on_record_start(&mut self, _set_id: u16, _template: &Template) {
+            self.0 += 1;
+        }
+    }
+    let templates = RefCell::new(HashMap::new());
+    let mut visitor = CountRecords(0);
+    visit_ipfix_message(&bytes, &templates, &formatter, &mut visitor)?;
+    assert_eq!(visitor.0, 0);
+    Ok(())
+}
+
+#[test]
+fn data_set_padding() {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let options = ParseOptions {
+        strict_booleans: true,
+        ..Default::default()
+    };
+
+    // template 256: dataRecordsReliability (1 byte), octetDeltaCount (4 bytes)
+    let template_bytes = hex::decode("00020010010000020114000100010004").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, options),
+    )
+    .unwrap();
+
+    // one record followed by 3 bytes of padding, which would be an
+    // invalid boolean if read as a record
+    let data_bytes = hex::decode("0100000C0100000005000000").unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, options),
+    )
+    .unwrap();
+    assert_eq!(
+        set.records,
+        Records::Data {
+            set_id: 256,
+            data: vec![data_record! {
+                "dataRecordsReliability": Bool(true),
+                "octetDeltaCount": U32(5),
+            }],
+        }
+    );
+}
+
+#[test]
+fn skip_reserved_sets() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let skip = ParseOptions {
+        skip_reserved_sets: true,
+        ..Default::default()
+    };
+
+    // a set with reserved id 4, then a template set and data set
+    let bytes = hex::decode(concat!(
+        "000A002C000000000000000000000001",
+        "0004000801020304",
+        "0002000C0100000100080004",
+        "010000080A000001",
+    ))
+    .unwrap();
+    assert!(parse_ipfix_message(&bytes, &templates, &formatter).is_err());
+
+    let message = Message::read_args(&mut Cursor::new(&bytes), (&templates, &formatter, skip))?;
+    assert_eq!(
+        message.sets[0].records,
+        Records::Unsupported {
+            set_id: 4,
+            data: vec![1, 2, 3, 4],
+        }
+    );
+    assert_eq!(
+        message.iter_data_records().collect::<Vec<_>>(),
+        [&data_record! { "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)) }]
+    );
+
+    // unsupported sets are written back as they were
+    let mut writer = Cursor::new(Vec::new());
+    message.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::default()),
+    )?;
+    assert_eq!(writer.into_inner(), bytes);
+
+    // sets with other IDs that fail to read still fail, rather than
+    // being skipped: a data set with a missing template
+    let missing_template = hex::decode(concat!(
+        "000A0018000000000000000000000001",
+        "012C00080A000001",
+    ))
+    .unwrap();
+    assert!(Message::read_args(
+        &mut Cursor::new(&missing_template),
+        (&templates, &formatter, skip)
+    )
+    .is_err());
+    // which a buffering store can hold until its template arrives
+    let buffering = BufferingTemplateStore::new(RefCell::new(HashMap::new()), 16);
+    let message = Message::read_args(
+        &mut Cursor::new(&missing_template),
+        (&buffering, &formatter, skip),
+    )?;
+    assert!(message.sets.is_empty());
+    assert_eq!(buffering.buffered_sets(), 1);
+
+    // and a template set with a reserved Template ID
+    let malformed_template = hex::decode(concat!(
+        "000A001C000000000000000000000001",
+        "0002000C0005000100080004",
+    ))
+    .unwrap();
+    assert!(Message::read_args(
+        &mut Cursor::new(&malformed_template),
+        (&templates, &formatter, skip)
+    )
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn read_lenient() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // a template set, a data set with a missing template, a good data
+    // set, then a set with an invalid length
+    let bytes = hex::decode(concat!(
+        "000A0030000000000000000000000001",
+        "0002000C0100000100080004",
+        "012C00080A000002",
+        "010000080A000001",
+        "01000002",
+    ))
+    .unwrap();
+    assert!(parse_ipfix_message(&bytes, &templates, &formatter).is_err());
+
+    let (message, errors) = Message::read_lenient(
+        &mut Cursor::new(&bytes),
+        &templates,
+        &formatter,
+        ParseOptions::default(),
+    )?;
+    assert_eq!(message.sets.len(), 2);
+    assert_eq!(
+        message.iter_data_records().collect::<Vec<_>>(),
+        [&data_record! { "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)) }]
+    );
+    let locations: Vec<(usize, u64)> = errors.iter().map(|e| (e.index, e.offset)).collect();
+    assert_eq!(locations, [(1, 28), (3, 44)]);
+
+    // the header must still be valid
+    assert!(Message::read_lenient(
+        &mut Cursor::new(&bytes[2..]),
+        &templates,
+        &formatter,
+        ParseOptions::default(),
+    )
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn parse_multiple_messages() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let templates = RefCell::new(HashMap::new());
+    let expected = vec![
+        parse_ipfix_message(template_bytes, &templates, &formatter)?,
+        parse_ipfix_message(data_bytes, &templates, &formatter)?,
+    ];
+
+    let bytes = [&template_bytes[..], &data_bytes[..]].concat();
+    let templates = RefCell::new(HashMap::new());
+    let messages = parse_ipfix_messages(&bytes, &templates, &formatter)?;
+    similar_asserts::assert_eq!(expected: expected, actual: messages);
+
+    // a truncated message is an error after the complete ones
+    let templates = RefCell::new(HashMap::new());
+    let results: Vec<_> = iter_ipfix_messages(
+        &bytes[..bytes.len() - 1],
+        &templates,
+        &formatter,
+        ParseOptions::default(),
+    )
+    .collect();
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+
+    Ok(())
+}
+
+#[test]
+fn message_stream() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+    let bytes = [&template_bytes[..], &data_bytes[..]].concat();
+
+    let templates = RefCell::new(HashMap::new());
+    let expected = parse_ipfix_messages(&bytes, &templates, &formatter)?;
+
+    // any reader works, such as a slice
+    let templates = RefCell::new(HashMap::new());
+    let messages = MessageStream::new(&bytes[..], &templates, &formatter)
+        .collect::<binrw::BinResult<Vec<_>>>()?;
+    similar_asserts::assert_eq!(expected: expected, actual: messages);
+
+    // a truncated message is an error, then the stream ends
+    let templates = RefCell::new(HashMap::new());
+    let mut stream = MessageStream::new(&bytes[..bytes.len() - 1], &templates, &formatter);
+    assert!(stream.next().unwrap().is_ok());
+    assert!(stream.next().unwrap().is_err());
+    assert!(stream.next().is_none());
+
+    // as is something that isn't a message
+    let templates = RefCell::new(HashMap::new());
+    let mut stream = MessageStream::new(&b"not ipfix"[..], &templates, &formatter);
+    assert!(stream.next().unwrap().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn record_iterators() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    // templates 256 (sourceIPv4Address) and 257 (sourceTransportPort),
+    // then a data set for each
+    let bytes = hex::decode(concat!(
+        "000A0032000000000000000000000001",
+        "0002001401000001000800040101000100070002",
+        "010000080A000001",
+        "010100060050",
+    ))
+    .unwrap();
+    let message = parse_ipfix_message(&bytes, &templates, &formatter)?;
+
+    let address = data_record! { "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)) };
+    let port = data_record! { "sourceTransportPort": U16(80) };
+    assert_eq!(
+        message
+            .iter_data_records_with_template()
+            .collect::<Vec<_>>(),
+        [(256, &address), (257, &port)]
+    );
+
+    assert_eq!(
+        message
+            .clone()
+            .into_template_records()
+            .map(|t| t.template_id)
+            .collect::<Vec<_>>(),
+        [256, 257]
+    );
+    assert_eq!(message.clone().into_options_template_records().count(), 0);
+    assert_eq!(
+        message.clone().into_data_records().collect::<Vec<_>>(),
+        [address.clone(), port.clone()]
+    );
+    assert_eq!(
+        message
+            .into_data_records_with_template()
+            .collect::<Vec<_>>(),
+        [(256, address), (257, port)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn keep_raw_sets() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let options = ParseOptions {
+        keep_raw_sets: true,
+        ..Default::default()
+    };
+
+    // a data set for template 256 before its template has arrived
+    let data_bytes = hex::decode("000A0018000000000000000000000001010000080A000001").unwrap();
+    assert!(parse_ipfix_message(&data_bytes, &templates, &formatter).is_err());
+    let message = Message::read_args(
+        &mut Cursor::new(&data_bytes),
+        (&templates, &formatter, options),
+    )?;
+    let set = message.sets[0].clone();
+    assert_eq!(
+        set.records,
+        Records::RawData {
+            set_id: 256,
+            bytes: vec![10, 0, 0, 1],
+        }
+    );
+
+    // written back as it was
+    let mut writer = Cursor::new(Vec::new());
+    message.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::default()),
+    )?;
+    assert_eq!(writer.into_inner(), data_bytes);
+
+    // and decoded once the template is known
+    let template_bytes =
+        hex::decode("000A001C0000000000000000000000010002000C0100000100080004").unwrap();
+    parse_ipfix_message(&template_bytes, &templates, &formatter)?;
+    assert_eq!(
+        set.records.decode(&templates, &formatter, options)?,
+        Records::Data {
+            set_id: 256,
+            data: vec![data_record! { "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)) }],
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn sequence_tracker() {
+    let message = |observation_domain_id, sequence_number, records| Message {
+        export_time: 0,
+        sequence_number,
+        observation_domain_id,
+        sets: [Set {
+            records: Records::Data {
+                set_id: 256,
+                data: vec![data_record! { "octetDeltaCount": U64(1) }; records],
+            },
+        }]
+        .into_iter()
+        .collect(),
+    };
+    let mut tracker = SequenceTracker::new().max_gap(1000);
+
+    assert_eq!(
+        tracker.observe("a", &message(1, 10, 2)),
+        SequenceEvent::First
+    );
+    assert_eq!(
+        tracker.observe("a", &message(1, 12, 3)),
+        SequenceEvent::InOrder
+    );
+    // other peers and observation domains are tracked separately
+    assert_eq!(
+        tracker.observe("a", &message(2, 0, 1)),
+        SequenceEvent::First
+    );
+    assert_eq!(
+        tracker.observe("b", &message(1, 0, 1)),
+        SequenceEvent::First
+    );
+    assert_eq!(
+        tracker.observe("a", &message(1, 20, 1)),
+        SequenceEvent::Gap {
+            expected: 15,
+            received: 20,
+            missing: 5,
+        }
+    );
+    assert_eq!(
+        tracker.observe("a", &message(1, 12, 3)),
+        SequenceEvent::Duplicate {
+            expected: 21,
+            received: 12,
+        }
+    );
+    assert_eq!(
+        tracker.observe("a", &message(1, 21, 1)),
+        SequenceEvent::InOrder
+    );
+    assert_eq!(
+        tracker.observe("a", &message(1, 100_000, 1)),
+        SequenceEvent::Reset {
+            expected: 22,
+            received: 100_000,
+        }
+    );
+    // wrapping past 2^32 is in order
+    let mut tracker = SequenceTracker::new();
+    tracker.observe((), &message(1, u32::MAX, 2));
+    assert_eq!(
+        tracker.observe((), &message(1, 1, 1)),
+        SequenceEvent::InOrder
+    );
+
+    let mut tracker = SequenceTracker::new();
+    tracker.observe("a", &message(1, 0, 5));
+    tracker.observe("a", &message(1, 10, 5));
+    tracker.observe("b", &message(1, 0, 1));
+    let statistics = tracker.statistics("a", 1).unwrap();
+    assert_eq!(
+        statistics,
+        SequenceStatistics {
+            messages: 2,
+            data_records: 10,
+            lost_data_records: 5,
+            gaps: 1,
+            duplicates: 0,
+            resets: 0,
+            octets: 0,
+        }
+    );
+    assert!((statistics.loss_ratio() - 1.0 / 3.0).abs() < 1e-9);
+    assert_eq!(tracker.total_statistics().messages, 3);
+    assert_eq!(tracker.remove("a", 1), Some(statistics));
+    assert_eq!(tracker.statistics("a", 1), None);
+}
+
+#[test]
+fn collector_session() -> binrw::BinResult<()> {
+    let mut session = CollectorSession::new(get_default_formatter(), Duration::from_millis(50));
+    let template_bytes =
+        hex::decode("000A001C0000000000000000000000010002000C0100000100080004").unwrap();
+    // sequence number 0 again, as the template message had no records
+    let data_bytes = hex::decode("000A0018000000000000000000000001010000080A000001").unwrap();
+
+    let collected = session.handle_datagram("a", &template_bytes)?;
+    assert_eq!(
+        collected.events,
+        [
+            SessionEvent::TemplateAdded {
+                observation_domain_id: 1,
+                template_id: 256,
+            },
+            SessionEvent::Sequence {
+                observation_domain_id: 1,
+                event: SequenceEvent::First,
+            },
+        ]
+    );
+
+    let collected = session.handle_datagram("a", &data_bytes)?;
+    assert_eq!(collected.events, []);
+    assert_eq!(collected.message.iter_data_records().count(), 1);
+    let collected = session.handle_datagram("a", &data_bytes)?;
+    assert_eq!(
+        collected.events,
+        [SessionEvent::Sequence {
+            observation_domain_id: 1,
+            event: SequenceEvent::Duplicate {
+                expected: 1,
+                received: 0,
+            },
+        }]
+    );
+
+    // templates are separate for each peer
+    assert!(session.handle_datagram("b", &data_bytes).is_err());
+
+    std::thread::sleep(Duration::from_millis(60));
+    assert_eq!(
+        session.sweep(),
+        [SessionEvent::TemplateExpired {
+            observation_domain_id: 1,
+            template_id: 256,
+        }]
+    );
+    assert!(session.handle_datagram("a", &data_bytes).is_err());
+    assert_eq!(session.statistics("a", 1).unwrap().duplicates, 1);
+    // failed messages aren't counted
+    assert_eq!(
+        session.metering_statistics("a", 1),
+        Some(MeteringProcessStatistics {
+            observation_domain_id: 1,
+            exported_message_total_count: 3,
+            exported_flow_record_total_count: 2,
+            exported_octet_total_count: 76,
+        })
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn collector_metrics() {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    metrics::with_local_recorder(&recorder, || {
+        let mut session = CollectorSession::new(get_default_formatter(), Duration::from_secs(60));
+        session
+            .handle_datagram("a", include_bytes!("../resources/tests/parse_temp.bin"))
+            .unwrap();
+        session
+            .handle_datagram("a", include_bytes!("../resources/tests/parse_data.bin"))
+            .unwrap();
+        let mut bad_version = [0; 16];
+        bad_version[..4].copy_from_slice(&[0, 11, 0, 16]);
+        assert!(session.handle_datagram("a", &bad_version).is_err());
+
+        // a message the manager rejects counts as an error, not a message
+        let mut manager = SessionManager::new(
+            get_default_formatter(),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+        assert!(manager
+            .handle_message("b", include_bytes!("../resources/tests/parse_data.bin"))
+            .is_err());
+    });
+
+    let counters: Vec<_> = snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .map(|(key, _, _, value)| {
+            let key = key.key();
+            let labels: Vec<_> = key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect();
+            let DebugValue::Counter(value) = value else {
+                panic!("{key:?} isn't a counter");
+            };
+            (key.name().to_string(), labels.join(","), value)
+        })
+        .collect();
+    let counter = |name: &str, labels: &str| {
+        counters
+            .iter()
+            .find(|(n, l, _)| n == name && l == labels)
+            .map(|(_, _, value)| *value)
+    };
+    assert_eq!(counter(ipfixrw::metrics::MESSAGES, ""), Some(2));
+    assert_eq!(
+        counters
+            .iter()
+            .filter(|(name, _, _)| name == ipfixrw::metrics::DATA_RECORDS)
+            .map(|(_, _, value)| value)
+            .sum::<u64>(),
+        21
+    );
+    assert_eq!(counter(ipfixrw::metrics::TEMPLATES_LEARNED, ""), Some(3));
+    assert_eq!(counter(ipfixrw::metrics::SEQUENCE_GAPS, ""), Some(1));
+    assert_eq!(counter(ipfixrw::metrics::LOST_DATA_RECORDS, ""), Some(949));
+    assert_eq!(
+        counter(ipfixrw::metrics::PARSE_ERRORS, "kind=bad_version"),
+        Some(1)
+    );
+    assert_eq!(
+        counter(ipfixrw::metrics::PARSE_ERRORS, "kind=missing_template"),
+        Some(1)
+    );
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_events() -> binrw::BinResult<()> {
+    #[derive(Clone, Default)]
+    struct Output(Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let output = Output::default();
+    let writer = output.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_max_level(tracing::Level::TRACE)
+        .without_time()
+        .finish();
+    tracing::subscriber::with_default(subscriber, || -> binrw::BinResult<()> {
+        let formatter = get_default_formatter();
+        let templates = RefCell::new(HashMap::new());
+        let options = ParseOptions {
+            lenient_field_lengths: true,
+            ..Default::default()
+        };
+        // a 6 byte sourceIPv4Address, then its withdrawal
+        let message = hex::decode(concat!(
+            "000A0026000000000000000000000001",
+            "0002000C0100000100080006",
+            "0100000A010203040506",
+        ))
+        .unwrap();
+        let message =
+            Message::read_args(&mut Cursor::new(message), (&templates, &formatter, options))?;
+        message.to_bytes(&templates, &formatter, WriteOptions::default())?;
+        let withdrawal = hex::decode("000A00180000000000000000000000010002000801000000").unwrap();
+        parse_ipfix_message(&withdrawal, &templates, &formatter)?;
+        // a data set for a missing template is skipped
+        let missing = hex::decode("000A00180000000000000000000000010101000801020304").unwrap();
+        let (_, errors) =
+            Message::read_lenient(&mut Cursor::new(missing), &templates, &formatter, options)?;
+        assert_eq!(errors.len(), 1);
+        Ok(())
+    })?;
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    for expected in [
+        "read_message{offset=0 export_time=0}: ipfixrw::template_store: template inserted template_id=256 field_count=1",
+        "reading a field of the wrong length as bytes ty=Ipv4Addr length=6",
+        "read_message{offset=0 export_time=0}: ipfixrw::parser: read set offset=28 set_id=256",
+        "write_message{offset=0 observation_domain_id=1 sequence_number=0}: ipfixrw::parser: write set offset=16 set_id=2",
+        "template withdrawn template_id=256",
+        "skipped a set that failed to decode offset=16 index=0",
+    ] {
+        assert!(output.contains(expected), "{expected:?} not in {output}");
+    }
+    Ok(())
+}
+
+#[test]
+fn session_manager() -> binrw::BinResult<()> {
+    let mut manager = SessionManager::new(
+        get_default_formatter(),
+        Duration::from_secs(60),
+        Duration::from_millis(50),
+    );
+    let template_bytes =
+        hex::decode("000A001C0000000000000000000000010002000C0100000100080004").unwrap();
+    // options template 257, with observationDomainId as scope
+    let options_template_bytes = hex::decode(concat!(
+        "000A001E000000000000000000000001",
+        "0003000E0101000100010095000400",
+    ))
+    .unwrap();
+    let data_bytes = hex::decode("000A0018000000000000000000000001010000080A000001").unwrap();
+    // a withdrawal of template 256, then a data set for it
+    let withdrawal_bytes =
+        hex::decode("000A00200000000000000000000000010002000801000000010000080A000001").unwrap();
+
+    manager.handle_message("a", &template_bytes)?;
+    manager.handle_message("a", &options_template_bytes)?;
+    assert_eq!(
+        manager
+            .handle_message("a", &data_bytes)?
+            .message
+            .iter_data_records()
+            .count(),
+        1
+    );
+
+    let collected = manager.handle_message("a", &withdrawal_bytes)?;
+    assert_eq!(collected.message.sets.len(), 1);
+    assert!(collected.events.contains(&SessionEvent::TemplateWithdrawn {
+        observation_domain_id: 1,
+        template_id: 256,
+    }));
+    assert!(collected.events.contains(&SessionEvent::DataSetDiscarded {
+        observation_domain_id: 1,
+        template_id: 256,
+    }));
+    let collected = manager.handle_message("a", &data_bytes)?;
+    assert!(collected.message.sets.is_empty());
+
+    // options templates have their own, shorter lifetime here
+    manager.handle_message("a", &template_bytes)?;
+    std::thread::sleep(Duration::from_millis(60));
+    assert_eq!(
+        manager.sweep(),
+        [SessionEvent::TemplateExpired {
+            observation_domain_id: 1,
+            template_id: 257,
+        }]
+    );
+    assert!(manager.handle_message("a", &data_bytes).is_ok());
+
+    // templates received over TCP don't expire, but reconnecting drops them
+    manager.open("b", Transport::Tcp);
+    assert_eq!(manager.transport(&"b"), Some(Transport::Tcp));
+    manager.handle_message("b", &template_bytes)?;
+    assert!(manager.handle_message("b", &data_bytes).is_ok());
+    assert_eq!(
+        manager.metering_statistics("b", 1),
+        Some(MeteringProcessStatistics {
+            observation_domain_id: 1,
+            exported_message_total_count: 2,
+            exported_flow_record_total_count: 1,
+            exported_octet_total_count: 52,
+        })
+    );
+    manager.open("b", Transport::Tcp);
+    assert!(manager.handle_message("b", &data_bytes).is_err());
+    manager.close(&"b");
+    assert_eq!(manager.transport(&"b"), None);
+
+    Ok(())
+}
+
+#[test]
+fn netflow_v5() -> binrw::BinResult<()> {
+    let bytes = hex::decode(concat!(
+        "000500020000271000000001000001F40000000A01020000",
+        "0A0000010A0000020A0000FE0001000200000003000000B4",
+        "00002328000027100400005000120600FDE8FDE918180000",
+        "0A0000020A0000010000000000020001000000010000002800002328",
+        "000027100050040000100600000000000000000000",
+    ))
+    .unwrap();
+    let formatter = get_default_formatter();
+    let message = V5Message::parse(&bytes, &formatter, ParseOptions::default())?;
+    assert_eq!(message.sys_uptime, 10000);
+    assert_eq!(message.flow_sequence, 10);
+    assert_eq!(message.observation_domain_id(), 0x0102);
+    assert_eq!(message.records.len(), 2);
+    let record = &message.records[0];
+    assert_eq!(
+        record.values[&DataRecordKey::Str("sourceIPv4Address")],
+        DataRecordValue::Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1))
+    );
+    assert_eq!(
+        record.values[&DataRecordKey::Str("octetDeltaCount")],
+        DataRecordValue::U32(180)
+    );
+    assert_eq!(
+        record.values[&DataRecordKey::Str("destinationTransportPort")],
+        DataRecordValue::U16(80)
+    );
+    assert_eq!(
+        record.values[&DataRecordKey::Str("bgpDestinationAsNumber")],
+        DataRecordValue::U16(65001)
+    );
+    assert_eq!(record.values.len(), v5::template_fields().len());
+
+    // the converted message writes and parses as IPFIX
+    let ipfix = message.clone().into_ipfix();
+    let templates = RefCell::new(HashMap::new());
+    let written = ipfix.to_bytes(&templates, &formatter, WriteOptions::default())?;
+    let templates = RefCell::new(HashMap::new());
+    let parsed = Message::read_args(
+        &mut Cursor::new(written),
+        (&templates, &formatter, ParseOptions::default()),
+    )?;
+    assert_eq!(parsed, ipfix);
+
+    // collectors accept v5 alongside IPFIX
+    let mut session = CollectorSession::new(formatter.clone(), Duration::from_secs(60));
+    assert_eq!(session.handle_datagram("a", &bytes)?.message, ipfix);
+    let collected = session.handle_datagram("a", &bytes)?;
+    assert!(!collected.events.iter().any(|event| matches!(
+        event,
+        SessionEvent::TemplateAdded { .. } | SessionEvent::TemplateReplaced { .. }
+    )));
+    Ok(())
+}
+
+#[test]
+fn netflow_v9() -> binrw::BinResult<()> {
+    let bytes = hex::decode(concat!(
+        "0009000300000064000000010000000700000001",
+        // template 256 with sourceIPv4Address and vendor field 1
+        "00000010010000020008000480010002",
+        // options template 257 with System scope, padded
+        "0001001401010004000400010004002900080000",
+        // a data record, padded
+        "0100000C0A00000100050000",
+    ))
+    .unwrap();
+    let formatter = get_default_formatter();
+    let templates = RefCell::new(HashMap::new());
+    let message = V9Message::parse(&bytes, &templates, &formatter, ParseOptions::default())?;
+    assert_eq!(message.sys_uptime, 100);
+    assert_eq!(message.source_id, 1);
+    assert_eq!(
+        message.sets[0].records,
+        Records::Template(vec![TemplateRecord {
+            template_id: 256,
+            field_specifiers: [
+                FieldSpecifier::new(None, 8, 4),
+                FieldSpecifier::new(Some(VENDOR_ENTERPRISE_NUMBER), 1, 2),
+            ]
+            .into_iter()
+            .collect(),
+        }])
+    );
+    assert_eq!(
+        message.sets[1].records,
+        Records::OptionsTemplate(vec![OptionsTemplateRecord::new(
+            257,
+            vec![FieldSpecifier::new(None, 144, 4)],
+            vec![FieldSpecifier::new(None, 41, 8)],
+        )
+        .unwrap()])
+    );
+    let records: Vec<_> = message
+        .clone()
+        .into_ipfix()
+        .iter_data_records()
+        .cloned()
+        .collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(
+        records[0].values[&DataRecordKey::Str("sourceIPv4Address")],
+        DataRecordValue::Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1))
+    );
+
+    // the same templates decode IPFIX messages, and back again
+    let written = message.to_bytes(&templates, &formatter)?;
+    assert_eq!(&written[..4], [0, 9, 0, 3]);
+    let templates = RefCell::new(HashMap::new());
+    assert_eq!(
+        V9Message::parse(&written, &templates, &formatter, ParseOptions::default())?,
+        message
+    );
+    let ipfix = message.clone().into_ipfix();
+    assert_eq!(V9Message::from_ipfix(ipfix, 100), message);
+
+    // collectors accept v9 alongside IPFIX
+    let mut session = CollectorSession::new(formatter.clone(), Duration::from_secs(60));
+    let collected = session.handle_datagram("a", &bytes)?;
+    assert_eq!(collected.message, message.clone().into_ipfix());
+    assert_eq!(collected.events.len(), 2);
+
+    // enterprise-specific fields from other vendors can't be sent
+    let mut message = message;
+    message.sets = [Set {
+        records: Records::Template(vec![TemplateRecord {
+            template_id: 258,
+            field_specifiers: [FieldSpecifier::new(Some(29305), 1, 4)]
+                .into_iter()
+                .collect(),
+        }]),
+    }]
+    .into_iter()
+    .collect();
+    assert!(message.to_bytes(&templates, &formatter).is_err());
+    Ok(())
+}
+
+#[cfg(feature = "cisco")]
+#[test]
+fn cisco_elements() -> binrw::BinResult<()> {
+    use ipfixrw::information_elements::cisco;
+
+    let mut formatter = get_default_formatter();
+    formatter.extend(cisco::elements());
+    formatter.extend(cisco::nsel_elements());
+
+    // an ASA NetFlow v9 export with firewallEvent, NF_F_FW_EXT_EVENT
+    // (33002) and NF_F_USERNAME (40000)
+    let bytes = hex::decode(concat!(
+        "0009000200000064000000010000000700000001",
+        "000000140100000300E9000180EA00029C400005",
+        "0100000C0107D161646D696E",
+    ))
+    .unwrap();
+    let templates = RefCell::new(HashMap::new());
+    let message = V9Message::parse(&bytes, &templates, &formatter, ParseOptions::default())?;
+    let message = message.into_ipfix();
+    let record = message.iter_data_records().next().unwrap();
+    assert_eq!(
+        record.values[&DataRecordKey::Str("firewallEvent")],
+        DataRecordValue::U8(1)
+    );
+    assert_eq!(
+        record.values[&DataRecordKey::Str("NF_F_FW_EXT_EVENT")],
+        DataRecordValue::U16(2001)
+    );
+    assert_eq!(
+        record.values[&DataRecordKey::Str("NF_F_USERNAME")],
+        DataRecordValue::String("admin".to_string())
+    );
+
+    // Flexible NetFlow AVC elements
+    let fields = [("applicationCategoryName", u16::MAX), ("connectionId", 4)]
+        .map(|(name, length)| FieldSpecifier::by_name(name, length, &formatter).unwrap());
+    assert_eq!(
+        fields[1],
+        FieldSpecifier::new(Some(cisco::ENTERPRISE_NUMBER), 12240, 4)
+    );
+    let record = data_record! {
+        "applicationCategoryName": String("browsing".to_string()),
+        "connectionId": U32(42),
+    };
+    let message = Message {
+        export_time: 0,
+        sequence_number: 0,
+        observation_domain_id: 0,
+        sets: [
+            Set {
+                records: Records::Template(vec![TemplateRecord {
+                    template_id: 256,
+                    field_specifiers: fields.iter().cloned().collect(),
+                }]),
+            },
+            Set {
+                records: Records::Data {
+                    set_id: 256,
+                    data: vec![record.clone()],
+                },
+            },
+        ]
+        .into_iter()
+        .collect(),
+    };
+    let bytes = message.to_bytes(
+        &RefCell::new(HashMap::new()),
+        &formatter,
+        WriteOptions::default(),
+    )?;
+    let parsed = parse_ipfix_message(&bytes, &RefCell::new(HashMap::new()), &formatter)?;
+    assert_eq!(
+        parsed.iter_data_records().collect::<Vec<_>>(),
+        vec![&record]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "vmware")]
+#[test]
+fn vmware_elements() -> binrw::BinResult<()> {
+    use ipfixrw::information_elements::vmware;
+
+    let mut formatter = get_default_formatter();
+    formatter.extend(vmware::elements());
+    assert_eq!(
+        formatter.resolve("tenantSourceIPv4"),
+        Some((vmware::ENTERPRISE_NUMBER, 881, DataRecordType::Ipv4Addr))
+    );
+
+    // an overlay flow from a distributed switch
+    let fields = [
+        ("layer2SegmentId", 8),
+        ("tenantProtocol", 1),
+        ("tenantSourceIPv4", 4),
+        ("tenantDestIPv4", 4),
+        ("tenantSourcePort", 2),
+        ("tenantDestPort", 2),
+    ]
+    .map(|(name, length)| FieldSpecifier::by_name(name, length, &formatter).unwrap());
+    let record = data_record! {
+        "layer2SegmentId": U64(5001),
+        "tenantProtocol": U8(6),
+        "tenantSourceIPv4": Ipv4Addr(Ipv4Addr::new(192, 168, 1, 10)),
+        "tenantDestIPv4": Ipv4Addr(Ipv4Addr::new(192, 168, 1, 20)),
+        "tenantSourcePort": U16(49152),
+        "tenantDestPort": U16(443),
+    };
+    let message = Message {
+        export_time: 0,
+        sequence_number: 0,
+        observation_domain_id: 0,
+        sets: [
+            Set {
+                records: Records::Template(vec![TemplateRecord {
+                    template_id: 256,
+                    field_specifiers: fields.iter().cloned().collect(),
+                }]),
+            },
+            Set {
+                records: Records::Data {
+                    set_id: 256,
+                    data: vec![record.clone()],
+                },
+            },
+        ]
+        .into_iter()
+        .collect(),
+    };
+    let bytes = message.to_bytes(
+        &RefCell::new(HashMap::new()),
+        &formatter,
+        WriteOptions::default(),
+    )?;
+    let parsed = parse_ipfix_message(&bytes, &RefCell::new(HashMap::new()), &formatter)?;
+    assert_eq!(
+        parsed.iter_data_records().collect::<Vec<_>>(),
+        vec![&record]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "juniper")]
+#[test]
+fn juniper_elements() -> binrw::BinResult<()> {
+    use ipfixrw::information_elements::juniper;
+
+    let mut formatter = get_default_formatter();
+    formatter.extend(juniper::elements());
+
+    // template 256 with sourceIPv4Address and commonPropertiesId
+    let bytes = hex::decode(concat!(
+        "000a0034000000000000000000000000",
+        "0002001401000002000800048089000800000a4c",
+        "010000100a0000010000000000000021",
+    ))
+    .unwrap();
+    let message = parse_ipfix_message(&bytes, &RefCell::new(HashMap::new()), &formatter)?;
+    let record = message.iter_data_records().next().unwrap();
+    assert_eq!(
+        record.values[&DataRecordKey::Str("commonPropertiesId")],
+        DataRecordValue::U64(0x21)
+    );
+    Ok(())
+}
+
+#[test]
+fn udp_collector() -> Result<(), Box<dyn std::error::Error>> {
+    let mut collector = UdpCollector::bind(
+        "127.0.0.1:0",
+        get_default_formatter(),
+        Duration::from_millis(100),
+    )?;
+    let sender = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    sender.connect(collector.local_addr()?)?;
+    sender.send(include_bytes!("../resources/tests/parse_temp.bin"))?;
+    sender.send(include_bytes!("../resources/tests/parse_data.bin"))?;
+
+    let mut collected = vec![];
+    collector.run(|event| match event {
+        UdpEvent::Datagram { peer, result } => {
+            assert_eq!(peer, sender.local_addr().unwrap());
+            collected.push(result.unwrap());
+            if collected.len() == 2 {
+                std::ops::ControlFlow::Break(())
+            } else {
+                std::ops::ControlFlow::Continue(())
+            }
+        }
+        UdpEvent::Swept(events) => panic!("unexpected sweep {events:?}"),
+    })?;
+    assert_eq!(collected[0].events.len(), 4);
+    assert!(matches!(
+        collected[1].events[..],
+        [SessionEvent::Sequence {
+            event: SequenceEvent::Gap { missing: 949, .. },
+            ..
+        }]
+    ));
+    assert_eq!(collected[1].message.iter_data_records().count(), 21);
+
+    // the templates expire without being refreshed
+    match collector.recv()? {
+        UdpEvent::Swept(events) => assert_eq!(events.len(), 3),
+        event => panic!("unexpected {event:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn udp_collector_thread() -> Result<(), Box<dyn std::error::Error>> {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    let addr = socket.local_addr()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    UdpCollector::spawn(
+        socket,
+        get_default_formatter(),
+        Duration::from_secs(60),
+        ParseOptions::default(),
+        tx,
+    );
+
+    let sender = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    sender.send_to(include_bytes!("../resources/tests/parse_data.bin"), addr)?;
+    match rx.recv_timeout(Duration::from_secs(5))? {
+        UdpEvent::Datagram { result, .. } => assert!(result.is_err()),
+        event => panic!("unexpected {event:?}"),
+    }
+
+    Ok(())
+}
+
+/// Reads one byte at a time, to split messages across reads
+struct ByteReader<'a>(&'a [u8]);
+
+impl std::io::Read for ByteReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match (self.0.split_first(), buf.first_mut()) {
+            (Some((&byte, rest)), Some(out)) => {
+                *out = byte;
+                self.0 = rest;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+#[test]
+fn tcp_session() {
+    let bytes = [
+        &include_bytes!("../resources/tests/parse_temp.bin")[..],
+        include_bytes!("../resources/tests/parse_data.bin"),
+        &[0, 9, 0, 16],
+    ]
+    .concat();
+    let peer = "127.0.0.1:4739".parse().unwrap();
+    let results: Vec<_> =
+        TcpSession::new(ByteReader(&bytes), peer, get_default_formatter()).collect();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].as_ref().unwrap().events.len(), 4);
+    assert_eq!(
+        results[1]
+            .as_ref()
+            .unwrap()
+            .message
+            .iter_data_records()
+            .count(),
+        21
+    );
+    // a bad version ends the session
+    assert!(matches!(results[2], Err(binrw::Error::BadMagic { .. })));
+}
+
+#[test]
+fn tcp_collector() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let collector = TcpCollector::bind("127.0.0.1:0", get_default_formatter())?;
+    let addr = collector.local_addr()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    collector.spawn(tx);
+    let recv = || rx.recv_timeout(Duration::from_secs(5));
+
+    let mut first = std::net::TcpStream::connect(addr)?;
+    assert!(matches!(recv()?, TcpEvent::Connected(peer) if peer == first.local_addr()?));
+    first.write_all(include_bytes!("../resources/tests/parse_temp.bin"))?;
+    assert!(matches!(recv()?, TcpEvent::Message { result: Ok(_), .. }));
+
+    // templates are separate for each connection
+    let mut second = std::net::TcpStream::connect(addr)?;
+    assert!(matches!(recv()?, TcpEvent::Connected(_)));
+    second.write_all(include_bytes!("../resources/tests/parse_data.bin"))?;
+    assert!(matches!(recv()?, TcpEvent::Message { result: Err(_), .. }));
+
+    first.write_all(include_bytes!("../resources/tests/parse_data.bin"))?;
+    assert!(matches!(recv()?, TcpEvent::Message { result: Ok(_), .. }));
+    drop(first);
+    assert!(matches!(recv()?, TcpEvent::Disconnected(_)));
+
+    Ok(())
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tcp_collector_tls() -> Result<(), Box<dyn std::error::Error>> {
+    use ipfixrw::stream::MessageWriter;
+    use ipfixrw::tls::rustls::pki_types::pem::PemObject;
+    use ipfixrw::tls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use ipfixrw::tls::rustls::RootCertStore;
+    use ipfixrw::tls::{client_config, connect, server_config, CertifiedKey};
+
+    fn identity(cert: &[u8], key: &[u8]) -> CertifiedKey {
+        let chain = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        (chain, PrivateKeyDer::from_pem_slice(key).unwrap())
+    }
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_slice(include_bytes!(
+        "../resources/tests/tls/ca.pem"
+    ))?)?;
+
+    let server = server_config(
+        identity(
+            include_bytes!("../resources/tests/tls/collector.pem"),
+            include_bytes!("../resources/tests/tls/collector.key"),
+        ),
+        Some(roots.clone()),
+    )?;
+    let collector = TcpCollector::bind("127.0.0.1:0", get_default_formatter())?.tls(server);
+    let addr = collector.local_addr()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    collector.spawn(tx);
+    let recv = || rx.recv_timeout(Duration::from_secs(5));
+
+    let client = client_config(
+        roots.clone(),
+        Some(identity(
+            include_bytes!("../resources/tests/tls/exporter.pem"),
+            include_bytes!("../resources/tests/tls/exporter.key"),
+        )),
+    )?;
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let message = parse_ipfix_message(
+        include_bytes!("../resources/tests/parse_temp.bin"),
+        &templates,
+        &formatter,
+    )?;
+    let stream = connect(addr, client, "collector.example")?;
+    let mut writer = MessageWriter::new(stream, &templates, &formatter);
+    writer.write(&message)?;
+    writer.flush()?;
+    assert!(matches!(recv()?, TcpEvent::Connected(_)));
+    match recv()? {
+        TcpEvent::Message { result, .. } => assert_eq!(result?.message, message),
+        event => panic!("unexpected {event:?}"),
+    }
+
+    // the Collector requires a client certificate
+    let client = client_config(roots, None)?;
+    let mut stream = connect(addr, client, "collector.example")?;
+    let _ = stream.conn.complete_io(&mut stream.sock);
+    assert!(matches!(recv()?, TcpEvent::Connected(_)));
+    assert!(matches!(recv()?, TcpEvent::Message { result: Err(_), .. }));
+
+    Ok(())
+}
+
+#[cfg(feature = "dtls")]
+#[test]
+fn dtls_collector() -> Result<(), Box<dyn std::error::Error>> {
+    use ipfixrw::dtls::openssl::ssl::{
+        SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode,
+    };
+    use ipfixrw::dtls::{DtlsCollector, DtlsEvent, DtlsExporter};
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::dtls())?;
+    acceptor.set_certificate_chain_file("resources/tests/tls/collector.pem")?;
+    acceptor.set_private_key_file("resources/tests/tls/collector.key", SslFiletype::PEM)?;
+    acceptor.set_ca_file("resources/tests/tls/ca.pem")?;
+    acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    let collector = DtlsCollector::new(
+        std::net::UdpSocket::bind("127.0.0.1:0")?,
+        acceptor,
+        get_default_formatter(),
+        Duration::from_secs(60),
+    )?
+    .idle_timeout(Duration::from_secs(1));
+    let addr = collector.local_addr()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    collector.spawn(tx);
+    let recv = || rx.recv_timeout(Duration::from_secs(5));
+
+    // a ClientHello without a cookie only gets a HelloVerifyRequest
+    let spoofed = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    spoofed.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut client_hello = hex::decode("16fefd00000000000000000030010000240000000000000024fefd")?;
+    client_hello.extend_from_slice(&[0; 34]);
+    spoofed.send_to(&client_hello, addr)?;
+    let mut buf = [0; 1500];
+    let length = spoofed.recv(&mut buf)?;
+    assert_eq!((buf[0], buf[13], buf[27]), (22, 3, 32));
+    assert_eq!(length, 13 + 12 + 3 + 32);
+    assert!(rx.try_recv().is_err());
+
+    let mut connector = SslConnector::builder(SslMethod::dtls())?;
+    connector.set_certificate_chain_file("resources/tests/tls/exporter.pem")?;
+    connector.set_private_key_file("resources/tests/tls/exporter.key", SslFiletype::PEM)?;
+    connector.set_ca_file("resources/tests/tls/ca.pem")?;
+    let connector = connector.build();
+
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let template_message = parse_ipfix_message(
+        include_bytes!("../resources/tests/parse_temp.bin"),
+        &templates,
+        &formatter,
+    )?;
+    let data_message = parse_ipfix_message(
+        include_bytes!("../resources/tests/parse_data.bin"),
+        &templates,
+        &formatter,
+    )?;
+
+    let mut exporter = DtlsExporter::connect(
+        std::net::UdpSocket::bind("127.0.0.1:0")?,
+        addr,
+        &connector,
+        "collector.example",
+        1500,
+        &templates,
+        &formatter,
+    )?;
+    assert!(matches!(recv()?, DtlsEvent::Connected(_)));
+    exporter.send(&template_message)?;
+    exporter.send(&data_message)?;
+    for expected in [&template_message, &data_message] {
+        match recv()? {
+            DtlsEvent::Message { result, .. } => assert_eq!(&result?.message, expected),
+            event => panic!("unexpected {event:?}"),
+        }
+    }
+
+    // messages must fit in one datagram
+    let mut big_message = data_message.clone();
+    big_message.sets.extend(data_message.sets.iter().cloned());
+    assert!(exporter.send(&big_message).is_err());
+
+    // closing the association drops its templates
+    exporter.close()?;
+    assert!(matches!(recv()?, DtlsEvent::Disconnected(_)));
+
+    // idle associations are closed
+    let _idle = DtlsExporter::connect(
+        std::net::UdpSocket::bind("127.0.0.1:0")?,
+        addr,
+        &connector,
+        "collector.example",
+        1500,
+        &templates,
+        &formatter,
+    )?;
+    assert!(matches!(recv()?, DtlsEvent::Connected(_)));
+    assert!(matches!(recv()?, DtlsEvent::Disconnected(_)));
+
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_record_streams() -> Result<(), Box<dyn std::error::Error>> {
+    use futures_util::StreamExt;
+    use ipfixrw::async_io::{AsyncTcpSession, AsyncUdpCollector, SessionKey};
+
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let collector = AsyncUdpCollector::bind(
+        "127.0.0.1:0",
+        get_default_formatter(),
+        Duration::from_secs(60),
+    )
+    .await?;
+    let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    sender.connect(collector.local_addr()?).await?;
+    // data sent before its template yields no records
+    sender.send(data_bytes).await?;
+    sender.send(template_bytes).await?;
+    sender.send(data_bytes).await?;
+    let records: Vec<_> = collector.into_record_stream().take(21).collect().await;
+    let key = SessionKey {
+        peer: sender.local_addr()?,
+        observation_domain_id: 0,
+    };
+    assert!(records.iter().all(|(k, _)| k.peer == key.peer));
+
+    let bytes = [&template_bytes[..], data_bytes, data_bytes].concat();
+    let session = AsyncTcpSession::new(&bytes[..], key.peer, get_default_formatter());
+    let tcp_records: Vec<_> = session.into_record_stream().collect().await;
+    assert_eq!(tcp_records.len(), 42);
+    assert_eq!(tcp_records[..21], records[..]);
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_collectors() -> Result<(), Box<dyn std::error::Error>> {
+    use ipfixrw::async_io::{AsyncTcpSession, AsyncUdpCollector};
+
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let mut collector = AsyncUdpCollector::bind(
+        "127.0.0.1:0",
+        get_default_formatter(),
+        Duration::from_secs(60),
+    )
+    .await?;
+    let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    sender.connect(collector.local_addr()?).await?;
+    sender.send(template_bytes).await?;
+    sender.send(data_bytes).await?;
+    for records in [0, 21] {
+        match collector.recv().await? {
+            UdpEvent::Datagram { result, .. } => {
+                assert_eq!(result?.message.iter_data_records().count(), records)
+            }
+            event => panic!("unexpected {event:?}"),
+        }
+    }
+
+    // a stream split across reads, with a bad version at the end
+    let bytes = [&template_bytes[..], data_bytes, &[0, 9, 0, 16]].concat();
+    let (mut write, read) = tokio::io::duplex(16);
+    let peer = "127.0.0.1:4739".parse()?;
+    let mut session = AsyncTcpSession::new(read, peer, get_default_formatter());
+    let (_, results) = tokio::join!(
+        tokio::io::AsyncWriteExt::write_all(&mut write, &bytes),
+        async {
+            let mut results = vec![];
+            while let Some(result) = session.next().await {
+                results.push(result);
+            }
+            results
+        }
+    );
+    assert_eq!(results.len(), 3);
+    assert_eq!(
+        results[1]
+            .as_ref()
+            .unwrap()
+            .message
+            .iter_data_records()
+            .count(),
+        21
+    );
+    assert!(results[2].is_err());
+
+    Ok(())
+}
+
+#[test]
+fn sctp_stream_mapping() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let mut message = parse_ipfix_message(
+        include_bytes!("../resources/tests/parse_temp.bin"),
+        &templates,
+        &formatter,
+    )?;
+    let data = parse_ipfix_message(
+        include_bytes!("../resources/tests/parse_data.bin"),
+        &templates,
+        &formatter,
+    )?;
+    message.sequence_number = data.sequence_number;
+    message.sets.extend(data.sets.iter().cloned());
+
+    let lifetime = Duration::from_secs(1);
+    let mut mapper = StreamMapper::new(2).data_lifetime(lifetime);
+    let parts = mapper.split(&message, &templates);
+    // templates 500, 999 and 501, then data sets for 999, 500 and 999
+    let layout: Vec<_> = parts
+        .iter()
+        .map(|part| (part.stream, part.reliability, part.message.sets.len()))
+        .collect();
+    let partial = Reliability::PartiallyReliable { lifetime };
+    assert_eq!(
+        layout,
+        [
+            (0, Reliability::Reliable, 1),
+            (1, Reliability::Reliable, 1),
+            (0, Reliability::Reliable, 1),
+            (1, partial, 1),
+            (0, partial, 1),
+            (1, partial, 1),
+        ]
+    );
+    let records_before = parts[3].message.iter_data_records().count() as u32;
+    assert_eq!(parts[3].message.sequence_number, data.sequence_number);
+    assert_eq!(
+        parts[4].message.sequence_number,
+        data.sequence_number + records_before
+    );
+    assert_eq!(mapper.stream_for(message.observation_domain_id, 501), 0);
+
+    // records of raw data sets count too
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+    let set_length = usize::from(u16::from_be_bytes([data_bytes[18], data_bytes[19]]));
+    let mut raw_message = message.clone();
+    let first_data = raw_message
+        .sets
+        .iter()
+        .position(|set| matches!(set.records, Records::Data { .. }))
+        .unwrap();
+    let set_id = u16::from_be_bytes([data_bytes[16], data_bytes[17]]);
+    raw_message.sets[first_data] = Set {
+        records: Records::RawData {
+            set_id,
+            bytes: data_bytes[20..16 + set_length].to_vec(),
+        },
+    };
+    let raw_parts = StreamMapper::new(2).split(&raw_message, &templates);
+    assert_eq!(
+        raw_parts[4].message.sequence_number,
+        parts[4].message.sequence_number
+    );
+
+    // withdrawing all templates goes on every stream
+    let withdrawal = Message {
+        export_time: 0,
+        sequence_number: 0,
+        observation_domain_id: message.observation_domain_id,
+        sets: [Set {
+            records: Records::withdraw_all(),
+        }]
+        .into_iter()
+        .collect(),
+    };
+    let streams: Vec<_> = mapper
+        .split(&withdrawal, &templates)
+        .iter()
+        .map(|part| part.stream)
+        .collect();
+    assert_eq!(streams, [0, 1]);
+
+    Ok(())
+}
+
+#[cfg(all(feature = "sctp", target_os = "linux"))]
+#[test]
+fn sctp_association() -> binrw::BinResult<()> {
+    use ipfixrw::sctp::socket::{SctpCollector, SctpEvent, SctpExporter};
+
+    let formatter = get_default_formatter();
+    let collector = match SctpCollector::bind("127.0.0.1:0", 2, formatter.clone()) {
+        Ok(collector) => collector,
+        // EPROTONOSUPPORT, on kernels without SCTP such as in some containers
+        Err(err) if err.raw_os_error() == Some(93) => {
+            eprintln!("skipping, SCTP isn't supported: {err}");
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let addr = collector.local_addr()?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+    collector.spawn(sender);
+
+    let templates = RefCell::new(HashMap::new());
+    let mut message = parse_ipfix_message(
+        include_bytes!("../resources/tests/parse_temp.bin"),
+        &templates,
+        &formatter,
+    )?;
+    let data = parse_ipfix_message(
+        include_bytes!("../resources/tests/parse_data.bin"),
+        &templates,
+        &formatter,
+    )?;
+    message.sequence_number = data.sequence_number;
+    message.sets.extend(data.sets.iter().cloned());
+
+    let mapper = StreamMapper::new(2).data_lifetime(Duration::from_secs(1));
+    let mut exporter = SctpExporter::connect(addr, mapper)?;
+    exporter.send_message(&message, &templates, &formatter, WriteOptions::default())?;
+    let timeout = Duration::from_secs(5);
+    assert!(matches!(
+        receiver.recv_timeout(timeout).unwrap(),
+        SctpEvent::Connected(_)
+    ));
+
+    // templates are ahead of their data on each stream, so every part
+    // decodes, and together they hold all of the data records
+    let mut streams = vec![];
+    let mut data_records = 0;
+    for _ in 0..6 {
+        let SctpEvent::Message { stream, result, .. } = receiver.recv_timeout(timeout).unwrap()
+        else {
+            panic!("expected a message");
+        };
+        streams.push(stream);
+        data_records += result?.message.iter_data_records().count();
+    }
+    streams.sort_unstable();
+    assert_eq!(streams, [0, 0, 0, 1, 1, 1]);
+    assert_eq!(data_records, data.iter_data_records().count());
+
+    drop(exporter);
+    assert!(matches!(
+        receiver.recv_timeout(timeout).unwrap(),
+        SctpEvent::Disconnected(_)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn borrowed_decoding() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+
+    let templates = RefCell::new(HashMap::new());
+    parse_ipfix_message(template_bytes, &templates, &formatter)?;
+    let expected = parse_ipfix_message(data_bytes, &templates, &formatter)?;
+
+    let templates = RefCell::new(HashMap::new());
+    let template_message = BorrowedMessage::parse(template_bytes, &templates, &formatter)?;
+    assert_eq!(template_message.iter_data_sets().count(), 0);
+    let message = BorrowedMessage::parse(data_bytes, &templates, &formatter)?;
+    assert_eq!(message.sequence_number, expected.sequence_number);
+
+    let mut records = vec![];
+    for data_set in message.iter_data_sets() {
+        for record in data_set.records() {
+            records.push(record?.to_owned());
+        }
+    }
+    let expected_records: Vec<DataRecord> = expected.iter_data_records().cloned().collect();
+    similar_asserts::assert_eq!(expected: expected_records, actual: records);
+
+    // values borrow from the input
+    let data_set = message.iter_data_sets().next().unwrap();
+    let record = data_set.records().next().unwrap()?;
+    for (field, (key, value)) in record.fields().iter().zip(record.values()) {
+        assert!(data_bytes.as_ptr_range().contains(&field.as_ptr()));
+        if let BorrowedValue::Bytes(bytes) = value {
+            assert_eq!(bytes.as_ptr(), field.as_ptr());
+        }
+        assert_eq!(record.get(key.clone()), Some(value));
+    }
+    assert_eq!(record.get("notInTheTemplate"), None);
+
+    Ok(())
+}
+
+#[test]
+fn compact_decoding() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let templates = RefCell::new(HashMap::new());
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+    parse_ipfix_message(template_bytes, &templates, &formatter)?;
+    let expected = parse_ipfix_message(data_bytes, &templates, &formatter)?;
+
+    let message =
+        CompactMessage::parse(data_bytes, &templates, &formatter, ParseOptions::default())?;
+    let records: Vec<DataRecord> = message
+        .iter_data_sets()
+        .flat_map(|data_set| data_set.records())
+        .map(|record| record.to_data_record())
+        .collect();
+    let expected_records: Vec<DataRecord> = expected.iter_data_records().cloned().collect();
+    similar_asserts::assert_eq!(expected: expected_records, actual: records);
+
+    // look a field up once, then by index in each record
+    let data_set = message.iter_data_sets().next().unwrap();
+    assert_eq!(data_set.len(), data_set.records().count());
+    let (key, _) = data_set.records().next().unwrap().values().next().unwrap();
+    let index = data_set.field_index(key.clone()).unwrap();
+    for record in data_set.records() {
+        assert_eq!(record.value(index), record.get(key.clone()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn parser_reuse() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let templates = RefCell::new(HashMap::new());
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+    parse_ipfix_message(template_bytes, &templates, &formatter)?;
+    let expected = parse_ipfix_message(data_bytes, &templates, &formatter)?;
+    let expected_records: Vec<DataRecord> = expected.iter_data_records().cloned().collect();
+
+    let mut parser = Parser::new(&templates, &formatter);
+    let view = parser.parse_into(template_bytes)?;
+    assert_eq!(view.iter_data_records().count(), 0);
+    for _ in 0..2 {
+        let view = parser.parse_into(data_bytes)?;
+        assert_eq!(view.sequence_number, expected.sequence_number);
+        let records: Vec<DataRecord> = view
+            .iter_data_records()
+            .map(|record| record.to_data_record())
+            .collect();
+        similar_asserts::assert_eq!(expected: expected_records, actual: records);
+    }
+
+    // templates are cached until cleared
+    templates.borrow_mut().clear();
+    assert!(parser.parse_into(data_bytes).is_ok());
+    parser.clear_template_cache();
+    assert!(parser.parse_into(data_bytes).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn visitor() -> binrw::BinResult<()> {
+    #[derive(Default)]
+    struct Collect {
+        template_ids: Vec<u16>,
+        records: Vec<DataRecord>,
+    }
+
+    impl Visitor for Collect {
+        fn on_template(&mut self, template_id: u16, _template: &Template) {
+            self.template_ids.push(template_id);
+        }
+        fn on_record_start(&mut self, _set_id: u16, _template: &Template) {
+            self.records.push(DataRecord::default());
+        }
+        fn on_record_field(&mut self, field_spec: &ExpandedFieldSpecifier, value: BorrowedValue) {
+            let record = self.records.last_mut().unwrap();
+            record
+                .values
+                .insert(field_spec.name.clone(), value.to_owned());
+        }
+    }
+
+    let formatter = get_default_formatter();
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+    let templates = RefCell::new(HashMap::new());
+    parse_ipfix_message(template_bytes, &templates, &formatter)?;
+    let expected = parse_ipfix_message(data_bytes, &templates, &formatter)?;
+    let expected_records: Vec<DataRecord> = expected.iter_data_records().cloned().collect();
+
+    let templates = RefCell::new(HashMap::new());
+    let mut visitor = Collect::default();
+    let length = visit_ipfix_message(template_bytes, &templates, &formatter, &mut visitor)?;
+    assert_eq!(length, template_bytes.len());
+    assert_eq!(visitor.template_ids, vec![500, 999, 501]);
+    visit_ipfix_message(data_bytes, &templates, &formatter, &mut visitor)?;
+    similar_asserts::assert_eq!(expected: expected_records, actual: visitor.records);
+
+    Ok(())
+}
+
+#[test]
+fn lazy_records() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let templates = RefCell::new(HashMap::new());
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+    parse_ipfix_message(template_bytes, &templates, &formatter)?;
+    let expected = parse_ipfix_message(data_bytes, &templates, &formatter)?;
+    let expected_records: Vec<DataRecord> = expected.iter_data_records().cloned().collect();
+
+    let message = BorrowedMessage::parse(data_bytes, &templates, &formatter)?;
+    let mut records = vec![];
+    for data_set in message.iter_data_sets() {
+        for (lazy, borrowed) in data_set.lazy_records().zip(data_set.records()) {
+            let (lazy, borrowed) = (lazy?, borrowed?);
+            for (i, (key, value)) in borrowed.values().enumerate() {
+                assert_eq!(lazy.get(key.clone()), Some(value.clone()));
+                assert_eq!(lazy.get_index(i), Some(value));
+            }
+            assert_eq!(lazy.fields().count(), borrowed.fields().len());
+            records.push(lazy.to_owned()?);
+        }
+    }
+    similar_asserts::assert_eq!(expected: expected_records, actual: records);
+
+    Ok(())
+}
+
+#[test]
+fn decode_plan() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    // template 256: sourceIPv4Address, interfaceName (variable length)
+    let template_bytes = hex::decode("0002001001000002000800040052FFFF").unwrap();
+    Set::read_args(
+        &mut Cursor::new(&template_bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )?;
+    let template = templates.get_template(256).unwrap();
+
+    let record_bytes = hex::decode("0A0000010465746830").unwrap();
+    let record = template
+        .plan()
+        .read_record(&mut Cursor::new(&record_bytes), ParseOptions::default())?;
+    assert_eq!(
+        record,
+        DataRecord::read_options(
+            &mut Cursor::new(&record_bytes),
+            Endian::Big,
+            (256, &templates, ParseOptions::default()),
+        )?
+    );
+    assert_eq!(
+        record.values.get(&DataRecordKey::Str("interfaceName")),
+        Some(&DataRecordValue::String("eth0".to_string()))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn fixed_size_records() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    templates
+        .insert_template_records(
+            &[
+                // sourceIPv4Address, destinationTransportPort, octetDeltaCount
+                TemplateRecord {
+                    template_id: 256,
+                    field_specifiers: [
+                        FieldSpecifier::new(None, 8, 4),
+                        FieldSpecifier::new(None, 11, 2),
+                        FieldSpecifier::new(None, 1, 8),
+                    ]
+                    .into_iter()
+                    .collect(),
+                },
+                // with a variable length interfaceName
+                TemplateRecord {
+                    template_id: 257,
+                    field_specifiers: [
+                        FieldSpecifier::new(None, 8, 4),
+                        FieldSpecifier::new(None, 82, u16::MAX),
+                    ]
+                    .into_iter()
+                    .collect(),
+                },
+            ],
+            &formatter,
+        )
+        .unwrap();
+    assert_eq!(
+        templates.get_template(256).unwrap().plan().record_length(),
+        Some(14)
+    );
+    assert_eq!(
+        templates.get_template(257).unwrap().plan().record_length(),
+        None
+    );
+
+    // two records, then 3 bytes of padding
+    let bytes =
+        hex::decode("010000230A0000010050000000000000000AC0A8000101BB0000000000000014000000")
+            .unwrap();
+    let set = Set::read_args(
+        &mut Cursor::new(&bytes),
+        (&templates, &formatter, ParseOptions::default()),
+    )?;
+    assert_eq!(
+        set.records,
+        Records::Data {
+            set_id: 256,
+            data: vec![
+                data_record! {
+                    "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(10, 0, 0, 1)),
+                    "destinationTransportPort": U16(80),
+                    "octetDeltaCount": U64(10),
+                },
+                data_record! {
+                    "sourceIPv4Address": Ipv4Addr(Ipv4Addr::new(192, 168, 0, 1)),
+                    "destinationTransportPort": U16(443),
+                    "octetDeltaCount": U64(20),
+                },
+            ],
+        }
+    );
+
+    Ok(())
 }
@@ -1,6 +1,6 @@
 //! Samples from pskreporter documentation <https://pskreporter.info/pskdev.html>
 
-use std::{cell::RefCell, io::Cursor, rc::Rc};
+use std::{cell::RefCell, io::Cursor};
 
 use ahash::{HashMap, HashMapExt};
 use binrw::{BinRead, BinResult, BinWrite};
@@ -12,7 +12,7 @@ use ipfixrw::{
     parse_ipfix_message,
     parser::{
         DataRecord, DataRecordKey, DataRecordType, DataRecordValue, FieldSpecifier, Message,
-        OptionsTemplateRecord, Records, Set, TemplateRecord,
+        OptionsTemplateRecord, ParseOptions, Records, Set, TemplateRecord, WriteOptions,
     },
 };
 
@@ -29,11 +29,11 @@ use ipfixrw::{
             records: Records::OptionsTemplate(vec![OptionsTemplateRecord {
                 template_id: 0x9992,
                 scope_field_count: 0,
-                field_specifiers: vec![
+                field_specifiers: [
                     FieldSpecifier::new(Some(30351), 2, u16::MAX),
                     FieldSpecifier::new(Some(30351), 4, u16::MAX),
                     FieldSpecifier::new(Some(30351), 8, u16::MAX),
-                ],
+                ].into_iter().collect(),
             }]),
         } ; "receiverCallsign, receiverLocator, decodingSoftware")]
 #[test_case(
@@ -49,12 +49,12 @@ use ipfixrw::{
             records: Records::OptionsTemplate(vec![OptionsTemplateRecord {
                 template_id: 0x9992,
                 scope_field_count: 0,
-                field_specifiers: vec![
+                field_specifiers: [
                     FieldSpecifier::new(Some(30351), 2, u16::MAX),
                     FieldSpecifier::new(Some(30351), 4, u16::MAX),
                     FieldSpecifier::new(Some(30351), 8, u16::MAX),
                     FieldSpecifier::new(Some(30351), 9, u16::MAX),
-                ],
+                ].into_iter().collect(),
             }]),
         } ; "receiverCallsign, receiverLocator, decodingSoftware, anntennaInformation")]
 // sender information templates
@@ -69,13 +69,13 @@ use ipfixrw::{
         ), Set {
             records: Records::Template(vec![TemplateRecord {
                 template_id: 0x9993,
-                field_specifiers: vec![
+                field_specifiers: [
                     FieldSpecifier::new(Some(30351), 1, u16::MAX),
                     FieldSpecifier::new(Some(30351), 5, 4),
                     FieldSpecifier::new(Some(30351), 10, u16::MAX),
                     FieldSpecifier::new(Some(30351), 11, 1),
                     FieldSpecifier::new(None, 150, 4),
-                ],
+                ].into_iter().collect(),
             }])
         } ; "senderCallsign, frequency, mode, informationSource (1 byte), flowStartSeconds")]
 #[test_case(
@@ -90,14 +90,14 @@ use ipfixrw::{
         ), Set {
             records: Records::Template(vec![TemplateRecord {
                 template_id: 0x9993,
-                field_specifiers: vec![
+                field_specifiers: [
                     FieldSpecifier::new(Some(30351), 1, u16::MAX),
                     FieldSpecifier::new(Some(30351), 5, 4),
                     FieldSpecifier::new(Some(30351), 10, u16::MAX),
                     FieldSpecifier::new(Some(30351), 11, 1),
                     FieldSpecifier::new(Some(30351), 3, u16::MAX),
                     FieldSpecifier::new(None, 150, 4),
-                ],
+                ].into_iter().collect(),
             }])
         } ; "senderCallsign, frequency, mode, informationSource (1 byte), senderLocator, flowStartSeconds")]
 #[test_case(
@@ -113,7 +113,7 @@ use ipfixrw::{
         ), Set {
             records: Records::Template(vec![TemplateRecord {
                 template_id: 0x9993,
-                field_specifiers: vec![
+                field_specifiers: [
                     FieldSpecifier::new(Some(30351), 1, u16::MAX),
                     FieldSpecifier::new(Some(30351), 5, 4),
                     FieldSpecifier::new(Some(30351), 6, 1),
@@ -121,7 +121,7 @@ use ipfixrw::{
                     FieldSpecifier::new(Some(30351), 10, u16::MAX),
                     FieldSpecifier::new(Some(30351), 11, 1),
                     FieldSpecifier::new(None, 150, 4),
-                ],
+                ].into_iter().collect(),
             }])
         } ; "senderCallsign, frequency, sNR (1 byte), iMD (1 byte), mode, informationSource (1 byte), flowStartSeconds")]
 #[test_case(
@@ -138,7 +138,7 @@ use ipfixrw::{
         ), Set {
             records: Records::Template(vec![TemplateRecord {
                 template_id: 0x9993,
-                field_specifiers: vec![
+                field_specifiers: [
                     FieldSpecifier::new(Some(30351), 1, u16::MAX),
                     FieldSpecifier::new(Some(30351), 5, 4),
                     FieldSpecifier::new(Some(30351), 6, 1),
@@ -147,24 +147,27 @@ use ipfixrw::{
                     FieldSpecifier::new(Some(30351), 11, 1),
                     FieldSpecifier::new(Some(30351), 3, u16::MAX),
                     FieldSpecifier::new(None, 150, 4),
-                ],
+                ].into_iter().collect(),
             }])
         } ; "senderCallsign, frequency, sNR (1 byte), iMD (1 byte), mode, informationSource (1 byte), senderLocator, flowStartSeconds")]
 
 fn test_template_example(bytes_str: &'static str, expected_set: Set) -> BinResult<()> {
     let template_bytes = hex::decode(bytes_str).unwrap();
 
-    let templates = Rc::new(RefCell::new(HashMap::new()));
-    let formatter = Rc::new(pskreporter_formatter());
+    let templates = RefCell::new(HashMap::new());
+    let formatter = pskreporter_formatter();
 
     let parsed = Set::read_args(
         &mut Cursor::new(template_bytes.clone()),
-        (templates.clone(), formatter.clone()),
+        (&templates, &formatter, ParseOptions::default()),
     )?;
     similar_asserts::assert_eq!(expected: expected_set, parsed: parsed);
 
     let mut writer = Cursor::new(Vec::new());
-    expected_set.write_args(&mut writer, (templates, formatter, 4))?;
+    expected_set.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::aligned(4)),
+    )?;
     similar_asserts::assert_eq!(expected: template_bytes, parsed: writer.into_inner());
 
     Ok(())
@@ -215,28 +218,32 @@ fn test_full_examples() -> BinResult<()> {
         export_time: 1200960114,
         sequence_number: 1,
         observation_domain_id: 0,
-        sets: vec![
+        sets: [
             Set {
                 records: Records::OptionsTemplate(vec![OptionsTemplateRecord {
                     template_id: 0x9992,
                     scope_field_count: 0,
-                    field_specifiers: vec![
+                    field_specifiers: [
                         FieldSpecifier::new(Some(30351), 2, u16::MAX),
                         FieldSpecifier::new(Some(30351), 4, u16::MAX),
                         FieldSpecifier::new(Some(30351), 8, u16::MAX),
-                    ],
+                    ]
+                    .into_iter()
+                    .collect(),
                 }]),
             },
             Set {
                 records: Records::Template(vec![TemplateRecord {
                     template_id: 0x9993,
-                    field_specifiers: vec![
+                    field_specifiers: [
                         FieldSpecifier::new(Some(30351), 1, u16::MAX),
                         FieldSpecifier::new(Some(30351), 5, 4),
                         FieldSpecifier::new(Some(30351), 10, u16::MAX),
                         FieldSpecifier::new(Some(30351), 11, 1),
                         FieldSpecifier::new(None, 150, 4),
-                    ],
+                    ]
+                    .into_iter()
+                    .collect(),
                 }]),
             },
             Set {
@@ -270,7 +277,9 @@ fn test_full_examples() -> BinResult<()> {
                     ],
                 },
             },
-        ],
+        ]
+        .into_iter()
+        .collect(),
     };
 
     #[rustfmt::skip]
@@ -289,19 +298,17 @@ fn test_full_examples() -> BinResult<()> {
         sequence_number: 4,
         observation_domain_id: 0,
         // same as full packet, but without the templates and option templates sets
-        sets: expected_full_message.sets[2..].to_vec(),
+        sets: expected_full_message.sets.iter().skip(2).cloned().collect(),
     };
 
-    let templates = Rc::new(RefCell::new(HashMap::new()));
-    let formatter = Rc::new(pskreporter_formatter());
+    let templates = RefCell::new(HashMap::new());
+    let formatter = pskreporter_formatter();
 
-    let full_message =
-        parse_ipfix_message(&full_packet_bytes, templates.clone(), formatter.clone())?;
+    let full_message = parse_ipfix_message(&full_packet_bytes, &templates, &formatter)?;
 
     similar_asserts::assert_eq!(expected: expected_full_message, actual: full_message);
 
-    let data_only_message =
-        parse_ipfix_message(&data_only_packet_bytes, templates, formatter.clone())?;
+    let data_only_message = parse_ipfix_message(&data_only_packet_bytes, &templates, &formatter)?;
 
     similar_asserts::assert_eq!(
         expected: expected_data_only_message,
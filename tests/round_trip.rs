@@ -1,20 +1,30 @@
 use std::cell::RefCell;
 use std::io::Cursor;
-use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use ahash::{HashMap, HashMapExt};
 use binrw::BinWrite;
-use ipfixrw::parse_ipfix_message;
+use ipfixrw::{data_record, parse_ipfix_message, write_ipfix_message};
 use test_case::test_case;
 
+use ipfixrw::export::batch::BatchWriter;
+use ipfixrw::export::{ExportSession, Exporter};
+use ipfixrw::file::{ExportSessionDetails, FileReader, FileWriter, MessageDetails, TimeWindow};
 use ipfixrw::information_elements::get_default_formatter;
+use ipfixrw::mediator::Mediator;
+use ipfixrw::parser::{
+    DataRecord, DataRecordKey, DataRecordValue, FieldSpecifier, IpfixError, Message,
+    OptionsTemplateRecord, Records, Set, TemplateRecord, WriteOptions,
+};
+use ipfixrw::statistics::{ExportingProcessStatistics, MeteringProcessStatistics};
+use ipfixrw::stream::MessageWriter;
 
 #[test_case(&["parse_temp.bin", "parse_data.bin"], 1; "parse sample")]
 #[test_case(&["parse_temp_1.bin", "dns_samp.bin"], 4; "nprobe dns sample")]
 #[test_case(&["parse_temp_2.bin","http_samp.bin"], 4; "nprobe http sample")]
 fn test_round_trip(filenames: &[&'static str], alignment: u8) -> binrw::BinResult<()> {
-    let templates = Rc::new(RefCell::new(HashMap::new()));
-    let formatter = Rc::new(get_default_formatter());
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
 
     for filename in filenames {
         let path: std::path::PathBuf = [env!("CARGO_MANIFEST_DIR"), "resources", "tests", filename]
@@ -22,14 +32,990 @@ fn test_round_trip(filenames: &[&'static str], alignment: u8) -> binrw::BinResul
             .collect();
         let file_bytes = std::fs::read(path)?;
 
-        let msg = parse_ipfix_message(&file_bytes, templates.clone(), formatter.clone())?;
+        let msg = parse_ipfix_message(&file_bytes, &templates, &formatter)?;
+        let bytes = write_ipfix_message(&msg, &templates, &formatter, alignment)?;
+        similar_asserts::assert_eq!(expected: file_bytes, actual: bytes);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn statistics_round_trip() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    let metering = MeteringProcessStatistics {
+        observation_domain_id: 1,
+        exported_message_total_count: 10,
+        exported_flow_record_total_count: 200,
+        exported_octet_total_count: 30000,
+    };
+    let exporting = ExportingProcessStatistics {
+        exporting_process_id: 2,
+        not_sent_flow_total_count: 3,
+        not_sent_packet_total_count: 4,
+        not_sent_octet_total_count: 500,
+    };
+    let message = Message {
+        export_time: 0,
+        sequence_number: 0,
+        observation_domain_id: 1,
+        sets: [
+            Set {
+                records: Records::OptionsTemplate(vec![
+                    MeteringProcessStatistics::options_template(256),
+                    ExportingProcessStatistics::options_template(257),
+                ]),
+            },
+            metering.data_set(256),
+            exporting.data_set(257),
+        ]
+        .into_iter()
+        .collect(),
+    };
+    let mut writer = Cursor::new(Vec::new());
+    message.write_args(
+        &mut writer,
+        (&templates, &formatter, WriteOptions::aligned(4)),
+    )?;
+
+    let read_templates = RefCell::new(HashMap::new());
+    let read_message = parse_ipfix_message(&writer.into_inner(), &read_templates, &formatter)?;
+    similar_asserts::assert_eq!(expected: message, actual: read_message);
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test_case(&["parse_temp.bin", "parse_data.bin"]; "parse sample")]
+#[test_case(&["parse_temp_1.bin", "dns_samp.bin"]; "nprobe dns sample")]
+#[test_case(&["parse_temp_2.bin","http_samp.bin"]; "nprobe http sample")]
+fn test_serde_round_trip(filenames: &[&'static str]) -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    for filename in filenames {
+        let path: std::path::PathBuf = [env!("CARGO_MANIFEST_DIR"), "resources", "tests", filename]
+            .iter()
+            .collect();
+        let file_bytes = std::fs::read(path)?;
+
+        let msg = parse_ipfix_message(&file_bytes, &templates, &formatter)?;
+        let json = serde_json::to_string(&msg).unwrap();
+        let read_msg: Message = serde_json::from_str(&json).unwrap();
+        similar_asserts::assert_eq!(expected: msg, actual: read_msg);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[test_case(&["parse_temp.bin", "parse_data.bin"]; "parse sample")]
+#[test_case(&["parse_temp_1.bin", "dns_samp.bin"]; "nprobe dns sample")]
+#[test_case(&["parse_temp_2.bin","http_samp.bin"]; "nprobe http sample")]
+fn test_json_round_trip(filenames: &[&'static str]) -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let read_templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    for filename in filenames {
+        let path: std::path::PathBuf = [env!("CARGO_MANIFEST_DIR"), "resources", "tests", filename]
+            .iter()
+            .collect();
+        let file_bytes = std::fs::read(path)?;
+
+        let msg = parse_ipfix_message(&file_bytes, &templates, &formatter)?;
+        let json = serde_json::to_string(&msg.to_json()).unwrap();
+        let read_msg = Message::from_json(
+            &serde_json::from_str(&json).unwrap(),
+            &read_templates,
+            &formatter,
+        )
+        .unwrap();
+        similar_asserts::assert_eq!(expected: msg, actual: read_msg);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn message_builder() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let mut session = ExportSession::new(7);
+
+    let record = data_record! { "octetDeltaCount": U64(100) };
+    let first = session
+        .message()
+        .export_time(1000)
+        .template_set(vec![TemplateRecord {
+            template_id: 256,
+            field_specifiers: [FieldSpecifier::new(None, 1, 8)].into_iter().collect(),
+        }])
+        .data_set(256, vec![record.clone(), record.clone()])
+        .build();
+    assert_eq!(
+        (
+            first.export_time,
+            first.sequence_number,
+            first.observation_domain_id
+        ),
+        (1000, 0, 7)
+    );
+    assert_eq!(session.sequence_number(), 2);
+
+    let second = session.message().data_set(256, vec![record]).build();
+    assert_eq!(second.sequence_number, 2);
+    assert!(second.export_time > 1_600_000_000);
+    assert_eq!(session.sequence_number(), 3);
+
+    // both messages can be written and read back
+    let read_templates = RefCell::new(HashMap::new());
+    for message in [first, second] {
         let mut writer = Cursor::new(Vec::new());
-        msg.write_args(
+        message.write_args(
             &mut writer,
-            (templates.clone(), formatter.clone(), alignment),
+            (&templates, &formatter, WriteOptions::default()),
         )?;
-        similar_asserts::assert_eq!(expected: file_bytes, actual: writer.into_inner().as_slice());
+        let read_message = parse_ipfix_message(&writer.into_inner(), &read_templates, &formatter)?;
+        similar_asserts::assert_eq!(expected: message, actual: read_message);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn exporter() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let mut exporter = Exporter::new(7, formatter.clone());
+    assert_eq!(exporter.flush()?, []);
+
+    let fields = vec![FieldSpecifier::new(None, 1, 8)];
+    let template_id = exporter.add_template(fields.clone()).unwrap();
+    assert_eq!(template_id, 256);
+    // identical templates are reused
+    assert_eq!(exporter.add_template(fields).unwrap(), 256);
+    let other_id = exporter
+        .add_template(vec![FieldSpecifier::new(None, 2, 8)])
+        .unwrap();
+    assert_eq!(other_id, 257);
+    assert!(matches!(
+        exporter.push(300, data_record! { "octetDeltaCount": U64(1) }),
+        Err(IpfixError::MissingTemplate(300))
+    ));
+
+    for i in 0..3 {
+        exporter
+            .push(template_id, data_record! { "octetDeltaCount": U64(i) })
+            .unwrap();
     }
+    assert_eq!(exporter.pending_records(), 3);
+    let first = exporter.flush_bytes()?;
+    assert_eq!(exporter.pending_records(), 0);
+
+    // templates are only sent before their first use
+    exporter
+        .push(other_id, data_record! { "packetDeltaCount": U64(5) })
+        .unwrap();
+    assert!(exporter.withdraw_template(template_id));
+    let second = exporter.flush_at(1000)?;
+    assert_eq!(second.len(), 1);
+    assert_eq!(second[0].export_time, 1000);
+    assert_eq!(second[0].sequence_number, 3);
+    assert!(matches!(
+        &second[0].sets[..],
+        [
+            Set {
+                records: Records::Data { set_id: 257, .. }
+            },
+            Set {
+                records: Records::Template(withdrawals)
+            },
+        ] if withdrawals == &[TemplateRecord::withdrawal(256)]
+    ));
+    let second = second[0].to_bytes(exporter.templates(), &formatter, WriteOptions::default())?;
+
+    let read_templates = RefCell::new(HashMap::new());
+    let message = parse_ipfix_message(&first[0], &read_templates, &formatter)?;
+    assert_eq!(message.observation_domain_id, 7);
+    assert_eq!(message.iter_template_records().count(), 2);
+    assert_eq!(message.iter_data_records().count(), 3);
+    let message = parse_ipfix_message(&second, &read_templates, &formatter)?;
+    assert_eq!(message.iter_data_records().count(), 1);
+    assert!(read_templates.borrow().get(&256).is_none());
+
+    // resending only includes templates that are still defined, and new
+    // IDs continue after the last one allocated
+    exporter.resend_templates();
+    let resent = exporter.flush_at(0)?;
+    assert_eq!(resent[0].iter_template_records().count(), 1);
+    assert_eq!(
+        exporter
+            .add_template(vec![FieldSpecifier::new(None, 3, 8)])
+            .unwrap(),
+        258
+    );
+
+    Ok(())
+}
+
+#[test]
+fn exporter_statistics() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let mut exporter = Exporter::new(7, formatter.clone());
+    let template_id = exporter
+        .add_template(vec![
+            FieldSpecifier::new(None, 1, 8),
+            FieldSpecifier::new(None, 2, 8),
+        ])
+        .unwrap();
+    for i in 0..2 {
+        exporter
+            .push(
+                template_id,
+                data_record! { "octetDeltaCount": U64(100 * i), "packetDeltaCount": U64(i) },
+            )
+            .unwrap();
+    }
+    // a 16 byte header, 16 byte template set and 36 byte data set
+    let messages = exporter.flush_bytes()?;
+    assert_eq!(messages.len(), 1);
+    assert_eq!(
+        exporter.statistics(),
+        MeteringProcessStatistics {
+            observation_domain_id: 7,
+            exported_message_total_count: 1,
+            exported_flow_record_total_count: 2,
+            exported_octet_total_count: 68,
+        }
+    );
+
+    // the counters can be exported with the exporter's own templates
+    let statistics_id = exporter
+        .add_options_template_record(MeteringProcessStatistics::options_template(0))
+        .unwrap();
+    let statistics = exporter.statistics();
+    exporter
+        .push(statistics_id, statistics.data_record())
+        .unwrap();
+    let messages = exporter.flush_bytes()?;
+    let read_templates = RefCell::new(HashMap::new());
+    let message = parse_ipfix_message(&messages[0], &read_templates, &formatter)?;
+    assert_eq!(
+        message.iter_data_records().collect::<Vec<_>>(),
+        [&statistics.data_record()]
+    );
+    assert_eq!(exporter.statistics().exported_message_total_count, 2);
+    assert_eq!(exporter.statistics().exported_flow_record_total_count, 3);
+
+    // records of a failed flush are counted as not sent
+    let mut exporter = Exporter::new(7, formatter).max_size(40);
+    let template_id = exporter
+        .add_template(vec![
+            FieldSpecifier::new(None, 1, 8),
+            FieldSpecifier::new(None, 2, 8),
+        ])
+        .unwrap();
+    exporter
+        .push(
+            template_id,
+            data_record! { "octetDeltaCount": U64(1500), "packetDeltaCount": U64(3) },
+        )
+        .unwrap();
+    assert!(exporter.flush().is_err());
+    assert_eq!(exporter.statistics().exported_message_total_count, 0);
+    assert_eq!(
+        exporter.not_sent_statistics(2),
+        ExportingProcessStatistics {
+            exporting_process_id: 2,
+            not_sent_flow_total_count: 1,
+            not_sent_packet_total_count: 3,
+            not_sent_octet_total_count: 1500,
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn split_messages() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let mut session = ExportSession::new(1);
+
+    let records: Vec<DataRecord> = (0..12)
+        .map(|i| {
+            data_record! {
+                "octetDeltaCount": U64(i),
+                "sourceIPv4Address": Ipv4Addr(std::net::Ipv4Addr::new(10, 0, 0, 1)),
+            }
+        })
+        .collect();
+    // 16 byte header, 16 byte template set, then 5 records of 12 bytes
+    let messages = session
+        .message()
+        .export_time(0)
+        .max_size(100)
+        .repeat_templates(true)
+        .template_set(vec![TemplateRecord {
+            template_id: 256,
+            field_specifiers: [
+                FieldSpecifier::new(None, 1, 8),
+                FieldSpecifier::new(None, 8, 4),
+            ]
+            .into_iter()
+            .collect(),
+        }])
+        .data_set(256, records.clone())
+        .build_messages(&templates, &formatter, WriteOptions::default())?;
+
+    assert_eq!(messages.len(), 3);
+    let sequence_numbers: Vec<u32> = messages.iter().map(|m| m.sequence_number).collect();
+    assert_eq!(sequence_numbers, [0, 5, 10]);
+    assert_eq!(session.sequence_number(), 12);
+
+    let mut read_records = vec![];
+    for message in &messages {
+        let mut writer = Cursor::new(Vec::new());
+        message.write_args(
+            &mut writer,
+            (&templates, &formatter, WriteOptions::default()),
+        )?;
+        let bytes = writer.into_inner();
+        assert!(bytes.len() <= 100);
+
+        // each message has its template, so can be read alone
+        let read_templates = RefCell::new(HashMap::new());
+        let read_message = parse_ipfix_message(&bytes, &read_templates, &formatter)?;
+        read_records.extend(read_message.iter_data_records().cloned());
+    }
+    assert_eq!(read_records, records);
+
+    // a single record larger than the limit is an error
+    assert!(session
+        .message()
+        .max_size(30)
+        .data_set(256, records)
+        .build_messages(&templates, &formatter, WriteOptions::default())
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn message_length_limits() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let mut session = ExportSession::new(1);
+
+    let template_set = Set {
+        records: Records::Template(vec![TemplateRecord {
+            template_id: 256,
+            field_specifiers: [FieldSpecifier::new(None, 1, 8)].into_iter().collect(),
+        }]),
+    };
+    let record = data_record! { "octetDeltaCount": U64(1) };
+
+    // messages written after others in the same stream, even beyond
+    // 64 KiB, get their own lengths
+    let small = session
+        .message()
+        .export_time(0)
+        .set(template_set.clone())
+        .data_set(256, vec![record.clone()])
+        .build();
+    let mut writer = Cursor::new(vec![0; 70000]);
+    writer.set_position(70000);
+    for _ in 0..2 {
+        small.write_args(
+            &mut writer,
+            (&templates, &formatter, WriteOptions::default()),
+        )?;
+    }
+    let bytes = writer.into_inner();
+    let length = (bytes.len() - 70000) / 2;
+    for message_bytes in bytes[70000..].chunks(length) {
+        let read_templates = RefCell::new(HashMap::new());
+        let read_message = parse_ipfix_message(&message_bytes, &read_templates, &formatter)?;
+        similar_asserts::assert_eq!(expected: small, actual: read_message);
+    }
+
+    // too many records for the 16 bit length is an error, not a panic
+    let large = session
+        .message()
+        .export_time(0)
+        .set(template_set)
+        .data_set(256, vec![record; 10000])
+        .build();
+    let err = large
+        .write_args(
+            &mut Cursor::new(Vec::new()),
+            (&templates, &formatter, WriteOptions::default()),
+        )
+        .unwrap_err();
+    assert!(matches!(
+        err.custom_err::<IpfixError>(),
+        Some(IpfixError::LengthOverflow { .. })
+    ));
+
+    Ok(())
+}
+
+#[test_case(WriteOptions::default(), 74; "no padding")]
+#[test_case(WriteOptions::aligned(4), 76; "all sets")]
+#[test_case(WriteOptions { pad_template_sets: false, ..WriteOptions::aligned(4) }, 74; "data sets only")]
+#[test_case(WriteOptions { message_alignment: 8, ..WriteOptions::default() }, 80; "message")]
+fn write_padding(options: WriteOptions, expected_length: usize) -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+
+    let metering = MeteringProcessStatistics {
+        observation_domain_id: 1,
+        exported_message_total_count: 10,
+        exported_flow_record_total_count: 200,
+        exported_octet_total_count: 30000,
+    };
+    // 16 byte header, 26 byte options template set, 32 byte data set
+    let message = Message {
+        export_time: 0,
+        sequence_number: 0,
+        observation_domain_id: 1,
+        sets: [
+            Set {
+                records: Records::OptionsTemplate(vec![
+                    MeteringProcessStatistics::options_template(256),
+                ]),
+            },
+            metering.data_set(256),
+        ]
+        .into_iter()
+        .collect(),
+    };
+    let mut writer = Cursor::new(Vec::new());
+    message.write_args(&mut writer, (&templates, &formatter, options))?;
+    let bytes = writer.into_inner();
+    assert_eq!(bytes.len(), expected_length);
+
+    let read_templates = RefCell::new(HashMap::new());
+    let read_message = parse_ipfix_message(&bytes, &read_templates, &formatter)?;
+    similar_asserts::assert_eq!(expected: message, actual: read_message);
+
+    Ok(())
+}
+
+#[test]
+fn to_bytes() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let file_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let message = parse_ipfix_message(file_bytes, &templates, &formatter)?;
+
+    let bytes = message.to_bytes(&templates, &formatter, WriteOptions::default())?;
+    assert_eq!(bytes, file_bytes);
+
+    // the buffer is replaced, not appended to
+    let mut buf = vec![1, 2, 3];
+    message.write_into(&mut buf, &templates, &formatter, WriteOptions::default())?;
+    assert_eq!(buf, file_bytes);
+
+    Ok(())
+}
+
+#[test]
+fn message_writer() -> binrw::BinResult<()> {
+    let templates = RefCell::new(HashMap::new());
+    let formatter = get_default_formatter();
+    let template_bytes = include_bytes!("../resources/tests/parse_temp.bin");
+    let data_bytes = include_bytes!("../resources/tests/parse_data.bin");
+    let messages = [
+        parse_ipfix_message(template_bytes, &templates, &formatter)?,
+        parse_ipfix_message(data_bytes, &templates, &formatter)?,
+    ];
+
+    // a Vec<u8> is Write but not Seek
+    let mut writer = MessageWriter::new(vec![], &templates, &formatter);
+    for message in &messages {
+        writer.write(message)?;
+    }
+    writer.flush()?;
+    assert_eq!(
+        writer.into_inner(),
+        [&template_bytes[..], &data_bytes[..]].concat()
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_message_reader_writer() -> binrw::BinResult<()> {
+    use ipfixrw::async_io::{AsyncMessageReader, AsyncMessageWriter};
+    use std::sync::RwLock;
+
+    let formatter = get_default_formatter();
+    let templates = RefCell::new(HashMap::new());
+    let messages = [
+        parse_ipfix_message(
+            include_bytes!("../resources/tests/parse_temp.bin"),
+            &templates,
+            &formatter,
+        )?,
+        parse_ipfix_message(
+            include_bytes!("../resources/tests/parse_data.bin"),
+            &templates,
+            &formatter,
+        )?,
+    ];
+
+    let write_templates = RwLock::new(HashMap::new());
+    let mut writer = AsyncMessageWriter::new(vec![], &write_templates, &formatter);
+    for message in &messages {
+        writer.write(message).await?;
+    }
+    let bytes = writer.into_inner();
+
+    // the store is Sync, so reading can be spawned on any thread
+    let read_templates = RwLock::new(HashMap::new());
+    let mut reader = AsyncMessageReader::new(&bytes[..], &read_templates, &formatter);
+    fn assert_send<T: Send>(_: &T) {}
+    assert_send(&reader.read());
+    for message in &messages {
+        assert_eq!(reader.read().await?.as_ref(), Some(message));
+    }
+    assert_eq!(reader.read().await?, None);
+
+    Ok(())
+}
+
+#[test]
+fn file_round_trip() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let template = Set {
+        records: Records::Template(vec![TemplateRecord {
+            template_id: 256,
+            field_specifiers: [FieldSpecifier::new(None, 1, 8)].into_iter().collect(),
+        }]),
+    };
+    let data = |value| Set {
+        records: Records::Data {
+            set_id: 256,
+            data: vec![data_record! { "octetDeltaCount": U64(value) }],
+        },
+    };
+    // two Observation Domains with the same Template ID
+    let messages: Vec<_> = [1, 2]
+        .into_iter()
+        .flat_map(|observation_domain_id| {
+            [
+                Message {
+                    export_time: 100,
+                    sequence_number: 0,
+                    observation_domain_id,
+                    sets: [template.clone(), data(observation_domain_id.into())]
+                        .into_iter()
+                        .collect(),
+                },
+                Message {
+                    export_time: 110,
+                    sequence_number: 1,
+                    observation_domain_id,
+                    sets: [data(10)].into_iter().collect(),
+                },
+            ]
+        })
+        .collect();
+
+    let mut writer = FileWriter::new(vec![], formatter.clone());
+    for message in &messages[..3] {
+        writer.write(message)?;
+    }
+    // data without a template written earlier in the file is an error
+    assert!(writer
+        .write(&Message {
+            observation_domain_id: 3,
+            ..messages[1].clone()
+        })
+        .is_err());
+    let first = writer.rotate(vec![])?;
+    writer.write(&messages[3])?;
+    let second = writer.finish()?;
+
+    let read = FileReader::new(Cursor::new(first), formatter.clone())
+        .collect::<binrw::BinResult<Vec<_>>>()?;
+    assert_eq!(read[..3], messages[..3]);
+    let windows: Vec<_> = read[3..]
+        .iter()
+        .map(|message| {
+            let record = message.iter_data_records().next().unwrap();
+            (
+                message.observation_domain_id,
+                message.sequence_number,
+                TimeWindow::from_record(record),
+            )
+        })
+        .collect();
+    assert_eq!(
+        windows,
+        [
+            (
+                1,
+                2,
+                Some(TimeWindow {
+                    min_export_seconds: 100,
+                    max_export_seconds: 110,
+                })
+            ),
+            (
+                2,
+                1,
+                Some(TimeWindow {
+                    min_export_seconds: 100,
+                    max_export_seconds: 100,
+                })
+            ),
+        ]
+    );
+
+    // the second file starts with the templates, so it reads on its own
+    let read =
+        FileReader::new(Cursor::new(second), formatter).collect::<binrw::BinResult<Vec<_>>>()?;
+    for message in &read[..2] {
+        assert_eq!(message.sets.len(), 2);
+        assert_eq!(message.iter_data_records().count(), 0);
+    }
+    assert_eq!(read[2], messages[3]);
+    assert_eq!(read.len(), 4);
+    Ok(())
+}
+
+#[test]
+fn file_session_details() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let export_session = ExportSessionDetails {
+        exporter: "192.0.2.1:4739".parse().unwrap(),
+        collector: "192.0.2.2:4739".parse().unwrap(),
+        transport_protocol: 17,
+    };
+    let message_details = MessageDetails {
+        collection_time_milliseconds: 100_500,
+        exporter: "[2001:db8::1]:4739".parse().unwrap(),
+        collector: "[2001:db8::2]:4739".parse().unwrap(),
+    };
+    let message = Message {
+        export_time: 100,
+        sequence_number: 0,
+        observation_domain_id: 1,
+        sets: [Set {
+            records: Records::Template(vec![TemplateRecord {
+                template_id: 256,
+                field_specifiers: [FieldSpecifier::new(None, 1, 8)].into_iter().collect(),
+            }]),
+        }]
+        .into_iter()
+        .collect(),
+    };
+
+    let mut writer = FileWriter::new(vec![], formatter.clone());
+    writer.export_session(1, export_session);
+    writer.write_with_details(&message, message_details)?;
+    writer.write_with_details(&message, message_details)?;
+    let file = writer.finish()?;
+
+    let read =
+        FileReader::new(Cursor::new(file), formatter).collect::<binrw::BinResult<Vec<_>>>()?;
+    assert_eq!(read.len(), 3);
+    // the Message Details template is only defined once
+    assert_eq!(read[0].sets.len(), 3);
+    assert_eq!(read[1].sets.len(), 2);
+    for message in &read[..2] {
+        let records: Vec<_> = message.iter_data_records().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            MessageDetails::from_record(records[0]),
+            Some(message_details)
+        );
+    }
+    let records: Vec<_> = read[2].iter_data_records().collect();
+    assert_eq!(
+        TimeWindow::from_record(records[0]),
+        Some(TimeWindow {
+            min_export_seconds: 100,
+            max_export_seconds: 100,
+        })
+    );
+    assert_eq!(
+        ExportSessionDetails::from_record(records[1]),
+        Some(export_session)
+    );
+    Ok(())
+}
+
+#[test]
+fn mediator() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let mut mediator = Mediator::new(Exporter::new(9, formatter.clone()));
+    let template = |template_id, ie| Set {
+        records: Records::Template(vec![TemplateRecord {
+            template_id,
+            field_specifiers: [FieldSpecifier::new(None, ie, 8)].into_iter().collect(),
+        }]),
+    };
+    let message = |observation_domain_id, sets: Vec<Set>| Message {
+        export_time: 0,
+        sequence_number: 0,
+        observation_domain_id,
+        sets: sets.into_iter().collect(),
+    };
+    let octets = Set {
+        records: Records::Data {
+            set_id: 256,
+            data: vec![data_record! { "octetDeltaCount": U64(1) }],
+        },
+    };
+
+    // the same upstream Template ID from two peers, with different fields
+    mediator
+        .handle(
+            "a",
+            &message(1, vec![template(256, 1), template(257, 2), octets.clone()]),
+        )
+        .unwrap();
+    mediator
+        .handle(
+            "b",
+            &message(
+                1,
+                vec![
+                    template(256, 2),
+                    Set {
+                        records: Records::RawData {
+                            set_id: 256,
+                            bytes: 5u64.to_be_bytes().to_vec(),
+                        },
+                    },
+                ],
+            ),
+        )
+        .unwrap();
+    assert_eq!(mediator.template_id(&"a", 1, 256), Some(256));
+    assert_eq!(mediator.template_id(&"a", 1, 257), Some(257));
+    // identical templates share a downstream template
+    assert_eq!(mediator.template_id(&"b", 1, 256), Some(257));
+    assert!(matches!(
+        mediator.handle("b", &message(2, vec![octets.clone()])),
+        Err(IpfixError::MissingTemplate(256))
+    ));
+
+    let messages = mediator.flush()?;
+    assert_eq!(messages.len(), 1);
+    // the raw set's record counts towards the Sequence Number
+    assert_eq!(mediator.exporter().session().sequence_number(), 2);
+    let bytes = messages[0].to_bytes(
+        mediator.exporter().templates(),
+        &formatter,
+        WriteOptions::default(),
+    )?;
+    let templates = RefCell::new(HashMap::new());
+    let parsed = parse_ipfix_message(&bytes, &templates, &formatter)?;
+    assert_eq!(parsed.observation_domain_id, 9);
+    assert_eq!(
+        parsed.iter_data_records().cloned().collect::<Vec<_>>(),
+        [
+            data_record! { "octetDeltaCount": U64(1) },
+            data_record! { "packetDeltaCount": U64(5) },
+        ]
+    );
+
+    // downstream templates are withdrawn once no upstream one uses them
+    mediator
+        .handle(
+            "a",
+            &message(
+                1,
+                vec![Set {
+                    records: Records::template_withdrawal([257]),
+                }],
+            ),
+        )
+        .unwrap();
+    assert_eq!(mediator.template_id(&"a", 1, 257), None);
+    assert_eq!(mediator.flush()?, []);
+    mediator.remove_peer(&"b");
+    let messages = mediator.flush()?;
+    assert_eq!(
+        messages[0].sets[..],
+        [Set {
+            records: Records::template_withdrawal([257]),
+        }]
+    );
+    mediator.remove_peer(&"a");
+    assert_eq!(
+        mediator.flush()?[0].sets[..],
+        [Set {
+            records: Records::template_withdrawal([256]),
+        }]
+    );
+
+    // an options template without scope fields isn't a withdrawal
+    let options_template = OptionsTemplateRecord {
+        template_id: 300,
+        scope_field_count: 0,
+        field_specifiers: [FieldSpecifier::new(None, 1, 8)].into_iter().collect(),
+    };
+    mediator
+        .handle(
+            "c",
+            &message(
+                1,
+                vec![Set {
+                    records: Records::OptionsTemplate(vec![options_template.clone()]),
+                }],
+            ),
+        )
+        .unwrap();
+    let template_id = mediator.template_id(&"c", 1, 300).unwrap();
+    assert_eq!(
+        mediator.flush()?[0].sets[..],
+        [Set {
+            records: Records::OptionsTemplate(vec![OptionsTemplateRecord {
+                template_id,
+                ..options_template
+            }]),
+        }]
+    );
+    Ok(())
+}
+
+#[test]
+fn batch_writer() -> binrw::BinResult<()> {
+    let formatter = get_default_formatter();
+    let mut exporter = Exporter::new(1, formatter.clone());
+    let octets = exporter
+        .add_template(vec![FieldSpecifier::new(None, 1, 8)])
+        .unwrap();
+    let packets = exporter
+        .add_template(vec![FieldSpecifier::new(None, 2, 8)])
+        .unwrap();
+    let sent = RefCell::new(vec![]);
+    let mut writer = BatchWriter::new(exporter, |bytes: &[u8]| {
+        sent.borrow_mut().push(bytes.to_vec());
+        Ok(())
+    })
+    .max_records(4)
+    .max_delay(Duration::from_millis(100))
+    .packets_per_second(2)
+    .template_refresh(Duration::from_secs(10));
+    assert!(writer
+        .push(300, data_record! { "octetDeltaCount": U64(0) })
+        .is_err());
+
+    // records are grouped by template, and flushed at max_records
+    let start = Instant::now();
+    for i in 0..4 {
+        let (template_id, record) = if i % 2 == 0 {
+            (octets, data_record! { "octetDeltaCount": U64(i) })
+        } else {
+            (packets, data_record! { "packetDeltaCount": U64(i) })
+        };
+        writer.push_at(template_id, record, start)?;
+    }
+    assert_eq!(writer.pending_records(), 0);
+    assert_eq!(writer.queued_messages(), 0);
+
+    // or once the oldest has waited max_delay
+    writer.push_at(octets, data_record! { "octetDeltaCount": U64(4) }, start)?;
+    writer.poll_at(start + Duration::from_millis(50))?;
+    assert_eq!(writer.pending_records(), 1);
+    assert_eq!(
+        writer.next_deadline(),
+        Some(start + Duration::from_millis(100))
+    );
+    writer.poll_at(start + Duration::from_millis(100))?;
+    assert_eq!(writer.pending_records(), 0);
+    assert_eq!(writer.queued_messages(), 0);
+
+    // the burst of 2 messages is used up, so the next waits for the budget
+    for i in 0..4 {
+        let (template_id, record) = if i % 2 == 0 {
+            (octets, data_record! { "octetDeltaCount": U64(i) })
+        } else {
+            (packets, data_record! { "packetDeltaCount": U64(i) })
+        };
+        writer.push_at(template_id, record, start + Duration::from_millis(100))?;
+    }
+    assert_eq!(writer.queued_messages(), 1);
+    let deadline = writer.next_deadline().unwrap();
+    assert!(deadline > start + Duration::from_millis(100));
+    writer.poll_at(deadline)?;
+    assert_eq!(writer.queued_messages(), 0);
+
+    // templates are refreshed without any data
+    writer.poll_at(start + Duration::from_secs(10))?;
+    writer.finish()?;
+    let templates = RefCell::new(HashMap::new());
+    let messages = sent
+        .borrow()
+        .iter()
+        .map(|bytes| parse_ipfix_message(bytes, &templates, &formatter))
+        .collect::<binrw::BinResult<Vec<_>>>()?;
+    let set_ids: Vec<Vec<u16>> = messages
+        .iter()
+        .map(|message| {
+            message
+                .sets
+                .iter()
+                .map(|set| match &set.records {
+                    Records::Template(_) => 2,
+                    Records::Data { set_id, .. } => *set_id,
+                    _ => 0,
+                })
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        set_ids,
+        [vec![2, 256, 257], vec![256], vec![256, 257], vec![2]]
+    );
+    Ok(())
+}
+
+#[test]
+fn batch_writer_queue_limit() -> binrw::BinResult<()> {
+    let mut exporter = Exporter::new(1, get_default_formatter());
+    let octets = exporter
+        .add_template(vec![FieldSpecifier::new(None, 1, 8)])
+        .unwrap();
+    let fail = std::cell::Cell::new(true);
+    let sent = std::cell::Cell::new(0);
+    let mut writer = BatchWriter::new(exporter, |_: &[u8]| {
+        if fail.get() {
+            return Err(std::io::ErrorKind::ConnectionRefused.into());
+        }
+        sent.set(sent.get() + 1);
+        Ok(())
+    })
+    .max_records(1)
+    .packets_per_second(1)
+    .max_queued_messages(1);
+    let start = Instant::now();
+    let record = |i| data_record! { "octetDeltaCount": U64(i) };
+
+    // a failed send keeps the message, and the budget
+    assert!(writer.push_at(octets, record(0), start).is_err());
+    assert_eq!(writer.queued_messages(), 1);
+    fail.set(false);
+    writer.push_at(octets, record(1), start)?;
+    assert_eq!(sent.get(), 1);
+    assert_eq!(writer.queued_messages(), 1);
 
+    // a full queue refuses records until the budget allows a send
+    let err = writer.push_at(octets, record(2), start).unwrap_err();
+    assert!(matches!(err, binrw::Error::Io(e) if e.kind() == std::io::ErrorKind::WouldBlock));
+    assert_eq!(writer.pending_records(), 0);
+    writer.poll_at(start + Duration::from_secs(1))?;
+    assert_eq!(writer.queued_messages(), 0);
+    writer.push_at(octets, record(2), start + Duration::from_secs(1))?;
+    assert_eq!(writer.queued_messages(), 1);
     Ok(())
 }